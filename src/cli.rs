@@ -0,0 +1,415 @@
+use std::{ffi::OsString, path::PathBuf};
+
+use fuser::MountOption;
+
+use crate::invariants::{violation::ViolationPolicy, Checks};
+
+pub const USAGE: &str = "\
+Usage: posix-invariant-checker [OPTIONS] <BASE> <MOUNTPOINT>
+       posix-invariant-checker fsck [FSCK OPTIONS] <BASE>
+
+Options:
+  -o <OPT>[,<OPT>...]     Mount options: ro, rw, fsname=<NAME>, subtype=<NAME>,
+                          allow_other, default_permissions, auto_unmount, ...
+      --no-allow-other    Don't pass allow_other (on by default)
+      --no-auto-unmount   Don't pass auto_unmount (on by default)
+      --snapshot <FILE>   File the model is persisted in [default: fs.snapshot]
+      --log <FILE>        Write the event log to FILE instead of stdout
+      --log-format <FMT>  text or json [default: text]
+      --verbosity <N>     0 = violations, 1 = calls, 2 = notes, 3 = permissions [default: 3]
+  -q, --quiet             Same as --verbosity 0
+      --policy <POLICY>   On violation: panic, log or eio [default: panic]
+      --checks <LIST>     Parts of the model to check: meta, dirs, data, xattr, or
+                          all / none. all is all but xattr, and the rest imply
+                          meta [default: all]
+      --checks-file <FILE>
+                          On SIGUSR1, switch to the checks listed in FILE, in the
+                          same form as --checks
+      --threads <N>       Serve requests on N worker threads [default: 1]
+  -h, --help              Print this help
+
+fsck compares BASE to the snapshot without mounting it. Fsck options:
+      --snapshot <FILE>   Snapshot to compare against [default: fs.snapshot]
+      --checks <LIST>     Parts of the model to compare [default: all in the snapshot]
+      --durable           Check only what was fsynced before a crash, using the
+                          durability record next to the snapshot
+      --json              Print one JSON object per difference
+
+Exit status is 0 if no violations (or differences) were found, 1 if there were, 2 on error.
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CliError {
+    Help,
+    Invalid(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub base: PathBuf,
+    pub mountpoint: PathBuf,
+    pub mount_options: Vec<MountOption>,
+    pub snapshot: PathBuf,
+    pub log_path: Option<PathBuf>,
+    pub log_format: LogFormat,
+    pub verbosity: u8,
+    pub policy: ViolationPolicy,
+    pub checks: Checks,
+    pub checks_file: Option<PathBuf>,
+    pub threads: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FsckOptions {
+    pub base: PathBuf,
+    pub snapshot: PathBuf,
+    pub checks: Option<Checks>,
+    pub durable: bool,
+    pub json: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Mount(Options),
+    Fsck(FsckOptions),
+}
+
+fn mount_option(opt: &str) -> MountOption {
+    match opt.split_once('=') {
+        Some(("fsname", v)) => MountOption::FSName(v.to_owned()),
+        Some(("subtype", v)) => MountOption::Subtype(v.to_owned()),
+        _ => match opt {
+            "ro" => MountOption::RO,
+            "rw" => MountOption::RW,
+            "allow_other" => MountOption::AllowOther,
+            "allow_root" => MountOption::AllowRoot,
+            "auto_unmount" => MountOption::AutoUnmount,
+            "default_permissions" => MountOption::DefaultPermissions,
+            "dev" => MountOption::Dev,
+            "nodev" => MountOption::NoDev,
+            "suid" => MountOption::Suid,
+            "nosuid" => MountOption::NoSuid,
+            "exec" => MountOption::Exec,
+            "noexec" => MountOption::NoExec,
+            "atime" => MountOption::Atime,
+            "noatime" => MountOption::NoAtime,
+            "dirsync" => MountOption::DirSync,
+            "sync" => MountOption::Sync,
+            "async" => MountOption::Async,
+            v => MountOption::CUSTOM(v.to_owned()),
+        },
+    }
+}
+
+impl Options {
+    pub fn parse<I: IntoIterator<Item = OsString>>(args: I) -> Result<Self, CliError> {
+        let mut args = args.into_iter();
+        let mut positional = vec![];
+        let mut mount_options = vec![];
+        let mut allow_other = true;
+        let mut auto_unmount = true;
+        let mut snapshot = PathBuf::from("fs.snapshot");
+        let mut log_path = None;
+        let mut log_format = LogFormat::Text;
+        let mut verbosity = 3;
+        let mut policy = ViolationPolicy::Panic;
+        let mut checks = Checks::default();
+        let mut checks_file = None;
+        let mut threads = 1;
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| CliError::Invalid(format!("{} requires a value", name)))
+            };
+            match arg.to_str() {
+                Some("-h" | "--help") => return Err(CliError::Help),
+                Some("-o") => {
+                    let v = value("-o")?;
+                    let v = v
+                        .to_str()
+                        .ok_or_else(|| CliError::Invalid(String::from("-o must be UTF-8")))?;
+                    mount_options.extend(v.split(',').filter(|x| !x.is_empty()).map(mount_option));
+                }
+                Some("--no-allow-other") => allow_other = false,
+                Some("--no-auto-unmount") => auto_unmount = false,
+                Some("--snapshot") => snapshot = value("--snapshot")?.into(),
+                Some("--log") => log_path = Some(value("--log")?.into()),
+                Some("--log-format") => {
+                    log_format = match value("--log-format")?.to_str() {
+                        Some("text") => LogFormat::Text,
+                        Some("json") => LogFormat::Json,
+                        v => return Err(CliError::Invalid(format!("Unknown log format {:?}", v))),
+                    }
+                }
+                Some("--verbosity") => {
+                    verbosity = value("--verbosity")?
+                        .to_str()
+                        .and_then(|x| x.parse().ok())
+                        .filter(|x| *x <= 3)
+                        .ok_or_else(|| {
+                            CliError::Invalid(String::from("--verbosity must be 0 to 3"))
+                        })?
+                }
+                Some("-q" | "--quiet") => verbosity = 0,
+                Some("--policy") => {
+                    policy = match value("--policy")?.to_str() {
+                        Some("panic") => ViolationPolicy::Panic,
+                        Some("log") => ViolationPolicy::Log,
+                        Some("eio") => ViolationPolicy::Eio,
+                        v => return Err(CliError::Invalid(format!("Unknown policy {:?}", v))),
+                    }
+                }
+                Some("--checks") => {
+                    checks = value("--checks")?
+                        .to_str()
+                        .ok_or_else(|| CliError::Invalid(String::from("--checks must be UTF-8")))
+                        .and_then(|x| Checks::parse(x).map_err(CliError::Invalid))?
+                }
+                Some("--checks-file") => checks_file = Some(value("--checks-file")?.into()),
+                Some("--threads") => {
+                    threads = value("--threads")?
+                        .to_str()
+                        .and_then(|x| x.parse().ok())
+                        .filter(|x| *x > 0)
+                        .ok_or_else(|| {
+                            CliError::Invalid(String::from("--threads must be a positive number"))
+                        })?
+                }
+                Some(v) if v.starts_with('-') => {
+                    return Err(CliError::Invalid(format!("Unknown option {}", v)))
+                }
+                _ => positional.push(PathBuf::from(arg)),
+            }
+        }
+
+        if allow_other && !mount_options.contains(&MountOption::AllowOther) {
+            mount_options.push(MountOption::AllowOther);
+        }
+        if auto_unmount && !mount_options.contains(&MountOption::AutoUnmount) {
+            mount_options.push(MountOption::AutoUnmount);
+        }
+
+        let mut positional = positional.into_iter();
+        match (positional.next(), positional.next(), positional.next()) {
+            (Some(base), Some(mountpoint), None) => Ok(Self {
+                base,
+                mountpoint,
+                mount_options,
+                snapshot,
+                log_path,
+                log_format,
+                verbosity,
+                policy,
+                checks,
+                checks_file,
+                threads,
+            }),
+            _ => Err(CliError::Invalid(String::from(
+                "Expected exactly two arguments: <BASE> <MOUNTPOINT>",
+            ))),
+        }
+    }
+}
+
+impl FsckOptions {
+    pub fn parse<I: IntoIterator<Item = OsString>>(args: I) -> Result<Self, CliError> {
+        let mut args = args.into_iter();
+        let mut positional = vec![];
+        let mut snapshot = PathBuf::from("fs.snapshot");
+        let mut checks = None;
+        let mut durable = false;
+        let mut json = false;
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| CliError::Invalid(format!("{} requires a value", name)))
+            };
+            match arg.to_str() {
+                Some("-h" | "--help") => return Err(CliError::Help),
+                Some("--snapshot") => snapshot = value("--snapshot")?.into(),
+                Some("--checks") => {
+                    checks = Some(
+                        value("--checks")?
+                            .to_str()
+                            .ok_or_else(|| {
+                                CliError::Invalid(String::from("--checks must be UTF-8"))
+                            })
+                            .and_then(|x| Checks::parse(x).map_err(CliError::Invalid))?,
+                    )
+                }
+                Some("--durable") => durable = true,
+                Some("--json") => json = true,
+                Some(v) if v.starts_with('-') => {
+                    return Err(CliError::Invalid(format!("Unknown option {}", v)))
+                }
+                _ => positional.push(PathBuf::from(arg)),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        match (positional.next(), positional.next()) {
+            (Some(base), None) => Ok(Self {
+                base,
+                snapshot,
+                checks,
+                durable,
+                json,
+            }),
+            _ => Err(CliError::Invalid(String::from(
+                "Expected exactly one argument: <BASE>",
+            ))),
+        }
+    }
+}
+
+impl Command {
+    pub fn parse<I: IntoIterator<Item = OsString>>(args: I) -> Result<Self, CliError> {
+        let mut args = args.into_iter().peekable();
+        if args.peek().and_then(|x| x.to_str()) == Some("fsck") {
+            args.next();
+            FsckOptions::parse(args).map(Command::Fsck)
+        } else {
+            Options::parse(args).map(Command::Mount)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, path::PathBuf};
+
+    use fuser::MountOption;
+
+    use super::{CliError, Command, FsckOptions, LogFormat, Options};
+    use crate::invariants::{violation::ViolationPolicy, Checks};
+
+    fn parse(args: &[&str]) -> Result<Options, CliError> {
+        Options::parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(
+            parse(&["base", "mnt"]),
+            Ok(Options {
+                base: PathBuf::from("base"),
+                mountpoint: PathBuf::from("mnt"),
+                mount_options: vec![MountOption::AllowOther, MountOption::AutoUnmount],
+                snapshot: PathBuf::from("fs.snapshot"),
+                log_path: None,
+                log_format: LogFormat::Text,
+                verbosity: 3,
+                policy: ViolationPolicy::Panic,
+                checks: Checks::default(),
+                checks_file: None,
+                threads: 1,
+            })
+        )
+    }
+
+    #[test]
+    fn test_options() {
+        let opts = parse(&[
+            "-o",
+            "ro,fsname=foo,subtype=bar",
+            "--no-allow-other",
+            "--no-auto-unmount",
+            "--snapshot",
+            "/tmp/state/fs.snapshot",
+            "--log",
+            "log.jsonl",
+            "--log-format",
+            "json",
+            "-q",
+            "--policy",
+            "eio",
+            "--checks",
+            "data",
+            "--checks-file",
+            "checks",
+            "--threads",
+            "4",
+            "base",
+            "mnt",
+        ])
+        .unwrap();
+        assert_eq!(
+            opts.mount_options,
+            vec![
+                MountOption::RO,
+                MountOption::FSName(String::from("foo")),
+                MountOption::Subtype(String::from("bar"))
+            ]
+        );
+        assert_eq!(opts.snapshot, PathBuf::from("/tmp/state/fs.snapshot"));
+        assert_eq!(opts.log_path, Some(PathBuf::from("log.jsonl")));
+        assert_eq!(opts.log_format, LogFormat::Json);
+        assert_eq!(opts.verbosity, 0);
+        assert_eq!(opts.policy, ViolationPolicy::Eio);
+        assert_eq!(opts.checks_file, Some(PathBuf::from("checks")));
+        assert_eq!(opts.threads, 4);
+        assert_eq!(
+            opts.checks,
+            Checks {
+                meta: true,
+                dirs: false,
+                data: true,
+                xattr: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_missing_mountpoint() {
+        assert!(matches!(parse(&["base"]), Err(CliError::Invalid(_))))
+    }
+
+    #[test]
+    fn test_missing_value() {
+        assert!(matches!(
+            parse(&["base", "mnt", "--policy"]),
+            Err(CliError::Invalid(_))
+        ))
+    }
+
+    #[test]
+    fn test_bad_policy() {
+        assert!(matches!(
+            parse(&["--policy", "ignore", "base", "mnt"]),
+            Err(CliError::Invalid(_))
+        ))
+    }
+
+    #[test]
+    fn test_fsck() {
+        let args = |args: &[&str]| Command::parse(args.iter().map(OsString::from));
+        assert_eq!(
+            args(&["fsck", "--checks", "meta", "--json", "base"]),
+            Ok(Command::Fsck(FsckOptions {
+                base: PathBuf::from("base"),
+                snapshot: PathBuf::from("fs.snapshot"),
+                checks: Some(Checks {
+                    meta: true,
+                    dirs: false,
+                    data: false,
+                    xattr: false
+                }),
+                durable: false,
+                json: true,
+            }))
+        );
+        assert!(matches!(
+            args(&["fsck", "base", "mnt"]),
+            Err(CliError::Invalid(_))
+        ));
+        assert!(matches!(args(&["base", "mnt"]), Ok(Command::Mount(_))));
+    }
+}
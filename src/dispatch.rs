@@ -0,0 +1,760 @@
+use std::{
+    ffi::OsStr,
+    path::Path,
+    sync::{mpsc, Arc, Mutex},
+    thread::JoinHandle,
+};
+
+use fuser::Filesystem;
+use libc::c_int;
+
+use crate::{
+    fs::InvFS,
+    req_rep::{
+        KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus,
+        ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek, ReplyOpen, ReplyWrite,
+        ReplyXattr, Request,
+    },
+};
+
+type Job = Box<dyn FnOnce(&InvFS) + Send>;
+
+/// Serves requests for an [`InvFS`] on a pool of worker threads,
+/// so one slow backend call doesn't hold up the rest of the mount.
+pub struct Dispatcher {
+    fs: Arc<InvFS>,
+    jobs: Option<mpsc::Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Dispatcher {
+    pub fn new(fs: InvFS, threads: usize) -> Self {
+        let fs = Arc::new(fs);
+        let (jobs, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        let workers = (0..threads.max(1))
+            .map(|i| {
+                let fs = fs.clone();
+                let rx = rx.clone();
+                std::thread::Builder::new()
+                    .name(format!("worker-{}", i))
+                    .spawn(move || loop {
+                        let job = match rx.lock().unwrap().recv() {
+                            Ok(job) => job,
+                            Err(_) => break,
+                        };
+                        run(&fs, job);
+                    })
+                    .expect("Failed to spawn worker thread")
+            })
+            .collect();
+        Self {
+            fs,
+            jobs: Some(jobs),
+            workers,
+        }
+    }
+
+    /// The filesystem the workers serve, for changing it while the session runs.
+    pub fn fs(&self) -> Arc<InvFS> {
+        self.fs.clone()
+    }
+
+    fn spawn(&self, job: impl FnOnce(&InvFS) + Send + 'static) {
+        self.jobs
+            .as_ref()
+            .expect("Dispatcher already shut down")
+            .send(Box::new(job))
+            .expect("All worker threads exited");
+    }
+
+    /// Like [`Dispatcher::spawn`], for a lock request. One that may `sleep` until another request
+    /// unlocks gets a thread of its own: waiting on a worker could leave none to run the unlock.
+    fn spawn_lock(&self, sleep: bool, job: impl FnOnce(&InvFS) + Send + 'static) {
+        if !sleep {
+            return self.spawn(job);
+        }
+        let fs = self.fs.clone();
+        std::thread::Builder::new()
+            .name(String::from("waiter"))
+            .spawn(move || run(&fs, Box::new(job)))
+            .expect("Failed to spawn waiter thread");
+    }
+}
+
+fn run(fs: &InvFS, job: Job) {
+    // A panicking worker would leave its request unanswered and the mount hung,
+    // so take the whole daemon down like the single-threaded loop did.
+    if std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| job(fs))).is_err() {
+        std::process::exit(101);
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl Filesystem for Dispatcher {
+    fn init(
+        &mut self,
+        req: &fuser::Request<'_>,
+        config: &mut fuser::KernelConfig,
+    ) -> Result<(), c_int> {
+        let config = KernelConfig::new(config);
+        self.fs.do_init(req.into(), &config)
+    }
+
+    fn destroy(&mut self) {
+        self.jobs = None;
+        for w in self.workers.drain(..) {
+            _ = w.join();
+        }
+        self.fs.do_destroy()
+    }
+
+    fn lookup(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        let req = Request::from(req);
+        let name = name.to_owned();
+        self.spawn(move |fs| {
+            let rep = ReplyEntry::new();
+            fs.do_lookup(req, parent, &name, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn forget(&mut self, req: &fuser::Request<'_>, ino: u64, nlookup: u64) {
+        self.fs.do_forget(req.into(), ino, nlookup)
+    }
+
+    fn getattr(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
+        let req = Request::from(req);
+        self.spawn(move |fs| {
+            let rep = ReplyAttr::new();
+            fs.do_getattr(req, ino, &rep);
+            rep.reply(reply)
+        })
+    }
+
+    fn setattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
+        ctime: Option<std::time::SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<std::time::SystemTime>,
+        chgtime: Option<std::time::SystemTime>,
+        bkuptime: Option<std::time::SystemTime>,
+        flags: Option<u32>,
+        reply: fuser::ReplyAttr,
+    ) {
+        let req = Request::from(req);
+        self.spawn(move |fs| {
+            let rep = ReplyAttr::new();
+            fs.do_setattr(
+                req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
+                flags, &rep,
+            );
+            rep.reply(reply);
+        })
+    }
+
+    fn readlink(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        let req = Request::from(req);
+        self.spawn(move |fs| fs.do_readlink(req, ino, reply))
+    }
+
+    fn mknod(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        let req = Request::from(req);
+        let name = name.to_owned();
+        self.spawn(move |fs| {
+            let rep = ReplyEntry::new();
+            fs.do_mknod(req, parent, &name, mode, umask, rdev, &rep);
+            rep.reply(reply)
+        })
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        let req = Request::from(req);
+        let name = name.to_owned();
+        self.spawn(move |fs| {
+            let rep = ReplyEntry::new();
+            fs.do_mkdir(req, parent, &name, mode, umask, &rep);
+            rep.reply(reply)
+        })
+    }
+
+    fn unlink(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let req = Request::from(req);
+        let name = name.to_owned();
+        self.spawn(move |fs| {
+            let rep = ReplyEmpty::new();
+            fs.do_unlink(req, parent, &name, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn rmdir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let req = Request::from(req);
+        let name = name.to_owned();
+        self.spawn(move |fs| {
+            let rep = ReplyEmpty::new();
+            fs.do_rmdir(req, parent, &name, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn symlink(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: fuser::ReplyEntry,
+    ) {
+        let req = Request::from(req);
+        let name = name.to_owned();
+        let link = link.to_owned();
+        self.spawn(move |fs| {
+            let rep = ReplyEntry::new();
+            fs.do_symlink(req, parent, &name, &link, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn rename(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let req = Request::from(req);
+        let name = name.to_owned();
+        let newname = newname.to_owned();
+        self.spawn(move |fs| {
+            let rep = ReplyEmpty::new();
+            fs.do_rename(req, parent, &name, newparent, &newname, flags, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn link(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        let req = Request::from(req);
+        let newname = newname.to_owned();
+        self.spawn(move |fs| {
+            let rep = ReplyEntry::new();
+            fs.do_link(req, ino, newparent, &newname, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn open(&mut self, req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        let req = Request::from(req);
+        self.spawn(move |fs| {
+            let rep = ReplyOpen::new();
+            fs.do_open(req, ino, flags, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn read(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: fuser::ReplyData,
+    ) {
+        let req = Request::from(req);
+        self.spawn(move |fs| {
+            let rep = ReplyData::new();
+            fs.do_read(req, ino, fh, offset, size, flags, lock_owner, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn write(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: fuser::ReplyWrite,
+    ) {
+        let req = Request::from(req);
+        let data = data.to_vec();
+        self.spawn(move |fs| {
+            let rep = ReplyWrite::new();
+            fs.do_write(
+                req,
+                ino,
+                fh,
+                offset,
+                &data,
+                write_flags,
+                flags,
+                lock_owner,
+                &rep,
+            );
+            rep.reply(reply);
+        })
+    }
+
+    fn flush(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let req = Request::from(req);
+        self.spawn(move |fs| fs.do_flush(req, ino, fh, lock_owner, reply))
+    }
+
+    fn release(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let req = Request::from(req);
+        self.spawn(move |fs| {
+            let rep = ReplyEmpty::new();
+            fs.do_release(req, ino, fh, flags, lock_owner, flush, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn fsync(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let req = Request::from(req);
+        self.spawn(move |fs| {
+            let rep = ReplyEmpty::new();
+            fs.do_fsync(req, ino, fh, datasync, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn opendir(&mut self, req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        let req = Request::from(req);
+        self.spawn(move |fs| {
+            let rep = ReplyOpen::new();
+            fs.do_opendir(req, ino, flags, &rep);
+            rep.reply(reply)
+        })
+    }
+
+    fn readdir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: fuser::ReplyDirectory,
+    ) {
+        let req = Request::from(req);
+        self.spawn(move |fs| {
+            let rep = ReplyDirectory::new();
+            fs.do_readdir(req, ino, fh, offset, &rep);
+            rep.reply(reply)
+        })
+    }
+
+    fn readdirplus(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: fuser::ReplyDirectoryPlus,
+    ) {
+        let req = Request::from(req);
+        self.spawn(move |fs| {
+            let rep = ReplyDirectoryPlus::new();
+            fs.do_readdirplus(req, ino, fh, offset, &rep);
+            rep.reply(reply)
+        })
+    }
+
+    fn releasedir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let req = Request::from(req);
+        self.spawn(move |fs| fs.do_releasedir(req, ino, fh, flags, reply))
+    }
+
+    fn fsyncdir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let req = Request::from(req);
+        self.spawn(move |fs| fs.do_fsyncdir(req, ino, fh, datasync, reply))
+    }
+
+    fn statfs(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyStatfs) {
+        let req = Request::from(req);
+        self.spawn(move |fs| fs.do_statfs(req, ino, reply))
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let req = Request::from(req);
+        let name = name.to_owned();
+        let value = value.to_vec();
+        self.spawn(move |fs| {
+            let rep = ReplyEmpty::new();
+            fs.do_setxattr(req, ino, &name, &value, flags, position, &rep);
+            rep.reply(reply)
+        })
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        let req = Request::from(req);
+        let name = name.to_owned();
+        self.spawn(move |fs| {
+            let rep = ReplyXattr::new();
+            fs.do_getxattr(req, ino, &name, size, &rep);
+            rep.reply(reply)
+        })
+    }
+
+    fn listxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        let req = Request::from(req);
+        self.spawn(move |fs| {
+            let rep = ReplyXattr::new();
+            fs.do_listxattr(req, ino, size, &rep);
+            rep.reply(reply)
+        })
+    }
+
+    fn removexattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let req = Request::from(req);
+        let name = name.to_owned();
+        self.spawn(move |fs| {
+            let rep = ReplyEmpty::new();
+            fs.do_removexattr(req, ino, &name, &rep);
+            rep.reply(reply)
+        })
+    }
+
+    fn access(&mut self, req: &fuser::Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        let req = Request::from(req);
+        self.spawn(move |fs| {
+            let rep = ReplyEmpty::new();
+            fs.do_access(req, ino, mask, &rep);
+            rep.reply(reply)
+        })
+    }
+
+    fn create(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: fuser::ReplyCreate,
+    ) {
+        let req = Request::from(req);
+        let name = name.to_owned();
+        self.spawn(move |fs| {
+            let rep = ReplyCreate::new();
+            fs.do_create(req, parent, &name, mode, umask, flags, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn getlk(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: fuser::ReplyLock,
+    ) {
+        let req = Request::from(req);
+        self.spawn(move |fs| {
+            let rep = ReplyLock::new();
+            fs.do_getlk(req, ino, fh, lock_owner, start, end, typ, pid, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn setlk(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let req = Request::from(req);
+        // BSD locks would arrive here too, told apart only by `FUSE_LK_FLOCK`, which fuser
+        // doesn't pass on. So `FUSE_FLOCK_LOCKS` isn't asked for, and the kernel keeps them to
+        // itself: checking them has to wait for fuser.
+        self.spawn_lock(sleep, move |fs| {
+            let rep = ReplyEmpty::new();
+            fs.do_setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn bmap(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        blocksize: u32,
+        idx: u64,
+        reply: fuser::ReplyBmap,
+    ) {
+        let req = Request::from(req);
+        self.spawn(move |fs| fs.do_bmap(req, ino, blocksize, idx, reply))
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: fuser::ReplyIoctl,
+    ) {
+        let req = Request::from(req);
+        let in_data = in_data.to_vec();
+        self.spawn(move |fs| {
+            let rep = ReplyIoctl::new();
+            fs.do_ioctl(req, ino, fh, flags, cmd, &in_data, out_size, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn fallocate(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let req = Request::from(req);
+        self.spawn(move |fs| {
+            let rep = ReplyEmpty::new();
+            fs.do_fallocate(req, ino, fh, offset, length, mode, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn lseek(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: fuser::ReplyLseek,
+    ) {
+        let req = Request::from(req);
+        self.spawn(move |fs| {
+            let rep = ReplyLseek::new();
+            fs.do_lseek(req, ino, fh, offset, whence, &rep);
+            rep.reply(reply);
+        })
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: fuser::ReplyWrite,
+    ) {
+        let req = Request::from(req);
+        self.spawn(move |fs| {
+            let rep = ReplyWrite::new();
+            fs.do_copy_file_range(
+                req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags, &rep,
+            );
+            rep.reply(reply);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc::{self, Receiver},
+        time::Duration,
+    };
+
+    use crate::{
+        invariants::posix_locks::OFFSET_MAX,
+        req_rep::ReplyEmpty,
+        test::{create, init, open, ROOT},
+    };
+
+    use super::Dispatcher;
+
+    #[test]
+    fn test_blocking_lock() {
+        let d = Dispatcher::new(crate::test::create_ifs(), 1);
+        init(&d.fs);
+        let ino = create(&d.fs, "foo", 0o644);
+        let (fh1, fh2) = (
+            open(&d.fs, ino, libc::O_RDWR),
+            open(&d.fs, ino, libc::O_RDWR),
+        );
+        let setlk = |fh, owner: u64, typ, sleep| -> Receiver<Result<(), i32>> {
+            let (tx, rx) = mpsc::channel();
+            d.spawn_lock(sleep, move |fs| {
+                let rep = ReplyEmpty::new();
+                fs.do_setlk(
+                    ROOT,
+                    ino,
+                    fh,
+                    owner,
+                    0,
+                    OFFSET_MAX,
+                    typ,
+                    owner as u32,
+                    sleep,
+                    &rep,
+                );
+                tx.send(rep.get()).unwrap();
+            });
+            rx
+        };
+        let wait = |rx: Receiver<_>| rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert_eq!(wait(setlk(fh1, 1, libc::F_WRLCK, false)), Ok(()));
+        let waiter = setlk(fh2, 2, libc::F_WRLCK, true);
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(waiter.try_recv().is_err());
+        // The only worker is free to run the unlock, which lets the waiter through.
+        assert_eq!(wait(setlk(fh1, 1, libc::F_UNLCK, false)), Ok(()));
+        assert_eq!(wait(waiter), Ok(()));
+        assert!(d.fs.violations().is_empty());
+    }
+}
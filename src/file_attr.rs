@@ -22,6 +22,8 @@ pub struct FileAttr {
     pub gid: u32,
     pub rdev: u32,
     pub blksize: u32,
+    /// macOS `st_flags`, always 0 here. Linux inode flags aren't part of `stat`, and are
+    /// tracked separately, see [`crate::invariants::inode_flags`].
     pub flags: u32,
 }
 
@@ -1,41 +1,270 @@
 use std::{
+    cell::Cell,
     collections::BTreeMap,
     ffi::CString,
+    fs::File,
     mem::MaybeUninit,
-    os::unix::prelude::OsStrExt,
+    os::unix::prelude::{AsRawFd, OsStrExt},
     path::{Path, PathBuf},
-    sync::Mutex,
+    sync::{Arc, Mutex, MutexGuard},
     time::Duration,
 };
 
 use crate::{
-    invariants::FSData,
-    log_more,
-    logging::CallID,
-    req_rep::{
-        KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyEmpty, ReplyEntry, ReplyOpen,
-        ReplyWrite,
+    invariants::{
+        fs::init::scan_backend,
+        locks::{check_serializations, ModelLocks, Tree, WatchId},
+        perm::{caps, MODELLED_CAPS},
+        posix_locks::OFFSET_MAX,
+        violation::{Violation, ViolationPolicy},
+        Checks, FSData,
     },
+    log_more,
+    logging::{emit, CallID, Event},
 };
-use fuser::Filesystem;
 
 use libc::c_int;
 use procfs::ProcResult;
 
 const TTL: Duration = Duration::new(0, 0);
 
-#[derive(Default)]
+/// A directory stream opened by `opendir`, and the offset it is at if known. A read that carries
+/// on from there needs no `seekdir`.
+struct DirHandle(*mut libc::DIR, Option<i64>);
+
+// The stream is only ever used with the `dir_fhs` lock held.
+unsafe impl Send for DirHandle {}
+
 pub struct InvFS {
     pub(crate) root: PathBuf,
+    /// The backing directory. All backend calls resolve paths relative to this
+    /// rather than the working directory.
+    root_dir: File,
+    pub(crate) snapshot: PathBuf,
     data: Mutex<FSData>,
-    dir_fhs: BTreeMap<u64, *mut libc::DIR>,
+    /// Which parts of the model are in use. Taken before `data`, never while holding it.
+    locks: ModelLocks,
+    dir_fhs: Mutex<BTreeMap<u64, DirHandle>>,
+    /// The backend descriptors record locks are taken on, by inode and lock owner.
+    lock_fds: Mutex<BTreeMap<(u64, u64), File>>,
+    policy: ViolationPolicy,
+    violations: Arc<Mutex<Vec<Violation>>>,
 }
 
 impl InvFS {
     pub fn new(root: PathBuf) -> Self {
+        let root_dir = File::open(&root)
+            .unwrap_or_else(|e| panic!("Failed to open backing directory {:?}: {}", root, e));
         Self {
             root,
-            ..Default::default()
+            root_dir,
+            snapshot: PathBuf::from("fs.snapshot"),
+            data: Mutex::new(FSData::new()),
+            locks: ModelLocks::new(),
+            dir_fhs: Mutex::new(BTreeMap::new()),
+            lock_fds: Mutex::new(BTreeMap::new()),
+            policy: ViolationPolicy::default(),
+            violations: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn with_policy(self, policy: ViolationPolicy) -> Self {
+        Self { policy, ..self }
+    }
+
+    /// The file the model is persisted in between mounts. Defaults to `fs.snapshot`.
+    pub fn with_snapshot(self, snapshot: PathBuf) -> Self {
+        Self { snapshot, ..self }
+    }
+
+    /// Which check categories to start with. See [`InvFS::set_checks`] to change them later.
+    pub fn with_checks(mut self, checks: Checks) -> Self {
+        self.data.get_mut().unwrap().checks = checks.normalize();
+        self
+    }
+
+    pub fn checks(&self) -> Checks {
+        self.data.lock().unwrap().checks
+    }
+
+    /// Switch check categories on or off while mounted. See [`InvFS::reload_checks`].
+    /// Newly enabled categories have no model yet, so theirs is rebuilt from the backing directory.
+    pub fn set_checks(&self, checks: Checks) -> Result<(), Violation> {
+        let checks = checks.normalize();
+        let _guard = self.locks.lock(Tree::Exclusive, &[], &[]);
+        let mut dl = self.data.lock().unwrap();
+        let added = checks.added(dl.checks);
+        dl.checks = checks;
+        if !checks.meta {
+            dl.INV_INODE_CONTENTS.clear();
+            dl.INV_INODE_FLAGS.clear();
+        }
+        if !checks.dirs {
+            dl.INV_DIR_CONTENTS.clear();
+        }
+        if !checks.data {
+            dl.INV_FILE_CONTENTS.clear();
+            dl.INV_FILE_DATA.clear();
+        }
+        if !checks.xattr {
+            dl.INV_XATTR_CONTENTS.clear();
+        }
+        if added.any() {
+            scan_backend(&self.root, added, &mut dl)?;
+        }
+        Ok(())
+    }
+
+    /// Switch to the check categories listed in the file at `path`, in the form `--checks`
+    /// takes. This is how a running mount is told to change them.
+    pub fn reload_checks(&self, path: &Path) -> Result<Checks, String> {
+        let list = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+        let checks = Checks::parse(list.trim())?;
+        self.set_checks(checks).map_err(|v| v.to_string())?;
+        Ok(self.checks())
+    }
+
+    /// Every violation recorded so far under the `Log` and `Eio` policies.
+    pub fn violations(&self) -> Vec<Violation> {
+        self.violations.lock().unwrap().clone()
+    }
+
+    /// A handle on the recorded violations that outlives the filesystem,
+    /// so they can be inspected once it has been unmounted.
+    pub fn violation_log(&self) -> Arc<Mutex<Vec<Violation>>> {
+        self.violations.clone()
+    }
+
+    /// Apply the violation policy to the result of an invariant check.
+    /// Returns the result that should be handed back to the kernel.
+    pub(crate) fn handle_violation<T>(
+        &self,
+        callid: CallID,
+        op: &'static str,
+        ino: Option<u64>,
+        check: Result<(), Violation>,
+        res: Result<T, i32>,
+    ) -> Result<T, i32> {
+        self.handle_violation_with(callid, op, ino, check, res, drop)
+    }
+
+    /// Like [`InvFS::handle_violation`], for a result holding something the kernel would have
+    /// to release, such as a file descriptor. If EIO replaces it, `cleanup` releases it instead.
+    pub(crate) fn handle_violation_with<T>(
+        &self,
+        callid: CallID,
+        op: &'static str,
+        ino: Option<u64>,
+        check: Result<(), Violation>,
+        res: Result<T, i32>,
+        cleanup: impl FnOnce(T),
+    ) -> Result<T, i32> {
+        let violation = match check {
+            Ok(()) => return res,
+            Err(v) => v.context(op, callid, ino),
+        };
+        emit(Event::Violation {
+            callid,
+            violation: violation.clone(),
+        });
+        match self.policy {
+            ViolationPolicy::Panic => panic!("{}", violation),
+            ViolationPolicy::Log => {
+                self.violations.lock().unwrap().push(violation);
+                res
+            }
+            ViolationPolicy::Eio => {
+                self.violations.lock().unwrap().push(violation);
+                if let Ok(v) = res {
+                    cleanup(v);
+                }
+                Err(libc::EIO)
+            }
+        }
+    }
+
+    /// Finish checking an operation that observed `watch.ino` without locking it.
+    /// `check` is run against every state a concurrent change could have left the inode in.
+    fn check_observed(
+        &self,
+        watch: Option<WatchId>,
+        mut check: impl FnMut(&mut MutexGuard<'_, FSData>) -> Result<(), Violation>,
+    ) -> Result<(), Violation> {
+        let watch = match watch {
+            Some(v) => v,
+            None => return check(&mut self.data.lock().unwrap()),
+        };
+        let ino = watch.ino;
+        let end = {
+            let mut dl = self.data.lock().unwrap();
+            let end = self.locks.ticket();
+            dl.watches.close(&watch, end);
+            end
+        };
+        self.locks.wait_for(ino, end);
+        let mut dl = self.data.lock().unwrap();
+        let states = dl.watches.finish(watch);
+        check_serializations(&mut dl, ino, states, check)
+    }
+
+    fn root_fd(&self) -> c_int {
+        self.root_dir.as_raw_fd()
+    }
+
+    /// `path` relative to the backing directory, for the `*at()` calls on [`InvFS::root_fd`].
+    fn rel_path(&self, path: &Path) -> CString {
+        let rel = path.strip_prefix(&self.root).unwrap_or(path);
+        if rel.as_os_str().is_empty() {
+            CString::new(".").unwrap()
+        } else {
+            CString::new(rel.as_os_str().as_bytes()).unwrap()
+        }
+    }
+
+    /// `path` reached through the root fd, for calls that have no `*at()` form.
+    fn fd_path(&self, path: &Path) -> CString {
+        let mut p = format!("/proc/self/fd/{}/", self.root_fd()).into_bytes();
+        p.extend_from_slice(self.rel_path(path).as_bytes());
+        CString::new(p).unwrap()
+    }
+
+    /// The backend descriptor `lock_owner`'s record locks on `ino` are taken on, reopened from
+    /// `fh` the first time. Open file description locks belong to the description rather than
+    /// the process, so giving each owner its own makes the backend tell owners apart.
+    fn lock_fd(&self, ino: u64, fh: u64, lock_owner: u64) -> Result<c_int, i32> {
+        let mut fds = self.lock_fds.lock().unwrap();
+        if let Some(f) = fds.get(&(ino, lock_owner)) {
+            return Ok(f.as_raw_fd());
+        }
+        let path = format!("/proc/self/fd/{}", fh);
+        // A read lock needs a readable descriptor and a write lock a writable one, so get both
+        // if the file allows it.
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .or_else(|_| File::open(&path))
+            .or_else(|_| File::options().write(true).open(&path))
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        let fd = file.as_raw_fd();
+        fds.insert((ino, lock_owner), file);
+        Ok(fd)
+    }
+
+    unsafe fn stat_path(&self, tgt_path: &Path) -> Result<libc::stat, i32> {
+        let tgt = self.rel_path(tgt_path);
+        let mut buf = MaybeUninit::zeroed().assume_init();
+        let res = libc::fstatat(
+            self.root_fd(),
+            tgt.as_ptr(),
+            &mut buf,
+            libc::AT_SYMLINK_NOFOLLOW,
+        );
+        if res == 0 {
+            Ok(buf)
+        } else {
+            Err(*libc::__errno_location())
         }
     }
 }
@@ -80,485 +309,81 @@ pub mod symlink;
 pub mod unlink;
 pub mod write;
 
-#[cfg(not(tarpaulin_include))]
-impl Filesystem for InvFS {
-    fn init(
-        &mut self,
-        req: &fuser::Request<'_>,
-        config: &mut fuser::KernelConfig,
-    ) -> Result<(), c_int> {
-        let config = KernelConfig::new(config);
-        self.do_init(req.into(), &config)
-    }
-
-    fn destroy(&mut self) {
-        self.do_destroy()
-    }
-
-    fn lookup(
-        &mut self,
-        req: &fuser::Request<'_>,
-        parent: u64,
-        name: &std::ffi::OsStr,
-        reply: fuser::ReplyEntry,
-    ) {
-        let rep = ReplyEntry::new();
-        self.do_lookup(req.into(), parent, name, &rep);
-        rep.reply(reply);
-    }
-
-    fn forget(&mut self, req: &fuser::Request<'_>, ino: u64, nlookup: u64) {
-        self.do_forget(req, ino, nlookup)
-    }
-
-    fn getattr(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
-        let rep = ReplyAttr::new();
-        self.do_getattr(req.into(), ino, &rep);
-        rep.reply(reply)
-    }
-
-    fn setattr(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        mode: Option<u32>,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        size: Option<u64>,
-        atime: Option<fuser::TimeOrNow>,
-        mtime: Option<fuser::TimeOrNow>,
-        ctime: Option<std::time::SystemTime>,
-        fh: Option<u64>,
-        crtime: Option<std::time::SystemTime>,
-        chgtime: Option<std::time::SystemTime>,
-        bkuptime: Option<std::time::SystemTime>,
-        flags: Option<u32>,
-        reply: fuser::ReplyAttr,
-    ) {
-        let rep = ReplyAttr::new();
-        self.do_setattr(
-            req.into(),
-            ino,
-            mode,
-            uid,
-            gid,
-            size,
-            atime,
-            mtime,
-            ctime,
-            fh,
-            crtime,
-            chgtime,
-            bkuptime,
-            flags,
-            &rep,
-        );
-        rep.reply(reply);
-    }
-
-    fn readlink(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
-        self.do_readlink(req, ino, reply)
-    }
-
-    fn mknod(
-        &mut self,
-        req: &fuser::Request<'_>,
-        parent: u64,
-        name: &std::ffi::OsStr,
-        mode: u32,
-        umask: u32,
-        rdev: u32,
-        reply: fuser::ReplyEntry,
-    ) {
-        let rep = ReplyEntry::new();
-        self.do_mknod(req.into(), parent, name, mode, umask, rdev, &rep);
-        rep.reply(reply)
-    }
-
-    fn mkdir(
-        &mut self,
-        req: &fuser::Request<'_>,
-        parent: u64,
-        name: &std::ffi::OsStr,
-        mode: u32,
-        umask: u32,
-        reply: fuser::ReplyEntry,
-    ) {
-        let rep = ReplyEntry::new();
-        self.do_mkdir(req.into(), parent, name, mode, umask, &rep);
-        rep.reply(reply)
-    }
-
-    fn unlink(
-        &mut self,
-        req: &fuser::Request<'_>,
-        parent: u64,
-        name: &std::ffi::OsStr,
-        reply: fuser::ReplyEmpty,
-    ) {
-        let rep = ReplyEmpty::new();
-        self.do_unlink(req.into(), parent, name, &rep);
-        rep.reply(reply);
-    }
-
-    fn rmdir(
-        &mut self,
-        req: &fuser::Request<'_>,
-        parent: u64,
-        name: &std::ffi::OsStr,
-        reply: fuser::ReplyEmpty,
-    ) {
-        let rep = ReplyEmpty::new();
-        self.do_rmdir(req.into(), parent, name, &rep);
-        rep.reply(reply);
-    }
-
-    fn symlink(
-        &mut self,
-        req: &fuser::Request<'_>,
-        parent: u64,
-        name: &std::ffi::OsStr,
-        link: &std::path::Path,
-        reply: fuser::ReplyEntry,
-    ) {
-        let rep = ReplyEntry::new();
-        self.do_symlink(req.into(), parent, name, link, &rep);
-        rep.reply(reply);
-    }
-
-    fn rename(
-        &mut self,
-        req: &fuser::Request<'_>,
-        parent: u64,
-        name: &std::ffi::OsStr,
-        newparent: u64,
-        newname: &std::ffi::OsStr,
-        flags: u32,
-        reply: fuser::ReplyEmpty,
-    ) {
-        let rep = ReplyEmpty::new();
-        self.do_rename(req.into(), parent, name, newparent, newname, flags, &rep);
-        rep.reply(reply);
-    }
-
-    fn link(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        newparent: u64,
-        newname: &std::ffi::OsStr,
-        reply: fuser::ReplyEntry,
-    ) {
-        let rep = ReplyEntry::new();
-        self.do_link(req.into(), ino, newparent, newname, &rep);
-        rep.reply(reply);
-    }
-
-    fn open(&mut self, req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
-        let rep = ReplyOpen::new();
-        self.do_open(req.into(), ino, flags, &rep);
-        rep.reply(reply);
-    }
-
-    fn read(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        size: u32,
-        flags: i32,
-        lock_owner: Option<u64>,
-        reply: fuser::ReplyData,
-    ) {
-        let rep = ReplyData::new();
-        self.do_read(req.into(), ino, fh, offset, size, flags, lock_owner, &rep);
-        rep.reply(reply);
-    }
-
-    fn write(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        data: &[u8],
-        write_flags: u32,
-        flags: i32,
-        lock_owner: Option<u64>,
-        reply: fuser::ReplyWrite,
-    ) {
-        let rep = ReplyWrite::new();
-        self.do_write(
-            req.into(),
-            ino,
-            fh,
-            offset,
-            data,
-            write_flags,
-            flags,
-            lock_owner,
-            &rep,
-        );
-        rep.reply(reply);
-    }
-
-    fn flush(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        fh: u64,
-        lock_owner: u64,
-        reply: fuser::ReplyEmpty,
-    ) {
-        self.do_flush(req, ino, fh, lock_owner, reply)
-    }
-
-    fn release(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        fh: u64,
-        flags: i32,
-        lock_owner: Option<u64>,
-        flush: bool,
-        reply: fuser::ReplyEmpty,
-    ) {
-        self.do_release(req, ino, fh, flags, lock_owner, flush, reply)
-    }
-
-    fn fsync(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        fh: u64,
-        datasync: bool,
-        reply: fuser::ReplyEmpty,
-    ) {
-        self.do_fsync(req, ino, fh, datasync, reply)
-    }
-
-    fn opendir(&mut self, req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
-        self.do_opendir(req, ino, flags, reply)
-    }
-
-    fn readdir(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        reply: fuser::ReplyDirectory,
-    ) {
-        self.do_readdir(req, ino, fh, offset, reply)
-    }
-
-    fn readdirplus(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        reply: fuser::ReplyDirectoryPlus,
-    ) {
-        self.do_readdirplus(req, ino, fh, offset, reply)
-    }
-
-    fn releasedir(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        fh: u64,
-        flags: i32,
-        reply: fuser::ReplyEmpty,
-    ) {
-        self.do_releasedir(req, ino, fh, flags, reply)
-    }
-
-    fn fsyncdir(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        fh: u64,
-        datasync: bool,
-        reply: fuser::ReplyEmpty,
-    ) {
-        self.do_fsyncdir(req, ino, fh, datasync, reply)
-    }
-
-    fn statfs(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyStatfs) {
-        self.do_statfs(req, ino, reply)
-    }
-
-    fn setxattr(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        name: &std::ffi::OsStr,
-        value: &[u8],
-        flags: i32,
-        position: u32,
-        reply: fuser::ReplyEmpty,
-    ) {
-        self.do_setxattr(req, ino, name, value, flags, position, reply)
-    }
-
-    fn getxattr(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        name: &std::ffi::OsStr,
-        size: u32,
-        reply: fuser::ReplyXattr,
-    ) {
-        self.do_getxattr(req, ino, name, size, reply)
-    }
-
-    fn listxattr(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        size: u32,
-        reply: fuser::ReplyXattr,
-    ) {
-        self.do_listxattr(req, ino, size, reply)
-    }
-
-    fn removexattr(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        name: &std::ffi::OsStr,
-        reply: fuser::ReplyEmpty,
-    ) {
-        self.do_removexattr(req, ino, name, reply)
-    }
-
-    fn access(&mut self, req: &fuser::Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
-        self.do_access(req, ino, mask, reply);
-    }
-
-    fn create(
-        &mut self,
-        req: &fuser::Request<'_>,
-        parent: u64,
-        name: &std::ffi::OsStr,
-        mode: u32,
-        umask: u32,
-        flags: i32,
-        reply: fuser::ReplyCreate,
-    ) {
-        let rep = ReplyCreate::new();
-        self.do_create(req.into(), parent, name, mode, umask, flags, &rep);
-        rep.reply(reply);
-    }
+#[derive(Debug)]
+struct Ids {
+    uid: u32,
+    gid: u32,
+    gids: Vec<u32>,
+    umask: Option<u32>,
+    caps: [CapData; 2],
+}
 
-    fn getlk(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        fh: u64,
-        lock_owner: u64,
-        start: u64,
-        end: u64,
-        typ: i32,
-        pid: u32,
-        reply: fuser::ReplyLock,
-    ) {
-        self.do_getlk(req, ino, fh, lock_owner, start, end, typ, pid, reply)
-    }
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: c_int,
+}
 
-    fn setlk(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        fh: u64,
-        lock_owner: u64,
-        start: u64,
-        end: u64,
-        typ: i32,
-        pid: u32,
-        sleep: bool,
-        reply: fuser::ReplyEmpty,
-    ) {
-        self.do_setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep, reply)
-    }
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct CapData {
+    pub(crate) effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
 
-    fn bmap(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        blocksize: u32,
-        idx: u64,
-        reply: fuser::ReplyBmap,
-    ) {
-        self.do_bmap(req, ino, blocksize, idx, reply)
-    }
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
 
-    fn ioctl(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        fh: u64,
-        flags: u32,
-        cmd: u32,
-        in_data: &[u8],
-        out_size: u32,
-        reply: fuser::ReplyIoctl,
-    ) {
-        self.do_ioctl(req, ino, fh, flags, cmd, in_data, out_size, reply)
-    }
+/// The capability sets of the calling thread.
+pub(crate) unsafe fn get_thread_caps() -> [CapData; 2] {
+    let mut hdr = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    let rc = libc::syscall(libc::SYS_capget, &mut hdr, data.as_mut_ptr());
+    assert_eq!(rc, 0, "capget failed");
+    data
+}
 
-    fn fallocate(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        length: i64,
-        mode: i32,
-        reply: fuser::ReplyEmpty,
-    ) {
-        self.do_fallocate(req, ino, fh, offset, length, mode, reply)
-    }
+/// Set the capability sets of the calling thread only.
+pub(crate) unsafe fn set_thread_caps(data: &[CapData; 2]) -> c_int {
+    let mut hdr = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    libc::syscall(libc::SYS_capset, &mut hdr, data.as_ptr()) as c_int
+}
 
-    fn lseek(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        whence: i32,
-        reply: fuser::ReplyLseek,
-    ) {
-        self.do_lseek(req, ino, fh, offset, whence, reply)
-    }
+/// A lock request for the inclusive range `[start, end]` the kernel sends, as `fcntl` wants it.
+fn to_flock(start: u64, end: u64, typ: i32) -> libc::flock {
+    let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+    fl.l_type = typ.try_into().unwrap();
+    fl.l_whence = libc::SEEK_SET.try_into().unwrap();
+    fl.l_start = start.try_into().unwrap();
+    // A length of zero runs to EOF, which the kernel sends as an end of `OFFSET_MAX`.
+    fl.l_len = if end >= OFFSET_MAX {
+        0
+    } else {
+        (end - start + 1).try_into().unwrap()
+    };
+    fl
+}
 
-    fn copy_file_range(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino_in: u64,
-        fh_in: u64,
-        offset_in: i64,
-        ino_out: u64,
-        fh_out: u64,
-        offset_out: i64,
-        len: u64,
-        flags: u32,
-        reply: fuser::ReplyWrite,
-    ) {
-        self.do_copy_file_range(
-            req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags, reply,
-        )
-    }
+/// The inclusive range and type of a lock `fcntl` reported.
+fn from_flock(fl: &libc::flock) -> (u64, u64, i32) {
+    let start: u64 = fl.l_start.try_into().unwrap();
+    let end = if fl.l_len == 0 {
+        OFFSET_MAX
+    } else {
+        start + u64::try_from(fl.l_len).unwrap() - 1
+    };
+    (start, end, fl.l_type.into())
 }
 
-#[derive(Debug)]
-struct Ids {
-    uid: u32,
-    gid: u32,
-    gids: Vec<u32>,
-    umask: Option<u32>,
+/// The effective capability set of `pid`.
+pub fn get_caps(pid: i32) -> ProcResult<u64> {
+    Ok(procfs::process::Process::new(pid)?.status()?.capeff)
 }
 
 pub fn get_groups(pid: i32) -> ProcResult<Vec<u32>> {
@@ -570,16 +395,38 @@ pub fn get_groups(pid: i32) -> ProcResult<Vec<u32>> {
         .collect())
 }
 
-pub fn chdirin(root: &Path) -> PathBuf {
-    let cwd = std::env::current_dir().unwrap();
-    std::env::set_current_dir(root).unwrap();
-    cwd
+thread_local! {
+    static PRIVATE_FS: Cell<bool> = Cell::new(false);
 }
 
-pub fn chdirout(prev: PathBuf) {
-    std::env::set_current_dir(prev).unwrap();
+/// Give the calling thread its own umask, so setting it for one request
+/// doesn't leak into requests being served on other threads.
+fn unshare_fs() {
+    PRIVATE_FS.with(|x| {
+        if !x.get() {
+            let rc = unsafe { libc::unshare(libc::CLONE_FS) };
+            assert_eq!(rc, 0, "unshare(CLONE_FS) failed");
+            x.set(true);
+        }
+    })
+}
+
+/// Set the supplementary groups of the calling thread only.
+/// The libc wrapper applies them to every thread in the process.
+unsafe fn set_thread_groups(gids: &[u32]) -> c_int {
+    libc::syscall(
+        libc::SYS_setgroups,
+        gids.len(),
+        if gids.is_empty() {
+            std::ptr::null()
+        } else {
+            gids.as_ptr()
+        },
+    ) as c_int
 }
 
+/// Switch the calling thread to the requester's credentials.
+/// Only the filesystem ids are changed, and only for this thread.
 fn set_ids(callid: CallID, req: crate::req_rep::Request, umask: Option<u32>) -> Ids {
     let gids = get_groups(req.pid().try_into().unwrap()).unwrap_or(vec![]);
     log_more!(
@@ -589,9 +436,11 @@ fn set_ids(callid: CallID, req: crate::req_rep::Request, umask: Option<u32>) ->
         req.gid(),
         gids
     );
+    unshare_fs();
     let orig = unsafe {
-        let uid = libc::geteuid();
-        let gid = libc::getegid();
+        // An invalid id leaves the fsuid/fsgid alone and just returns the current one.
+        let uid = libc::setfsuid(u32::MAX) as u32;
+        let gid = libc::setfsgid(u32::MAX) as u32;
         let mut gids = [libc::gid_t::MIN; 256];
         let ngroups = libc::getgroups(256, gids.as_mut_ptr());
         assert_ne!(ngroups, -1, "getgroups failed");
@@ -601,62 +450,100 @@ fn set_ids(callid: CallID, req: crate::req_rep::Request, umask: Option<u32>) ->
             gid,
             gids: Vec::from(&gids[..ngroups.try_into().unwrap()]),
             umask: umask_orig,
+            caps: get_thread_caps(),
         }
     };
     unsafe {
-        let rc = libc::setgroups(
-            gids.len(),
-            if gids.is_empty() {
-                std::ptr::null()
-            } else {
-                &gids[0]
-            },
-        );
-        if rc != 0 {
-            panic!("{}", *libc::__errno_location());
-        }
-        let rc = libc::setegid(req.gid());
-        if rc != 0 {
-            panic!("{}", *libc::__errno_location());
-        }
-        let rc = libc::seteuid(req.uid());
+        let rc = set_thread_groups(&gids);
         if rc != 0 {
             panic!("{}", *libc::__errno_location());
         }
+        libc::setfsgid(req.gid());
+        assert_eq!(
+            libc::setfsgid(u32::MAX) as u32,
+            req.gid(),
+            "setfsgid failed"
+        );
+        libc::setfsuid(req.uid());
+        assert_eq!(
+            libc::setfsuid(u32::MAX) as u32,
+            req.uid(),
+            "setfsuid failed"
+        );
+        // Changing the fsuid raises or drops the filesystem capabilities wholesale, and leaves
+        // CAP_SYS_ADMIN alone. Give the thread the ones the requester has instead, so the backend
+        // judges the request as the oracle does.
+        let req_caps = caps(req.pid(), req.uid()).0 & MODELLED_CAPS;
+        let mut caps = get_thread_caps();
+        caps[0].effective &= !(MODELLED_CAPS as u32);
+        caps[0].effective |= req_caps as u32 & caps[0].permitted;
+        assert_eq!(set_thread_caps(&caps), 0, "capset failed");
     }
     orig
 }
 fn restore_ids(ids: Ids) {
     unsafe {
-        assert_eq!(libc::seteuid(ids.uid), 0, "seteuid failed");
-        assert_eq!(libc::geteuid(), ids.uid, "failed to restore euid");
-        assert_eq!(libc::setegid(ids.gid), 0, "setegid failed");
-        assert_eq!(libc::getegid(), ids.gid, "failed to restore egid");
+        libc::setfsuid(ids.uid);
         assert_eq!(
-            libc::setgroups(
-                ids.gids.len(),
-                if ids.gids.is_empty() {
-                    std::ptr::null()
-                } else {
-                    &ids.gids[0] as *const u32
-                }
-            ),
-            0,
-            "setgroups failed"
+            libc::setfsuid(u32::MAX) as u32,
+            ids.uid,
+            "failed to restore fsuid"
+        );
+        libc::setfsgid(ids.gid);
+        assert_eq!(
+            libc::setfsgid(u32::MAX) as u32,
+            ids.gid,
+            "failed to restore fsgid"
         );
+        assert_eq!(set_thread_groups(&ids.gids), 0, "setgroups failed");
         if let Some(umask) = ids.umask {
             libc::umask(umask);
         }
+        assert_eq!(
+            set_thread_caps(&ids.caps),
+            0,
+            "failed to restore capabilities"
+        );
     }
 }
 
-unsafe fn stat_path(tgt_path: &Path) -> Result<libc::stat, i32> {
-    let tgt = CString::new(tgt_path.as_os_str().as_bytes()).unwrap();
-    let mut buf = MaybeUninit::zeroed().assume_init();
-    let res = libc::lstat(tgt.as_ptr(), &mut buf);
-    if res == 0 {
-        Ok(buf)
-    } else {
-        Err(*libc::__errno_location())
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+
+    use crate::req_rep::Request;
+
+    use super::{restore_ids, set_ids};
+
+    #[test]
+    fn test_ids_are_per_thread() {
+        let fsuid = || unsafe { libc::setfsuid(u32::MAX) as u32 };
+        let umask = || unsafe {
+            let v = libc::umask(0);
+            libc::umask(v);
+            v
+        };
+        let (uid, mask) = (fsuid(), umask());
+        let barrier = Barrier::new(2);
+        std::thread::scope(|s| {
+            let worker = s.spawn(|| {
+                let req = Request {
+                    uid: 1234,
+                    gid: 1234,
+                    pid: 0,
+                };
+                let ids = set_ids(0, req, Some(0o077));
+                let set = fsuid();
+                barrier.wait();
+                barrier.wait();
+                restore_ids(ids);
+                (set, fsuid())
+            });
+            barrier.wait();
+            let other = (fsuid(), umask());
+            barrier.wait();
+            assert_eq!(worker.join().unwrap(), (1234, uid));
+            assert_eq!(other, (uid, mask));
+        });
     }
 }
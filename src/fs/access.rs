@@ -1,30 +1,29 @@
-use std::{ffi::CString, os::unix::prelude::OsStrExt};
-
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
+    fs::{restore_ids, set_ids},
+    invariants::{
+        fs::access::{inv_access_after, inv_access_before},
+        locks::Tree,
+    },
     log_call, log_more, log_res,
+    req_rep::{ReplyEmpty, Request},
 };
 
 use super::InvFS;
 
 impl InvFS {
-    pub fn do_access(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        mask: i32,
-        reply: fuser::ReplyEmpty,
-    ) {
-        let callid = log_call!("ACCESS", "ino={},mask={:x}", ino, mask);
-        let cwd = chdirin(&self.root);
-        let ids = set_ids(callid, req.into(), None);
-        let dl = self.data.lock().unwrap();
-        let ip = &dl.INODE_PATHS;
-        let path = ip.get(ino);
+    pub fn do_access(&self, req: Request, ino: u64, mask: i32, reply: &ReplyEmpty) {
+        let callid = log_call!("ACCESS", ino, mask: "{:x}");
+        let _guard = self.locks.lock(Tree::Shared, &[], &[]);
+        let mut dl = self.data.lock().unwrap();
+        let inv = inv_access_before(callid, &req, &self.root, ino, mask, &mut dl);
+        let watch = dl.watches.open(ino);
+        let path = dl.INODE_PATHS.get(ino).to_owned();
+        drop(dl);
+        let ids = set_ids(callid, req, None);
         log_more!(callid, "path={:?}", path);
         let res = unsafe {
-            let tgt = CString::new(path.as_os_str().as_bytes()).unwrap();
-            let res = libc::euidaccess(tgt.as_ptr(), mask);
+            let tgt = self.rel_path(&path);
+            let res = libc::faccessat(self.root_fd(), tgt.as_ptr(), mask, libc::AT_EACCESS);
             if res == 0 {
                 Ok(())
             } else {
@@ -33,10 +32,58 @@ impl InvFS {
         };
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        chdirout(cwd);
+        let check = self.check_observed(Some(watch), |dl| {
+            inv_access_after(callid, inv.clone(), &res, dl)
+        });
+        let res = self.handle_violation(callid, "ACCESS", Some(ino), check, res);
         match res {
             Ok(()) => reply.ok(),
             Err(v) => reply.error(v),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        req_rep::{ReplyEmpty, Request},
+        test::{create, init, ROOT},
+    };
+
+    #[test]
+    fn test_access() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        let access = |req, ino, mask| {
+            let rep = ReplyEmpty::new();
+            ifs.do_access(req, ino, mask, &rep);
+            rep.get()
+        };
+        let user = || Request {
+            uid: 1000,
+            gid: 1000,
+            pid: 0,
+        };
+
+        let foo = create(&ifs, "foo", 0o644);
+        assert_eq!(access(ROOT, foo, libc::F_OK), Ok(()));
+        assert_eq!(access(ROOT, foo, libc::R_OK | libc::W_OK), Ok(()));
+        // Root may only execute what someone may
+        assert_eq!(access(ROOT, foo, libc::X_OK), Err(libc::EACCES));
+        assert_eq!(access(ROOT, 1, libc::X_OK), Ok(()));
+        assert_eq!(access(user(), foo, libc::R_OK), Ok(()));
+        assert_eq!(access(user(), foo, libc::W_OK), Err(libc::EACCES));
+        // Every bit of a combined mask must be granted
+        assert_eq!(
+            access(user(), foo, libc::R_OK | libc::W_OK),
+            Err(libc::EACCES)
+        );
+
+        let bar = create(&ifs, "bar", 0o700);
+        assert_eq!(access(ROOT, bar, libc::X_OK), Ok(()));
+        assert_eq!(access(user(), bar, libc::F_OK), Ok(()));
+        assert_eq!(access(user(), bar, libc::R_OK), Err(libc::EACCES));
+
+        assert!(ifs.violations().is_empty());
+    }
+}
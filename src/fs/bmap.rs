@@ -1,9 +1,11 @@
+use crate::req_rep::Request;
+
 use super::InvFS;
 
 impl InvFS {
     pub fn do_bmap(
-        &mut self,
-        _req: &fuser::Request<'_>,
+        &self,
+        _req: Request,
         _ino: u64,
         _blocksize: u32,
         _idx: u64,
@@ -1,19 +1,116 @@
+use crate::{
+    fs::{restore_ids, set_ids},
+    invariants::{
+        fs::copy_file_range::{inv_copy_file_range_after, inv_copy_file_range_before},
+        locks::{record, Tree},
+    },
+    log_call, log_res,
+    req_rep::{ReplyWrite, Request},
+};
+
 use super::InvFS;
 
 impl InvFS {
     pub fn do_copy_file_range(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        _ino_in: u64,
-        _fh_in: u64,
-        _offset_in: i64,
-        _ino_out: u64,
-        _fh_out: u64,
-        _offset_out: i64,
-        _len: u64,
-        _flags: u32,
-        _reply: fuser::ReplyWrite,
+        &self,
+        req: Request,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: &ReplyWrite,
     ) {
-        todo!("COPY_FILE_RANGE");
+        let callid = log_call!(
+            "COPY_FILE_RANGE",
+            ino_in,
+            fh_in: "{:x}",
+            offset_in: "{:x}",
+            ino_out,
+            fh_out: "{:x}",
+            offset_out: "{:x}",
+            len: "{:x}",
+            flags: "{:x}",
+        );
+        // The source can't change under the copy, or the model wouldn't know what was copied.
+        let guard = self.locks.lock(Tree::Shared, &[ino_in], &[ino_out]);
+        let mut dl = self.data.lock().unwrap();
+        let inv = inv_copy_file_range_before(
+            callid, &req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags,
+            &mut dl,
+        );
+        drop(dl);
+        let ids = set_ids(callid, req, None);
+        let res = unsafe {
+            let mut off_in = offset_in;
+            let mut off_out = offset_out;
+            // Explicit offsets: other requests may be using the same handles concurrently.
+            let res = libc::copy_file_range(
+                fh_in as i32,
+                &mut off_in,
+                fh_out as i32,
+                &mut off_out,
+                len.try_into().unwrap_or(usize::MAX),
+                flags,
+            );
+            if res != -1 {
+                Ok(res as usize)
+            } else {
+                Err(*libc::__errno_location())
+            }
+        };
+        log_res!(callid, "{:?}", res);
+        restore_ids(ids);
+        let mut dl = self.data.lock().unwrap();
+        let check = record(callid, &mut dl, &[ino_out], guard.ticket(), |dl| {
+            inv_copy_file_range_after(callid, inv, &res, dl)
+        });
+        drop(dl);
+        match self.handle_violation(callid, "COPY_FILE_RANGE", Some(ino_out), check, res) {
+            Ok(v) => reply.written(v.try_into().unwrap()),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        req_rep::ReplyWrite,
+        test::{create, init, open, ROOT},
+    };
+
+    #[test]
+    fn test_copy_file_range() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        let src = create(&ifs, "src", 0o644);
+        let dst = create(&ifs, "dst", 0o644);
+        let src_fh = open(&ifs, src, libc::O_RDWR);
+        let dst_fh = open(&ifs, dst, libc::O_WRONLY);
+        let w_rep = ReplyWrite::new();
+        ifs.do_write(ROOT, src, src_fh, 0, b"abcdef", 0, 0, None, &w_rep);
+        assert_eq!(w_rep.get(), Ok(6));
+
+        let copy = |ino_in, fh_in, off_in, ino_out, fh_out, off_out, len| {
+            let rep = ReplyWrite::new();
+            ifs.do_copy_file_range(
+                ROOT, ino_in, fh_in, off_in, ino_out, fh_out, off_out, len, 0, &rep,
+            );
+            rep.get()
+        };
+        assert_eq!(copy(src, src_fh, 2, dst, dst_fh, 1, 100), Ok(4));
+        assert_eq!(copy(src, src_fh, 10, dst, dst_fh, 0, 4), Ok(0));
+        assert_eq!(copy(src, src_fh, 0, src, src_fh, 2, 4), Err(libc::EINVAL));
+        assert_eq!(copy(dst, dst_fh, 0, src, src_fh, 0, 1), Err(libc::EBADF));
+
+        let dl = ifs.data.lock().unwrap();
+        let backend = std::fs::read(ifs.root.join("dst")).unwrap();
+        assert_eq!(backend, b"\0cdef");
+        assert_eq!(dl.INV_FILE_CONTENTS.get(&dst), Some(&backend));
+        assert_eq!(dl.INV_INODE_CONTENTS.get(&dst).unwrap().size, 5);
     }
 }
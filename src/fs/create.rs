@@ -1,9 +1,10 @@
-use std::{ffi::CString, os::unix::prelude::OsStrExt};
-
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids, stat_path, TTL},
+    fs::{restore_ids, set_ids, TTL},
     fs_to_fuse::FsToFuseAttr,
-    invariants::fs::create::{inv_create_after, inv_create_before},
+    invariants::{
+        fs::create::{inv_create_after, inv_create_before},
+        locks::{record, Tree},
+    },
     log_call, log_more, log_res,
     req_rep::{ReplyCreate, Request},
 };
@@ -12,7 +13,7 @@ use super::InvFS;
 
 impl InvFS {
     pub fn do_create(
-        &mut self,
+        &self,
         req: Request,
         parent: u64,
         name: &std::ffi::OsStr,
@@ -23,40 +24,56 @@ impl InvFS {
     ) {
         let callid = log_call!(
             "CREATE",
-            "parent={},name={:?},mode={:o},umask={:o},flags={:o}",
             parent,
-            name,
-            mode,
-            umask,
-            flags
+            name: "{:?}",
+            mode: "{:o}",
+            umask: "{:o}",
+            flags: "{:o}",
         );
-        let cwd = chdirin(&self.root);
+        let guard = self.locks.lock(Tree::Shared, &[], &[parent]);
         let mut dl = self.data.lock().unwrap();
         let inv = inv_create_before(
             callid, &req, &self.root, parent, name, mode, umask, flags, &mut dl,
         );
-        let ids = set_ids(callid, req, Some(umask));
-        let ip = &mut dl.INODE_PATHS;
-        let p_path = ip.get(parent);
+        let p_path = dl.INODE_PATHS.get(parent);
         log_more!(callid, "parent={:?}", p_path);
         let child = p_path.join(name);
         log_more!(callid, "child={:?}", child);
+        drop(dl);
+        let ids = set_ids(callid, req, Some(umask));
         let res = unsafe {
-            let tgt = CString::new(child.as_os_str().as_bytes()).unwrap();
-            let res = libc::open(tgt.as_ptr(), flags, mode);
+            let tgt = self.rel_path(&child);
+            let res = libc::openat(self.root_fd(), tgt.as_ptr(), flags, mode);
             if res != -1 {
-                stat_path(&child).map(|x| {
-                    let ino = ip.insert(x.st_ino, child);
-                    (x.to_fuse_attr(ino), res)
-                })
+                self.stat_path(&child).map(|x| (x, res))
             } else {
                 Err(*libc::__errno_location())
             }
         };
-        log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        inv_create_after(callid, inv, &res, &mut dl);
-        chdirout(cwd);
+        let mut dl = self.data.lock().unwrap();
+        let res = res.map(|(x, fh)| {
+            let ino = dl.INODE_PATHS.lookup(x.st_ino, child);
+            (x.to_fuse_attr(ino), fh)
+        });
+        log_res!(callid, "{:?}", res);
+        if res.is_ok() {
+            dl.listings.touch(parent, name);
+        }
+        let check = record(callid, &mut dl, &[parent], guard.ticket(), |dl| {
+            inv_create_after(callid, inv, &res, dl)
+        });
+        drop(dl);
+        let res = self.handle_violation_with(
+            callid,
+            "CREATE",
+            Some(parent),
+            check,
+            res,
+            |(_, fd)| unsafe {
+                libc::close(fd);
+            },
+        );
         match res {
             Ok((attr, fh)) => reply.created(&TTL, &attr, 0, fh.try_into().unwrap(), 0),
             Err(v) => reply.error(v),
@@ -66,25 +83,23 @@ impl InvFS {
 
 #[cfg(test)]
 mod tests {
-    use std::ffi::OsString;
+    use std::{
+        ffi::{CString, OsString},
+        os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+        sync::mpsc::channel,
+    };
 
     use crate::{
-        fs::TTL,
-        req_rep::{KernelConfig, ReplyCreate, Request},
+        fs::{get_thread_caps, set_thread_caps, TTL},
+        invariants::violation::ViolationPolicy,
+        req_rep::{ReplyCreate, ReplyEntry, Request},
+        test::{init, ROOT},
     };
 
     #[test]
     fn test_create() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep = ReplyCreate::new();
         ifs.do_create(
             Request {
@@ -126,4 +141,97 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn test_create_caps() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        let rep = ReplyEntry::new();
+        ifs.do_mkdir(ROOT, 1, &OsString::from("locked"), 0, 0, &rep);
+        let dir = rep.get().unwrap().1.ino;
+        let create = |req, name: &str| {
+            let rep = ReplyCreate::new();
+            ifs.do_create(
+                req,
+                dir,
+                &OsString::from(name),
+                0o644,
+                0,
+                libc::O_CREAT,
+                &rep,
+            );
+            rep.get().map(|_| ())
+        };
+
+        // Capabilities, not the uid, get past the mode bits: this process holds them all
+        let holder = Request {
+            uid: 1000,
+            gid: 1000,
+            pid: std::process::id(),
+        };
+        assert_eq!(create(holder, "a"), Ok(()));
+        let user = Request {
+            uid: 1000,
+            gid: 1000,
+            pid: 0,
+        };
+        assert_eq!(create(user, "b"), Err(libc::EACCES));
+
+        // A thread that drops its capabilities stands in for a root process without them
+        let (tid_tx, tid_rx) = channel();
+        let (done_tx, done_rx) = channel::<()>();
+        std::thread::scope(|s| {
+            s.spawn(move || unsafe {
+                let mut caps = get_thread_caps();
+                caps[0].effective = 0;
+                assert_eq!(set_thread_caps(&caps), 0);
+                tid_tx.send(libc::gettid() as u32).unwrap();
+                done_rx.recv().unwrap();
+            });
+            let dropped = Request {
+                uid: 0,
+                gid: 0,
+                pid: tid_rx.recv().unwrap(),
+            };
+            assert_eq!(create(dropped, "c"), Err(libc::EACCES));
+            done_tx.send(()).unwrap();
+        });
+
+        assert!(ifs.violations().is_empty());
+    }
+
+    #[test]
+    fn test_create_violation_closes() {
+        let ifs = crate::test::create_ifs().with_policy(ViolationPolicy::Eio);
+        init(&ifs);
+        let rep = ReplyEntry::new();
+        ifs.do_mkdir(ROOT, 1, &OsString::from("sgid"), 0o777, 0, &rep);
+        let dir = rep.get().unwrap().1.ino;
+        // Behind the model's back: children of a setgid directory take its group.
+        let path = ifs.root.join("sgid");
+        let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::chown(cpath.as_ptr(), u32::MAX, 5) }, 0);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o2777)).unwrap();
+
+        let rep = ReplyCreate::new();
+        ifs.do_create(
+            ROOT,
+            dir,
+            &OsString::from("foo"),
+            0o644,
+            0,
+            libc::O_CREAT,
+            &rep,
+        );
+        assert_eq!(rep.get(), Err(libc::EIO));
+        assert_eq!(ifs.violations().len(), 1);
+        // The kernel never got the handle, so it must not be left open.
+        let foo = path.join("foo");
+        for fd in std::fs::read_dir("/proc/self/fd").unwrap() {
+            assert_ne!(
+                std::fs::read_link(fd.unwrap().path()).ok(),
+                Some(foo.clone())
+            );
+        }
+    }
 }
@@ -1,5 +1,76 @@
+use crate::{
+    invariants::{durable, locks::Tree},
+    log_call, log_err, log_res, snapshot,
+};
+
 use super::InvFS;
 
 impl InvFS {
-    pub fn do_destroy(&mut self) {}
+    /// Checkpoint the model, so the next mount can check the backend against it.
+    pub fn do_destroy(&self) {
+        let callid = log_call!("DESTROY", snapshot: "{:?}" = self.snapshot);
+        let _guard = self.locks.lock(Tree::Exclusive, &[], &[]);
+        let mut dl = self.data.lock().unwrap();
+        if !dl.checks.any() {
+            log_res!(callid, "No checks enabled, nothing to save");
+            return;
+        }
+        // The kernel may not have sent every forget yet.
+        dl.drop_orphans();
+        match snapshot::save(&dl, &self.snapshot) {
+            Ok(()) => {
+                // The snapshot checks everything the durability record would, and more.
+                let record = durable::record_path(&self.snapshot);
+                if let Err(e) = std::fs::remove_file(&record) {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        log_err!(callid, "Failed to remove {:?}: {}", record, e);
+                    }
+                }
+                log_res!(callid, "Saved the model")
+            }
+            Err(e) => log_err!(
+                callid,
+                "Failed to save the model to {:?}: {}",
+                self.snapshot,
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{fs::InvFS, invariants::violation::ViolationPolicy, test::init};
+
+    #[test]
+    fn test_changed_while_unmounted() {
+        let ifs = crate::test::create_ifs();
+        std::fs::write(ifs.root.join("foo"), "foo").unwrap();
+        init(&ifs);
+        ifs.do_destroy();
+        assert!(ifs.snapshot.exists());
+
+        let remount = || {
+            InvFS::new(ifs.root.clone())
+                .with_snapshot(ifs.snapshot.clone())
+                .with_policy(ViolationPolicy::Log)
+        };
+        let clean = remount();
+        init(&clean);
+        assert_eq!(clean.violations(), vec![]);
+        // Loading the snapshot consumes it.
+        assert!(!ifs.snapshot.exists());
+        clean.do_destroy();
+
+        std::fs::write(ifs.root.join("foo"), "bar").unwrap();
+        let changed = remount();
+        init(&changed);
+        let violations = changed.violations();
+        assert_eq!(violations.len(), 1);
+        assert!(
+            violations[0].message.contains("data: "),
+            "{}",
+            violations[0]
+        );
+    }
 }
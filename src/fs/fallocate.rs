@@ -1,16 +1,101 @@
+use crate::{
+    fs::{restore_ids, set_ids},
+    invariants::{
+        fs::fallocate::{inv_fallocate_after, inv_fallocate_before},
+        locks::{record, Tree},
+    },
+    log_call, log_res,
+    req_rep::{ReplyEmpty, Request},
+};
+
 use super::InvFS;
 
 impl InvFS {
     pub fn do_fallocate(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        _offset: i64,
-        _length: i64,
-        _mode: i32,
-        _reply: fuser::ReplyEmpty,
+        &self,
+        req: Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: &ReplyEmpty,
     ) {
-        todo!("FALLOCATE");
+        let callid = log_call!("FALLOCATE", ino, fh, offset: "{:x}", length: "{:x}", mode: "{:x}");
+        let guard = self.locks.lock(Tree::Shared, &[], &[ino]);
+        let mut dl = self.data.lock().unwrap();
+        let inv = inv_fallocate_before(callid, &req, ino, fh, offset, length, mode, &mut dl);
+        drop(dl);
+        let ids = set_ids(callid, req, None);
+        let res = unsafe {
+            let res = libc::fallocate(fh.try_into().unwrap(), mode, offset, length);
+            if res == 0 {
+                Ok(())
+            } else {
+                Err(*libc::__errno_location())
+            }
+        };
+        log_res!(callid, "{:?}", res);
+        restore_ids(ids);
+        let mut dl = self.data.lock().unwrap();
+        let check = record(callid, &mut dl, &[ino], guard.ticket(), |dl| {
+            inv_fallocate_after(callid, inv, &res, dl)
+        });
+        drop(dl);
+        match self.handle_violation(callid, "FALLOCATE", Some(ino), check, res) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::req_rep::{ReplyEmpty, ReplyWrite};
+
+    use crate::test::{create, init, open, ROOT};
+
+    #[test]
+    fn test_fallocate() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        let ino = create(&ifs, "foo", 0o644);
+        let fh = open(&ifs, ino, libc::O_RDWR);
+        let w_rep = ReplyWrite::new();
+        ifs.do_write(ROOT, ino, fh, 0, b"abcdef", 0, 0, None, &w_rep);
+        assert_eq!(w_rep.get(), Ok(6));
+
+        let fallocate = |fh, offset, length, mode| {
+            let rep = ReplyEmpty::new();
+            ifs.do_fallocate(ROOT, ino, fh, offset, length, mode, &rep);
+            rep.get()
+        };
+        assert_eq!(fallocate(fh, 4, 4, 0), Ok(()));
+        assert_eq!(fallocate(fh, 0, 0, 0), Err(libc::EINVAL));
+        assert_eq!(
+            fallocate(fh, 0, 1, libc::FALLOC_FL_PUNCH_HOLE),
+            Err(libc::EOPNOTSUPP)
+        );
+        assert_eq!(
+            fallocate(open(&ifs, ino, libc::O_RDONLY), 0, 1, 0),
+            Err(libc::EBADF)
+        );
+        // Not every backend can punch holes, but the model has to follow whatever it did.
+        let _ = fallocate(
+            fh,
+            1,
+            2,
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+        );
+
+        let dl = ifs.data.lock().unwrap();
+        let backend = std::fs::read(ifs.root.join("foo")).unwrap();
+        assert_eq!(dl.INV_FILE_CONTENTS.get(&ino), Some(&backend));
+        assert_eq!(
+            dl.INV_INODE_CONTENTS.get(&ino).unwrap().size,
+            backend.len() as u64
+        );
+        assert_eq!(backend.len(), 8);
     }
 }
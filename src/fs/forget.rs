@@ -1,10 +1,19 @@
-use crate::{log_call, log_res};
+use crate::{log_call, log_res, req_rep::Request};
 
 use super::InvFS;
 
 impl InvFS {
-    pub fn do_forget(&mut self, _req: &fuser::Request<'_>, _ino: u64, _nlookup: u64) {
-        let callid = log_call!("FORGET", "ino={}", _ino);
-        log_res!(callid, "We currently take no action here.")
+    pub fn do_forget(&self, _req: Request, ino: u64, nlookup: u64) {
+        let callid = log_call!("FORGET", ino, nlookup);
+        let mut dl = self.data.lock().unwrap();
+        let left = dl.INODE_PATHS.forget(ino, nlookup);
+        // The last lookup of an unlinked inode was all that kept its model around.
+        if left.unwrap_or(0) == 0 && dl.INV_INODE_PATHS.get_all(ino).is_none() {
+            dl.drop_inode(ino);
+        }
+        match left {
+            Some(v) => log_res!(callid, "ino={} has {} lookups left", ino, v),
+            None => log_res!(callid, "Forgot more lookups than were handed out"),
+        }
     }
 }
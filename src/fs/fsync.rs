@@ -1,22 +1,18 @@
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
+    fs::{restore_ids, set_ids},
+    invariants::{durable::sync_file, locks::Tree},
     log_call, log_res,
+    req_rep::{ReplyEmpty, Request},
 };
 
 use super::InvFS;
 
 impl InvFS {
-    pub fn do_fsync(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        fh: u64,
-        datasync: bool,
-        reply: fuser::ReplyEmpty,
-    ) {
-        let callid = log_call!("FSYNC", "ino={},fh={},datasync={}", ino, fh, datasync);
-        let cwd = chdirin(&self.root);
-        let ids = set_ids(callid, req.into(), None);
+    pub fn do_fsync(&self, req: Request, ino: u64, fh: u64, datasync: bool, reply: &ReplyEmpty) {
+        let callid = log_call!("FSYNC", ino, fh, datasync);
+        // Keep writers out, so what gets recorded is what was synced.
+        let _guard = self.locks.lock(Tree::Shared, &[ino], &[]);
+        let ids = set_ids(callid, req, None);
         let res = unsafe {
             let res = if datasync {
                 libc::fdatasync(fh.try_into().unwrap())
@@ -31,12 +27,76 @@ impl InvFS {
         };
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        chdirout(cwd);
         match res {
             Ok(()) => {
+                // fdatasync skips timestamps but not the size, which is all we record besides data.
+                sync_file(callid, &mut self.data.lock().unwrap(), ino);
                 reply.ok();
             }
             Err(v) => reply.error(v),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use crate::{
+        fs::InvFS,
+        invariants::violation::ViolationPolicy,
+        req_rep::{ReplyEmpty, ReplyEntry, ReplyWrite},
+        test::{create, init, open, ROOT},
+    };
+
+    fn write(ifs: &InvFS, ino: u64, fh: u64, data: &[u8]) {
+        let rep = ReplyWrite::new();
+        ifs.do_write(ROOT, ino, fh, 0, data, 0, 0, None, &rep);
+        assert_eq!(rep.get(), Ok(data.len() as u32));
+    }
+
+    #[test]
+    fn test_lost_after_crash() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        let ino = create(&ifs, "foo", 0o644);
+        let fh = open(&ifs, ino, libc::O_RDWR);
+        write(&ifs, ino, fh, b"synced");
+        let rep = ReplyEmpty::new();
+        ifs.do_fsync(ROOT, ino, fh, false, &rep);
+        assert_eq!(rep.get(), Ok(()));
+
+        // Crash without destroy, losing the synced data.
+        let crash = |contents: &str| {
+            std::fs::write(ifs.root.join("foo"), contents).unwrap();
+            let remount = InvFS::new(ifs.root.clone())
+                .with_snapshot(ifs.snapshot.clone())
+                .with_policy(ViolationPolicy::Log);
+            init(&remount);
+            remount.violations()
+        };
+        let violations = crash("synce");
+        assert_eq!(violations.len(), 1);
+        assert!(
+            violations[0].message.contains("did not survive"),
+            "{}",
+            violations[0]
+        );
+        // The record is only checked once.
+        assert_eq!(crash("synce"), vec![]);
+
+        // Writing after the sync withdraws the promise.
+        let ifs2 = InvFS::new(ifs.root.clone()).with_snapshot(ifs.snapshot.clone());
+        init(&ifs2);
+        let rep = ReplyEntry::new();
+        ifs2.do_lookup(ROOT, 1, &OsString::from("foo"), &rep);
+        assert_eq!(rep.get().unwrap().1.ino, ino);
+        let fh = open(&ifs2, ino, libc::O_RDWR);
+        let rep = ReplyEmpty::new();
+        ifs2.do_fsync(ROOT, ino, fh, true, &rep);
+        assert_eq!(rep.get(), Ok(()));
+        write(&ifs2, ino, fh, b"unsynced");
+        drop(ifs2);
+        assert_eq!(crash("uns"), vec![]);
+    }
+}
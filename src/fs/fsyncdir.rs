@@ -1,21 +1,44 @@
-use crate::{log_call, log_res};
+use crate::{
+    invariants::{durable::sync_dir, locks::Tree},
+    log_call, log_res,
+    req_rep::Request,
+};
 
 use super::InvFS;
 
 impl InvFS {
     pub fn do_fsyncdir(
-        &mut self,
-        _req: &fuser::Request<'_>,
+        &self,
+        _req: Request,
         ino: u64,
         fh: u64,
         datasync: bool,
         reply: fuser::ReplyEmpty,
     ) {
-        let callid = log_call!("FSYNC", "ino={},fh={},datasync={}", ino, fh, datasync);
-        log_res!(
-            callid,
-            "We assume the underlying filesystem syncs directories."
-        );
-        reply.ok()
+        let callid = log_call!("FSYNCDIR", ino, fh, datasync);
+        let _guard = self.locks.lock(Tree::Shared, &[ino], &[]);
+        let res = unsafe {
+            let dir_fhs = self.dir_fhs.lock().unwrap();
+            let dir = dir_fhs.get(&fh).unwrap().0;
+            let fd = libc::dirfd(dir);
+            let res = if datasync {
+                libc::fdatasync(fd)
+            } else {
+                libc::fsync(fd)
+            };
+            if res == 0 {
+                Ok(())
+            } else {
+                Err(*libc::__errno_location())
+            }
+        };
+        log_res!(callid, "{:?}", res);
+        match res {
+            Ok(()) => {
+                sync_dir(callid, &mut self.data.lock().unwrap(), ino);
+                reply.ok()
+            }
+            Err(v) => reply.error(v),
+        }
     }
 }
@@ -1,7 +1,10 @@
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids, stat_path, TTL},
+    fs::{restore_ids, set_ids, TTL},
     fs_to_fuse::FsToFuseAttr,
-    invariants::fs::getattr::{inv_getattr_after, inv_getattr_before},
+    invariants::{
+        fs::getattr::{inv_getattr_after, inv_getattr_before},
+        locks::Tree,
+    },
     log_call, log_more, log_res,
     req_rep::{ReplyAttr, Request},
 };
@@ -9,22 +12,25 @@ use crate::{
 use super::InvFS;
 
 impl InvFS {
-    pub fn do_getattr(&mut self, req: Request, ino: u64, reply: &ReplyAttr) {
-        let callid = log_call!("GETATTR", "ino={}", ino);
-        let cwd = chdirin(&self.root);
+    pub fn do_getattr(&self, req: Request, ino: u64, reply: &ReplyAttr) {
+        let callid = log_call!("GETATTR", ino);
+        let _guard = self.locks.lock(Tree::Shared, &[], &[]);
         let mut dl = self.data.lock().unwrap();
         let inv = inv_getattr_before(callid, &req, &self.root, ino, &mut dl);
+        let watch = dl.watches.open(ino);
+        let path = dl.INODE_PATHS.get(ino).to_owned();
+        drop(dl);
         let ids = set_ids(callid, req, None);
-        let ip = &dl.INODE_PATHS;
-        let path = ip.get(ino);
         log_more!(callid, "path={:?}", path);
 
-        let res = unsafe { stat_path(path).map(|x| x.to_fuse_attr(ino)) };
+        let res = unsafe { self.stat_path(&path).map(|x| x.to_fuse_attr(ino)) };
 
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        inv_getattr_after(callid, inv, &res, &mut dl);
-        chdirout(cwd);
+        let check = self.check_observed(Some(watch), |dl| {
+            inv_getattr_after(callid, inv.clone(), &res, dl)
+        });
+        let res = self.handle_violation(callid, "GETATTR", Some(ino), check, res);
         match res {
             Ok(v) => reply.attr(&TTL, &v),
             Err(v) => reply.error(v),
@@ -38,21 +44,14 @@ mod tests {
 
     use crate::{
         fs::TTL,
-        req_rep::{KernelConfig, ReplyAttr, ReplyCreate, Request},
+        req_rep::{ReplyAttr, ReplyCreate, Request},
+        test::init,
     };
 
     #[test]
     fn test_getattr() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep_c = ReplyCreate::new();
         ifs.do_create(
             Request {
@@ -1,18 +1,133 @@
-use super::InvFS;
+use crate::{
+    invariants::{
+        fs::getlk::{inv_getlk_after, inv_getlk_before},
+        locks::Tree,
+    },
+    log_call, log_res,
+    req_rep::{ReplyLock, Request},
+};
+
+use super::{from_flock, to_flock, InvFS};
 
 impl InvFS {
     pub fn do_getlk(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _typ: i32,
-        _pid: u32,
-        _reply: fuser::ReplyLock,
+        &self,
+        req: Request,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: &ReplyLock,
     ) {
-        todo!("GETLK");
+        let callid = log_call!(
+            "GETLK",
+            ino,
+            fh: "{:x}",
+            lock_owner: "{:x}",
+            start: "{:x}",
+            end: "{:x}",
+            typ,
+            pid,
+        );
+        let _guard = self.locks.lock(Tree::Shared, &[], &[ino]);
+        let mut dl = self.data.lock().unwrap();
+        let inv = inv_getlk_before(
+            callid, &req, ino, fh, lock_owner, start, end, typ, pid, &mut dl,
+        );
+        drop(dl);
+        let res = self.lock_fd(ino, fh, lock_owner).and_then(|fd| unsafe {
+            let mut fl = to_flock(start, end, typ);
+            if libc::fcntl(fd, libc::F_OFD_GETLK, &mut fl) == 0 {
+                Ok(Some(from_flock(&fl)).filter(|(_, _, t)| *t != libc::F_UNLCK))
+            } else {
+                Err(*libc::__errno_location())
+            }
+        });
+        log_res!(callid, "{:?}", res);
+        let mut dl = self.data.lock().unwrap();
+        let check = inv_getlk_after(callid, inv, &res, &mut dl);
+        // Only the range and type of the conflict come from the backend, and those are checked.
+        // OFD locks report a pid of -1, so whose lock it is can't be checked: the pid is the one
+        // the model has for the first of its conflicts with that range and type, or 0 if none.
+        let holder = |(s, e, t)| {
+            dl.posix_locks
+                .conflicts(ino, lock_owner, start, end, typ)
+                .into_iter()
+                .find(|l| (l.start, l.end, l.typ) == (s, e, t))
+                .map_or(0, |l| l.pid)
+        };
+        let res = res.map(|v| match v {
+            Some(l) => (l.0, l.1, l.2, holder(l)),
+            None => (start, end, libc::F_UNLCK, 0),
+        });
+        drop(dl);
+        match self.handle_violation(callid, "GETLK", Some(ino), check, res) {
+            Ok((start, end, typ, pid)) => reply.locked(start, end, typ, pid),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, os::unix::prelude::AsRawFd};
+
+    use crate::{
+        fs::{to_flock, ViolationPolicy},
+        req_rep::{ReplyEmpty, ReplyLock},
+        test::{create, init, open, ROOT},
+    };
+
+    #[test]
+    fn test_getlk_owner() {
+        let ifs = crate::test::create_ifs().with_policy(ViolationPolicy::Log);
+        init(&ifs);
+        let ino = create(&ifs, "foo", 0o644);
+        let fh = open(&ifs, ino, libc::O_RDWR);
+        for owner in [1, 2] {
+            let rep = ReplyEmpty::new();
+            ifs.do_setlk(
+                ROOT,
+                ino,
+                fh,
+                owner,
+                0,
+                9,
+                libc::F_RDLCK,
+                owner as u32,
+                false,
+                &rep,
+            );
+            assert_eq!(rep.get(), Ok(()));
+        }
+        let getlk = |start, end| {
+            let rep = ReplyLock::new();
+            ifs.do_getlk(ROOT, ino, fh, 3, start, end, libc::F_WRLCK, 3, &rep);
+            rep.get()
+        };
+
+        // Both readers stand in the way, and the backend can't say which one it found. The pid
+        // is the model's first, whichever lock the backend meant.
+        assert_eq!(getlk(0, 9), Ok((0, 9, libc::F_RDLCK, 1)));
+        assert!(ifs.violations().is_empty());
+
+        // The range and type are the backend's own, so a lock the model doesn't know of is caught.
+        let other = File::options()
+            .read(true)
+            .write(true)
+            .open(ifs.root.join("foo"))
+            .unwrap();
+        let fl = to_flock(20, 29, libc::F_WRLCK);
+        assert_eq!(
+            unsafe { libc::fcntl(other.as_raw_fd(), libc::F_OFD_SETLK, &fl) },
+            0
+        );
+        assert_eq!(getlk(20, 29), Ok((20, 29, libc::F_WRLCK, 0)));
+        let violations = ifs.violations();
+        assert_eq!(violations.len(), 1, "{:?}", violations);
+        assert!(violations[0].message.contains("conflicting lock"));
     }
 }
@@ -1,8 +1,13 @@
 use std::{ffi::CString, os::unix::prelude::OsStrExt};
 
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
+    fs::{restore_ids, set_ids},
+    invariants::{
+        fs::getxattr::{inv_getxattr_after, inv_getxattr_before},
+        locks::Tree,
+    },
     log_call, log_more, log_res,
+    req_rep::{ReplyXattr, Request, Xattr},
 };
 use libc::c_void;
 
@@ -10,42 +15,46 @@ use super::InvFS;
 
 impl InvFS {
     pub fn do_getxattr(
-        &mut self,
-        req: &fuser::Request<'_>,
+        &self,
+        req: Request,
         ino: u64,
         name: &std::ffi::OsStr,
         size: u32,
-        reply: fuser::ReplyXattr,
+        reply: &ReplyXattr,
     ) {
-        let callid = log_call!("GETXATTR", "ino={},name={:?},size={:x}", ino, name, size);
-        let cwd = chdirin(&self.root);
-        let ids = set_ids(callid, req.into(), None);
-        let dl = self.data.lock().unwrap();
-        let ip = &dl.INODE_PATHS;
-        let path = ip.get(ino);
+        let callid = log_call!("GETXATTR", ino, name: "{:?}", size: "{:x}");
+        let _guard = self.locks.lock(Tree::Shared, &[], &[]);
+        let mut dl = self.data.lock().unwrap();
+        let inv = inv_getxattr_before(callid, &req, &self.root, ino, name, size, &mut dl);
+        let path = dl.INODE_PATHS.get(ino).to_owned();
+        drop(dl);
         log_more!(callid, "path={:?}", path);
+        let ids = set_ids(callid, req, None);
         let res = unsafe {
             let nm = CString::new(name.as_bytes()).unwrap();
-            let tgt = CString::new(path.as_os_str().as_bytes()).unwrap();
+            let tgt = self.fd_path(&path);
             let mut buf = vec![0u8; size.try_into().unwrap()];
-            let res = libc::getxattr(
+            let res = libc::lgetxattr(
                 tgt.as_ptr(),
                 nm.as_ptr(),
                 buf.as_mut_ptr() as *mut c_void,
                 size.try_into().unwrap(),
             );
-            if res != -1 {
-                buf.truncate(size.try_into().unwrap());
-                Ok(buf)
-            } else {
+            if res == -1 {
                 Err(*libc::__errno_location())
+            } else if size == 0 {
+                Ok(Xattr::Size(res.try_into().unwrap()))
+            } else {
+                buf.truncate(res.try_into().unwrap());
+                Ok(Xattr::Data(buf))
             }
         };
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        chdirout(cwd);
-        match res {
-            Ok(v) => reply.data(&v),
+        let check = inv_getxattr_after(callid, inv, &res, &mut self.data.lock().unwrap());
+        match self.handle_violation(callid, "GETXATTR", Some(ino), check, res) {
+            Ok(Xattr::Size(v)) => reply.size(v),
+            Ok(Xattr::Data(v)) => reply.data(&v),
             Err(v) => reply.error(v),
         }
     }
@@ -1,24 +1,46 @@
+use fuser::consts;
 use libc::c_int;
 
 use crate::{
-    invariants::fs::init::{inv_init_after, inv_init_before},
-    log_call,
+    invariants::{
+        fs::init::{inv_init_after, inv_init_before},
+        locks::Tree,
+    },
+    log_call, log_err, log_more,
     req_rep::{KernelConfig, Request},
 };
 
 use super::InvFS;
 
 impl InvFS {
-    pub fn do_init(&mut self, req: Request, config: &KernelConfig) -> Result<(), c_int> {
-        let callid = log_call!("INIT", "config={:?}", config);
+    pub fn do_init(&self, req: Request, config: &KernelConfig) -> Result<(), c_int> {
+        let callid = log_call!("INIT", config: "{:?}");
+        let _guard = self.locks.lock(Tree::Exclusive, &[], &[]);
+        // Without POSIX_LOCKS the kernel keeps record locks to itself and never sends GETLK or
+        // SETLK. Capabilities are granted all or nothing, so it's asked for on its own.
+        if config.add_capabilities(consts::FUSE_POSIX_LOCKS).is_err() {
+            log_err!(
+                callid,
+                "The kernel refused POSIX_LOCKS: record locks won't be checked"
+            );
+        }
+        // READDIRPLUS is asked for adaptively, so plain READDIR still gets sent too.
+        if let Err(e) =
+            config.add_capabilities(consts::FUSE_DO_READDIRPLUS | consts::FUSE_READDIRPLUS_AUTO)
+        {
+            log_more!(callid, "unsupported capabilities={:x}", e);
+        }
         let inv = inv_init_before(callid, self, req, config);
         self.data
             .lock()
             .unwrap()
             .INODE_PATHS
             .insert(1, self.root.clone());
-        let res = Ok(());
-        inv_init_after(callid, inv, &res, &mut self.data.lock().unwrap());
+        let mut res = Ok(());
+        let violations = inv_init_after(callid, inv, &res, &mut self.data.lock().unwrap());
+        for v in violations {
+            res = self.handle_violation(callid, "INIT", Some(1), Err(v), res);
+        }
         res
     }
 }
@@ -27,21 +49,76 @@ impl InvFS {
 mod test {
     use maplit::{btreemap, btreeset};
 
-    use crate::req_rep::{KernelConfig, Request};
+    use crate::{
+        fs::InvFS,
+        invariants::{violation::ViolationPolicy, Checks},
+        req_rep::{ReplyEmpty, ReplyWrite},
+        snapshot,
+        test::{create, init, open, ROOT},
+    };
 
     #[test]
     fn test_init() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let ips = ifs.data.lock().unwrap().INODE_PATHS.store();
         assert_eq!(ips, btreemap! {1 => btreeset!{ifs.root}})
     }
+
+    #[test]
+    fn test_set_checks() {
+        let ifs = crate::test::create_ifs().with_checks(Checks::none());
+        init(&ifs);
+        assert!(ifs.data.lock().unwrap().INV_INODE_CONTENTS.is_empty());
+        std::fs::write(ifs.root.join("foo"), "foo").unwrap();
+        ifs.set_checks(Checks::default()).unwrap();
+        assert_eq!(ifs.checks(), Checks::default());
+        let ino = std::os::unix::fs::MetadataExt::ino(&ifs.root.join("foo").metadata().unwrap());
+        assert_eq!(
+            ifs.data.lock().unwrap().INV_FILE_CONTENTS.get(&ino),
+            Some(&b"foo".to_vec())
+        );
+        ifs.set_checks(Checks::none()).unwrap();
+        assert!(ifs.data.lock().unwrap().INV_FILE_CONTENTS.is_empty());
+    }
+
+    #[test]
+    fn test_reload_checks() {
+        let ifs = crate::test::create_ifs().with_checks(Checks::none());
+        init(&ifs);
+        let path = ifs.snapshot.with_extension("checks");
+        std::fs::write(&path, "meta,dirs\n").unwrap();
+        assert_eq!(ifs.reload_checks(&path), Checks::parse("dirs"));
+        assert!(ifs.data.lock().unwrap().INV_DIR_CONTENTS.contains_key(&1));
+        std::fs::write(&path, "bogus").unwrap();
+        assert!(ifs.reload_checks(&path).is_err());
+        assert_eq!(ifs.checks(), Checks::parse("dirs").unwrap());
+    }
+
+    #[test]
+    fn test_reports_every_check() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        let ino = create(&ifs, "foo", 0o644);
+        let fh = open(&ifs, ino, libc::O_RDWR);
+        let rep = ReplyWrite::new();
+        ifs.do_write(ROOT, ino, fh, 0, b"synced", 0, 0, None, &rep);
+        assert_eq!(rep.get(), Ok(6));
+        let rep = ReplyEmpty::new();
+        ifs.do_fsync(ROOT, ino, fh, false, &rep);
+        assert_eq!(rep.get(), Ok(()));
+        // Crash halfway through destroy, after the snapshot is saved but before the durability
+        // record is removed.
+        snapshot::save(&ifs.data.lock().unwrap(), &ifs.snapshot).unwrap();
+
+        std::fs::write(ifs.root.join("foo"), "lost").unwrap();
+        let remount = InvFS::new(ifs.root.clone())
+            .with_snapshot(ifs.snapshot.clone())
+            .with_policy(ViolationPolicy::Log);
+        init(&remount);
+        let violations = remount.violations();
+        assert_eq!(violations.len(), 2, "{:?}", violations);
+        assert!(violations[0].message.contains("did not survive"));
+        assert!(violations[1].message.contains("changed while unmounted"));
+    }
 }
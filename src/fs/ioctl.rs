@@ -1,17 +1,206 @@
+use crate::{
+    fs::{restore_ids, set_ids},
+    invariants::{
+        fs::ioctl::{inv_ioctl_after, inv_ioctl_before},
+        inode_flags::FS_IOC_FSGETXATTR,
+        locks::{apply, Tree},
+    },
+    log_call, log_res,
+    req_rep::{ReplyIoctl, Request},
+};
+
 use super::InvFS;
 
+/// Set when the handle is from `opendir`. fuser only exports it with ABI 7.18.
+const FUSE_IOCTL_DIR: u32 = 1 << 4;
+
+/// The size of `struct fsxattr`.
+const FSXATTR_SIZE: usize = 28;
+
 impl InvFS {
+    /// Only the inode flag ioctls are passed through. Anything else gets ENOTTY, as from a file
+    /// that doesn't know the command.
     pub fn do_ioctl(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        _flags: u32,
-        _cmd: u32,
-        _in_data: &[u8],
-        _out_size: u32,
-        _reply: fuser::ReplyIoctl,
+        &self,
+        req: Request,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: &ReplyIoctl,
     ) {
-        todo!("IOCTL");
+        let callid = log_call!(
+            "IOCTL",
+            ino,
+            fh: "{:x}",
+            flags: "{:x}",
+            cmd: "{:x}",
+            in_data: "[{:x}]" = in_data.len(),
+            out_size,
+        );
+        let _guard = self.locks.lock(Tree::Shared, &[], &[ino]);
+        let mut dl = self.data.lock().unwrap();
+        let inv = inv_ioctl_before(
+            callid, &req, ino, fh, flags, cmd, in_data, out_size, &mut dl,
+        );
+        drop(dl);
+        let ids = set_ids(callid, req, None);
+        let res = unsafe {
+            let dir_fhs = self.dir_fhs.lock().unwrap();
+            let fd = if flags & FUSE_IOCTL_DIR != 0 {
+                libc::dirfd(dir_fhs.get(&fh).unwrap().0)
+            } else {
+                fh.try_into().unwrap()
+            };
+            let mut buf = vec![0u8; FSXATTR_SIZE];
+            let res = if cmd == libc::FS_IOC_GETFLAGS as u32 || cmd == FS_IOC_FSGETXATTR {
+                libc::ioctl(fd, cmd as _, buf.as_mut_ptr())
+            } else if cmd == libc::FS_IOC_SETFLAGS as u32 && in_data.len() >= 4 {
+                buf[..4].copy_from_slice(&in_data[..4]);
+                libc::ioctl(fd, cmd as _, buf.as_ptr())
+            } else if cmd == libc::FS_IOC_SETFLAGS as u32 {
+                *libc::__errno_location() = libc::EINVAL;
+                -1
+            } else {
+                *libc::__errno_location() = libc::ENOTTY;
+                -1
+            };
+            if res == 0 {
+                buf.truncate(out_size.try_into().unwrap());
+                Ok(buf)
+            } else {
+                Err(*libc::__errno_location())
+            }
+        };
+        log_res!(callid, "{:?}", res);
+        restore_ids(ids);
+        let mut dl = self.data.lock().unwrap();
+        let check = apply(callid, &mut dl, &[ino], |dl| {
+            inv_ioctl_after(callid, inv, &res, dl)
+        });
+        drop(dl);
+        match self.handle_violation(callid, "IOCTL", Some(ino), check, res) {
+            Ok(v) => reply.ioctl(0, &v),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use crate::{
+        invariants::inode_flags::{FS_APPEND_FL, FS_IMMUTABLE_FL},
+        req_rep::{ReplyAttr, ReplyEmpty, ReplyIoctl, ReplyWrite},
+        test::{create, init, open, ROOT},
+    };
+
+    #[test]
+    fn test_ioctl_flags() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        let ino = create(&ifs, "foo", 0o644);
+        let fh = open(&ifs, ino, libc::O_RDWR);
+
+        let ioctl = |cmd: libc::Ioctl, data: &[u8], out_size| {
+            let rep = ReplyIoctl::new();
+            ifs.do_ioctl(ROOT, ino, fh, 0, cmd as u32, data, out_size, &rep);
+            rep.get()
+        };
+        let set = |flags: u32| ioctl(libc::FS_IOC_SETFLAGS, &flags.to_ne_bytes(), 0);
+        let get = || {
+            let v = ioctl(libc::FS_IOC_GETFLAGS, &[], 4).unwrap().1;
+            u32::from_ne_bytes(v.try_into().unwrap())
+        };
+        let write = |offset, data: &[u8]| {
+            let rep = ReplyWrite::new();
+            ifs.do_write(ROOT, ino, fh, offset, data, 0, 0, None, &rep);
+            rep.get()
+        };
+        let chmod = || {
+            let rep = ReplyAttr::new();
+            ifs.do_setattr(
+                ROOT,
+                ino,
+                Some(0o600),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                &rep,
+            );
+            rep.get().map(|_| ())
+        };
+        let truncate = || {
+            let rep = ReplyAttr::new();
+            ifs.do_setattr(
+                ROOT,
+                ino,
+                None,
+                None,
+                None,
+                Some(0),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                &rep,
+            );
+            rep.get().map(|_| ())
+        };
+        let unlink = || {
+            let rep = ReplyEmpty::new();
+            ifs.do_unlink(ROOT, 1, &OsString::from("foo"), &rep);
+            rep.get()
+        };
+        let rename = || {
+            let rep = ReplyEmpty::new();
+            ifs.do_rename(
+                ROOT,
+                1,
+                &OsString::from("foo"),
+                1,
+                &OsString::from("bar"),
+                0,
+                &rep,
+            );
+            rep.get()
+        };
+
+        assert_eq!(write(0, b"abc"), Ok(3));
+        assert_eq!(get() & FS_IMMUTABLE_FL, 0);
+        let base = get();
+        assert!(set(base | FS_IMMUTABLE_FL).is_ok());
+        assert_ne!(get() & FS_IMMUTABLE_FL, 0);
+        assert_eq!(write(0, b"x"), Err(libc::EPERM));
+        assert_eq!(chmod(), Err(libc::EPERM));
+        assert_eq!(truncate(), Err(libc::EPERM));
+        assert_eq!(unlink(), Err(libc::EPERM));
+        assert_eq!(rename(), Err(libc::EPERM));
+
+        assert!(set(base | FS_APPEND_FL).is_ok());
+        assert_eq!(truncate(), Err(libc::EPERM));
+        assert_eq!(chmod(), Err(libc::EPERM));
+        assert_eq!(unlink(), Err(libc::EPERM));
+        assert_eq!(write(3, b"d"), Ok(1));
+
+        assert!(set(base).is_ok());
+        assert_eq!(ioctl(0x1234, &[], 0), Err(libc::ENOTTY));
+        assert_eq!(unlink(), Ok(()));
+        assert!(ifs.violations().is_empty());
     }
 }
@@ -1,9 +1,10 @@
-use std::{ffi::CString, os::unix::prelude::OsStrExt};
-
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids, stat_path, TTL},
+    fs::{restore_ids, set_ids, TTL},
     fs_to_fuse::FsToFuseAttr,
-    invariants::fs::link::{inv_link_after, inv_link_before},
+    invariants::{
+        fs::link::{inv_link_after, inv_link_before},
+        locks::{record, Tree},
+    },
     log_call, log_more, log_res,
     req_rep::{ReplyEntry, Request},
 };
@@ -12,45 +13,53 @@ use super::InvFS;
 
 impl InvFS {
     pub fn do_link(
-        &mut self,
+        &self,
         req: Request,
         ino: u64,
         newparent: u64,
         newname: &std::ffi::OsStr,
         reply: &ReplyEntry,
     ) {
-        let callid = log_call!(
-            "LINK",
-            "ino={},newparent={},newname={:?}",
-            ino,
-            newparent,
-            newname,
-        );
-        let cwd = chdirin(&self.root);
+        let callid = log_call!("LINK", ino, newparent, newname: "{:?}");
+        let guard = self.locks.lock(Tree::Shared, &[], &[ino, newparent]);
         let mut dl = self.data.lock().unwrap();
         let inv = inv_link_before(callid, &req, &self.root, ino, newparent, newname, &mut dl);
-        let ids = set_ids(callid, req, None);
-        let ip = &mut dl.INODE_PATHS;
+        let ip = &dl.INODE_PATHS;
         let p_path = ip.get(newparent);
         log_more!(callid, "newparent={:?}", p_path);
         let newchild = p_path.join(newname);
         log_more!(callid, "newchild={:?}", newchild);
-        let old_file = ip.get(ino);
+        let old_file = ip.get(ino).to_owned();
+        drop(dl);
+        let ids = set_ids(callid, req, None);
         let res = unsafe {
-            let old = CString::new(old_file.as_os_str().as_bytes()).unwrap();
-            let new = CString::new(newchild.as_os_str().as_bytes()).unwrap();
-            let res = libc::link(old.as_ptr(), new.as_ptr());
+            let old = self.rel_path(&old_file);
+            let new = self.rel_path(&newchild);
+            let res = libc::linkat(
+                self.root_fd(),
+                old.as_ptr(),
+                self.root_fd(),
+                new.as_ptr(),
+                0,
+            );
             if res == 0 {
-                ip.insert(ino, newchild.clone());
-                stat_path(&newchild).map(|x| x.to_fuse_attr(ino))
+                self.stat_path(&newchild).map(|x| x.to_fuse_attr(ino))
             } else {
                 Err(*libc::__errno_location())
             }
         };
-        log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        inv_link_after(callid, inv, &res, &mut dl);
-        chdirout(cwd);
+        let mut dl = self.data.lock().unwrap();
+        if res.is_ok() {
+            dl.INODE_PATHS.lookup(ino, newchild);
+            dl.listings.touch(newparent, newname);
+        }
+        log_res!(callid, "{:?}", res);
+        let check = record(callid, &mut dl, &[ino, newparent], guard.ticket(), |dl| {
+            inv_link_after(callid, inv, &res, dl)
+        });
+        drop(dl);
+        let res = self.handle_violation(callid, "LINK", Some(ino), check, res);
         match res {
             Ok(attr) => reply.entry(&TTL, &attr, 0),
             Err(v) => reply.error(v),
@@ -64,21 +73,14 @@ mod tests {
 
     use crate::{
         fs::TTL,
-        req_rep::{KernelConfig, ReplyCreate, ReplyEntry, Request},
+        req_rep::{ReplyCreate, ReplyEntry, Request},
+        test::init,
     };
 
     #[test]
     fn test_link() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep_c = ReplyCreate::new();
         ifs.do_create(
             Request {
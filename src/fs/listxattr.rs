@@ -1,47 +1,48 @@
-use std::{ffi::CString, os::unix::prelude::OsStrExt};
-
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
+    fs::{restore_ids, set_ids},
+    invariants::{
+        fs::listxattr::{inv_listxattr_after, inv_listxattr_before},
+        locks::Tree,
+    },
     log_call, log_more, log_res,
+    req_rep::{ReplyXattr, Request, Xattr},
 };
 
 use super::InvFS;
 
 impl InvFS {
-    pub fn do_listxattr(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        size: u32,
-        reply: fuser::ReplyXattr,
-    ) {
-        let callid = log_call!("LISTXATTR", "ino={},size={:x}", ino, size);
-        let cwd = chdirin(&self.root);
-        let ids = set_ids(callid, req.into(), None);
-        let dl = self.data.lock().unwrap();
-        let ip = &dl.INODE_PATHS;
-        let path = ip.get(ino);
+    pub fn do_listxattr(&self, req: Request, ino: u64, size: u32, reply: &ReplyXattr) {
+        let callid = log_call!("LISTXATTR", ino, size: "{:x}");
+        let _guard = self.locks.lock(Tree::Shared, &[], &[]);
+        let mut dl = self.data.lock().unwrap();
+        let inv = inv_listxattr_before(callid, &req, &self.root, ino, size, &mut dl);
+        let path = dl.INODE_PATHS.get(ino).to_owned();
+        drop(dl);
         log_more!(callid, "path={:?}", path);
+        let ids = set_ids(callid, req, None);
         let res = unsafe {
-            let tgt = CString::new(path.as_os_str().as_bytes()).unwrap();
+            let tgt = self.fd_path(&path);
             let mut buf = vec![0u8; size.try_into().unwrap()];
-            let res = libc::listxattr(
+            let res = libc::llistxattr(
                 tgt.as_ptr(),
                 buf.as_mut_ptr() as *mut i8,
                 size.try_into().unwrap(),
             );
-            if res != -1 {
-                buf.truncate(size.try_into().unwrap());
-                Ok(buf)
-            } else {
+            if res == -1 {
                 Err(*libc::__errno_location())
+            } else if size == 0 {
+                Ok(Xattr::Size(res.try_into().unwrap()))
+            } else {
+                buf.truncate(res.try_into().unwrap());
+                Ok(Xattr::Data(buf))
             }
         };
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        chdirout(cwd);
-        match res {
-            Ok(v) => reply.data(&v),
+        let check = inv_listxattr_after(callid, inv, &res, &mut self.data.lock().unwrap());
+        match self.handle_violation(callid, "LISTXATTR", Some(ino), check, res) {
+            Ok(Xattr::Size(v)) => reply.size(v),
+            Ok(Xattr::Data(v)) => reply.data(&v),
             Err(v) => reply.error(v),
         }
     }
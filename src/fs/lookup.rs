@@ -1,7 +1,10 @@
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids, stat_path, TTL},
+    fs::{restore_ids, set_ids, TTL},
     fs_to_fuse::FsToFuseAttr,
-    invariants::fs::lookup::{inv_lookup_after, inv_lookup_before},
+    invariants::{
+        fs::lookup::{inv_lookup_after, inv_lookup_before},
+        locks::Tree,
+    },
     log_call, log_more, log_res,
     req_rep::{ReplyEntry, Request},
 };
@@ -9,31 +12,33 @@ use crate::{
 use super::InvFS;
 
 impl InvFS {
-    pub fn do_lookup(
-        &mut self,
-        req: Request,
-        parent: u64,
-        name: &std::ffi::OsStr,
-        reply: &ReplyEntry,
-    ) {
-        let callid = log_call!("LOOKUP", "parent={},name={:?}", parent, name);
-        let cwd = chdirin(&self.root);
+    pub fn do_lookup(&self, req: Request, parent: u64, name: &std::ffi::OsStr, reply: &ReplyEntry) {
+        let callid = log_call!("LOOKUP", parent, name: "{:?}");
+        let _guard = self.locks.lock(Tree::Shared, &[parent], &[]);
         let mut dl = self.data.lock().unwrap();
         let inv = inv_lookup_before(callid, &req, &self.root, parent, name, &mut dl);
-        let ids = set_ids(callid, req, None);
-        let ip = &mut dl.INODE_PATHS;
-        let p_path = ip.get(parent);
+        let watch = inv.child().map(|x| dl.watches.open(x));
+        let p_path = dl.INODE_PATHS.get(parent);
         log_more!(callid, "parent={:?}", p_path);
         let child = p_path.join(name);
         log_more!(callid, "child={:?}", child);
-        let res = unsafe { stat_path(&child) }.map(|v| {
-            let ino = ip.insert(v.st_ino, child);
+        drop(dl);
+        let ids = set_ids(callid, req, None);
+        let res = unsafe { self.stat_path(&child) };
+        restore_ids(ids);
+        let res = res.map(|v| {
+            let ino = self
+                .data
+                .lock()
+                .unwrap()
+                .INODE_PATHS
+                .lookup(v.st_ino, child);
             v.to_fuse_attr(ino)
         });
         log_res!(callid, "{:#?}", res);
-        restore_ids(ids);
-        inv_lookup_after(callid, inv, &res, &mut dl);
-        chdirout(cwd);
+        let check =
+            self.check_observed(watch, |dl| inv_lookup_after(callid, inv.clone(), &res, dl));
+        let res = self.handle_violation(callid, "LOOKUP", Some(parent), check, res);
         match res {
             Ok(v) => reply.entry(&TTL, &v, 0),
             Err(v) => reply.error(v),
@@ -47,21 +52,14 @@ mod tests {
 
     use crate::{
         fs::TTL,
-        req_rep::{KernelConfig, ReplyCreate, ReplyEntry, Request},
+        req_rep::{ReplyCreate, ReplyEntry, Request},
+        test::init,
     };
 
     #[test]
     fn test_lookup() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep_c = ReplyCreate::new();
         ifs.do_create(
             Request {
@@ -1,15 +1,133 @@
+use crate::{
+    fs::{restore_ids, set_ids},
+    invariants::{
+        fs::lseek::{inv_lseek_after, inv_lseek_before},
+        locks::Tree,
+    },
+    log_call, log_res,
+    req_rep::{ReplyLseek, Request},
+};
+
 use super::InvFS;
 
 impl InvFS {
+    /// The kernel handles SEEK_SET, SEEK_CUR and SEEK_END itself, but forwards SEEK_DATA and
+    /// SEEK_HOLE, which need to know where the holes are.
     pub fn do_lseek(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        _offset: i64,
-        _whence: i32,
-        _reply: fuser::ReplyLseek,
+        &self,
+        req: Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: &ReplyLseek,
     ) {
-        todo!("LSEEK should not be needed? Read has an offset?");
+        let callid = log_call!("LSEEK", ino, fh: "{:x}", offset: "{:x}", whence);
+        let _guard = self.locks.lock(Tree::Shared, &[], &[]);
+        let mut dl = self.data.lock().unwrap();
+        let inv = inv_lseek_before(callid, &req, ino, fh, offset, whence, &mut dl);
+        let watch = dl.watches.open(ino);
+        drop(dl);
+        let ids = set_ids(callid, req, None);
+        let res = unsafe {
+            let res = libc::lseek(fh as i32, offset, whence);
+            if res != -1 {
+                Ok(res)
+            } else {
+                Err(*libc::__errno_location())
+            }
+        };
+        log_res!(callid, "{:?}", res);
+        restore_ids(ids);
+        let check = self.check_observed(Some(watch), |dl| {
+            inv_lseek_after(callid, inv.clone(), &res, dl)
+        });
+        match self.handle_violation(callid, "LSEEK", Some(ino), check, res) {
+            Ok(v) => reply.offset(v),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use crate::req_rep::{ReplyEmpty, ReplyLseek, ReplyWrite};
+
+    use crate::test::{create, init, open, ROOT};
+
+    #[test]
+    fn test_lseek() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        let ino = create(&ifs, "foo", 0o644);
+        let fh = open(&ifs, ino, libc::O_RDWR);
+        // Data, a hole of a few blocks, then data again.
+        let w_rep = ReplyWrite::new();
+        ifs.do_write(ROOT, ino, fh, 0, b"head", 0, 0, None, &w_rep);
+        assert_eq!(w_rep.get(), Ok(4));
+        let w_rep = ReplyWrite::new();
+        ifs.do_write(ROOT, ino, fh, 1 << 20, b"tail", 0, 0, None, &w_rep);
+        assert_eq!(w_rep.get(), Ok(4));
+
+        let lseek = |offset, whence| {
+            let rep = ReplyLseek::new();
+            ifs.do_lseek(ROOT, ino, fh, offset, whence, &rep);
+            rep.get()
+        };
+        let size = (1 << 20) + 4;
+        assert_eq!(lseek(0, libc::SEEK_DATA), Ok(0));
+        let hole = lseek(0, libc::SEEK_HOLE).unwrap();
+        assert!((4..=size).contains(&hole));
+        let data = lseek(4, libc::SEEK_DATA).unwrap();
+        assert!((4..=1 << 20).contains(&data));
+        assert_eq!(lseek(size, libc::SEEK_DATA), Err(libc::ENXIO));
+        assert_eq!(lseek(size, libc::SEEK_HOLE), Err(libc::ENXIO));
+
+        // Punching the head out leaves nothing that must be data before the tail.
+        let rep = ReplyEmpty::new();
+        ifs.do_fallocate(
+            ROOT,
+            ino,
+            fh,
+            0,
+            4,
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            &rep,
+        );
+        let data = lseek(0, libc::SEEK_DATA).unwrap();
+        assert!((0..=1 << 20).contains(&data));
+    }
+
+    #[test]
+    fn test_lseek_unlinked() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        let ino = create(&ifs, "foo", 0o644);
+        let fh = open(&ifs, ino, libc::O_RDWR);
+        let w_rep = ReplyWrite::new();
+        ifs.do_write(ROOT, ino, fh, 0, b"head", 0, 0, None, &w_rep);
+        assert_eq!(w_rep.get(), Ok(4));
+        let rep = ReplyEmpty::new();
+        ifs.do_unlink(ROOT, 1, &OsString::from("foo"), &rep);
+        assert_eq!(rep.get(), Ok(()));
+
+        // Still open, so still usable and still checked.
+        let rep = ReplyLseek::new();
+        ifs.do_lseek(ROOT, ino, fh, 0, libc::SEEK_HOLE, &rep);
+        assert_eq!(rep.get(), Ok(4));
+        let w_rep = ReplyWrite::new();
+        ifs.do_write(ROOT, ino, fh, 4, b"tail", 0, 0, None, &w_rep);
+        assert_eq!(w_rep.get(), Ok(4));
+        assert!(ifs.violations().is_empty());
+
+        let rep = ReplyEmpty::new();
+        ifs.do_release(ROOT, ino, fh, 0, None, false, &rep);
+        assert_eq!(rep.get(), Ok(()));
+        ifs.do_forget(ROOT, ino, 1);
+        let dl = ifs.data.lock().unwrap();
+        assert!(!dl.INV_INODE_CONTENTS.contains_key(&ino));
+        assert!(!dl.INV_FILE_CONTENTS.contains_key(&ino));
     }
 }
@@ -1,9 +1,10 @@
-use std::{ffi::CString, os::unix::prelude::OsStrExt};
-
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids, stat_path, TTL},
+    fs::{restore_ids, set_ids, TTL},
     fs_to_fuse::FsToFuseAttr,
-    invariants::fs::mkdir::{inv_mkdir_after, inv_mkdir_before},
+    invariants::{
+        fs::mkdir::{inv_mkdir_after, inv_mkdir_before},
+        locks::{record, Tree},
+    },
     log_call, log_more, log_res,
     req_rep::{ReplyEntry, Request},
 };
@@ -12,7 +13,7 @@ use super::InvFS;
 
 impl InvFS {
     pub fn do_mkdir(
-        &mut self,
+        &self,
         req: Request,
         parent: u64,
         name: &std::ffi::OsStr,
@@ -20,39 +21,40 @@ impl InvFS {
         umask: u32,
         reply: &ReplyEntry,
     ) {
-        let callid = log_call!(
-            "MKDIR",
-            "parent={},name={:?},mode={:o},umask={:o}",
-            parent,
-            name,
-            mode,
-            umask
-        );
-        let cwd = chdirin(&self.root);
+        let callid = log_call!("MKDIR", parent, name: "{:?}", mode: "{:o}", umask: "{:o}");
+        let guard = self.locks.lock(Tree::Shared, &[], &[parent]);
         let mut dl = self.data.lock().unwrap();
         let inv = inv_mkdir_before(callid, &req, &self.root, parent, name, mode, umask, &mut dl);
-        let ids = set_ids(callid, req, Some(umask));
-        let ip = &mut dl.INODE_PATHS;
-        let p_path = ip.get(parent);
+        let p_path = dl.INODE_PATHS.get(parent);
         log_more!(callid, "parent={:?}", p_path);
         let child = p_path.join(name);
         log_more!(callid, "child={:?}", child);
+        drop(dl);
+        let ids = set_ids(callid, req, Some(umask));
         let res = unsafe {
-            let tgt = CString::new(child.as_os_str().as_bytes()).unwrap();
-            let res = libc::mkdir(tgt.as_ptr(), mode);
+            let tgt = self.rel_path(&child);
+            let res = libc::mkdirat(self.root_fd(), tgt.as_ptr(), mode);
             if res == 0 {
-                stat_path(&child).map(|x| {
-                    let ino = ip.insert(x.st_ino, child);
-                    x.to_fuse_attr(ino)
-                })
+                self.stat_path(&child)
             } else {
                 Err(*libc::__errno_location())
             }
         };
-        log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        inv_mkdir_after(callid, inv, &res, &mut dl);
-        chdirout(cwd);
+        let mut dl = self.data.lock().unwrap();
+        let res = res.map(|x| {
+            let ino = dl.INODE_PATHS.lookup(x.st_ino, child);
+            x.to_fuse_attr(ino)
+        });
+        log_res!(callid, "{:?}", res);
+        if res.is_ok() {
+            dl.listings.touch(parent, name);
+        }
+        let check = record(callid, &mut dl, &[parent], guard.ticket(), |dl| {
+            inv_mkdir_after(callid, inv, &res, dl)
+        });
+        drop(dl);
+        let res = self.handle_violation(callid, "MKDIR", Some(parent), check, res);
         match res {
             Ok(v) => reply.entry(&TTL, &v, 0),
             Err(v) => reply.error(v),
@@ -62,25 +64,22 @@ impl InvFS {
 
 #[cfg(test)]
 mod tests {
-    use std::ffi::OsString;
+    use std::{
+        ffi::{CString, OsString},
+        os::unix::prelude::{OsStrExt, PermissionsExt},
+    };
 
     use crate::{
         fs::TTL,
-        req_rep::{KernelConfig, ReplyEntry, Request},
+        invariants::violation::ViolationPolicy,
+        req_rep::{ReplyAttr, ReplyEmpty, ReplyEntry, Request},
+        test::{init, ROOT},
     };
 
     #[test]
     fn test_mkdir() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep = ReplyEntry::new();
         ifs.do_mkdir(
             Request {
@@ -119,4 +118,33 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn test_violation_resyncs() {
+        let ifs = crate::test::create_ifs().with_policy(ViolationPolicy::Log);
+        init(&ifs);
+        let mkdir = |parent, name: &str| {
+            let rep = ReplyEntry::new();
+            ifs.do_mkdir(ROOT, parent, &OsString::from(name), 0o755, 0, &rep);
+            rep.get().unwrap().1.ino
+        };
+        let dir = mkdir(1, "foo");
+        // Behind the model's back: children of a setgid directory take its group.
+        let path = ifs.root.join("foo");
+        let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::chown(cpath.as_ptr(), u32::MAX, 5) }, 0);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o2755)).unwrap();
+
+        mkdir(dir, "bar");
+        assert_eq!(ifs.violations().len(), 1);
+        // The model caught up with the backend, so what follows is checked against it.
+        let rep = ReplyAttr::new();
+        ifs.do_getattr(ROOT, dir, &rep);
+        assert!(rep.get().is_ok());
+        let rep = ReplyEmpty::new();
+        ifs.do_rmdir(ROOT, dir, &OsString::from("bar"), &rep);
+        assert_eq!(rep.get(), Ok(()));
+        mkdir(1, "baz");
+        assert_eq!(ifs.violations().len(), 1);
+    }
 }
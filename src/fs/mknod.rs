@@ -1,9 +1,10 @@
-use std::{ffi::CString, os::unix::prelude::OsStrExt};
-
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids, stat_path, TTL},
+    fs::{restore_ids, set_ids, TTL},
     fs_to_fuse::FsToFuseAttr,
-    invariants::fs::mknod::{inv_mknod_after, inv_mknod_before},
+    invariants::{
+        fs::mknod::{inv_mknod_after, inv_mknod_before},
+        locks::{record, Tree},
+    },
     log_call, log_more, log_res,
     req_rep::{ReplyEntry, Request},
 };
@@ -12,7 +13,7 @@ use super::InvFS;
 
 impl InvFS {
     pub fn do_mknod(
-        &mut self,
+        &self,
         req: Request,
         parent: u64,
         name: &std::ffi::OsStr,
@@ -21,42 +22,42 @@ impl InvFS {
         rdev: u32,
         reply: &ReplyEntry,
     ) {
-        let callid = log_call!(
-            "MKNOD",
-            "parent={},name={:?},mode={:o},umask={:o},rdev={}",
-            parent,
-            name,
-            mode,
-            umask,
-            rdev
-        );
-        let cwd = chdirin(&self.root);
+        let callid = log_call!("MKNOD", parent, name: "{:?}", mode: "{:o}", umask: "{:o}", rdev);
+        let guard = self.locks.lock(Tree::Shared, &[], &[parent]);
         let mut dl = self.data.lock().unwrap();
         let inv = inv_mknod_before(
             callid, &req, &self.root, parent, name, mode, umask, rdev, &mut dl,
         );
-        let ids = set_ids(callid, req, Some(umask));
-        let ip = &mut dl.INODE_PATHS;
-        let p_path = ip.get(parent);
+        let p_path = dl.INODE_PATHS.get(parent);
         log_more!(callid, "parent={:?}", p_path);
         let child = p_path.join(name);
         log_more!(callid, "child={:?}", child);
+        drop(dl);
+        let ids = set_ids(callid, req, Some(umask));
         let res = unsafe {
-            let tgt = CString::new(child.as_os_str().as_bytes()).unwrap();
-            let res = libc::mknod(tgt.as_ptr(), mode, rdev.into());
+            let tgt = self.rel_path(&child);
+            let res = libc::mknodat(self.root_fd(), tgt.as_ptr(), mode, rdev.into());
             if res == 0 {
-                stat_path(&child).map(|x| {
-                    let ino = ip.insert(x.st_ino, child);
-                    x.to_fuse_attr(ino)
-                })
+                self.stat_path(&child)
             } else {
                 Err(*libc::__errno_location())
             }
         };
-        log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        inv_mknod_after(callid, inv, &res, &mut dl);
-        chdirout(cwd);
+        let mut dl = self.data.lock().unwrap();
+        let res = res.map(|x| {
+            let ino = dl.INODE_PATHS.lookup(x.st_ino, child);
+            x.to_fuse_attr(ino)
+        });
+        log_res!(callid, "{:?}", res);
+        if res.is_ok() {
+            dl.listings.touch(parent, name);
+        }
+        let check = record(callid, &mut dl, &[parent], guard.ticket(), |dl| {
+            inv_mknod_after(callid, inv, &res, dl)
+        });
+        drop(dl);
+        let res = self.handle_violation(callid, "MKNOD", Some(parent), check, res);
         match res {
             Ok(attr) => reply.entry(&TTL, &attr, 0),
             Err(v) => reply.error(v),
@@ -70,21 +71,14 @@ mod tests {
 
     use crate::{
         fs::TTL,
-        req_rep::{KernelConfig, ReplyEntry, Request},
+        req_rep::{ReplyEntry, Request},
+        test::init,
     };
 
     #[test]
     fn test_mknod() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep = ReplyEntry::new();
         ifs.do_mknod(
             Request {
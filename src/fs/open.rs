@@ -1,7 +1,9 @@
-use std::{ffi::CString, os::unix::prelude::OsStrExt};
-
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
+    fs::{restore_ids, set_ids},
+    invariants::{
+        fs::open::{inv_open_after, inv_open_before},
+        locks::{record, Tree},
+    },
     log_call, log_more, log_res,
     req_rep::{ReplyOpen, Request},
 };
@@ -9,17 +11,25 @@ use crate::{
 use super::InvFS;
 
 impl InvFS {
-    pub fn do_open(&mut self, req: Request, ino: u64, flags: i32, reply: &ReplyOpen) {
-        let callid = log_call!("OPEN", "ino={},flags={:x}", ino, flags);
-        let cwd = chdirin(&self.root);
+    pub fn do_open(&self, req: Request, ino: u64, flags: i32, reply: &ReplyOpen) {
+        let callid = log_call!("OPEN", ino, flags: "{:x}");
+        // Truncating changes the file; anything else only looks at it.
+        let trunc = flags & libc::O_TRUNC != 0;
+        let guard = if trunc {
+            self.locks.lock(Tree::Shared, &[], &[ino])
+        } else {
+            self.locks.lock(Tree::Shared, &[], &[])
+        };
+        let mut dl = self.data.lock().unwrap();
+        let inv = inv_open_before(callid, &req, &self.root, ino, flags, &mut dl);
+        let watch = (!trunc).then(|| dl.watches.open(ino));
+        let path = dl.INODE_PATHS.get(ino).to_owned();
+        drop(dl);
         let ids = set_ids(callid, req, None);
-        let dl = self.data.lock().unwrap();
-        let ip = &dl.INODE_PATHS;
-        let path = ip.get(ino);
         log_more!(callid, "path={:?}", path);
         let res = unsafe {
-            let tgt = CString::new(path.as_os_str().as_bytes()).unwrap();
-            let res = libc::open(tgt.as_ptr(), flags);
+            let tgt = self.rel_path(&path);
+            let res = libc::openat(self.root_fd(), tgt.as_ptr(), flags);
             if res != -1 {
                 Ok(res)
             } else {
@@ -28,7 +38,17 @@ impl InvFS {
         };
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        chdirout(cwd);
+        let check = if trunc {
+            let mut dl = self.data.lock().unwrap();
+            record(callid, &mut dl, &[ino], guard.ticket(), |dl| {
+                inv_open_after(callid, inv, &res, dl)
+            })
+        } else {
+            self.check_observed(watch, |dl| inv_open_after(callid, inv.clone(), &res, dl))
+        };
+        let res = self.handle_violation_with(callid, "OPEN", Some(ino), check, res, |fd| unsafe {
+            libc::close(fd);
+        });
         match res {
             Ok(v) => reply.opened(v.try_into().unwrap(), 0),
             Err(v) => reply.error(v),
@@ -38,25 +58,18 @@ impl InvFS {
 
 #[cfg(test)]
 mod tests {
-    use std::ffi::OsString;
+    use std::{ffi::OsString, path::Path};
 
     use crate::{
         fs::TTL,
-        req_rep::{KernelConfig, ReplyCreate, ReplyOpen, Request},
+        req_rep::{ReplyAttr, ReplyCreate, ReplyEntry, ReplyOpen, ReplyWrite, Request},
+        test::{init, ROOT},
     };
 
     #[test]
     fn test_open() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep = ReplyCreate::new();
         ifs.do_create(
             Request {
@@ -112,4 +125,64 @@ mod tests {
         assert_ne!(o_rep.get().unwrap().0, 0);
         assert_eq!(o_rep.get().unwrap().1, 0);
     }
+
+    #[test]
+    fn test_open_flags() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        let create = |name: &str, flags| {
+            let rep = ReplyCreate::new();
+            let flags = libc::O_CREAT | libc::O_RDWR | flags;
+            ifs.do_create(ROOT, 1, &OsString::from(name), 0o644, 0, flags, &rep);
+            rep.get().map(|x| (x.1.ino, x.3))
+        };
+        let open = |req, ino, flags| {
+            let rep = ReplyOpen::new();
+            ifs.do_open(req, ino, flags, &rep);
+            rep.get().map(|_| ())
+        };
+        let size = |ino| {
+            let rep = ReplyAttr::new();
+            ifs.do_getattr(ROOT, ino, &rep);
+            rep.get().unwrap().1.size
+        };
+
+        let (foo, fh) = create("foo", 0).unwrap();
+        let rep = ReplyWrite::new();
+        ifs.do_write(ROOT, foo, fh, 0, b"hello", 0, 0, None, &rep);
+        assert_eq!(rep.get(), Ok(5));
+        assert_eq!(open(ROOT, foo, libc::O_RDONLY), Ok(()));
+        assert_eq!(size(foo), 5);
+        assert_eq!(open(ROOT, foo, libc::O_WRONLY | libc::O_TRUNC), Ok(()));
+        assert_eq!(size(foo), 0);
+
+        // Creating over an existing file opens it, unless O_EXCL
+        let rep = ReplyWrite::new();
+        ifs.do_write(ROOT, foo, fh, 0, b"hello", 0, 0, None, &rep);
+        assert_eq!(create("foo", libc::O_EXCL), Err(libc::EEXIST));
+        assert_eq!(create("foo", libc::O_TRUNC).map(|x| x.0), Ok(foo));
+        assert_eq!(size(foo), 0);
+
+        assert_eq!(open(ROOT, 1, libc::O_RDONLY), Ok(()));
+        assert_eq!(open(ROOT, 1, libc::O_RDWR), Err(libc::EISDIR));
+        assert_eq!(open(ROOT, foo, libc::O_DIRECTORY), Err(libc::ENOTDIR));
+        let rep = ReplyEntry::new();
+        ifs.do_symlink(ROOT, 1, &OsString::from("link"), Path::new("foo"), &rep);
+        let link = rep.get().unwrap().1.ino;
+        assert_eq!(open(ROOT, link, libc::O_NOFOLLOW), Err(libc::ELOOP));
+
+        let user = || Request {
+            uid: 1000,
+            gid: 1000,
+            pid: 0,
+        };
+        assert_eq!(open(user(), foo, libc::O_RDONLY), Ok(()));
+        assert_eq!(open(user(), foo, libc::O_RDWR), Err(libc::EACCES));
+        assert_eq!(
+            open(user(), foo, libc::O_RDONLY | libc::O_TRUNC),
+            Err(libc::EACCES)
+        );
+
+        assert!(ifs.violations().is_empty());
+    }
 }
@@ -1,30 +1,31 @@
-use std::{ffi::CString, os::unix::prelude::OsStrExt};
-
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
+    fs::{restore_ids, set_ids},
+    invariants::locks::Tree,
     log_call, log_more, log_res,
+    req_rep::{ReplyOpen, Request},
 };
 
-use super::InvFS;
+use super::{DirHandle, InvFS};
 
 impl InvFS {
-    pub fn do_opendir(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
-        flags: i32,
-        reply: fuser::ReplyOpen,
-    ) {
-        let callid = log_call!("OPENDIR", "ino={},flags={:x}", ino, flags);
-        let cwd = chdirin(&self.root);
-        let ids = set_ids(callid, req.into(), None);
-        let dl = self.data.lock().unwrap();
-        let ip = &dl.INODE_PATHS;
-        let path = ip.get(ino);
+    pub fn do_opendir(&self, req: Request, ino: u64, flags: i32, reply: &ReplyOpen) {
+        let callid = log_call!("OPENDIR", ino, flags: "{:x}");
+        let ids = set_ids(callid, req, None);
+        let _guard = self.locks.lock(Tree::Shared, &[], &[]);
+        let path = self.data.lock().unwrap().INODE_PATHS.get(ino).to_owned();
         log_more!(callid, "path={:?}", path);
         let res = unsafe {
-            let tgt = CString::new(path.as_os_str().as_bytes()).unwrap();
-            let res = libc::opendir(tgt.as_ptr());
+            let tgt = self.rel_path(&path);
+            let fd = libc::openat(
+                self.root_fd(),
+                tgt.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            );
+            let res = if fd != -1 {
+                libc::fdopendir(fd)
+            } else {
+                std::ptr::null_mut()
+            };
             if !res.is_null() {
                 Ok(res)
             } else {
@@ -33,11 +34,12 @@ impl InvFS {
         };
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        chdirout(cwd);
         match res {
             Ok(v) => {
-                let fh = self.dir_fhs.iter().last().map(|(x, _)| *x).unwrap_or(0) + 1;
-                self.dir_fhs.insert(fh, v);
+                let mut dir_fhs = self.dir_fhs.lock().unwrap();
+                let fh = dir_fhs.iter().last().map(|(x, _)| *x).unwrap_or(0) + 1;
+                dir_fhs.insert(fh, DirHandle(v, Some(0)));
+                self.data.lock().unwrap().listings.open(fh, ino);
                 reply.opened(fh, 0)
             }
             Err(v) => reply.error(v),
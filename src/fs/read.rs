@@ -1,8 +1,11 @@
 use libc::c_void;
 
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
-    invariants::fs::read::{inv_read_after, inv_read_before},
+    fs::{restore_ids, set_ids},
+    invariants::{
+        fs::read::{inv_read_after, inv_read_before},
+        locks::Tree,
+    },
     log_call, log_res,
     logwrapper::LogWrapper,
     req_rep::{ReplyData, Request},
@@ -12,7 +15,7 @@ use super::InvFS;
 
 impl InvFS {
     pub fn do_read(
-        &mut self,
+        &self,
         req: Request,
         ino: u64,
         fh: u64,
@@ -24,32 +27,39 @@ impl InvFS {
     ) {
         let callid = log_call!(
             "READ",
-            "ino={},fh={:x},offset={:x},size={:x},flags={:x},lock_owner={:?}",
             ino,
-            fh,
-            offset,
-            size,
-            flags,
-            lock_owner
+            fh: "{:x}",
+            offset: "{:x}",
+            size: "{:x}",
+            flags: "{:x}",
+            lock_owner: "{:?}",
         );
-        let cwd = chdirin(&self.root);
+        let _guard = self.locks.lock(Tree::Shared, &[], &[]);
         let mut dl = self.data.lock().unwrap();
         let inv = inv_read_before(
             callid, &req, &self.root, ino, fh, offset, size, flags, lock_owner, &mut dl,
         );
+        let watch = dl.watches.open(ino);
+        drop(dl);
         let ids = set_ids(callid, req, None);
         let res = unsafe {
-            let offs = libc::lseek(fh as i32, offset, libc::SEEK_SET);
-            assert_eq!(offs, offset, "failed to seek");
             let mut buf = vec![0u8; size as usize];
-            let res = libc::read(fh as i32, buf.as_mut_ptr() as *mut c_void, size as usize);
+            // Not lseek + read: other requests may be using the same handle concurrently.
+            let res = libc::pread(
+                fh as i32,
+                buf.as_mut_ptr() as *mut c_void,
+                size as usize,
+                offset,
+            );
             buf.truncate(res.try_into().unwrap());
             Ok(buf)
         };
         log_res!(callid, "{}", res.lw());
         restore_ids(ids);
-        inv_read_after(callid, inv, &res, &mut dl);
-        chdirout(cwd);
+        let check = self.check_observed(Some(watch), |dl| {
+            inv_read_after(callid, inv.clone(), &res, dl)
+        });
+        let res = self.handle_violation(callid, "READ", Some(ino), check, res);
         match res {
             Ok(v) => reply.data(v),
             Err(e) => reply.error(e),
@@ -63,21 +73,14 @@ mod tests {
 
     use crate::{
         fs::TTL,
-        req_rep::{KernelConfig, ReplyCreate, ReplyData, ReplyOpen, ReplyWrite, Request},
+        req_rep::{ReplyCreate, ReplyData, ReplyOpen, ReplyWrite, Request},
+        test::{create, init, open, ROOT},
     };
 
     #[test]
     fn test_read() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep = ReplyCreate::new();
         ifs.do_create(
             Request {
@@ -169,4 +172,32 @@ mod tests {
         );
         assert_eq!(r_rep.get(), Ok(vec![b'f', b'o', b'o']));
     }
+
+    #[test]
+    fn test_read_racing_write() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        let ino = create(&ifs, "foo", 0);
+        let fh = open(&ifs, ino, libc::O_RDWR);
+        let w_rep = ReplyWrite::new();
+        ifs.do_write(ROOT, ino, fh, 0, b"aaa", 0, 0, None, &w_rep);
+        assert_eq!(w_rep.get(), Ok(3));
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..200 {
+                    let w_rep = ReplyWrite::new();
+                    let data = if i % 2 == 0 { b"bbb" } else { b"aaa" };
+                    ifs.do_write(ROOT, ino, fh, 0, data, 0, 0, None, &w_rep);
+                    assert_eq!(w_rep.get(), Ok(3));
+                }
+            });
+            for _ in 0..200 {
+                // Checked against the contents before and after every write it overlapped.
+                let r_rep = ReplyData::new();
+                ifs.do_read(ROOT, ino, fh, 0, 3, 0, None, &r_rep);
+                let v = r_rep.get().unwrap();
+                assert!(v == b"aaa" || v == b"bbb", "{:?}", v);
+            }
+        });
+    }
 }
@@ -6,38 +6,79 @@ use std::{
 use fuser::FileType;
 
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
-    log_call, log_res,
+    fs::{restore_ids, set_ids},
+    invariants::{
+        fs::readdir::{inv_readdir_after, inv_readdir_before},
+        locks::Tree,
+    },
+    log_call, log_more, log_res,
+    req_rep::{ReplyDirectory, Request},
 };
 
 use super::InvFS;
 
 impl InvFS {
-    pub fn do_readdir(
-        &mut self,
-        req: &fuser::Request<'_>,
-        ino: u64,
+    pub fn do_readdir(&self, req: Request, ino: u64, fh: u64, offset: i64, reply: &ReplyDirectory) {
+        let callid = log_call!("READDIR", ino, fh: "{:x}", offset: "{:x}");
+        let _guard = self.locks.lock(Tree::Shared, &[ino], &[]);
+        let inv = inv_readdir_before(callid, ino, fh, offset, &mut self.data.lock().unwrap());
+        let ids = set_ids(callid, req, None);
+        let mut entries = Vec::new();
+        let mut next = offset;
+        let res = loop {
+            match self.read_entry(fh, next) {
+                Ok(Some((d_ino, off, kind, name))) => {
+                    if reply.add(d_ino, off, kind, &name) {
+                        break Ok(entries);
+                    }
+                    entries.push((d_ino, off, kind, name));
+                    next = off;
+                }
+                Ok(None) => break Ok(entries),
+                Err(e) if entries.is_empty() => break Err(e),
+                // What was read so far still goes out; the next read will run into it again.
+                Err(e) => {
+                    log_more!(callid, "stopping at error={}", e);
+                    break Ok(entries);
+                }
+            }
+        };
+        log_res!(callid, "{:?}", res);
+        restore_ids(ids);
+        let check = inv_readdir_after(callid, inv, &res, &mut self.data.lock().unwrap());
+        match self.handle_violation(callid, "READDIR", Some(ino), check, res) {
+            Ok(_) => reply.ok(),
+            Err(v) => reply.error(v),
+        }
+    }
+
+    /// The entry of the directory stream `fh` at `offset`, or `None` at the end of the stream.
+    /// The stream is left after it, so asking for the next one costs no `seekdir`.
+    pub(super) fn read_entry(
+        &self,
         fh: u64,
         offset: i64,
-        mut reply: fuser::ReplyDirectory,
-    ) {
-        let callid = log_call!("READDIR", "ino={},fh={:x},offset={:x}", ino, fh, offset);
-        let cwd = chdirin(&self.root);
-        let ids = set_ids(callid, req.into(), None);
-        let dir = self.dir_fhs.get(&fh).unwrap();
-        let res = unsafe {
-            libc::seekdir(*dir, offset);
+    ) -> Result<Option<(u64, i64, FileType, OsString)>, i32> {
+        let mut dir_fhs = self.dir_fhs.lock().unwrap();
+        let handle = dir_fhs.get_mut(&fh).unwrap();
+        let dir = handle.0;
+        unsafe {
+            if handle.1 != Some(offset) {
+                libc::seekdir(dir, offset);
+                handle.1 = Some(offset);
+            }
             *libc::__errno_location() = 0;
-            let res = libc::readdir(*dir);
+            let res = libc::readdir(dir);
             if res.is_null() {
                 if *libc::__errno_location() == 0 {
                     Ok(None)
                 } else {
+                    handle.1 = None;
                     Err(*libc::__errno_location())
                 }
             } else {
                 let name = std::ffi::CStr::from_ptr(&(*res).d_name as *const i8);
-                let name = OsStr::from_bytes(name.to_bytes());
+                let name = OsStr::from_bytes(name.to_bytes()).to_owned();
                 let kind = match (*res).d_type {
                     libc::DT_REG => FileType::RegularFile,
                     libc::DT_DIR => FileType::Directory,
@@ -46,21 +87,154 @@ impl InvFS {
                     libc::DT_CHR => FileType::CharDevice,
                     libc::DT_LNK => FileType::Symlink,
                     libc::DT_SOCK => FileType::Socket,
-                    v => todo!("Readdir kind: {:?}", v),
+                    _ => match stat_kind(dir, (*res).d_name.as_ptr()) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            handle.1 = None;
+                            return Err(e);
+                        }
+                    },
                 };
+                handle.1 = Some((*res).d_off);
                 Ok(Some(((*res).d_ino, (*res).d_off, kind, name)))
             }
-        };
-        log_res!(callid, "{:?}", res);
-        restore_ids(ids);
-        chdirout(cwd);
-        match res {
-            Ok(Some((ino, offset, kind, name))) => {
-                _ = reply.add(ino, offset, kind, OsString::from(name));
-                reply.ok()
+        }
+    }
+}
+
+/// The type of entry `name` of `dir`, for filesystems that leave it out of the entry itself
+/// (`DT_UNKNOWN`).
+unsafe fn stat_kind(dir: *mut libc::DIR, name: *const libc::c_char) -> Result<FileType, i32> {
+    let mut st: libc::stat = std::mem::zeroed();
+    if libc::fstatat(libc::dirfd(dir), name, &mut st, libc::AT_SYMLINK_NOFOLLOW) != 0 {
+        return Err(*libc::__errno_location());
+    }
+    match st.st_mode & libc::S_IFMT {
+        libc::S_IFREG => Ok(FileType::RegularFile),
+        libc::S_IFDIR => Ok(FileType::Directory),
+        libc::S_IFIFO => Ok(FileType::NamedPipe),
+        libc::S_IFBLK => Ok(FileType::BlockDevice),
+        libc::S_IFCHR => Ok(FileType::CharDevice),
+        libc::S_IFLNK => Ok(FileType::Symlink),
+        libc::S_IFSOCK => Ok(FileType::Socket),
+        _ => Err(libc::EIO),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::{CString, OsString},
+        os::unix::prelude::OsStrExt,
+    };
+
+    use fuser::FileType;
+
+    use crate::{
+        fs::{InvFS, ViolationPolicy},
+        req_rep::{ReplyDirectory, ReplyEmpty, ReplyOpen},
+        test::{create, init, ROOT},
+    };
+
+    use super::stat_kind;
+
+    /// Read `fh` from the start, calling `between` after each batch. Returns the names and how
+    /// many reads it took.
+    fn pass(ifs: &InvFS, fh: u64, mut between: impl FnMut()) -> (Vec<OsString>, usize) {
+        let mut names = Vec::new();
+        let mut offset = 0;
+        for reads in 1.. {
+            let rep = ReplyDirectory::new();
+            ifs.do_readdir(ROOT, 1, fh, offset, &rep);
+            let entries = rep.get().unwrap();
+            match entries.last() {
+                Some((_, off, _, _)) => offset = *off,
+                None => {
+                    names.sort();
+                    return (names, reads);
+                }
             }
-            Ok(None) => reply.ok(),
-            Err(v) => reply.error(v),
+            names.extend(entries.into_iter().map(|(_, _, _, name)| name));
+            between();
         }
+        unreachable!()
+    }
+
+    #[test]
+    fn test_readdir() {
+        let ifs = crate::test::create_ifs().with_policy(ViolationPolicy::Log);
+        init(&ifs);
+        create(&ifs, "foo", 0o644);
+        create(&ifs, "bar", 0o644);
+        let rep = ReplyOpen::new();
+        ifs.do_opendir(ROOT, 1, libc::O_RDONLY, &rep);
+        let fh = rep.get().unwrap().0;
+        assert_eq!(
+            pass(&ifs, fh, || ()),
+            (vec![".".into(), "..".into(), "bar".into(), "foo".into()], 2)
+        );
+
+        // Changing the directory mid-pass excuses the names changed, and only those.
+        let mut changed = false;
+        let (names, _) = pass(&ifs, fh, || {
+            if !changed {
+                changed = true;
+                create(&ifs, "baz", 0o644);
+                let rep = ReplyEmpty::new();
+                ifs.do_unlink(ROOT, 1, &OsString::from("bar"), &rep);
+                assert_eq!(rep.get(), Ok(()));
+            }
+        });
+        assert!(names.contains(&".".into()) && names.contains(&"foo".into()));
+        assert_eq!(ifs.violations(), vec![]);
+
+        // Taking a name away behind our back is not.
+        std::fs::remove_file(ifs.root.join("foo")).unwrap();
+        assert_eq!(pass(&ifs, fh, || ()).0, vec![".", "..", "baz"]);
+        let violations = ifs.violations();
+        assert_eq!(violations.len(), 1, "{:?}", violations);
+        assert!(
+            violations[0].message.contains("without returning"),
+            "{}",
+            violations[0]
+        );
+    }
+
+    #[test]
+    fn test_readdir_batches() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        let mut expected: Vec<OsString> = vec![".".into(), "..".into()];
+        for i in 0..300 {
+            let name = format!("file{:03}", i);
+            create(&ifs, &name, 0o644);
+            expected.push(name.into());
+        }
+        let rep = ReplyOpen::new();
+        ifs.do_opendir(ROOT, 1, libc::O_RDONLY, &rep);
+        let fh = rep.get().unwrap().0;
+        // 32 bytes an entry, so a page holds 128 of them.
+        assert_eq!(pass(&ifs, fh, || ()), (expected.clone(), 4));
+        assert_eq!(pass(&ifs, fh, || ()), (expected, 4));
+    }
+
+    #[test]
+    fn test_stat_kind() {
+        let dir = crate::test::tempdir();
+        std::fs::write(dir.join("file"), b"").unwrap();
+        std::fs::create_dir(dir.join("dir")).unwrap();
+        std::os::unix::fs::symlink("file", dir.join("link")).unwrap();
+        let c = CString::new(dir.as_os_str().as_bytes()).unwrap();
+        let kind = |name: &str| unsafe {
+            let d = libc::opendir(c.as_ptr());
+            let name = CString::new(name).unwrap();
+            let res = stat_kind(d, name.as_ptr());
+            libc::closedir(d);
+            res
+        };
+        assert_eq!(kind("file"), Ok(FileType::RegularFile));
+        assert_eq!(kind("dir"), Ok(FileType::Directory));
+        assert_eq!(kind("link"), Ok(FileType::Symlink));
+        assert_eq!(kind("gone"), Err(libc::ENOENT));
     }
 }
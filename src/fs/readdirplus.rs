@@ -1,14 +1,240 @@
+use crate::{
+    fs::{restore_ids, set_ids, TTL},
+    fs_to_fuse::FsToFuseAttr,
+    invariants::{
+        fs::{
+            readdir::inv_readdir_pass,
+            readdirplus::{inv_readdirplus_after, inv_readdirplus_before},
+        },
+        locks::Tree,
+    },
+    log_call, log_more, log_res,
+    req_rep::{ReplyDirectoryPlus, Request},
+};
+
 use super::InvFS;
 
 impl InvFS {
     pub fn do_readdirplus(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        _offset: i64,
-        _reply: fuser::ReplyDirectoryPlus,
+        &self,
+        req: Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: &ReplyDirectoryPlus,
     ) {
-        todo!("READDIRPLUS");
+        let callid = log_call!("READDIRPLUS", ino, fh: "{:x}", offset: "{:x}");
+        let _guard = self.locks.lock(Tree::Shared, &[ino], &[]);
+        let mut dl = self.data.lock().unwrap();
+        let inv = inv_readdirplus_before(callid, ino, fh, offset, &mut dl);
+        let p_path = dl.INODE_PATHS.get(ino).to_owned();
+        log_more!(callid, "path={:?}", p_path);
+        drop(dl);
+        let ids = set_ids(callid, req, None);
+        let mut entries = Vec::new();
+        let mut next = offset;
+        let res = loop {
+            let (d_ino, off, name) = match self.read_entry(fh, next) {
+                Ok(Some((d_ino, off, _, name))) => (d_ino, off, name),
+                Ok(None) => break Ok(entries),
+                Err(e) if entries.is_empty() => break Err(e),
+                // What was read so far still goes out; the next read will run into it again.
+                Err(e) => {
+                    log_more!(callid, "stopping at error={}", e);
+                    break Ok(entries);
+                }
+            };
+            if reply.full(&name) {
+                break Ok(entries);
+            }
+            // The kernel takes neither a lookup nor attributes from `.` and `..`.
+            let dots = name == "." || name == "..";
+            let watch = (!dots).then(|| self.data.lock().unwrap().watches.open(d_ino));
+            let child = p_path.join(&name);
+            match unsafe { self.stat_path(&child) } {
+                Ok(v) => {
+                    let ino = match name.to_str() {
+                        Some(".") => ino,
+                        Some("..") => d_ino,
+                        _ => v.st_ino,
+                    };
+                    let attr = v.to_fuse_attr(ino);
+                    assert!(!reply.add(ino, off, &name, TTL, attr, 0));
+                    entries.push((off, name, attr, child, watch));
+                    next = off;
+                }
+                Err(e) => {
+                    if let Some(w) = watch {
+                        self.data.lock().unwrap().watches.cancel(w);
+                    }
+                    if entries.is_empty() {
+                        break Err(e);
+                    }
+                    log_more!(callid, "stopping at error={}", e);
+                    break Ok(entries);
+                }
+            }
+        };
+        restore_ids(ids);
+        log_res!(callid, "{:#?}", res);
+        let pass = inv.pass();
+        let (res, check) = match res {
+            Ok(entries) => {
+                let mut check = Ok(());
+                let mut out = Vec::new();
+                for (off, name, attr, child, watch) in entries {
+                    let entry = Ok(Some((off, name.clone(), attr)));
+                    let this = self.check_observed(watch, |dl| {
+                        inv_readdirplus_after(callid, inv.clone(), &entry, dl)
+                    });
+                    // Every watch is finished, even past the first violation.
+                    check = check.and(this);
+                    out.push((off, name, attr, child));
+                }
+                (Ok(out), check)
+            }
+            Err(e) => {
+                let check = inv_readdirplus_after(
+                    callid,
+                    inv.clone(),
+                    &Err(e),
+                    &mut self.data.lock().unwrap(),
+                );
+                (Err(e), check)
+            }
+        };
+        // Outside the observations, as it moves the pass along and must only run once.
+        let check = check.and_then(|()| {
+            let entry = res
+                .as_ref()
+                .map(|e| {
+                    e.iter()
+                        .map(|(off, name, _, _)| (*off, name.clone()))
+                        .collect()
+                })
+                .map_err(|e| *e);
+            inv_readdir_pass(pass, &entry, &mut self.data.lock().unwrap())
+        });
+        match self.handle_violation(callid, "READDIRPLUS", Some(ino), check, res) {
+            Ok(entries) => {
+                // Only now that they reach the kernel do the entries count as lookups.
+                let mut dl = self.data.lock().unwrap();
+                for (_, name, attr, child) in entries {
+                    if name != "." && name != ".." {
+                        dl.INODE_PATHS.lookup(attr.ino, child);
+                    }
+                }
+                reply.ok()
+            }
+            Err(v) => reply.error(v),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use crate::{
+        fs::ViolationPolicy,
+        req_rep::{ReplyCreate, ReplyDirectoryPlus, ReplyOpen},
+        test::{create, init, ROOT},
+    };
+
+    #[test]
+    fn test_readdirplus() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        let rep = ReplyCreate::new();
+        ifs.do_create(
+            ROOT,
+            1,
+            &OsString::from("foo"),
+            0o644,
+            0,
+            libc::O_CREAT,
+            &rep,
+        );
+        let attr = rep.get().unwrap().1;
+        let rep = ReplyOpen::new();
+        ifs.do_opendir(ROOT, 1, libc::O_RDONLY, &rep);
+        let fh = rep.get().unwrap().0;
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let rep = ReplyDirectoryPlus::new();
+            ifs.do_readdirplus(ROOT, 1, fh, offset, &rep);
+            let batch = rep.get().unwrap();
+            if batch.is_empty() {
+                break;
+            }
+            for (ino, off, name, _, a, _) in batch {
+                assert_eq!(ino, a.ino);
+                entries.push((name, a));
+                offset = off;
+            }
+        }
+        let mut names: Vec<_> = entries.iter().map(|(n, _)| n.clone()).collect();
+        names.sort();
+        assert_eq!(names, vec![".", "..", "foo"]);
+        let foo = &entries.iter().find(|(n, _)| n == "foo").unwrap().1;
+        assert_eq!(foo.ino, attr.ino);
+        assert_eq!(foo.size, 0);
+        assert_eq!(foo.perm, 0o644);
+
+        // One lookup from the create and one from the listing; `.` and `..` hand out none.
+        let dl = ifs.data.lock().unwrap();
+        assert_eq!(dl.INODE_PATHS.lookups(attr.ino), 2);
+        assert_eq!(dl.INODE_PATHS.lookups(1), 0);
+        drop(dl);
+        ifs.do_forget(ROOT, attr.ino, 2);
+        assert_eq!(ifs.data.lock().unwrap().INODE_PATHS.lookups(attr.ino), 0);
+    }
+
+    #[test]
+    fn test_readdirplus_batches() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        for i in 0..100 {
+            create(&ifs, &format!("file{:03}", i), 0o644);
+        }
+        let rep = ReplyOpen::new();
+        ifs.do_opendir(ROOT, 1, libc::O_RDONLY, &rep);
+        let fh = rep.get().unwrap().0;
+        let mut names = Vec::new();
+        let mut replies = 0;
+        let mut offset = 0;
+        loop {
+            let rep = ReplyDirectoryPlus::new();
+            ifs.do_readdirplus(ROOT, 1, fh, offset, &rep);
+            let batch = rep.get().unwrap();
+            replies += 1;
+            match batch.last() {
+                Some(e) => offset = e.1,
+                None => break,
+            }
+            names.extend(batch.into_iter().map(|e| e.2));
+        }
+        assert_eq!(names.len(), 102);
+        // 160 bytes an entry, so a page holds 25 of them.
+        assert_eq!(replies, 6);
+        assert!(ifs.violations().is_empty());
+    }
+
+    #[test]
+    fn test_readdirplus_violation() {
+        let ifs = crate::test::create_ifs().with_policy(ViolationPolicy::Eio);
+        init(&ifs);
+        let ino = create(&ifs, "foo", 0o644);
+        std::fs::write(ifs.root.join("foo"), b"behind our back").unwrap();
+        let rep = ReplyOpen::new();
+        ifs.do_opendir(ROOT, 1, libc::O_RDONLY, &rep);
+        let fh = rep.get().unwrap().0;
+        let rep = ReplyDirectoryPlus::new();
+        ifs.do_readdirplus(ROOT, 1, fh, 0, &rep);
+        assert_eq!(rep.get(), Err(libc::EIO));
+        // The kernel never saw the entry, so it holds only the lookup from the create.
+        assert_eq!(ifs.data.lock().unwrap().INODE_PATHS.lookups(ino), 1);
     }
 }
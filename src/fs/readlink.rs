@@ -1,25 +1,28 @@
-use std::{ffi::CString, os::unix::prelude::OsStrExt};
-
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
+    fs::{restore_ids, set_ids},
+    invariants::locks::Tree,
     log_call, log_more, log_res,
+    req_rep::Request,
 };
 
 use super::InvFS;
 
 impl InvFS {
-    pub fn do_readlink(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
-        let callid = log_call!("READLINK", "ino={}", ino);
-        let cwd = chdirin(&self.root);
-        let ids = set_ids(callid, req.into(), None);
-        let dl = self.data.lock().unwrap();
-        let ip = &dl.INODE_PATHS;
-        let path = ip.get(ino);
+    pub fn do_readlink(&self, req: Request, ino: u64, reply: fuser::ReplyData) {
+        let callid = log_call!("READLINK", ino);
+        let ids = set_ids(callid, req, None);
+        let _guard = self.locks.lock(Tree::Shared, &[], &[]);
+        let path = self.data.lock().unwrap().INODE_PATHS.get(ino).to_owned();
         log_more!(callid, "path={:?}", path);
         let res = unsafe {
-            let tgt = CString::new(path.as_os_str().as_bytes()).unwrap();
+            let tgt = self.rel_path(&path);
             let mut buf = vec![0u8; (libc::PATH_MAX + 1).try_into().unwrap()];
-            let res = libc::readlink(tgt.as_ptr(), buf.as_mut_ptr() as *mut i8, buf.len());
+            let res = libc::readlinkat(
+                self.root_fd(),
+                tgt.as_ptr(),
+                buf.as_mut_ptr() as *mut i8,
+                buf.len(),
+            );
             if res != -1 {
                 assert_ne!(
                     res,
@@ -34,7 +37,6 @@ impl InvFS {
         };
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        chdirout(cwd);
         match res {
             Ok(v) => reply.data(&v),
             Err(v) => reply.error(v),
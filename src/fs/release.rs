@@ -1,32 +1,26 @@
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
+    fs::{restore_ids, set_ids},
+    invariants::locks::Tree,
     log_call, log_res,
+    req_rep::{ReplyEmpty, Request},
 };
 
 use super::InvFS;
 
 impl InvFS {
     pub fn do_release(
-        &mut self,
-        req: &fuser::Request<'_>,
+        &self,
+        req: Request,
         ino: u64,
         fh: u64,
         flags: i32,
         lock_owner: Option<u64>,
         flush: bool,
-        reply: fuser::ReplyEmpty,
+        reply: &ReplyEmpty,
     ) {
-        let callid = log_call!(
-            "RELEASE",
-            "ino={},fh={},flags={},lock_owner={:?},flush={}",
-            ino,
-            fh,
-            flags,
-            lock_owner,
-            flush
-        );
-        let cwd = chdirin(&self.root);
-        let ids = set_ids(callid, req.into(), None);
+        let callid = log_call!("RELEASE", ino, fh, flags, lock_owner: "{:?}", flush);
+        let _guard = self.locks.lock(Tree::Shared, &[], &[ino]);
+        let ids = set_ids(callid, req, None);
         let res = unsafe {
             let res = libc::close(fh.try_into().unwrap());
             if res == 0 {
@@ -37,7 +31,17 @@ impl InvFS {
         };
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        chdirout(cwd);
+        // The owner's locks go with its last handle. Closing the descriptors they were taken
+        // on releases them in the backend too.
+        let mut dl = self.data.lock().unwrap();
+        if let Some(owner) = lock_owner {
+            dl.posix_locks.drop_owner(ino, owner);
+        }
+        self.lock_fds
+            .lock()
+            .unwrap()
+            .retain(|(i, o), _| *i != ino || dl.posix_locks.holds(ino, *o));
+        drop(dl);
         match res {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
@@ -1,23 +1,24 @@
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
+    fs::{restore_ids, set_ids},
     log_call, log_res,
+    req_rep::Request,
 };
 
 use super::InvFS;
 
 impl InvFS {
     pub fn do_releasedir(
-        &mut self,
-        req: &fuser::Request<'_>,
+        &self,
+        req: Request,
         ino: u64,
         fh: u64,
         flags: i32,
         reply: fuser::ReplyEmpty,
     ) {
-        let callid = log_call!("RELEASEDIR", "ino={},fh={},flags={}", ino, fh, flags);
-        let cwd = chdirin(&self.root);
-        let ids = set_ids(callid, req.into(), None);
-        let dirp = self.dir_fhs.remove(&fh).unwrap();
+        let callid = log_call!("RELEASEDIR", ino, fh, flags);
+        let ids = set_ids(callid, req, None);
+        let dirp = self.dir_fhs.lock().unwrap().remove(&fh).unwrap().0;
+        self.data.lock().unwrap().listings.close(fh);
         let res = unsafe {
             let res = libc::closedir(dirp);
             if res == 0 {
@@ -28,7 +29,6 @@ impl InvFS {
         };
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        chdirout(cwd);
         match res {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
@@ -1,33 +1,38 @@
 use std::{ffi::CString, os::unix::prelude::OsStrExt};
 
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
-    invariants::fs::removexattr::{inv_removexattr_after, inv_removexattr_before},
+    fs::{restore_ids, set_ids},
+    invariants::{
+        fs::removexattr::{inv_removexattr_after, inv_removexattr_before},
+        locks::{apply, Tree},
+    },
     log_call, log_more, log_res,
+    req_rep::{ReplyEmpty, Request},
 };
 
 use super::InvFS;
 
 impl InvFS {
     pub fn do_removexattr(
-        &mut self,
-        req: &fuser::Request<'_>,
+        &self,
+        req: Request,
         ino: u64,
         name: &std::ffi::OsStr,
-        reply: fuser::ReplyEmpty,
+        reply: &ReplyEmpty,
     ) {
-        let callid = log_call!("REMOVEXATTR", "ino={},name={:?}", ino, name);
-        let cwd = chdirin(&self.root);
+        let callid = log_call!("REMOVEXATTR", ino, name: "{:?}");
+        // Extended attributes can carry ACLs, which change permission checks.
+        let _guard = self.locks.lock(Tree::Exclusive, &[], &[]);
         let mut dl = self.data.lock().unwrap();
-        let inv = inv_removexattr_before(callid, req, &self.root, ino, name, &mut dl);
-        let ids = set_ids(callid, req.into(), None);
+        let inv = inv_removexattr_before(callid, &req, &self.root, ino, name, &mut dl);
+        let ids = set_ids(callid, req, None);
         let ip = &dl.INODE_PATHS;
         let path = ip.get(ino);
         log_more!(callid, "path={:?}", path);
         let res = unsafe {
             let nm = CString::new(name.as_bytes()).unwrap();
-            let tgt = CString::new(path.as_os_str().as_bytes()).unwrap();
-            let res = libc::removexattr(tgt.as_ptr(), nm.as_ptr());
+            let tgt = self.fd_path(path);
+            let res = libc::lremovexattr(tgt.as_ptr(), nm.as_ptr());
             if res == 0 {
                 Ok(())
             } else {
@@ -37,8 +42,10 @@ impl InvFS {
         log_res!(callid, "{:?}", res);
 
         restore_ids(ids);
-        inv_removexattr_after(callid, inv, &res);
-        chdirout(cwd);
+        let check = apply(callid, &mut dl, &[ino], |dl| {
+            inv_removexattr_after(callid, inv, &res, dl)
+        });
+        let res = self.handle_violation(callid, "REMOVEXATTR", Some(ino), check, res);
         match res {
             Ok(()) => reply.ok(),
             Err(v) => reply.error(v),
@@ -1,8 +1,7 @@
-use std::{ffi::CString, os::unix::prelude::OsStrExt};
-
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
+    fs::{restore_ids, set_ids},
     invariants::fs::rename::{inv_rename_after, inv_rename_before},
+    invariants::locks::{apply, Tree},
     log_call, log_more, log_res,
     req_rep::{ReplyEmpty, Request},
 };
@@ -11,7 +10,7 @@ use super::InvFS;
 
 impl InvFS {
     pub fn do_rename(
-        &mut self,
+        &self,
         req: Request,
         parent: u64,
         name: &std::ffi::OsStr,
@@ -22,18 +21,22 @@ impl InvFS {
     ) {
         let callid = log_call!(
             "RENAME",
-            "parent={},name={:?},newparent={},newname={:?},flags={:x}",
             parent,
-            name,
+            name: "{:?}",
             newparent,
-            newname,
-            flags
+            newname: "{:?}",
+            flags: "{:x}",
         );
-        let cwd = chdirin(&self.root);
+        let _guard = self.locks.lock(Tree::Exclusive, &[], &[]);
         let mut dl = self.data.lock().unwrap();
         let inv = inv_rename_before(
             callid, &req, &self.root, parent, name, newparent, newname, flags, &mut dl,
         );
+        let replaced = dl
+            .INV_DIR_CONTENTS
+            .get(&newparent)
+            .and_then(|x| x.get(newname))
+            .copied();
         let ids = set_ids(callid, req, None);
         let ip = &mut dl.INODE_PATHS;
         let old_parent = ip.get(parent);
@@ -45,9 +48,9 @@ impl InvFS {
         let new_child = new_parent.join(newname);
         log_more!(callid, "new_child={:?}", new_child);
         let res = unsafe {
-            let old = CString::new(old_child.as_os_str().as_bytes()).unwrap();
-            let new = CString::new(new_child.as_os_str().as_bytes()).unwrap();
-            let res = libc::rename(old.as_ptr(), new.as_ptr());
+            let old = self.rel_path(&old_child);
+            let new = self.rel_path(&new_child);
+            let res = libc::renameat(self.root_fd(), old.as_ptr(), self.root_fd(), new.as_ptr());
             if res == 0 {
                 Ok(())
             } else {
@@ -56,13 +59,20 @@ impl InvFS {
         };
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        inv_rename_after(callid, inv, &res, &mut dl);
-        chdirout(cwd);
-        match res {
-            Ok(()) => {
-                dl.INODE_PATHS.rename(old_child, new_child);
-                reply.ok()
-            }
+        let check = apply(callid, &mut dl, &[parent, newparent], |dl| {
+            inv_rename_after(callid, inv, &res, dl)
+        });
+        if res.is_ok() {
+            dl.INODE_PATHS.rename(callid, old_child, new_child);
+            dl.listings.touch(parent, name);
+            dl.listings.touch(newparent, newname);
+            // A directory replaced by the rename takes its synced entries with it.
+            let mut changed = vec![parent, newparent];
+            changed.extend(replaced);
+            dl.durable.forget(callid, &changed);
+        }
+        match self.handle_violation(callid, "RENAME", Some(parent), check, res) {
+            Ok(()) => reply.ok(),
             Err(v) => reply.error(v),
         }
     }
@@ -74,21 +84,14 @@ mod tests {
 
     use crate::{
         fs::TTL,
-        req_rep::{KernelConfig, ReplyCreate, ReplyEmpty, ReplyEntry, Request},
+        req_rep::{ReplyCreate, ReplyEmpty, ReplyEntry, Request},
+        test::init,
     };
 
     #[test]
     fn test_rename() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep_c = ReplyCreate::new();
         ifs.do_create(
             Request {
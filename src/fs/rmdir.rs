@@ -1,8 +1,7 @@
-use std::{ffi::CString, os::unix::prelude::OsStrExt};
-
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
+    fs::{restore_ids, set_ids},
     invariants::fs::rmdir::{inv_rmdir_after, inv_rmdir_before},
+    invariants::locks::{apply, Tree},
     log_call, log_more, log_res,
     req_rep::{ReplyEmpty, Request},
 };
@@ -10,17 +9,16 @@ use crate::{
 use super::InvFS;
 
 impl InvFS {
-    pub fn do_rmdir(
-        &mut self,
-        req: Request,
-        parent: u64,
-        name: &std::ffi::OsStr,
-        reply: &ReplyEmpty,
-    ) {
-        let callid = log_call!("RMDIR", "parent={},name={:?}", parent, name);
-        let cwd = chdirin(&self.root);
+    pub fn do_rmdir(&self, req: Request, parent: u64, name: &std::ffi::OsStr, reply: &ReplyEmpty) {
+        let callid = log_call!("RMDIR", parent, name: "{:?}");
+        let _guard = self.locks.lock(Tree::Exclusive, &[], &[]);
         let mut dl = self.data.lock().unwrap();
         let inv = inv_rmdir_before(callid, &req, &self.root, parent, name, &mut dl);
+        let child_ino = dl
+            .INV_DIR_CONTENTS
+            .get(&parent)
+            .and_then(|x| x.get(name))
+            .copied();
         let ids = set_ids(callid, req, None);
         let ip = &mut dl.INODE_PATHS;
         let p_path = ip.get(parent);
@@ -28,8 +26,8 @@ impl InvFS {
         let child = p_path.join(name);
         log_more!(callid, "child={:?}", child);
         let res = unsafe {
-            let tgt = CString::new(child.as_os_str().as_bytes()).unwrap();
-            let res = libc::rmdir(tgt.as_ptr());
+            let tgt = self.rel_path(&child);
+            let res = libc::unlinkat(self.root_fd(), tgt.as_ptr(), libc::AT_REMOVEDIR);
             if res == 0 {
                 Ok(())
             } else {
@@ -38,13 +36,19 @@ impl InvFS {
         };
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        inv_rmdir_after(callid, inv, &res, &mut dl);
-        chdirout(cwd);
-        match res {
-            Ok(()) => {
-                dl.INODE_PATHS.remove(&child);
-                reply.ok()
-            }
+        let check = apply(callid, &mut dl, &[parent], |dl| {
+            inv_rmdir_after(callid, inv, &res, dl)
+        });
+        if res.is_ok() {
+            dl.INODE_PATHS.remove(&child);
+            dl.listings.touch(parent, name);
+            // The removed directory's synced entries went with it.
+            let mut changed = vec![parent];
+            changed.extend(child_ino);
+            dl.durable.forget(callid, &changed);
+        }
+        match self.handle_violation(callid, "RMDIR", Some(parent), check, res) {
+            Ok(()) => reply.ok(),
             Err(v) => reply.error(v),
         }
     }
@@ -54,20 +58,14 @@ impl InvFS {
 mod tests {
     use std::ffi::OsString;
 
-    use crate::req_rep::{KernelConfig, ReplyEmpty, ReplyEntry, Request};
+    use crate::req_rep::{ReplyEmpty, ReplyEntry, Request};
+
+    use crate::test::init;
 
     #[test]
     fn test_rmdir() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep = ReplyEntry::new();
         ifs.do_mkdir(
             Request {
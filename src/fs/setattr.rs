@@ -1,17 +1,14 @@
-use std::{
-    ffi::CString,
-    mem::MaybeUninit,
-    os::unix::prelude::OsStrExt,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use fuser::TimeOrNow;
-use libc::timeval;
 
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids, stat_path, TTL},
+    fs::{restore_ids, set_ids, TTL},
     fs_to_fuse::FsToFuseAttr,
-    invariants::fs::setattr::{inv_setattr_after, inv_setattr_before},
+    invariants::{
+        fs::setattr::{inv_setattr_after, inv_setattr_before},
+        locks::{record, Tree},
+    },
     log_call, log_more, log_res,
     req_rep::{ReplyAttr, Request},
 };
@@ -20,7 +17,7 @@ use super::InvFS;
 
 impl InvFS {
     pub fn do_setattr(
-        &mut self,
+        &self,
         req: Request,
         ino: u64,
         mode: Option<u32>,
@@ -37,131 +34,85 @@ impl InvFS {
         flags: Option<u32>,
         reply: &ReplyAttr,
     ) {
-        let callid = log_call!("SETATTR", "ino={}", ino);
-        let cwd = chdirin(&self.root);
+        let callid = log_call!("SETATTR", ino);
+        // A change of owner or mode can change the permission checks of everything below it.
+        let guard = if mode.is_some() || uid.is_some() || gid.is_some() {
+            self.locks.lock(Tree::Exclusive, &[], &[])
+        } else {
+            self.locks.lock(Tree::Shared, &[], &[ino])
+        };
         let mut dl = self.data.lock().unwrap();
         let inv = inv_setattr_before(
             callid, &req, &self.root, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime,
             chgtime, bkuptime, flags, &mut dl,
         );
+        let path = dl.INODE_PATHS.get(ino).to_owned();
+        drop(dl);
         let ids = set_ids(callid, req, None);
-        let ip = &dl.INODE_PATHS;
-        let path = ip.get(ino);
         log_more!(callid, "path={:?}", path);
         let res = (|| unsafe {
-            let tgt = CString::new(path.as_os_str().as_bytes()).unwrap();
+            let tgt = self.rel_path(&path);
             if let Some(v) = mode {
                 log_more!(callid, "mode={:o} ({})", v, v);
-                if libc::chmod(tgt.as_ptr(), v) != 0 {
+                if libc::fchmodat(self.root_fd(), tgt.as_ptr(), v, 0) != 0 {
                     return Err(*libc::__errno_location());
                 }
             }
             if let Some(v) = uid {
                 log_more!(callid, "uid={}", v);
-                if libc::lchown(tgt.as_ptr(), v, u32::MAX) != 0 {
+                if libc::fchownat(
+                    self.root_fd(),
+                    tgt.as_ptr(),
+                    v,
+                    u32::MAX,
+                    libc::AT_SYMLINK_NOFOLLOW,
+                ) != 0
+                {
                     return Err(*libc::__errno_location());
                 }
             }
             if let Some(v) = gid {
                 log_more!(callid, "gid={}", v);
-                if libc::lchown(tgt.as_ptr(), u32::MAX, v) != 0 {
+                if libc::fchownat(
+                    self.root_fd(),
+                    tgt.as_ptr(),
+                    u32::MAX,
+                    v,
+                    libc::AT_SYMLINK_NOFOLLOW,
+                ) != 0
+                {
                     return Err(*libc::__errno_location());
                 }
             }
             if let Some(v) = size {
                 log_more!(callid, "size={}", v);
-                if libc::truncate(tgt.as_ptr(), v.try_into().unwrap()) != 0 {
+                if libc::truncate(self.fd_path(&path).as_ptr(), v.try_into().unwrap()) != 0 {
                     return Err(*libc::__errno_location());
                 }
             }
-            match (atime, mtime) {
-                (Some(a), Some(m)) => {
-                    log_more!(callid, "atime={:?},mtime={:?}", a, m);
-                    let a = if let TimeOrNow::SpecificTime(t) = a {
-                        t
-                    } else {
-                        SystemTime::now()
-                    };
-                    let m = if let TimeOrNow::SpecificTime(t) = m {
-                        t
-                    } else {
-                        SystemTime::now()
+            if atime.is_some() || mtime.is_some() {
+                log_more!(callid, "atime={:?},mtime={:?}", atime, mtime);
+                let ts = |t: Option<TimeOrNow>| {
+                    let t = match t {
+                        Some(TimeOrNow::SpecificTime(t)) => t,
+                        Some(TimeOrNow::Now) => SystemTime::now(),
+                        None => {
+                            return libc::timespec {
+                                tv_sec: 0,
+                                tv_nsec: libc::UTIME_OMIT,
+                            }
+                        }
                     };
-                    let a_s = a.duration_since(UNIX_EPOCH).unwrap().as_secs();
-                    let a_u = a.duration_since(UNIX_EPOCH).unwrap().as_micros() % 1_000_000;
-                    let m_s = m.duration_since(UNIX_EPOCH).unwrap().as_secs();
-                    let m_u = m.duration_since(UNIX_EPOCH).unwrap().as_micros() % 1_000_000;
-                    let times = [
-                        timeval {
-                            tv_sec: a_s.try_into().unwrap(),
-                            tv_usec: a_u.try_into().unwrap(),
-                        },
-                        timeval {
-                            tv_sec: m_s.try_into().unwrap(),
-                            tv_usec: m_u.try_into().unwrap(),
-                        },
-                    ];
-                    if libc::utimes(tgt.as_ptr(), times.as_ptr()) != 0 {
-                        return Err(*libc::__errno_location());
-                    }
-                }
-                (Some(a), None) => {
-                    let a = if let TimeOrNow::SpecificTime(t) = a {
-                        t
-                    } else {
-                        SystemTime::now()
-                    };
-                    let a_s = a.duration_since(UNIX_EPOCH).unwrap().as_secs();
-                    let a_u = a.duration_since(UNIX_EPOCH).unwrap().as_micros() % 1_000_000;
-
-                    let mut buf = MaybeUninit::zeroed().assume_init();
-                    libc::stat(tgt.as_ptr(), &mut buf);
-                    let m_s = buf.st_mtime;
-                    let m_u = buf.st_mtime_nsec / 1000;
-
-                    let times = [
-                        timeval {
-                            tv_sec: a_s.try_into().unwrap(),
-                            tv_usec: a_u.try_into().unwrap(),
-                        },
-                        timeval {
-                            tv_sec: m_s,
-                            tv_usec: m_u,
-                        },
-                    ];
-                    if libc::utimes(tgt.as_ptr(), times.as_ptr()) != 0 {
-                        return Err(*libc::__errno_location());
-                    }
-                }
-                (None, Some(m)) => {
-                    let mut buf = MaybeUninit::zeroed().assume_init();
-                    libc::stat(tgt.as_ptr(), &mut buf);
-                    let a_s = buf.st_atime;
-                    let a_u = buf.st_atime_nsec / 1000;
-
-                    let m = if let TimeOrNow::SpecificTime(t) = m {
-                        t
-                    } else {
-                        SystemTime::now()
-                    };
-                    let m_s = m.duration_since(UNIX_EPOCH).unwrap().as_secs();
-                    let m_u = m.duration_since(UNIX_EPOCH).unwrap().as_micros() % 1_000_000;
-
-                    let times = [
-                        timeval {
-                            tv_sec: a_s,
-                            tv_usec: a_u,
-                        },
-                        timeval {
-                            tv_sec: m_s.try_into().unwrap(),
-                            tv_usec: m_u.try_into().unwrap(),
-                        },
-                    ];
-                    if libc::utimes(tgt.as_ptr(), times.as_ptr()) != 0 {
-                        return Err(*libc::__errno_location());
+                    let d = t.duration_since(UNIX_EPOCH).unwrap();
+                    libc::timespec {
+                        tv_sec: d.as_secs().try_into().unwrap(),
+                        tv_nsec: d.subsec_nanos().try_into().unwrap(),
                     }
+                };
+                let times = [ts(atime), ts(mtime)];
+                if libc::utimensat(self.root_fd(), tgt.as_ptr(), times.as_ptr(), 0) != 0 {
+                    return Err(*libc::__errno_location());
                 }
-                (None, None) => {}
             }
             if let Some(v) = ctime {
                 log_more!(callid, "ctime={:?}", v);
@@ -186,14 +137,18 @@ impl InvFS {
                 log_more!(callid, "flags={}", v);
                 todo!("SETATTR flags");
             }
-            stat_path(path).map(|x| x.to_fuse_attr(ino))
+            self.stat_path(&path).map(|x| x.to_fuse_attr(ino))
         })();
 
         log_res!(callid, "{:?}", res);
 
         restore_ids(ids);
-        inv_setattr_after(callid, inv, &res, &mut dl);
-        chdirout(cwd);
+        let mut dl = self.data.lock().unwrap();
+        let check = record(callid, &mut dl, &[ino], guard.ticket(), |dl| {
+            inv_setattr_after(callid, inv, &res, dl)
+        });
+        drop(dl);
+        let res = self.handle_violation(callid, "SETATTR", Some(ino), check, res);
         match res {
             Ok(v) => reply.attr(&TTL, &v),
             Err(v) => reply.error(v),
@@ -207,21 +162,14 @@ mod tests {
 
     use crate::{
         fs::TTL,
-        req_rep::{KernelConfig, ReplyAttr, ReplyCreate, Request},
+        req_rep::{ReplyAttr, ReplyCreate, Request},
+        test::init,
     };
 
     #[test]
     fn test_setattr_none() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep_c = ReplyCreate::new();
         ifs.do_create(
             Request {
@@ -285,16 +233,8 @@ mod tests {
 
     #[test]
     fn test_setattr_mode() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep_c = ReplyCreate::new();
         ifs.do_create(
             Request {
@@ -358,16 +298,8 @@ mod tests {
 
     #[test]
     fn test_setattr_uid() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep_c = ReplyCreate::new();
         ifs.do_create(
             Request {
@@ -431,16 +363,8 @@ mod tests {
 
     #[test]
     fn test_setattr_gid() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep_c = ReplyCreate::new();
         ifs.do_create(
             Request {
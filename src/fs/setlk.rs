@@ -1,19 +1,194 @@
-use super::InvFS;
+use std::{thread, time::Duration};
+
+use crate::{
+    invariants::{
+        fs::setlk::{inv_setlk_after, inv_setlk_before},
+        locks::Tree,
+    },
+    log_call, log_more, log_res,
+    req_rep::{ReplyEmpty, Request},
+};
+
+use super::{to_flock, InvFS};
+
+/// How long a blocking request waits before trying again.
+const RETRY: Duration = Duration::from_millis(10);
+
+/// Whether the thread behind a request would have been interrupted by now: a signal it doesn't
+/// block is pending, or it is gone. fuser answers `FUSE_INTERRUPT` itself, so the kernel never
+/// tells us.
+fn interrupted(pid: u32) -> bool {
+    // Not sent on behalf of any process.
+    if pid == 0 {
+        return false;
+    }
+    let status = match std::fs::read_to_string(format!("/proc/{}/status", pid)) {
+        Ok(v) => v,
+        Err(_) => return true,
+    };
+    let mask = |field: &str| {
+        status
+            .lines()
+            .find_map(|l| l.strip_prefix(field))
+            .and_then(|v| u64::from_str_radix(v.trim(), 16).ok())
+            .unwrap_or(0)
+    };
+    (mask("SigPnd:") | mask("ShdPnd:")) & !mask("SigBlk:") != 0
+}
 
 impl InvFS {
     pub fn do_setlk(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _typ: i32,
-        _pid: u32,
-        _sleep: bool,
-        _reply: fuser::ReplyEmpty,
+        &self,
+        req: Request,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: &ReplyEmpty,
     ) {
-        todo!("SETLK");
+        let callid = log_call!(
+            "SETLK",
+            ino,
+            fh: "{:x}",
+            lock_owner: "{:x}",
+            start: "{:x}",
+            end: "{:x}",
+            typ,
+            pid,
+            sleep,
+        );
+        loop {
+            // The lock table has to change along with the backend's, so nothing else may lock
+            // this inode in between.
+            let guard = self.locks.lock(Tree::Shared, &[], &[ino]);
+            let mut dl = self.data.lock().unwrap();
+            let inv = inv_setlk_before(
+                callid, &req, ino, fh, lock_owner, start, end, typ, pid, &mut dl,
+            );
+            drop(dl);
+            // Taken as the daemon: locks don't check permissions, but reopening the handle would.
+            let res = self.lock_fd(ino, fh, lock_owner).and_then(|fd| unsafe {
+                let fl = to_flock(start, end, typ);
+                if libc::fcntl(fd, libc::F_OFD_SETLK, &fl) == 0 {
+                    Ok(())
+                } else {
+                    Err(*libc::__errno_location())
+                }
+            });
+            log_res!(callid, "{:?}", res);
+            let check = inv_setlk_after(callid, inv, &res, &mut self.data.lock().unwrap());
+            drop(guard);
+            // Blocking in the backend would hold the inode for as long as the wait, so a
+            // blocking request keeps retrying instead, on a thread of its own.
+            if sleep && check.is_ok() && res == Err(libc::EAGAIN) {
+                if !interrupted(req.pid()) {
+                    thread::sleep(RETRY);
+                    continue;
+                }
+                // The kernel restarts the call if the signal handler asks for it.
+                log_more!(callid, "interrupted");
+                reply.error(libc::EINTR);
+                return;
+            }
+            match self.handle_violation(callid, "SETLK", Some(ino), check, res) {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{
+        invariants::posix_locks::OFFSET_MAX,
+        req_rep::{ReplyEmpty, ReplyLock, Request},
+        test::{create, init, open, ROOT},
+    };
+
+    #[test]
+    fn test_setlk() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        let ino = create(&ifs, "foo", 0o644);
+        let (fh1, fh2) = (open(&ifs, ino, libc::O_RDWR), open(&ifs, ino, libc::O_RDWR));
+        let setlk = |fh, owner, start, end, typ| {
+            let rep = ReplyEmpty::new();
+            ifs.do_setlk(
+                ROOT,
+                ino,
+                fh,
+                owner,
+                start,
+                end,
+                typ,
+                owner as u32,
+                false,
+                &rep,
+            );
+            rep.get()
+        };
+        let getlk = |fh, owner, start, end, typ| {
+            let rep = ReplyLock::new();
+            ifs.do_getlk(ROOT, ino, fh, owner, start, end, typ, owner as u32, &rep);
+            rep.get()
+        };
+
+        assert_eq!(setlk(fh1, 1, 0, 99, libc::F_WRLCK), Ok(()));
+        assert_eq!(setlk(fh2, 2, 50, 59, libc::F_RDLCK), Err(libc::EAGAIN));
+        assert_eq!(
+            getlk(fh2, 2, 50, OFFSET_MAX, libc::F_RDLCK),
+            Ok((0, 99, libc::F_WRLCK, 1))
+        );
+        assert_eq!(
+            getlk(fh1, 1, 50, 59, libc::F_WRLCK),
+            Ok((50, 59, libc::F_UNLCK, 0))
+        );
+
+        assert_eq!(setlk(fh1, 1, 10, 19, libc::F_UNLCK), Ok(()));
+        assert_eq!(setlk(fh2, 2, 10, 19, libc::F_WRLCK), Ok(()));
+        assert_eq!(
+            getlk(fh2, 2, 20, 29, libc::F_RDLCK),
+            Ok((20, 99, libc::F_WRLCK, 1))
+        );
+
+        let rep = ReplyEmpty::new();
+        ifs.do_release(ROOT, ino, fh1, libc::O_RDWR, Some(1), false, &rep);
+        assert_eq!(rep.get(), Ok(()));
+        assert_eq!(setlk(fh2, 2, 0, OFFSET_MAX, libc::F_WRLCK), Ok(()));
+        assert!(ifs.violations().is_empty());
+    }
+
+    #[test]
+    fn test_setlk_interrupted() {
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
+        let ino = create(&ifs, "foo", 0o644);
+        let (fh1, fh2) = (open(&ifs, ino, libc::O_RDWR), open(&ifs, ino, libc::O_RDWR));
+        let rep = ReplyEmpty::new();
+        ifs.do_setlk(ROOT, ino, fh1, 1, 0, 99, libc::F_WRLCK, 1, false, &rep);
+        assert_eq!(rep.get(), Ok(()));
+
+        // A waiter whose process is killed stops waiting.
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let req = Request {
+            pid: child.id(),
+            ..ROOT
+        };
+        child.kill().unwrap();
+        child.wait().unwrap();
+        let rep = ReplyEmpty::new();
+        ifs.do_setlk(req, ino, fh2, 2, 0, 99, libc::F_WRLCK, 2, true, &rep);
+        assert_eq!(rep.get(), Err(libc::EINTR));
+        assert!(ifs.violations().is_empty());
     }
 }
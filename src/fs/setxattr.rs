@@ -3,46 +3,50 @@ use std::{ffi::CString, os::unix::prelude::OsStrExt};
 use libc::c_void;
 
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
-    invariants::fs::setxattr::{inv_setxattr_after, inv_setxattr_before},
+    fs::{restore_ids, set_ids},
+    invariants::{
+        fs::setxattr::{inv_setxattr_after, inv_setxattr_before},
+        locks::{apply, Tree},
+    },
     log_call, log_more, log_res,
+    req_rep::{ReplyEmpty, Request},
 };
 
 use super::InvFS;
 
 impl InvFS {
     pub fn do_setxattr(
-        &mut self,
-        req: &fuser::Request<'_>,
+        &self,
+        req: Request,
         ino: u64,
         name: &std::ffi::OsStr,
         value: &[u8],
         flags: i32,
         position: u32,
-        reply: fuser::ReplyEmpty,
+        reply: &ReplyEmpty,
     ) {
         let callid = log_call!(
             "SETXATTR",
-            "ino={},name={:?},value={},flags={},position={}",
             ino,
-            name,
-            String::from_utf8_lossy(value),
+            name: "{:?}",
+            value = String::from_utf8_lossy(value),
             flags,
-            position
+            position,
         );
-        let cwd = chdirin(&self.root);
+        // Extended attributes can carry ACLs, which change permission checks.
+        let _guard = self.locks.lock(Tree::Exclusive, &[], &[]);
         let mut dl = self.data.lock().unwrap();
         let inv = inv_setxattr_before(
-            callid, req, &self.root, ino, name, value, flags, position, &mut dl,
+            callid, &req, &self.root, ino, name, value, flags, position, &mut dl,
         );
-        let ids = set_ids(callid, req.into(), None);
+        let ids = set_ids(callid, req, None);
         let ip = &dl.INODE_PATHS;
         let path = ip.get(ino);
         log_more!(callid, "path={:?}", path);
         let res = unsafe {
             let nm = CString::new(name.as_bytes()).unwrap();
-            let tgt = CString::new(path.as_os_str().as_bytes()).unwrap();
-            let res = libc::setxattr(
+            let tgt = self.fd_path(path);
+            let res = libc::lsetxattr(
                 tgt.as_ptr(),
                 nm.as_ptr(),
                 value.as_ptr() as *mut c_void,
@@ -57,11 +61,214 @@ impl InvFS {
         };
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        inv_setxattr_after(callid, inv, &res);
-        chdirout(cwd);
+        let check = apply(callid, &mut dl, &[ino], |dl| {
+            inv_setxattr_after(callid, inv, &res, dl)
+        });
+        let res = self.handle_violation(callid, "SETXATTR", Some(ino), check, res);
         match res {
             Ok(()) => reply.ok(),
             Err(v) => reply.error(v),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, path::Path};
+
+    use crate::{
+        invariants::{
+            acl::{
+                Acl, AclEntry, ACL_ACCESS, ACL_DEFAULT, ACL_GROUP_OBJ, ACL_MASK, ACL_OTHER,
+                ACL_UNDEFINED_ID, ACL_USER, ACL_USER_OBJ,
+            },
+            Checks,
+        },
+        req_rep::{ReplyAttr, ReplyCreate, ReplyEmpty, ReplyEntry, ReplyXattr, Request, Xattr},
+        test::{create, init, ROOT},
+    };
+
+    const USER: Request = Request {
+        uid: 1000,
+        gid: 1000,
+        pid: 0,
+    };
+
+    #[test]
+    fn test_xattr() {
+        let ifs = crate::test::create_ifs().with_checks(Checks::parse("all,xattr").unwrap());
+        init(&ifs);
+        let ino = create(&ifs, "foo", 0o644);
+        let name = OsStr::new("user.foo");
+
+        let set = |value: &[u8], flags| {
+            let rep = ReplyEmpty::new();
+            ifs.do_setxattr(ROOT, ino, name, value, flags, 0, &rep);
+            rep.get()
+        };
+        assert_eq!(set(b"bar", libc::XATTR_REPLACE), Err(libc::ENODATA));
+        assert_eq!(set(b"bar", libc::XATTR_CREATE), Ok(()));
+        assert_eq!(set(b"bar", libc::XATTR_CREATE), Err(libc::EEXIST));
+        assert_eq!(set(b"hello", libc::XATTR_REPLACE), Ok(()));
+
+        let get = |size| {
+            let rep = ReplyXattr::new();
+            ifs.do_getxattr(ROOT, ino, name, size, &rep);
+            rep.get()
+        };
+        assert_eq!(get(0), Ok(Xattr::Size(5)));
+        assert_eq!(get(2), Err(libc::ERANGE));
+        assert_eq!(get(64), Ok(Xattr::Data(b"hello".to_vec())));
+
+        let list = |size| {
+            let rep = ReplyXattr::new();
+            ifs.do_listxattr(ROOT, ino, size, &rep);
+            rep.get()
+        };
+        assert_eq!(list(0), Ok(Xattr::Size(9)));
+        assert_eq!(list(64), Ok(Xattr::Data(b"user.foo\0".to_vec())));
+
+        let remove = || {
+            let rep = ReplyEmpty::new();
+            ifs.do_removexattr(ROOT, ino, name, &rep);
+            rep.get()
+        };
+        assert_eq!(remove(), Ok(()));
+        assert_eq!(remove(), Err(libc::ENODATA));
+        assert_eq!(get(64), Err(libc::ENODATA));
+        assert_eq!(list(64), Ok(Xattr::Data(vec![])));
+    }
+
+    #[test]
+    fn test_xattr_perm() {
+        let ifs = crate::test::create_ifs().with_checks(Checks::parse("all,xattr").unwrap());
+        init(&ifs);
+        let set = |req, ino, name: &str| {
+            let rep = ReplyEmpty::new();
+            ifs.do_setxattr(req, ino, OsStr::new(name), b"bar", 0, 0, &rep);
+            rep.get()
+        };
+        let get = |req, ino, name: &str| {
+            let rep = ReplyXattr::new();
+            ifs.do_getxattr(req, ino, OsStr::new(name), 64, &rep);
+            rep.get()
+        };
+
+        let file = create(&ifs, "foo", 0o644);
+        assert_eq!(set(USER, file, "user.a"), Err(libc::EACCES));
+        assert_eq!(get(USER, file, "user.a"), Err(libc::ENODATA));
+        assert_eq!(set(ROOT, file, "trusted.a"), Ok(()));
+        assert_eq!(set(USER, file, "trusted.a"), Err(libc::EPERM));
+        assert_eq!(get(USER, file, "trusted.a"), Err(libc::ENODATA));
+        let rep = ReplyXattr::new();
+        ifs.do_listxattr(USER, file, 64, &rep);
+        assert_eq!(rep.get(), Ok(Xattr::Data(vec![])));
+
+        let rep = ReplyEntry::new();
+        ifs.do_symlink(ROOT, 1, OsStr::new("bar"), Path::new("foo"), &rep);
+        let link = rep.get().unwrap().1.ino;
+        assert_eq!(set(ROOT, link, "user.a"), Err(libc::EPERM));
+
+        let rep = ReplyEntry::new();
+        ifs.do_mkdir(ROOT, 1, OsStr::new("sticky"), 0o1777, 0, &rep);
+        let dir = rep.get().unwrap().1.ino;
+        assert_eq!(set(USER, dir, "user.a"), Err(libc::EPERM));
+        assert_eq!(set(ROOT, dir, "user.a"), Ok(()));
+
+        assert!(ifs.violations().is_empty());
+    }
+
+    #[test]
+    fn test_acl() {
+        let ifs = crate::test::create_ifs().with_checks(Checks::parse("all,xattr").unwrap());
+        init(&ifs);
+        let entry = |tag, perm, id| AclEntry { tag, perm, id };
+        let acl = Acl::new(vec![
+            entry(ACL_USER_OBJ, 0o7, ACL_UNDEFINED_ID),
+            entry(ACL_USER, 0o7, 1000),
+            entry(ACL_GROUP_OBJ, 0o5, ACL_UNDEFINED_ID),
+            entry(ACL_MASK, 0o7, ACL_UNDEFINED_ID),
+            entry(ACL_OTHER, 0o5, ACL_UNDEFINED_ID),
+        ]);
+        let rep = ReplyEntry::new();
+        ifs.do_mkdir(ROOT, 1, OsStr::new("d"), 0o755, 0, &rep);
+        let dir = rep.get().unwrap().1.ino;
+        for name in [ACL_ACCESS, ACL_DEFAULT] {
+            let rep = ReplyEmpty::new();
+            ifs.do_setxattr(ROOT, dir, OsStr::new(name), &acl.to_bytes(), 0, 0, &rep);
+            assert_eq!(rep.get(), Ok(()));
+        }
+
+        // The named user may create in the directory, and the umask gives way to the default ACL
+        let rep = ReplyCreate::new();
+        ifs.do_create(
+            USER,
+            dir,
+            OsStr::new("f"),
+            0o666,
+            0o077,
+            libc::O_CREAT,
+            &rep,
+        );
+        let (_, attr, _, _, _) = rep.get().unwrap();
+        assert_eq!(attr.perm, 0o664);
+        let rep = ReplyEntry::new();
+        ifs.do_mkdir(USER, dir, OsStr::new("sub"), 0o777, 0o077, &rep);
+        assert_eq!(rep.get().unwrap().1.perm, 0o775);
+        let other = || Request {
+            uid: 2000,
+            gid: 2000,
+            pid: 0,
+        };
+        let rep = ReplyEntry::new();
+        ifs.do_mkdir(other(), dir, OsStr::new("nope"), 0o777, 0, &rep);
+        assert_eq!(rep.get(), Err(libc::EACCES));
+
+        // chmod rewrites the mask
+        let rep = ReplyAttr::new();
+        ifs.do_setattr(
+            USER,
+            attr.ino,
+            Some(0o600),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            &rep,
+        );
+        assert_eq!(rep.get().unwrap().1.perm, 0o600);
+        let rep = ReplyXattr::new();
+        ifs.do_getxattr(USER, attr.ino, OsStr::new(ACL_ACCESS), 64, &rep);
+        let acl = match rep.get() {
+            Ok(Xattr::Data(v)) => Acl::parse(&v).unwrap(),
+            v => panic!("{:?}", v),
+        };
+        assert_eq!(acl.mode(), 0o600);
+
+        // Anyone may read an ACL, only the owner may change it, and only directories take a
+        // default one
+        let rep = ReplyXattr::new();
+        ifs.do_getxattr(other(), attr.ino, OsStr::new(ACL_ACCESS), 64, &rep);
+        assert!(rep.get().is_ok());
+        let set = |req, ino, name| {
+            let rep = ReplyEmpty::new();
+            ifs.do_setxattr(req, ino, OsStr::new(name), &acl.to_bytes(), 0, 0, &rep);
+            rep.get()
+        };
+        assert_eq!(set(USER, dir, ACL_ACCESS), Err(libc::EPERM));
+        assert_eq!(set(USER, attr.ino, ACL_ACCESS), Ok(()));
+        assert_eq!(set(ROOT, attr.ino, ACL_DEFAULT), Err(libc::EACCES));
+        let rep = ReplyEmpty::new();
+        ifs.do_removexattr(other(), dir, OsStr::new(ACL_DEFAULT), &rep);
+        assert_eq!(rep.get(), Err(libc::EPERM));
+
+        assert!(ifs.violations().is_empty());
+    }
+}
@@ -1,23 +1,23 @@
-use std::{ffi::CString, mem::MaybeUninit, os::unix::prelude::OsStrExt};
+use std::mem::MaybeUninit;
 
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
+    fs::{restore_ids, set_ids},
+    invariants::locks::Tree,
     log_call, log_more, log_res,
+    req_rep::Request,
 };
 
 use super::InvFS;
 
 impl InvFS {
-    pub fn do_statfs(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyStatfs) {
-        let callid = log_call!("STATFS", "ino={}", ino);
-        let cwd = chdirin(&self.root);
-        let ids = set_ids(callid, req.into(), None);
-        let dl = self.data.lock().unwrap();
-        let ip = &dl.INODE_PATHS;
-        let path = ip.get(ino);
+    pub fn do_statfs(&self, req: Request, ino: u64, reply: fuser::ReplyStatfs) {
+        let callid = log_call!("STATFS", ino);
+        let ids = set_ids(callid, req, None);
+        let _guard = self.locks.lock(Tree::Shared, &[], &[]);
+        let path = self.data.lock().unwrap().INODE_PATHS.get(ino).to_owned();
         log_more!(callid, "path={:?}", path);
         let res = unsafe {
-            let tgt = CString::new(path.as_os_str().as_bytes()).unwrap();
+            let tgt = self.fd_path(&path);
             let mut res = MaybeUninit::zeroed().assume_init();
             let rc = libc::statfs(tgt.as_ptr(), &mut res);
             if rc == 0 {
@@ -28,7 +28,6 @@ impl InvFS {
         };
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        chdirout(cwd);
         match res {
             Ok(v) => reply.statfs(
                 v.f_blocks,
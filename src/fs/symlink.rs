@@ -1,9 +1,12 @@
 use std::{ffi::CString, os::unix::prelude::OsStrExt};
 
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids, stat_path, TTL},
+    fs::{restore_ids, set_ids, TTL},
     fs_to_fuse::FsToFuseAttr,
-    invariants::fs::symlink::{inv_symlink_after, inv_symlink_before},
+    invariants::{
+        fs::symlink::{inv_symlink_after, inv_symlink_before},
+        locks::{record, Tree},
+    },
     log_call, log_more, log_res,
     req_rep::{ReplyEntry, Request},
 };
@@ -12,46 +15,48 @@ use super::InvFS;
 
 impl InvFS {
     pub fn do_symlink(
-        &mut self,
+        &self,
         req: Request,
         parent: u64,
         name: &std::ffi::OsStr,
         link: &std::path::Path,
         reply: &ReplyEntry,
     ) {
-        let callid = log_call!(
-            "SYMLINK",
-            "parent={},name={:?},link={:?}",
-            parent,
-            name,
-            link
-        );
-        let cwd = chdirin(&self.root);
+        let callid = log_call!("SYMLINK", parent, name: "{:?}", link: "{:?}");
+        let guard = self.locks.lock(Tree::Shared, &[], &[parent]);
         let mut dl = self.data.lock().unwrap();
         let inv = inv_symlink_before(callid, &req, &self.root, parent, name, link, &mut dl);
-        let ids = set_ids(callid, req, None);
-        let ip = &mut dl.INODE_PATHS;
-        let p_path = ip.get(parent);
+        let p_path = dl.INODE_PATHS.get(parent);
         log_more!(callid, "parent={:?}", p_path);
         let child = p_path.join(name);
         log_more!(callid, "child={:?}", child);
+        drop(dl);
+        let ids = set_ids(callid, req, None);
         let res = unsafe {
-            let tgt = CString::new(child.as_os_str().as_bytes()).unwrap();
+            let tgt = self.rel_path(&child);
             let lk = CString::new(link.as_os_str().as_bytes()).unwrap();
-            let res = libc::symlink(lk.as_ptr(), tgt.as_ptr());
+            let res = libc::symlinkat(lk.as_ptr(), self.root_fd(), tgt.as_ptr());
             if res == 0 {
-                stat_path(&child).map(|x| {
-                    let ino = ip.insert(x.st_ino, child);
-                    x.to_fuse_attr(ino)
-                })
+                self.stat_path(&child)
             } else {
                 Err(*libc::__errno_location())
             }
         };
-        log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        inv_symlink_after(callid, inv, &res, &mut dl);
-        chdirout(cwd);
+        let mut dl = self.data.lock().unwrap();
+        let res = res.map(|x| {
+            let ino = dl.INODE_PATHS.lookup(x.st_ino, child);
+            x.to_fuse_attr(ino)
+        });
+        log_res!(callid, "{:?}", res);
+        if res.is_ok() {
+            dl.listings.touch(parent, name);
+        }
+        let check = record(callid, &mut dl, &[parent], guard.ticket(), |dl| {
+            inv_symlink_after(callid, inv, &res, dl)
+        });
+        drop(dl);
+        let res = self.handle_violation(callid, "SYMLINK", Some(parent), check, res);
         match res {
             Ok(attr) => reply.entry(&TTL, &attr, 0),
             Err(v) => reply.error(v),
@@ -65,21 +70,14 @@ mod tests {
 
     use crate::{
         fs::TTL,
-        req_rep::{KernelConfig, ReplyCreate, ReplyEntry, Request},
+        req_rep::{ReplyCreate, ReplyEntry, Request},
+        test::init,
     };
 
     #[test]
     fn test_symlink() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep_c = ReplyCreate::new();
         ifs.do_create(
             Request {
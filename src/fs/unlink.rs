@@ -1,8 +1,7 @@
-use std::{ffi::CString, os::unix::prelude::OsStrExt};
-
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
+    fs::{restore_ids, set_ids},
     invariants::fs::unlink::{inv_unlink_after, inv_unlink_before},
+    invariants::locks::{apply, Tree},
     log_call, log_more, log_res,
     req_rep::{ReplyEmpty, Request},
 };
@@ -10,15 +9,9 @@ use crate::{
 use super::InvFS;
 
 impl InvFS {
-    pub fn do_unlink(
-        &mut self,
-        req: Request,
-        parent: u64,
-        name: &std::ffi::OsStr,
-        reply: &ReplyEmpty,
-    ) {
-        let callid = log_call!("UNLINK", "parent={},name={:?}", parent, name);
-        let cwd = chdirin(&self.root);
+    pub fn do_unlink(&self, req: Request, parent: u64, name: &std::ffi::OsStr, reply: &ReplyEmpty) {
+        let callid = log_call!("UNLINK", parent, name: "{:?}");
+        let _guard = self.locks.lock(Tree::Exclusive, &[], &[]);
         let mut dl = self.data.lock().unwrap();
         let inv = inv_unlink_before(callid, &req, &self.root, parent, name, &mut dl);
         let ids = set_ids(callid, req, None);
@@ -28,8 +21,8 @@ impl InvFS {
         let child = p_path.join(name);
         log_more!(callid, "child={:?}", child);
         let res = unsafe {
-            let tgt = CString::new(child.as_os_str().as_bytes()).unwrap();
-            let res = libc::unlink(tgt.as_ptr());
+            let tgt = self.rel_path(&child);
+            let res = libc::unlinkat(self.root_fd(), tgt.as_ptr(), 0);
             if res == 0 {
                 Ok(())
             } else {
@@ -38,13 +31,16 @@ impl InvFS {
         };
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        inv_unlink_after(callid, inv, &res, &mut dl);
-        chdirout(cwd);
-        match res {
-            Ok(()) => {
-                dl.INODE_PATHS.remove(&child);
-                reply.ok()
-            }
+        let check = apply(callid, &mut dl, &[parent], |dl| {
+            inv_unlink_after(callid, inv, &res, dl)
+        });
+        if res.is_ok() {
+            dl.INODE_PATHS.remove(&child);
+            dl.listings.touch(parent, name);
+            dl.durable.forget(callid, &[parent]);
+        }
+        match self.handle_violation(callid, "UNLINK", Some(parent), check, res) {
+            Ok(()) => reply.ok(),
             Err(v) => reply.error(v),
         }
     }
@@ -54,20 +50,14 @@ impl InvFS {
 mod tests {
     use std::ffi::OsString;
 
-    use crate::req_rep::{KernelConfig, ReplyCreate, ReplyEmpty, Request};
+    use crate::req_rep::{ReplyCreate, ReplyEmpty, Request};
+
+    use crate::test::init;
 
     #[test]
     fn test_unlink() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep = ReplyCreate::new();
         ifs.do_create(
             Request {
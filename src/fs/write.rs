@@ -1,9 +1,12 @@
 use libc::c_void;
 
 use crate::{
-    fs::{chdirin, chdirout, restore_ids, set_ids},
-    invariants::fs::write::{inv_write_after, inv_write_before},
-    log_call, log_res,
+    fs::{restore_ids, set_ids},
+    invariants::{
+        fs::write::{inv_write_after, inv_write_before},
+        locks::{record, Tree},
+    },
+    log_call, log_more, log_res,
     req_rep::{ReplyWrite, Request},
 };
 
@@ -11,7 +14,7 @@ use super::InvFS;
 
 impl InvFS {
     pub fn do_write(
-        &mut self,
+        &self,
         req: Request,
         ino: u64,
         fh: u64,
@@ -24,16 +27,15 @@ impl InvFS {
     ) {
         let callid = log_call!(
             "WRITE",
-            "ino={},fh={:x},offset={:x},data=[{:x}],write_flags={:x},flags={:x},lock_owner={:?}",
             ino,
-            fh,
-            offset,
-            data.len(),
-            write_flags,
-            flags,
-            lock_owner
+            fh: "{:x}",
+            offset: "{:x}",
+            data: "[{:x}]" = data.len(),
+            write_flags: "{:x}",
+            flags: "{:x}",
+            lock_owner: "{:?}",
         );
-        let cwd = chdirin(&self.root);
+        let guard = self.locks.lock(Tree::Shared, &[], &[ino]);
         let mut dl = self.data.lock().unwrap();
         let inv = inv_write_before(
             callid,
@@ -48,17 +50,12 @@ impl InvFS {
             lock_owner,
             &mut dl,
         );
+        drop(dl);
         let ids = set_ids(callid, req, None);
         let res = unsafe {
-            println!("FH: {:?}", fh);
-            let offs = libc::lseek(fh as i32, offset, libc::SEEK_SET);
-            assert_eq!(
-                offs,
-                offset,
-                "failed to seek: {}",
-                *libc::__errno_location()
-            );
-            let res = libc::write(fh as i32, data.as_ptr() as *mut c_void, data.len());
+            log_more!(callid, "FH: {:?}", fh);
+            // Not lseek + write: other requests may be using the same handle concurrently.
+            let res = libc::pwrite(fh as i32, data.as_ptr() as *mut c_void, data.len(), offset);
             if res != -1 {
                 Ok(res)
             } else {
@@ -67,8 +64,12 @@ impl InvFS {
         };
         log_res!(callid, "{:?}", res);
         restore_ids(ids);
-        inv_write_after(callid, inv, &res, &mut dl);
-        chdirout(cwd);
+        let mut dl = self.data.lock().unwrap();
+        let check = record(callid, &mut dl, &[ino], guard.ticket(), |dl| {
+            inv_write_after(callid, inv, &res, dl)
+        });
+        drop(dl);
+        let res = self.handle_violation(callid, "WRITE", Some(ino), check, res);
         match res {
             Ok(v) => reply.written(v.try_into().unwrap()),
             Err(e) => reply.error(e),
@@ -82,21 +83,14 @@ mod tests {
 
     use crate::{
         fs::TTL,
-        req_rep::{KernelConfig, ReplyCreate, ReplyOpen, ReplyWrite, Request},
+        req_rep::{ReplyCreate, ReplyOpen, ReplyWrite, Request},
+        test::init,
     };
 
     #[test]
     fn test_write() {
-        let mut ifs = crate::test::create_ifs();
-        ifs.do_init(
-            Request {
-                uid: 0,
-                gid: 0,
-                pid: 0,
-            },
-            &KernelConfig::empty(),
-        )
-        .unwrap();
+        let ifs = crate::test::create_ifs();
+        init(&ifs);
         let rep = ReplyCreate::new();
         ifs.do_create(
             Request {
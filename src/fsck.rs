@@ -0,0 +1,204 @@
+//! Checking a backing directory against a saved model without mounting it.
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    inode_mapper::InodeMapper,
+    invariants::{
+        diff::{diff, Difference},
+        durable,
+        fs::init::{scan_backend, ScanError},
+        violation::Violation,
+        Checks, FSData,
+    },
+    snapshot::{self, SnapshotError},
+};
+
+#[derive(Debug)]
+pub enum FsckError {
+    /// There is no snapshot at the given path.
+    NoSnapshot(PathBuf),
+    Snapshot(SnapshotError),
+    /// An entry of the backing directory couldn't be read.
+    Io(PathBuf, std::io::Error),
+    /// The backing directory is inconsistent with itself, e.g. two links to one inode disagree.
+    Scan(Violation),
+}
+
+impl Display for FsckError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsckError::NoSnapshot(p) => write!(f, "no snapshot at {:?}", p),
+            FsckError::Snapshot(e) => write!(f, "{}", e),
+            FsckError::Io(p, e) => write!(f, "failed to read {:?}: {}", p, e),
+            FsckError::Scan(v) => write!(f, "{}", v.message),
+        }
+    }
+}
+
+impl From<SnapshotError> for FsckError {
+    fn from(e: SnapshotError) -> Self {
+        Self::Snapshot(e)
+    }
+}
+
+impl From<ScanError> for FsckError {
+    fn from(e: ScanError) -> Self {
+        match e {
+            ScanError::Io(p, e) => Self::Io(p, e),
+            ScanError::Inconsistent(v) => Self::Scan(v),
+        }
+    }
+}
+
+/// Move every path in `mapper` from under `from` to under `to`.
+fn rebase(mapper: &InodeMapper, from: &Path, to: &Path) -> InodeMapper {
+    InodeMapper::load(
+        mapper
+            .store()
+            .into_iter()
+            .map(|(ino, paths)| {
+                let paths = paths
+                    .into_iter()
+                    .map(|p| match p.strip_prefix(from) {
+                        Ok(rel) if rel.as_os_str().is_empty() => to.to_path_buf(),
+                        Ok(rel) => to.join(rel),
+                        Err(_) => p,
+                    })
+                    .collect();
+                (ino, paths)
+            })
+            .collect(),
+    )
+}
+
+/// Walk `base` the same way mounting does and compare it to the model saved in `snapshot`.
+///
+/// Only the categories in `checks` are compared; `None` compares everything the snapshot has.
+/// The snapshot is left in place. Paths are reported under `base`, even if the snapshot was taken
+/// with the backing directory spelled differently.
+pub fn fsck(
+    base: &Path,
+    snapshot: &Path,
+    checks: Option<Checks>,
+) -> Result<Vec<Difference>, FsckError> {
+    let mut prev =
+        snapshot::read(snapshot)?.ok_or_else(|| FsckError::NoSnapshot(snapshot.to_path_buf()))?;
+    let checks = match checks {
+        Some(c) => {
+            let c = c.normalize();
+            snapshot::covers(&prev, c)?;
+            c
+        }
+        None => prev.checks,
+    };
+    if let Some(root) = prev
+        .INV_INODE_PATHS
+        .get_all(1)
+        .and_then(|x| x.iter().next())
+        .cloned()
+    {
+        prev.INV_INODE_PATHS = rebase(&prev.INV_INODE_PATHS, &root, base);
+    }
+    let mut actual = FSData::new();
+    actual.checks = checks;
+    scan_backend(base, checks, &mut actual)?;
+    Ok(diff(&prev, &actual))
+}
+
+/// Walk `base` and check that everything the durability record next to `snapshot` promised is
+/// still there, e.g. after a crash. Unlike [`fsck`], changes made since the last sync are fine.
+pub fn fsck_durable(
+    base: &Path,
+    snapshot: &Path,
+    checks: Option<Checks>,
+) -> Result<Vec<Difference>, FsckError> {
+    let record = durable::record_path(snapshot);
+    let promised = snapshot::read_durable(&record)?.ok_or(FsckError::NoSnapshot(record))?;
+    let checks = checks.unwrap_or_default().normalize();
+    let mut actual = FSData::new();
+    actual.checks = checks;
+    scan_backend(base, checks, &mut actual)?;
+    Ok(durable::verify(&promised, &actual))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::prelude::PermissionsExt, path::PathBuf};
+
+    use crate::{
+        fs::{get_thread_caps, set_thread_caps},
+        invariants::{diff::DiffKind, fs::init::scan_backend, FSData},
+        snapshot,
+        test::tempdir,
+    };
+
+    use super::{fsck, FsckError};
+
+    #[test]
+    fn test_fsck() {
+        let base = tempdir();
+        std::fs::write(base.join("foo"), "foo").unwrap();
+        std::fs::write(base.join("bar"), "bar").unwrap();
+        let snap = base.with_extension("snapshot");
+        assert!(matches!(
+            fsck(&base, &snap, None),
+            Err(FsckError::NoSnapshot(_))
+        ));
+
+        let mut fs_data = FSData::new();
+        scan_backend(&base, fs_data.checks, &mut fs_data).unwrap();
+        snapshot::save(&fs_data, &snap).unwrap();
+        assert_eq!(fsck(&base, &snap, None).unwrap(), vec![]);
+
+        // The same directory, spelled differently.
+        let dotted = base.join(".").join("..").join(base.file_name().unwrap());
+        assert_eq!(fsck(&dotted, &snap, None).unwrap(), vec![]);
+
+        std::fs::write(base.join("foo"), "oof").unwrap();
+        std::fs::remove_file(base.join("bar")).unwrap();
+        std::fs::write(base.join("baz"), "").unwrap();
+        let kinds = fsck(&base, &snap, None)
+            .unwrap()
+            .into_iter()
+            .map(|d| (d.kind, d.path.unwrap()))
+            .collect::<Vec<_>>();
+        assert!(kinds.contains(&(DiffKind::Data, base.join("foo"))));
+        assert!(kinds.contains(&(DiffKind::MissingPath, base.join("bar"))));
+        assert!(kinds.contains(&(DiffKind::ExtraPath, base.join("baz"))));
+        assert!(kinds.contains(&(DiffKind::DirEntries, PathBuf::from(&base))));
+        // fsck never consumes the snapshot.
+        assert!(snap.exists());
+    }
+
+    #[test]
+    fn test_fsck_unreadable() {
+        let base = tempdir();
+        let snap = base.with_extension("snapshot");
+        let mut fs_data = FSData::new();
+        scan_backend(&base, fs_data.checks, &mut fs_data).unwrap();
+        snapshot::save(&fs_data, &snap).unwrap();
+        let locked = base.join("locked");
+        std::fs::create_dir(&locked).unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        // Without the capabilities that let root read anything.
+        let res = std::thread::spawn(move || unsafe {
+            let mut caps = get_thread_caps();
+            caps[0].effective = 0;
+            assert_eq!(set_thread_caps(&caps), 0);
+            fsck(&base, &snap, None)
+        })
+        .join()
+        .unwrap();
+        match res {
+            Err(FsckError::Io(p, e)) => {
+                assert_eq!(p, locked);
+                assert_eq!(e.raw_os_error(), Some(libc::EACCES));
+            }
+            v => panic!("Expected a read error, got {:?}", v),
+        }
+    }
+}
@@ -5,16 +5,19 @@ use std::{
 
 use maplit::{btreemap, btreeset};
 
+use crate::{log_more, logging::CallID};
+
+/// The paths each inode is known by, and how many lookups of it the kernel holds.
 #[derive(Debug, Default)]
-pub struct InodeMapper(BTreeMap<u64, BTreeSet<PathBuf>>);
+pub struct InodeMapper(BTreeMap<u64, BTreeSet<PathBuf>>, BTreeMap<u64, u64>);
 
 impl InodeMapper {
     pub fn new() -> Self {
-        Self(btreemap! {})
+        Self(btreemap! {}, btreemap! {})
     }
 
     pub fn load(v: BTreeMap<u64, BTreeSet<PathBuf>>) -> Self {
-        Self(v)
+        Self(v, btreemap! {})
     }
     pub fn store(&self) -> BTreeMap<u64, BTreeSet<PathBuf>> {
         self.0.clone()
@@ -48,6 +51,30 @@ impl InodeMapper {
         ino
     }
 
+    /// Like [`InodeMapper::insert`], for an inode handed to the kernel in an entry reply: the
+    /// kernel now holds one more lookup of it, which it gives back with `forget`.
+    pub fn lookup(&mut self, ino: u64, child: PathBuf) -> u64 {
+        let ino = self.insert(ino, child);
+        *self.1.entry(ino).or_default() += 1;
+        ino
+    }
+
+    /// Give back `n` lookups of `ino`. Returns how many the kernel still holds, or `None` if it
+    /// gave back more than it was handed.
+    pub fn forget(&mut self, ino: u64, n: u64) -> Option<u64> {
+        let held = self.lookups(ino);
+        let left = held.checked_sub(n);
+        match left {
+            Some(0) | None => self.1.remove(&ino),
+            Some(v) => self.1.insert(ino, v),
+        };
+        left
+    }
+
+    pub fn lookups(&self, ino: u64) -> u64 {
+        self.1.get(&ino).copied().unwrap_or(0)
+    }
+
     pub fn remove(&mut self, child: &Path) {
         self.0.retain(|_, v| {
             v.retain(|v| v != child);
@@ -55,7 +82,17 @@ impl InodeMapper {
         })
     }
 
-    pub fn rename(&mut self, old: PathBuf, new: PathBuf) {
+    /// Like [`InodeMapper::remove`], for one inode only: by now `child` may name another.
+    pub fn unmap(&mut self, ino: u64, child: &Path) {
+        if let Entry::Occupied(mut o) = self.0.entry(ino) {
+            o.get_mut().remove(child);
+            if o.get().is_empty() {
+                o.remove();
+            }
+        }
+    }
+
+    pub fn rename(&mut self, callid: CallID, old: PathBuf, new: PathBuf) {
         self.remove(&new);
         self.0.iter_mut().for_each(|(k, v)| {
             if v.remove(&old) {
@@ -65,7 +102,7 @@ impl InodeMapper {
                 .iter()
                 .map(|x| {
                     if let Ok(v) = x.strip_prefix(old.clone()) {
-                        println!("{:?} -R> ({}) {:?}", x, k, new.join(v));
+                        log_more!(callid, "{:?} -R> ({}) {:?}", x, k, new.join(v));
                         new.join(v)
                     } else {
                         x.clone()
@@ -116,6 +153,19 @@ mod tests {
         assert_eq!(im.store(), btreemap! {});
     }
     #[test]
+    fn lookup_forget() {
+        let mut im = InodeMapper::new();
+        assert_eq!(im.lookup(2, PathBuf::from("/foo")), 2);
+        assert_eq!(im.lookup(2, PathBuf::from("/bar")), 2);
+        assert_eq!(im.insert(2, PathBuf::from("/baz")), 2);
+        assert_eq!(im.lookups(2), 2);
+        assert_eq!(im.forget(2, 1), Some(1));
+        assert_eq!(im.forget(2, 1), Some(0));
+        assert_eq!(im.lookups(2), 0);
+        assert_eq!(im.forget(2, 1), None);
+        assert_eq!(im.get(2), PathBuf::from("/bar"));
+    }
+    #[test]
     fn load() {
         let im = InodeMapper::load(
             btreemap! {2=>btreeset!{PathBuf::from("/foo")},3=>btreeset!{PathBuf::from("/bar"),PathBuf::from("/baz")}},
@@ -141,17 +191,27 @@ mod tests {
         assert_eq!(im.store(), btreemap! {});
     }
     #[test]
+    fn unmap() {
+        let mut im = InodeMapper::new();
+        assert_eq!(im.insert(2, PathBuf::from("/foo")), 2);
+        assert_eq!(im.insert(3, PathBuf::from("/foo")), 3);
+        assert_eq!(im.insert(3, PathBuf::from("/bar")), 3);
+        im.unmap(2, &PathBuf::from("/foo"));
+        im.unmap(3, &PathBuf::from("/bar"));
+        assert_eq!(im.store(), btreemap! {3=>btreeset!{PathBuf::from("/foo")}});
+    }
+    #[test]
     fn rename() {
         let mut im = InodeMapper::new();
         assert_eq!(im.insert(2, PathBuf::from("/bar")), 2);
-        im.rename(PathBuf::from("/bar"), PathBuf::from("/baz"));
+        im.rename(0, PathBuf::from("/bar"), PathBuf::from("/baz"));
         assert_eq!(im.store(), btreemap! {2=>btreeset!{PathBuf::from("/baz")}});
     }
     #[test]
     fn rename_subdirs() {
         let mut im = InodeMapper::new();
         assert_eq!(im.insert(2, PathBuf::from("/bar/foo")), 2);
-        im.rename(PathBuf::from("/bar"), PathBuf::from("/baz"));
+        im.rename(0, PathBuf::from("/bar"), PathBuf::from("/baz"));
         assert_eq!(
             im.store(),
             btreemap! {2=>btreeset!{PathBuf::from("/baz/foo")}}
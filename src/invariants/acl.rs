@@ -0,0 +1,257 @@
+//! POSIX ACLs, as the backend stores them in the `system.posix_acl_access` and
+//! `system.posix_acl_default` attributes.
+//!
+//! Like the mode bits, ACLs are read from the backend when permissions are checked. An access
+//! ACL's owner, mask (or owning group) and other entries are the mode bits, so the model keeps
+//! the two in step.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    path::Path,
+};
+
+pub const ACL_ACCESS: &str = "system.posix_acl_access";
+pub const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+const ACL_VERSION: u32 = 2;
+
+pub const ACL_USER_OBJ: u16 = 0x01;
+pub const ACL_USER: u16 = 0x02;
+pub const ACL_GROUP_OBJ: u16 = 0x04;
+pub const ACL_GROUP: u16 = 0x08;
+pub const ACL_MASK: u16 = 0x10;
+pub const ACL_OTHER: u16 = 0x20;
+
+/// The id of the entries that don't name a user or group.
+pub const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AclEntry {
+    pub tag: u16,
+    pub perm: u16,
+    pub id: u32,
+}
+
+/// The entries of an ACL, in the order the kernel keeps them: by tag, then by id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl(Vec<AclEntry>);
+
+impl Acl {
+    pub fn new(mut entries: Vec<AclEntry>) -> Self {
+        entries.sort_by_key(|x| (x.tag, x.id));
+        Self(entries)
+    }
+
+    /// Decodes an ACL attribute, or `None` if it isn't a valid one.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 4 || (data.len() - 4) % 8 != 0 {
+            return None;
+        }
+        if u32::from_le_bytes(data[..4].try_into().unwrap()) != ACL_VERSION {
+            return None;
+        }
+        let entries: Vec<AclEntry> = data[4..]
+            .chunks(8)
+            .map(|x| AclEntry {
+                tag: u16::from_le_bytes(x[..2].try_into().unwrap()),
+                perm: u16::from_le_bytes(x[2..4].try_into().unwrap()),
+                id: u32::from_le_bytes(x[4..].try_into().unwrap()),
+            })
+            .collect();
+        let count = |tag| entries.iter().filter(|x| x.tag == tag).count();
+        let named = count(ACL_USER) + count(ACL_GROUP);
+        if count(ACL_USER_OBJ) != 1
+            || count(ACL_GROUP_OBJ) != 1
+            || count(ACL_OTHER) != 1
+            || count(ACL_MASK) != (named != 0) as usize
+        {
+            return None;
+        }
+        Some(Self::new(entries))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = ACL_VERSION.to_le_bytes().to_vec();
+        for e in &self.0 {
+            data.extend(e.tag.to_le_bytes());
+            data.extend(e.perm.to_le_bytes());
+            data.extend(e.id.to_le_bytes());
+        }
+        data
+    }
+
+    /// The ACL `path` has in the attribute `name`, if any.
+    pub fn read(path: &Path, name: &str) -> Option<Self> {
+        xattr::get(path, name)
+            .ok()
+            .flatten()
+            .and_then(|x| Self::parse(&x))
+    }
+
+    fn entry(&self, tag: u16) -> Option<&AclEntry> {
+        self.0.iter().find(|x| x.tag == tag)
+    }
+
+    fn entry_mut(&mut self, tag: u16) -> Option<&mut AclEntry> {
+        self.0.iter_mut().find(|x| x.tag == tag)
+    }
+
+    /// The entry that stands for the group bits of the mode: the mask if there is one.
+    fn group_bits(&mut self) -> &mut AclEntry {
+        let tag = if self.entry(ACL_MASK).is_some() {
+            ACL_MASK
+        } else {
+            ACL_GROUP_OBJ
+        };
+        self.entry_mut(tag).expect("ACL without group entry")
+    }
+
+    /// Whether the ACL says no more than the mode bits do, which the kernel doesn't store.
+    pub fn is_minimal(&self) -> bool {
+        self.0.len() == 3
+    }
+
+    /// The permission bits of the mode the ACL implies.
+    pub fn mode(&self) -> u32 {
+        let bits = |tag| u32::from(self.entry(tag).map_or(0, |x| x.perm));
+        let group = if self.entry(ACL_MASK).is_some() {
+            bits(ACL_MASK)
+        } else {
+            bits(ACL_GROUP_OBJ)
+        };
+        bits(ACL_USER_OBJ) << 6 | group << 3 | bits(ACL_OTHER)
+    }
+
+    /// Changes the entries that mirror the mode bits to match `mode`, as `chmod` does.
+    pub fn chmod(&mut self, mode: u32) {
+        let bits = |shift: u32| ((mode >> shift) & 0o7) as u16;
+        self.entry_mut(ACL_USER_OBJ).unwrap().perm = bits(6);
+        self.group_bits().perm = bits(3);
+        self.entry_mut(ACL_OTHER).unwrap().perm = bits(0);
+    }
+
+    /// The access ACL and mode of a node created with `mode` under a directory with this
+    /// default ACL: each entry standing for mode bits is limited to them, and the reverse.
+    pub fn create(&self, mode: u32) -> (Self, u32) {
+        let mut acl = self.clone();
+        let mut bits = mode & 0o777;
+        for (tag, shift) in [(ACL_USER_OBJ, 6), (ACL_OTHER, 0)] {
+            let e = acl.entry_mut(tag).unwrap();
+            e.perm &= ((bits >> shift) & 0o7) as u16;
+            bits &= !(0o7 << shift) | u32::from(e.perm) << shift;
+        }
+        let e = acl.group_bits();
+        e.perm &= ((bits >> 3) & 0o7) as u16;
+        bits &= !0o70 | u32::from(e.perm) << 3;
+        (acl, (mode & !0o777) | bits)
+    }
+
+    /// Whether the ACL grants `want` (as a single octal digit) to a requester.
+    pub fn permits(
+        &self,
+        owner: u32,
+        owner_group: u32,
+        uid: u32,
+        gid: u32,
+        sgids: &BTreeSet<u32>,
+        want: u16,
+    ) -> bool {
+        let in_group = |g: u32| g == gid || sgids.contains(&g);
+        let mask = self.entry(ACL_MASK).map_or(0o7, |x| x.perm);
+        let masked = |perm: u16| perm & mask & want == want;
+        let mut found = false;
+        for e in &self.0 {
+            match e.tag {
+                ACL_USER_OBJ if owner == uid => return e.perm & want == want,
+                ACL_USER if e.id == uid => return masked(e.perm),
+                ACL_GROUP_OBJ | ACL_GROUP => {
+                    let g = if e.tag == ACL_GROUP_OBJ {
+                        owner_group
+                    } else {
+                        e.id
+                    };
+                    if in_group(g) {
+                        found = true;
+                        if e.perm & want == want {
+                            return masked(e.perm);
+                        }
+                    }
+                }
+                ACL_OTHER => return !found && e.perm & want == want,
+                _ => {}
+            }
+        }
+        false
+    }
+}
+
+/// The permission bits and ACL attributes of a node created with `mode` in `parent`. If the
+/// parent has a default ACL it takes the place of the umask, and directories inherit it as
+/// their own default ACL.
+pub fn inherit(
+    parent: &Path,
+    mode: u32,
+    umask: u32,
+    dir: bool,
+) -> (u32, BTreeMap<OsString, Vec<u8>>) {
+    let mut xattrs = BTreeMap::new();
+    let default = match Acl::read(parent, ACL_DEFAULT) {
+        Some(v) => v,
+        None => return (mode & !umask & 0o7777, xattrs),
+    };
+    let (access, mode) = default.create(mode & 0o7777);
+    if !access.is_minimal() {
+        xattrs.insert(ACL_ACCESS.into(), access.to_bytes());
+    }
+    if dir {
+        xattrs.insert(ACL_DEFAULT.into(), default.to_bytes());
+    }
+    (mode, xattrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    fn entry(tag: u16, perm: u16, id: u32) -> AclEntry {
+        AclEntry { tag, perm, id }
+    }
+
+    fn acl() -> Acl {
+        Acl::new(vec![
+            entry(ACL_OTHER, 0o4, ACL_UNDEFINED_ID),
+            entry(ACL_USER_OBJ, 0o6, ACL_UNDEFINED_ID),
+            entry(ACL_USER, 0o7, 1000),
+            entry(ACL_GROUP_OBJ, 0o4, ACL_UNDEFINED_ID),
+            entry(ACL_GROUP, 0o6, 2000),
+            entry(ACL_MASK, 0o6, ACL_UNDEFINED_ID),
+        ])
+    }
+
+    #[test]
+    fn test_acl() {
+        let acl = acl();
+        assert_eq!(Acl::parse(&acl.to_bytes()), Some(acl.clone()));
+        assert_eq!(acl.mode(), 0o664);
+        let none = BTreeSet::new();
+        // Owner, then the named user limited by the mask
+        assert!(acl.permits(1, 1, 1, 1, &none, 0o6));
+        assert!(acl.permits(1, 1, 1000, 1000, &none, 0o6));
+        assert!(!acl.permits(1, 1, 1000, 1000, &none, 0o1));
+        // A matching group that doesn't grant it denies, rather than falling through to other
+        assert!(acl.permits(1, 1, 5, 5, &BTreeSet::from([2000]), 0o2));
+        assert!(!acl.permits(1, 1, 5, 1, &none, 0o2));
+        assert!(acl.permits(1, 1, 5, 5, &none, 0o4));
+        assert!(!acl.permits(1, 1, 5, 5, &none, 0o2));
+
+        let (access, mode) = acl.create(0o4751);
+        assert_eq!(mode, 0o4640);
+        assert_eq!(access.mode(), 0o640);
+        let mut chmodded = access;
+        chmodded.chmod(0o705);
+        assert_eq!(chmodded.mode(), 0o705);
+    }
+}
@@ -77,3 +77,17 @@ pub fn common_pre_ino(callid: CallID, ino: u64, fs_data: &mut MutexGuard<'_, FSD
 
     CPI { inode_path, exists }
 }
+
+/// Like [`common_pre_ino`], for a request on an open handle. The file may have been unlinked
+/// since it was opened, and then has no path left to check: it exists as long as the handle.
+pub fn common_pre_fh(
+    callid: CallID,
+    ino: u64,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> Option<CPI> {
+    if fs_data.INV_INODE_PATHS.get_all(ino).is_none() {
+        log_more!(callid, "Inode {} has no paths left", ino);
+        return None;
+    }
+    Some(common_pre_ino(callid, ino, fs_data))
+}
@@ -0,0 +1,245 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{Debug, Display},
+    path::PathBuf,
+};
+
+use serde::Serialize;
+
+use super::FSData;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum DiffKind {
+    /// A path the expected model has and the actual one doesn't.
+    MissingPath,
+    /// A path the actual model has and the expected one doesn't.
+    ExtraPath,
+    /// A path both have, pointing at different inodes.
+    InodeMismatch,
+    Metadata,
+    DirEntries,
+    Data,
+    Xattrs,
+}
+
+impl Display for DiffKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DiffKind::MissingPath => "missing path",
+            DiffKind::ExtraPath => "extra path",
+            DiffKind::InodeMismatch => "inode mismatch",
+            DiffKind::Metadata => "metadata",
+            DiffKind::DirEntries => "directory entries",
+            DiffKind::Data => "data",
+            DiffKind::Xattrs => "xattrs",
+        })
+    }
+}
+
+/// One way two models disagree about an inode.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Difference {
+    pub kind: DiffKind,
+    pub ino: u64,
+    pub path: Option<PathBuf>,
+    pub what: String,
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: ino {}", self.kind, self.ino)?;
+        if let Some(p) = &self.path {
+            write!(f, " ({:?})", p)?;
+        }
+        write!(f, ": {}", self.what)
+    }
+}
+
+fn diff_map<V: PartialEq + Debug>(
+    kind: DiffKind,
+    expected: &BTreeMap<u64, V>,
+    actual: &BTreeMap<u64, V>,
+    out: &mut Vec<Difference>,
+) {
+    let inos: BTreeSet<u64> = expected.keys().chain(actual.keys()).copied().collect();
+    for ino in inos {
+        let what = match (expected.get(&ino), actual.get(&ino)) {
+            (Some(e), Some(a)) if e != a => format!("changed from {:?} to {:?}", e, a),
+            (Some(_), None) => String::from("disappeared"),
+            (None, Some(_)) => String::from("appeared"),
+            _ => continue,
+        };
+        out.push(Difference {
+            kind,
+            ino,
+            path: None,
+            what,
+        });
+    }
+}
+
+fn by_path(m: BTreeMap<u64, BTreeSet<PathBuf>>) -> BTreeMap<PathBuf, u64> {
+    m.into_iter()
+        .flat_map(|(ino, paths)| paths.into_iter().map(move |p| (p, ino)))
+        .collect()
+}
+
+fn diff_paths(expected: &FSData, actual: &FSData, out: &mut Vec<Difference>) {
+    let expected = by_path(expected.INV_INODE_PATHS.store());
+    let actual = by_path(actual.INV_INODE_PATHS.store());
+    for (path, ino) in &expected {
+        match actual.get(path) {
+            None => out.push(Difference {
+                kind: DiffKind::MissingPath,
+                ino: *ino,
+                path: Some(path.clone()),
+                what: String::from("no longer exists"),
+            }),
+            Some(a) if a != ino => out.push(Difference {
+                kind: DiffKind::InodeMismatch,
+                ino: *ino,
+                path: Some(path.clone()),
+                what: format!("now points at ino {}", a),
+            }),
+            _ => (),
+        }
+    }
+    for (path, ino) in &actual {
+        if !expected.contains_key(path) {
+            out.push(Difference {
+                kind: DiffKind::ExtraPath,
+                ino: *ino,
+                path: Some(path.clone()),
+                what: String::from("was not there before"),
+            })
+        }
+    }
+}
+
+/// Compare the parts of two models that both track, e.g. a saved one against a fresh scan.
+/// Times are ignored, as they are everywhere else.
+pub fn diff(expected: &FSData, actual: &FSData) -> Vec<Difference> {
+    let mut out = vec![];
+    diff_paths(expected, actual, &mut out);
+    if expected.checks.meta && actual.checks.meta {
+        let reset = |m: &BTreeMap<u64, crate::file_attr::FileAttr>| {
+            m.iter()
+                .map(|(k, v)| (*k, v.reset_times()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        diff_map(
+            DiffKind::Metadata,
+            &reset(&expected.INV_INODE_CONTENTS),
+            &reset(&actual.INV_INODE_CONTENTS),
+            &mut out,
+        );
+    }
+    if expected.checks.dirs && actual.checks.dirs {
+        diff_map(
+            DiffKind::DirEntries,
+            &expected.INV_DIR_CONTENTS,
+            &actual.INV_DIR_CONTENTS,
+            &mut out,
+        );
+    }
+    if expected.checks.data && actual.checks.data {
+        let data = |ino: &u64, what: String| Difference {
+            kind: DiffKind::Data,
+            ino: *ino,
+            path: None,
+            what,
+        };
+        for (ino, e) in &expected.INV_FILE_CONTENTS {
+            match actual.INV_FILE_CONTENTS.get(ino) {
+                Some(a) if a != e => out.push(data(
+                    ino,
+                    format!("changed ({} bytes, was {})", a.len(), e.len()),
+                )),
+                None => out.push(data(ino, String::from("disappeared"))),
+                _ => (),
+            }
+        }
+        for ino in actual.INV_FILE_CONTENTS.keys() {
+            if !expected.INV_FILE_CONTENTS.contains_key(ino) {
+                out.push(data(ino, String::from("appeared")));
+            }
+        }
+    }
+    if expected.checks.xattr && actual.checks.xattr {
+        diff_map(
+            DiffKind::Xattrs,
+            &expected.INV_XATTR_CONTENTS,
+            &actual.INV_XATTR_CONTENTS,
+            &mut out,
+        );
+    }
+    for d in out.iter_mut().filter(|d| d.path.is_none()) {
+        d.path = expected
+            .INV_INODE_PATHS
+            .get_all(d.ino)
+            .or_else(|| actual.INV_INODE_PATHS.get_all(d.ino))
+            .and_then(|x| x.iter().next().cloned());
+    }
+    out.sort_by_key(|d| (d.ino, d.kind));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use maplit::btreemap;
+
+    use crate::invariants::FSData;
+
+    use super::{diff, DiffKind, Difference};
+
+    #[test]
+    fn test_diff() {
+        let mut expected = FSData::new();
+        expected
+            .INV_INODE_PATHS
+            .insert(2, PathBuf::from("/base/foo"));
+        expected.INV_FILE_CONTENTS = btreemap! { 2 => b"foo".to_vec() };
+        let mut actual = FSData::new();
+        actual.INV_INODE_PATHS.insert(2, PathBuf::from("/base/foo"));
+        assert_eq!(diff(&expected, &actual).len(), 1);
+        actual.INV_FILE_CONTENTS = btreemap! { 2 => b"bar".to_vec() };
+        assert_eq!(
+            diff(&expected, &actual),
+            vec![Difference {
+                kind: DiffKind::Data,
+                ino: 2,
+                path: Some(PathBuf::from("/base/foo")),
+                what: String::from("changed (3 bytes, was 3)")
+            }]
+        );
+        actual.INV_FILE_CONTENTS = expected.INV_FILE_CONTENTS.clone();
+        assert_eq!(diff(&expected, &actual), vec![]);
+    }
+
+    #[test]
+    fn test_diff_paths() {
+        let mut expected = FSData::new();
+        expected
+            .INV_INODE_PATHS
+            .insert(2, PathBuf::from("/base/foo"));
+        expected
+            .INV_INODE_PATHS
+            .insert(3, PathBuf::from("/base/bar"));
+        let mut actual = FSData::new();
+        actual.INV_INODE_PATHS.insert(4, PathBuf::from("/base/foo"));
+        actual.INV_INODE_PATHS.insert(5, PathBuf::from("/base/baz"));
+        assert_eq!(
+            diff(&expected, &actual)
+                .into_iter()
+                .map(|d| (d.kind, d.ino))
+                .collect::<Vec<_>>(),
+            vec![
+                (DiffKind::InodeMismatch, 2),
+                (DiffKind::MissingPath, 3),
+                (DiffKind::ExtraPath, 5)
+            ]
+        );
+    }
+}
@@ -0,0 +1,300 @@
+//! What fsync, fdatasync and fsyncdir promised would survive a crash.
+//!
+//! Every successful sync records the model's view of the inode at that moment. A later change to
+//! the inode drops the record, since from then on a crash may leave any mix of the old and new
+//! state behind. Every change is appended to the record on disk, so it outlives the process: after
+//! a crash the next mount (or `fsck --durable`) checks that everything in it is still there.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{log_err, logging::CallID, snapshot};
+
+use super::{
+    diff::{DiffKind, Difference},
+    FSData,
+};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DurableFile {
+    /// Only recorded with metadata checks on.
+    pub size: Option<u64>,
+    /// Only recorded with data checks on.
+    pub data: Option<Vec<u8>>,
+}
+
+/// One change to the record, as appended to it on disk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Change {
+    File(u64, Option<PathBuf>, DurableFile),
+    Dir(u64, Option<PathBuf>, BTreeMap<OsString, u64>),
+    Forget(Vec<u64>),
+}
+
+/// Appended changes may grow to this much before the record is rewritten, however small it is.
+const COMPACT_MIN: usize = 64 << 10;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Durable {
+    /// Where the record is kept. Nothing is recorded until it is set.
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// Size of the record as last rewritten in full, and how much was appended since.
+    #[serde(skip)]
+    written: usize,
+    #[serde(skip)]
+    appended: usize,
+    /// A path for every recorded inode, to report it by.
+    pub paths: BTreeMap<u64, PathBuf>,
+    pub files: BTreeMap<u64, DurableFile>,
+    pub dirs: BTreeMap<u64, BTreeMap<OsString, u64>>,
+}
+
+impl Durable {
+    /// An empty record kept at `path`.
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path: Some(path),
+            ..Self::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.dirs.is_empty()
+    }
+
+    pub fn apply(&mut self, change: Change) {
+        match change {
+            Change::File(ino, path, file) => {
+                self.paths.extend(path.map(|p| (ino, p)));
+                self.files.insert(ino, file);
+            }
+            Change::Dir(ino, path, entries) => {
+                self.paths.extend(path.map(|p| (ino, p)));
+                self.dirs.insert(ino, entries);
+            }
+            Change::Forget(inos) => {
+                for ino in inos {
+                    self.files.remove(&ino);
+                    self.dirs.remove(&ino);
+                    self.paths.remove(&ino);
+                }
+            }
+        }
+    }
+
+    /// Apply `change` and persist it. Only the change itself is written, unless the appended
+    /// changes outgrew the record they apply to: then the whole record is rewritten instead.
+    fn save(&mut self, callid: CallID, change: Change) {
+        self.apply(change.clone());
+        let path = match &self.path {
+            Some(v) => v,
+            None => return,
+        };
+        let res = if self.written > 0 && self.appended <= self.written.max(COMPACT_MIN) {
+            snapshot::append_durable(&change, path).map(|n| self.appended += n)
+        } else {
+            snapshot::save_durable(self, path).map(|n| {
+                self.written = n;
+                self.appended = 0;
+            })
+        };
+        if let Err(e) = res {
+            log_err!(
+                callid,
+                "Failed to save the durability record to {:?}: {}",
+                path,
+                e
+            );
+        }
+    }
+
+    /// Forget `inos`: they changed since they were last synced.
+    pub fn forget(&mut self, callid: CallID, inos: &[u64]) {
+        let inos: Vec<u64> = inos
+            .iter()
+            .filter(|ino| self.files.contains_key(ino) || self.dirs.contains_key(ino))
+            .copied()
+            .collect();
+        if !inos.is_empty() {
+            self.save(callid, Change::Forget(inos));
+        }
+    }
+}
+
+fn path_of(fs_data: &FSData, ino: u64) -> Option<PathBuf> {
+    // An open file may have no name left.
+    fs_data
+        .INV_INODE_PATHS
+        .get_all(ino)
+        .and_then(|x| x.iter().next())
+        .cloned()
+}
+
+/// Record `ino` as synced in the model's current state.
+pub fn sync_file(callid: CallID, fs_data: &mut FSData, ino: u64) {
+    if fs_data.durable.path.is_none() || !fs_data.checks.any() {
+        return;
+    }
+    let file = DurableFile {
+        size: fs_data.INV_INODE_CONTENTS.get(&ino).map(|x| x.size),
+        data: fs_data.INV_FILE_CONTENTS.get(&ino).cloned(),
+    };
+    let path = path_of(fs_data, ino);
+    fs_data.durable.save(callid, Change::File(ino, path, file));
+}
+
+/// Record the entries of directory `ino` as synced. Needs directory checks.
+pub fn sync_dir(callid: CallID, fs_data: &mut FSData, ino: u64) {
+    if fs_data.durable.path.is_none() || !fs_data.checks.dirs {
+        return;
+    }
+    let entries = match fs_data.INV_DIR_CONTENTS.get(&ino) {
+        Some(v) => v.clone(),
+        None => return,
+    };
+    let path = path_of(fs_data, ino);
+    fs_data
+        .durable
+        .save(callid, Change::Dir(ino, path, entries));
+}
+
+/// Everything `durable` promised that `actual`, a fresh scan of the backend, doesn't have.
+///
+/// A synced file that is gone entirely is only reported through its directory, and only if that
+/// was synced too: syncing a file doesn't make its name durable.
+pub fn verify(durable: &Durable, actual: &FSData) -> Vec<Difference> {
+    let mut out = vec![];
+    let path = |ino: &u64| durable.paths.get(ino).cloned();
+    for (ino, f) in &durable.files {
+        if actual.INV_INODE_PATHS.get_all(*ino).is_none() {
+            continue;
+        }
+        if let (Some(e), Some(a)) = (f.size, actual.INV_INODE_CONTENTS.get(ino)) {
+            if e != a.size {
+                out.push(Difference {
+                    kind: DiffKind::Metadata,
+                    ino: *ino,
+                    path: path(ino),
+                    what: format!("synced size {} came back as {}", e, a.size),
+                });
+            }
+        }
+        if let (Some(e), Some(a)) = (&f.data, actual.INV_FILE_CONTENTS.get(ino)) {
+            if e != a {
+                out.push(Difference {
+                    kind: DiffKind::Data,
+                    ino: *ino,
+                    path: path(ino),
+                    what: format!(
+                        "synced contents ({} bytes) came back different ({} bytes)",
+                        e.len(),
+                        a.len()
+                    ),
+                });
+            }
+        }
+    }
+    for (ino, e) in &durable.dirs {
+        match actual.INV_DIR_CONTENTS.get(ino) {
+            Some(a) if a != e => out.push(Difference {
+                kind: DiffKind::DirEntries,
+                ino: *ino,
+                path: path(ino),
+                what: format!("synced entries {:?} came back as {:?}", e, a),
+            }),
+            None => out.push(Difference {
+                kind: DiffKind::MissingPath,
+                ino: *ino,
+                path: path(ino),
+                what: String::from("synced directory is gone"),
+            }),
+            _ => (),
+        }
+    }
+    out.sort_by_key(|d| (d.ino, d.kind));
+    out
+}
+
+/// The durability record kept alongside the snapshot at `snapshot`.
+pub fn record_path(snapshot: &Path) -> PathBuf {
+    let mut p = snapshot.as_os_str().to_owned();
+    p.push(".durable");
+    p.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, fs, path::PathBuf};
+
+    use maplit::btreemap;
+
+    use crate::{invariants::FSData, snapshot, test::tempdir};
+
+    use super::{sync_dir, sync_file, verify, Durable};
+
+    #[test]
+    fn test_verify() {
+        let path = tempdir().join("fs.snapshot.durable");
+        let mut fs_data = FSData::new();
+        fs_data.durable = Durable::new(path.clone());
+        fs_data.INV_INODE_PATHS.insert(1, PathBuf::from("/base"));
+        fs_data
+            .INV_INODE_PATHS
+            .insert(2, PathBuf::from("/base/foo"));
+        fs_data.INV_DIR_CONTENTS = btreemap! { 1 => btreemap! { OsString::from("foo") => 2 } };
+        fs_data.INV_FILE_CONTENTS = btreemap! { 2 => b"foo".to_vec() };
+        sync_file(0, &mut fs_data, 2);
+        sync_dir(0, &mut fs_data, 1);
+
+        let saved = snapshot::read_durable(&path).unwrap().unwrap();
+        assert_eq!(saved.files, fs_data.durable.files);
+        assert_eq!(saved.dirs, fs_data.durable.dirs);
+        assert!(verify(&saved, &fs_data).is_empty());
+
+        let mut crashed = FSData::new();
+        crashed.INV_INODE_PATHS.insert(1, PathBuf::from("/base"));
+        crashed
+            .INV_INODE_PATHS
+            .insert(2, PathBuf::from("/base/foo"));
+        crashed.INV_DIR_CONTENTS = fs_data.INV_DIR_CONTENTS.clone();
+        crashed.INV_FILE_CONTENTS = btreemap! { 2 => b"fo".to_vec() };
+        assert_eq!(verify(&saved, &crashed).len(), 1);
+
+        // Once the file changes again, its old contents are no longer promised.
+        fs_data.durable.forget(0, &[2]);
+        let saved = snapshot::read_durable(&path).unwrap().unwrap();
+        assert!(verify(&saved, &crashed).is_empty());
+    }
+
+    #[test]
+    fn test_append() {
+        let path = tempdir().join("fs.snapshot.durable");
+        let mut fs_data = FSData::new();
+        fs_data.durable = Durable::new(path.clone());
+        fs_data.INV_FILE_CONTENTS = btreemap! { 2 => vec![7; 16 << 10], 3 => b"bar".to_vec() };
+        sync_file(0, &mut fs_data, 2);
+        let len = fs::metadata(&path).unwrap().len();
+        assert!(len > 16 << 10);
+
+        // Syncing a small file only adds that file, not the big one again.
+        sync_file(0, &mut fs_data, 3);
+        let grown = fs::metadata(&path).unwrap().len() - len;
+        assert!(grown < 1 << 10, "{}", grown);
+        fs_data.durable.forget(0, &[2]);
+        let saved = snapshot::read_durable(&path).unwrap().unwrap();
+        assert_eq!(saved.files, fs_data.durable.files);
+
+        // A change cut short by a crash was never promised.
+        let buf = fs::read(&path).unwrap();
+        fs::write(&path, &buf[..buf.len() - 3]).unwrap();
+        let saved = snapshot::read_durable(&path).unwrap().unwrap();
+        assert!(saved.files.contains_key(&2));
+        assert!(saved.files.contains_key(&3));
+    }
+}
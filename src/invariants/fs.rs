@@ -1,15 +1,27 @@
+pub mod access;
+pub mod copy_file_range;
 pub mod create;
+pub mod fallocate;
 pub mod getattr;
+pub mod getlk;
+pub mod getxattr;
 pub mod init;
+pub mod ioctl;
 pub mod link;
+pub mod listxattr;
 pub mod lookup;
+pub mod lseek;
 pub mod mkdir;
 pub mod mknod;
+pub mod open;
 pub mod read;
+pub mod readdir;
+pub mod readdirplus;
 pub mod removexattr;
 pub mod rename;
 pub mod rmdir;
 pub mod setattr;
+pub mod setlk;
 pub mod setxattr;
 pub mod symlink;
 pub mod unlink;
@@ -0,0 +1,89 @@
+use std::{path::Path, sync::MutexGuard};
+
+use crate::{
+    inv_assert, inv_assert_eq, inv_fail,
+    invariants::{
+        common::{common_pre_ino, CPI},
+        inode_flags::immutable,
+        perm::{check_perm, Access},
+        violation::Violation,
+        FSData,
+    },
+    log_inv,
+    logging::CallID,
+    req_rep::Request,
+};
+
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct AccessInv {
+    exists: bool,
+    perm: Option<i32>,
+}
+
+pub fn inv_access_before(
+    callid: CallID,
+    req: &Request,
+    base: &Path,
+    ino: u64,
+    mask: i32,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> AccessInv {
+    let CPI { inode_path, exists } = common_pre_ino(callid, ino, fs_data);
+
+    let perm = check_perm(
+        callid,
+        req.uid(),
+        req.gid(),
+        req.pid(),
+        &inode_path,
+        base,
+        Access::Mask(mask as u32),
+    );
+    // An immutable inode refuses writing after a read-only filesystem, but ahead of the mode.
+    let immutable = (mask & libc::W_OK != 0)
+        .then(|| immutable(fs_data, ino))
+        .flatten();
+    let perm = match perm {
+        Some(libc::EROFS) => perm,
+        _ => immutable.or(perm),
+    };
+
+    AccessInv { exists, perm }
+}
+
+pub fn inv_access_after(
+    callid: CallID,
+    inv: AccessInv,
+    res: &Result<(), i32>,
+    _fs_data: &mut MutexGuard<'_, FSData>,
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
+    match res {
+        Ok(()) => {
+            inv_assert!(inv.exists, "Failed to return ENOENT on nonexistant inode");
+            inv_assert!(
+                inv.perm.is_none(),
+                "Failed to return error on permission denied"
+            );
+        }
+        Err(libc::EACCES) => inv_assert_eq!(
+            inv.perm,
+            Some(libc::EACCES),
+            "Returned EACCES on path where we have permission"
+        ),
+        Err(libc::EPERM) => inv_assert_eq!(
+            inv.perm,
+            Some(libc::EPERM),
+            "Returned EPERM on a mutable inode"
+        ),
+        Err(libc::EROFS) => inv_assert_eq!(
+            inv.perm,
+            Some(libc::EROFS),
+            "Returned EROFS on a writable filesystem"
+        ),
+        Err(libc::ENOENT) => inv_assert!(!inv.exists, "Returned ENOENT on extant inode"),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
+    }
+    Ok(())
+}
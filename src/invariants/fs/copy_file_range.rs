@@ -0,0 +1,218 @@
+use std::sync::MutexGuard;
+
+use crate::{
+    inv_assert, inv_assert_eq, inv_fail,
+    invariants::{
+        inode_flags::{self, FS_IMMUTABLE_FL},
+        violation::Violation,
+        FSData,
+    },
+    log_inv,
+    logging::CallID,
+    req_rep::Request,
+};
+
+#[derive(Debug)]
+#[must_use]
+pub struct CopyFileRangeInv {
+    ino_in: u64,
+    offset_in: u64,
+    ino_out: u64,
+    offset_out: u64,
+    len: u64,
+    /// The error the VFS returns before the filesystem gets to see the call.
+    vfs_error: Option<i32>,
+    /// The handles are on different filesystems.
+    cross_device: bool,
+}
+
+struct Handle {
+    flags: i32,
+    stat: libc::stat,
+}
+
+fn handle(fh: u64) -> Handle {
+    let fd = fh.try_into().unwrap();
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        assert!(flags != -1, "Failed to get flags of handle {}", fh);
+        let mut stat: libc::stat = std::mem::zeroed();
+        assert!(
+            libc::fstat(fd, &mut stat) == 0,
+            "Failed to stat handle {}",
+            fh
+        );
+        Handle { flags, stat }
+    }
+}
+
+/// The checks `vfs_copy_file_range` and `generic_copy_file_checks` make, in their order.
+/// `output_flags` are the destination inode's tracked flags.
+fn vfs_error(
+    input: &Handle,
+    offset_in: i64,
+    output: &Handle,
+    offset_out: i64,
+    len: u64,
+    flags: u32,
+    output_flags: u32,
+) -> Option<i32> {
+    if flags != 0 {
+        return Some(libc::EINVAL);
+    }
+    let is = |h: &Handle, fmt| h.stat.st_mode & libc::S_IFMT == fmt;
+    if is(input, libc::S_IFDIR) || is(output, libc::S_IFDIR) {
+        return Some(libc::EISDIR);
+    }
+    if !is(input, libc::S_IFREG) || !is(output, libc::S_IFREG) {
+        return Some(libc::EINVAL);
+    }
+    if input.flags & libc::O_ACCMODE == libc::O_WRONLY
+        || output.flags & libc::O_ACCMODE == libc::O_RDONLY
+        || output.flags & libc::O_APPEND != 0
+    {
+        return Some(libc::EBADF);
+    }
+    if output_flags & FS_IMMUTABLE_FL != 0 {
+        return Some(libc::EPERM);
+    }
+    if offset_in < 0 || offset_out < 0 {
+        return Some(libc::EINVAL);
+    }
+    let len = len.min(i64::MAX as u64) as i64;
+    if offset_in.checked_add(len).is_none() || offset_out.checked_add(len).is_none() {
+        return Some(libc::EOVERFLOW);
+    }
+    let same = input.stat.st_dev == output.stat.st_dev && input.stat.st_ino == output.stat.st_ino;
+    if same && offset_out + len > offset_in && offset_in + len > offset_out {
+        return Some(libc::EINVAL);
+    }
+    None
+}
+
+pub fn inv_copy_file_range_before(
+    _callid: CallID,
+    _req: &Request,
+    ino_in: u64,
+    fh_in: u64,
+    offset_in: i64,
+    ino_out: u64,
+    fh_out: u64,
+    offset_out: i64,
+    len: u64,
+    flags: u32,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> CopyFileRangeInv {
+    let input = handle(fh_in);
+    let output = handle(fh_out);
+    CopyFileRangeInv {
+        ino_in,
+        offset_in: offset_in.max(0) as u64,
+        ino_out,
+        offset_out: offset_out.max(0) as u64,
+        len,
+        vfs_error: vfs_error(
+            &input,
+            offset_in,
+            &output,
+            offset_out,
+            len,
+            flags,
+            inode_flags::flags(fs_data, ino_out),
+        ),
+        cross_device: input.stat.st_dev != output.stat.st_dev,
+    }
+}
+
+pub fn inv_copy_file_range_after(
+    callid: CallID,
+    inv: CopyFileRangeInv,
+    res: &Result<usize, i32>,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
+    if let Some(e) = inv.vfs_error {
+        inv_assert_eq!(
+            Err::<usize, i32>(e),
+            *res,
+            "Returned the wrong result for arguments the VFS rejects"
+        );
+        return Ok(());
+    }
+    match res {
+        Ok(n) => {
+            let n = *n as u64;
+            inv_assert!(
+                n <= inv.len,
+                "Copied {} bytes, more than the {} asked for",
+                n,
+                inv.len
+            );
+            if fs_data.checks.meta {
+                let size_in = fs_data
+                    .INV_INODE_CONTENTS
+                    .get(&inv.ino_in)
+                    .expect("Source missing inode")
+                    .size;
+                inv_assert!(
+                    inv.offset_in + n <= size_in.max(inv.offset_in),
+                    "Copied {} bytes from {}, past the end of the source at {}",
+                    n,
+                    inv.offset_in,
+                    size_in
+                );
+                if n > 0 {
+                    let fa = fs_data
+                        .INV_INODE_CONTENTS
+                        .get_mut(&inv.ino_out)
+                        .expect("Destination missing inode");
+                    fa.size = fa.size.max(inv.offset_out + n);
+                }
+            }
+            if fs_data.checks.data && n > 0 {
+                let (off_in, off_out, n) =
+                    (inv.offset_in as usize, inv.offset_out as usize, n as usize);
+                let copied = fs_data
+                    .INV_FILE_CONTENTS
+                    .get(&inv.ino_in)
+                    .expect("Source missing contents")[off_in..off_in + n]
+                    .to_vec();
+                let fd = fs_data
+                    .INV_FILE_CONTENTS
+                    .get_mut(&inv.ino_out)
+                    .expect("Destination missing contents");
+                if off_out + n > fd.len() {
+                    fd.resize(off_out + n, 0);
+                }
+                fd[off_out..off_out + n].copy_from_slice(&copied);
+                // Copying may share the source's blocks, holes included, so only what had to be
+                // data there has to be data here.
+                if let Some(src) = fs_data.INV_FILE_DATA.get(&inv.ino_in).cloned() {
+                    if let Some(dst) = fs_data.INV_FILE_DATA.get_mut(&inv.ino_out) {
+                        dst.remove(off_out as u64, (off_out + n) as u64);
+                        let mut at = off_in as u64;
+                        while let Some(start) =
+                            src.next_data(at).filter(|x| *x < (off_in + n) as u64)
+                        {
+                            let end = src.containing(start).unwrap().min((off_in + n) as u64);
+                            dst.insert(
+                                start - off_in as u64 + off_out as u64,
+                                end - off_in as u64 + off_out as u64,
+                            );
+                            at = end;
+                        }
+                    }
+                }
+            }
+        }
+        Err(libc::EXDEV) => inv_assert!(
+            inv.cross_device,
+            "Returned EXDEV for handles on the same filesystem"
+        ),
+        // Some filesystems can't copy at all.
+        Err(libc::EOPNOTSUPP) => (),
+        Err(libc::ENOSPC) | Err(libc::EFBIG) => (),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
+    }
+    Ok(())
+}
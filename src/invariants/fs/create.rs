@@ -1,19 +1,25 @@
 use std::{
+    collections::BTreeMap,
     ffi::OsString,
+    os::linux::fs::MetadataExt,
     path::{Path, PathBuf},
     sync::MutexGuard,
 };
 
-use asserteq_pretty::assert_eq_pretty;
-
 use crate::{
     file_attr::{FileAttr, FileType},
+    inv_assert, inv_assert_eq, inv_assert_eq_pretty, inv_fail,
     invariants::{
+        acl::inherit,
         common::{common_pre_parent_name, CPPN},
+        fs::open::{open_perm, truncate},
+        inode_flags::read_flags,
         perm::{check_perm, Access},
+        sparse::DataRanges,
+        violation::Violation,
         FSData,
     },
-    log_more,
+    log_inv, log_more,
     logging::CallID,
     req_rep::Request,
 };
@@ -30,33 +36,61 @@ pub struct CreateInv {
     toolong: bool,
     mode: u32,
     child_path: PathBuf,
+    new_xattrs: BTreeMap<OsString, Vec<u8>>,
+    child_exists: bool,
+    excl: bool,
+    /// An existing regular file the call opens is emptied.
+    trunc: bool,
+    /// The existing child is a symlink the call follows, to a file the checks don't know.
+    follows: bool,
 }
 
 pub fn inv_create_before(
-    _callid: CallID,
+    callid: CallID,
     req: &Request,
     base: &Path,
     parent: u64,
     name: &std::ffi::OsStr,
     mode: u32,
-    _umask: u32,
-    _flags: i32,
+    umask: u32,
+    flags: i32,
     fs_data: &mut MutexGuard<'_, FSData>,
 ) -> CreateInv {
     let CPPN {
         child_path,
         parent_exists,
+        child_exists,
         toolong,
         ..
     } = common_pre_parent_name(parent, name, fs_data);
 
-    let perm = check_perm(
-        req.uid(),
-        req.gid(),
-        req.pid(),
-        &child_path,
-        base,
-        Access::Create,
+    let excl = flags & libc::O_EXCL != 0;
+    let child = child_path.symlink_metadata().ok();
+    let kind = child.as_ref().map(|x| x.st_mode() & libc::S_IFMT);
+    // Without O_EXCL an existing file is opened instead, which a directory can't be.
+    let perm = match &child {
+        Some(_) if excl => Some(libc::EEXIST),
+        Some(_) if kind == Some(libc::S_IFDIR) => Some(libc::EISDIR),
+        Some(meta) => {
+            let iflags = read_flags(&child_path);
+            open_perm(callid, req, base, &child_path, meta, iflags, flags)
+        }
+        None => check_perm(
+            callid,
+            req.uid(),
+            req.gid(),
+            req.pid(),
+            &child_path,
+            base,
+            Access::Create,
+        ),
+    };
+
+    let (perm_bits, new_xattrs) = inherit(
+        child_path.parent().expect("Child has no parent"),
+        mode,
+        umask,
+        false,
     );
 
     CreateInv {
@@ -67,8 +101,13 @@ pub fn inv_create_before(
         parent_exists,
         toolong,
         perm,
-        mode,
+        mode: perm_bits,
         child_path,
+        new_xattrs,
+        child_exists,
+        excl,
+        trunc: !excl && kind == Some(libc::S_IFREG) && flags & libc::O_TRUNC != 0,
+        follows: !excl && kind == Some(libc::S_IFLNK),
     }
 }
 pub fn inv_create_after(
@@ -76,22 +115,38 @@ pub fn inv_create_after(
     inv: CreateInv,
     res: &Result<(fuser::FileAttr, i32), i32>,
     fs_data: &mut MutexGuard<'_, FSData>,
-) {
-    log_more!(callid, "invariant={:?}", inv);
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
+    if inv.follows {
+        log_more!(callid, "Opened through a symlink");
+        return Ok(());
+    }
     match res {
         Ok(v) => {
-            assert!(
+            inv_assert!(
+                !(inv.excl && inv.child_exists),
+                "Failed to return EEXIST on O_EXCL of an existing file"
+            );
+            inv_assert!(
                 !inv.toolong,
                 "Failed to return ENAMETOOLONG on name too long"
             );
-            assert!(
+            inv_assert!(
                 inv.perm.is_none(),
                 "Failed to return error on permission denied"
             );
-            assert!(
+            inv_assert!(
                 inv.parent_exists,
                 "Failed to return ENOENT on nonexistant parent"
             );
+            if inv.child_exists {
+                // The file was already there, and has only been opened
+                if inv.trunc {
+                    inv_assert_eq!(0, v.0.size, "O_TRUNC left data in the file");
+                    truncate(fs_data, v.0.ino, v.0.mtime, v.0.ctime);
+                }
+                return Ok(());
+            }
             let fa = FileAttr {
                 ino: v.0.ino,
                 size: 0,
@@ -109,26 +164,23 @@ pub fn inv_create_after(
                 blksize: 4096,
                 flags: 0,
             };
-            println!("\t{:?}\n\t{:?}", FileAttr::from(v.0), fa);
-            println!("{:o} : {:o}", FileAttr::from(v.0).perm, fa.perm);
-            assert_eq_pretty!(FileAttr::from(v.0), fa);
-            #[cfg(feature = "check-meta")]
-            {
+            log_more!(callid, "\t{:?}\n\t{:?}", FileAttr::from(v.0), fa);
+            log_more!(callid, "{:o} : {:o}", FileAttr::from(v.0).perm, fa.perm);
+            inv_assert_eq_pretty!(fa, FileAttr::from(v.0));
+            if fs_data.checks.meta {
                 let ic = &mut fs_data.INV_INODE_CONTENTS;
                 ic.insert(v.0.ino, fa);
             }
-            #[cfg(feature = "check-data")]
-            {
+            if fs_data.checks.data {
                 let fc = &mut fs_data.INV_FILE_CONTENTS;
                 fc.insert(v.0.ino, Vec::new());
+                fs_data.INV_FILE_DATA.insert(v.0.ino, DataRanges::new());
             }
-            #[cfg(feature = "check-xattr")]
-            {
+            if fs_data.checks.xattr {
                 let xc = &mut fs_data.INV_XATTR_CONTENTS;
-                xc.insert(v.0.ino, BTreeMap::new());
+                xc.insert(v.0.ino, inv.new_xattrs);
             }
-            #[cfg(feature = "check-dirs")]
-            {
+            if fs_data.checks.dirs {
                 let dc = &mut fs_data.INV_DIR_CONTENTS;
                 dc.get_mut(&inv.parent)
                     .expect("Parent does not exist")
@@ -136,18 +188,29 @@ pub fn inv_create_after(
             }
             fs_data.INV_INODE_PATHS.insert(v.0.ino, inv.child_path);
         }
-        Err(libc::ENAMETOOLONG) => assert!(inv.toolong, "Returned ENAMETOOLONG on valid name"),
-        Err(libc::EACCES) => assert_eq!(
+        Err(libc::ENAMETOOLONG) => inv_assert!(inv.toolong, "Returned ENAMETOOLONG on valid name"),
+        Err(libc::EACCES) => inv_assert_eq!(
             inv.perm,
             Some(libc::EACCES),
             "Returned EACCES on path where we have permission"
         ),
-        Err(libc::EPERM) => assert_eq!(
+        Err(libc::EPERM) => inv_assert_eq!(
             inv.perm,
             Some(libc::EPERM),
             "Returned EPERM on path where we have permission"
         ),
-        Err(libc::ENOENT) => assert!(!inv.parent_exists, "Returned ENOENT on extant parent"),
-        Err(e) => panic!("Got unexpected error code {}", e),
+        Err(libc::EEXIST) => inv_assert!(
+            inv.excl && inv.child_exists,
+            "Returned EEXIST without O_EXCL of an existing file"
+        ),
+        Err(e @ (libc::ELOOP | libc::ENOTDIR | libc::EISDIR | libc::EROFS)) => inv_assert_eq!(
+            inv.perm,
+            Some(*e),
+            "Returned {} on a file that may be opened this way",
+            e
+        ),
+        Err(libc::ENOENT) => inv_assert!(!inv.parent_exists, "Returned ENOENT on extant parent"),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
     }
+    Ok(())
 }
@@ -0,0 +1,309 @@
+use std::sync::MutexGuard;
+
+use libc::{
+    FALLOC_FL_COLLAPSE_RANGE, FALLOC_FL_INSERT_RANGE, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE,
+    FALLOC_FL_UNSHARE_RANGE, FALLOC_FL_ZERO_RANGE,
+};
+
+use crate::{
+    inv_assert, inv_assert_eq, inv_fail,
+    invariants::{
+        inode_flags::{self, FS_APPEND_FL, FS_IMMUTABLE_FL},
+        sparse::DataRanges,
+        violation::Violation,
+        FSData,
+    },
+    log_inv,
+    logging::CallID,
+    req_rep::Request,
+};
+
+const SUPPORTED: i32 = FALLOC_FL_KEEP_SIZE
+    | FALLOC_FL_PUNCH_HOLE
+    | FALLOC_FL_COLLAPSE_RANGE
+    | FALLOC_FL_ZERO_RANGE
+    | FALLOC_FL_INSERT_RANGE
+    | FALLOC_FL_UNSHARE_RANGE
+    | 0x04; // FALLOC_FL_NO_HIDE_STALE, which libc doesn't export
+
+#[derive(Debug)]
+#[must_use]
+pub struct FallocateInv {
+    ino: u64,
+    offset: u64,
+    length: u64,
+    mode: i32,
+    /// The error the VFS returns before the filesystem gets to see the call.
+    vfs_error: Option<i32>,
+    /// Misaligned or out of range for a collapse or insert, which the filesystem must refuse.
+    fs_invalid: bool,
+}
+
+/// The argument checks `vfs_fallocate` makes, in its order. `flags` are the inode's tracked flags.
+fn vfs_error(offset: i64, length: i64, mode: i32, writable: bool, flags: u32) -> Option<i32> {
+    if offset < 0 || length <= 0 {
+        return Some(libc::EINVAL);
+    }
+    if mode & !SUPPORTED != 0 {
+        return Some(libc::EOPNOTSUPP);
+    }
+    if mode & FALLOC_FL_PUNCH_HOLE != 0 && mode & FALLOC_FL_ZERO_RANGE != 0 {
+        return Some(libc::EOPNOTSUPP);
+    }
+    if mode & FALLOC_FL_PUNCH_HOLE != 0 && mode & FALLOC_FL_KEEP_SIZE == 0 {
+        return Some(libc::EOPNOTSUPP);
+    }
+    if mode & FALLOC_FL_COLLAPSE_RANGE != 0 && mode != FALLOC_FL_COLLAPSE_RANGE {
+        return Some(libc::EINVAL);
+    }
+    if mode & FALLOC_FL_INSERT_RANGE != 0 && mode != FALLOC_FL_INSERT_RANGE {
+        return Some(libc::EINVAL);
+    }
+    if mode & FALLOC_FL_UNSHARE_RANGE != 0
+        && mode & !(FALLOC_FL_UNSHARE_RANGE | FALLOC_FL_KEEP_SIZE) != 0
+    {
+        return Some(libc::EINVAL);
+    }
+    if !writable {
+        return Some(libc::EBADF);
+    }
+    // Append-only files can only be allocated, not changed.
+    if mode & !FALLOC_FL_KEEP_SIZE != 0 && flags & FS_APPEND_FL != 0 {
+        return Some(libc::EPERM);
+    }
+    if flags & FS_IMMUTABLE_FL != 0 {
+        return Some(libc::EPERM);
+    }
+    if offset.checked_add(length).is_none() {
+        return Some(libc::EFBIG);
+    }
+    None
+}
+
+pub fn inv_fallocate_before(
+    _callid: CallID,
+    _req: &Request,
+    ino: u64,
+    fh: u64,
+    offset: i64,
+    length: i64,
+    mode: i32,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> FallocateInv {
+    let fd = fh.try_into().unwrap();
+    let (writable, stat) = unsafe {
+        let fl = libc::fcntl(fd, libc::F_GETFL);
+        assert!(fl != -1, "Failed to get flags of handle {}", fh);
+        let mut stat: libc::stat = std::mem::zeroed();
+        assert!(
+            libc::fstat(fd, &mut stat) == 0,
+            "Failed to stat handle {}",
+            fh
+        );
+        (fl & libc::O_ACCMODE != libc::O_RDONLY, stat)
+    };
+    let size = match fs_data.INV_INODE_CONTENTS.get(&ino) {
+        Some(fa) => fa.size,
+        None => stat.st_size.try_into().unwrap(),
+    };
+    let blksize: u64 = stat.st_blksize.try_into().unwrap();
+
+    let flags = inode_flags::flags(fs_data, ino);
+    let vfs_error = vfs_error(offset, length, mode, writable, flags);
+    let (offset, length) = (offset.max(0) as u64, length.max(0) as u64);
+    let aligned = offset % blksize == 0 && length % blksize == 0;
+    let fs_invalid = if mode == FALLOC_FL_COLLAPSE_RANGE {
+        !aligned || offset + length >= size
+    } else if mode == FALLOC_FL_INSERT_RANGE {
+        !aligned || offset >= size
+    } else {
+        false
+    };
+
+    FallocateInv {
+        ino,
+        offset,
+        length,
+        mode,
+        vfs_error,
+        fs_invalid,
+    }
+}
+
+/// What a successful call does to the contents of the file.
+fn apply(inv: &FallocateInv, contents: &mut Vec<u8>) {
+    let (off, len) = (inv.offset as usize, inv.length as usize);
+    let end = off + len;
+    let keep_size = inv.mode & FALLOC_FL_KEEP_SIZE != 0;
+    if inv.mode & FALLOC_FL_COLLAPSE_RANGE != 0 {
+        contents.drain(off..end);
+    } else if inv.mode & FALLOC_FL_INSERT_RANGE != 0 {
+        contents.splice(off..off, std::iter::repeat(0).take(len));
+    } else if inv.mode & (FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE) != 0 {
+        if !keep_size && end > contents.len() {
+            contents.resize(end, 0);
+        }
+        let stop = end.min(contents.len());
+        if off < stop {
+            contents[off..stop].fill(0);
+        }
+    } else if !keep_size && end > contents.len() {
+        // Plain allocation and unsharing only ever grow the file, with zeros.
+        contents.resize(end, 0);
+    }
+}
+
+/// What a successful call does to the parts of the file that must be data. Allocating doesn't
+/// make anything data: the new blocks read as zeros, and can be reported as a hole.
+fn apply_ranges(inv: &FallocateInv, ranges: &mut DataRanges) {
+    let end = inv.offset + inv.length;
+    if inv.mode & FALLOC_FL_COLLAPSE_RANGE != 0 {
+        ranges.collapse(inv.offset, inv.length);
+    } else if inv.mode & FALLOC_FL_INSERT_RANGE != 0 {
+        ranges.expand(inv.offset, inv.length);
+    } else if inv.mode & (FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE) != 0 {
+        ranges.remove(inv.offset, end);
+    }
+}
+
+/// What a successful call does to the size of the file.
+fn new_size(inv: &FallocateInv, size: u64) -> u64 {
+    let end = inv.offset + inv.length;
+    if inv.mode & FALLOC_FL_COLLAPSE_RANGE != 0 {
+        size - inv.length
+    } else if inv.mode & FALLOC_FL_INSERT_RANGE != 0 {
+        size + inv.length
+    } else if inv.mode & FALLOC_FL_KEEP_SIZE == 0 {
+        size.max(end)
+    } else {
+        size
+    }
+}
+
+pub fn inv_fallocate_after(
+    callid: CallID,
+    inv: FallocateInv,
+    res: &Result<(), i32>,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
+    if let Some(e) = inv.vfs_error {
+        inv_assert_eq!(
+            Err::<(), i32>(e),
+            *res,
+            "Returned the wrong result for arguments the VFS rejects"
+        );
+        return Ok(());
+    }
+    match res {
+        Ok(()) => {
+            inv_assert!(
+                !inv.fs_invalid,
+                "Collapsed or inserted a misaligned or out-of-range block"
+            );
+            if fs_data.checks.meta {
+                let fa = fs_data
+                    .INV_INODE_CONTENTS
+                    .get_mut(&inv.ino)
+                    .expect("File missing inode");
+                fa.size = new_size(&inv, fa.size);
+            }
+            if fs_data.checks.data {
+                let fd = fs_data
+                    .INV_FILE_CONTENTS
+                    .get_mut(&inv.ino)
+                    .expect("File missing contents");
+                apply(&inv, fd);
+                if let Some(r) = fs_data.INV_FILE_DATA.get_mut(&inv.ino) {
+                    apply_ranges(&inv, r);
+                }
+            }
+        }
+        Err(libc::EINVAL) => inv_assert!(inv.fs_invalid, "Returned EINVAL on a valid range"),
+        // The filesystem may not support this mode, or fallocate at all.
+        Err(libc::EOPNOTSUPP) => (),
+        Err(libc::ENOSPC) | Err(libc::EFBIG) => (),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::{
+        FALLOC_FL_COLLAPSE_RANGE, FALLOC_FL_INSERT_RANGE, FALLOC_FL_KEEP_SIZE,
+        FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE,
+    };
+
+    use super::{apply, new_size, vfs_error, FallocateInv};
+    use crate::invariants::inode_flags::{FS_APPEND_FL, FS_IMMUTABLE_FL};
+
+    fn inv(offset: u64, length: u64, mode: i32) -> FallocateInv {
+        FallocateInv {
+            ino: 2,
+            offset,
+            length,
+            mode,
+            vfs_error: None,
+            fs_invalid: false,
+        }
+    }
+
+    #[test]
+    fn test_vfs_error() {
+        assert_eq!(vfs_error(0, 0, 0, true, 0), Some(libc::EINVAL));
+        assert_eq!(vfs_error(-1, 1, 0, true, 0), Some(libc::EINVAL));
+        assert_eq!(
+            vfs_error(0, 1, FALLOC_FL_PUNCH_HOLE, true, 0),
+            Some(libc::EOPNOTSUPP)
+        );
+        assert_eq!(
+            vfs_error(
+                0,
+                1,
+                FALLOC_FL_COLLAPSE_RANGE | FALLOC_FL_KEEP_SIZE,
+                true,
+                0
+            ),
+            Some(libc::EINVAL)
+        );
+        assert_eq!(vfs_error(0, 1, 0, false, 0), Some(libc::EBADF));
+        assert_eq!(vfs_error(i64::MAX, 1, 0, true, 0), Some(libc::EFBIG));
+        assert_eq!(
+            vfs_error(0, 1, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, true, 0),
+            None
+        );
+        assert_eq!(
+            vfs_error(0, 1, FALLOC_FL_KEEP_SIZE, true, FS_APPEND_FL),
+            None
+        );
+        assert_eq!(vfs_error(0, 1, 0, true, FS_APPEND_FL), None);
+        assert_eq!(
+            vfs_error(0, 1, FALLOC_FL_ZERO_RANGE, true, FS_APPEND_FL),
+            Some(libc::EPERM)
+        );
+        assert_eq!(
+            vfs_error(0, 1, FALLOC_FL_KEEP_SIZE, true, FS_IMMUTABLE_FL),
+            Some(libc::EPERM)
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let run = |i: FallocateInv| {
+            let mut c = b"abcdef".to_vec();
+            apply(&i, &mut c);
+            assert_eq!(new_size(&i, 6), c.len() as u64);
+            c
+        };
+        assert_eq!(run(inv(4, 4, 0)), b"abcdef\0\0");
+        assert_eq!(run(inv(4, 4, FALLOC_FL_KEEP_SIZE)), b"abcdef");
+        assert_eq!(
+            run(inv(1, 2, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE)),
+            b"a\0\0def"
+        );
+        assert_eq!(run(inv(4, 4, FALLOC_FL_ZERO_RANGE)), b"abcd\0\0\0\0");
+        assert_eq!(run(inv(1, 2, FALLOC_FL_COLLAPSE_RANGE)), b"adef");
+        assert_eq!(run(inv(1, 2, FALLOC_FL_INSERT_RANGE)), b"a\0\0bcdef");
+    }
+}
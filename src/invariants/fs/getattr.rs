@@ -1,25 +1,27 @@
 use std::{path::Path, sync::MutexGuard};
 
-use asserteq_pretty::{assert_eq_pretty, PrettyDiff};
+use asserteq_pretty::PrettyDiff;
 
 use crate::{
     file_attr::{self, FileAttr},
+    inv_assert, inv_assert_eq, inv_assert_eq_pretty, inv_fail,
     invariants::{
         common::{common_pre_ino, CPI},
         perm::{check_perm, Access},
+        violation::Violation,
         FSData,
     },
-    log_more,
+    log_inv,
     logging::CallID,
     req_rep::Request,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct GetattrArgs {
     ino: u64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[must_use]
 pub struct GetattrInv {
     exists: bool,
@@ -37,6 +39,7 @@ pub fn inv_getattr_before(
     let CPI { inode_path, exists } = common_pre_ino(callid, ino, fs_data);
 
     let perm = check_perm(
+        callid,
         req.uid(),
         req.gid(),
         req.pid(),
@@ -56,38 +59,40 @@ pub fn inv_getattr_after(
     inv: GetattrInv,
     res: &Result<fuser::FileAttr, i32>,
     fs_data: &mut MutexGuard<'_, FSData>,
-) {
-    log_more!(callid, "invariant={:?}", inv);
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
     match res {
         Ok(v) => {
-            assert!(
+            inv_assert!(
                 inv.perm.is_none(),
                 "Failed to return error on permission denied"
             );
-            assert!(inv.exists, "Failed to return ENOENT on nonexistant inode");
-            #[cfg(feature = "check-meta")]
-            assert_eq_pretty!(
-                fs_data
-                    .INV_INODE_CONTENTS
-                    .get(&inv.args.ino)
-                    .map(|x| x.reset_times()),
-                Some(FileAttr::from(v).reset_times()),
-                "Result did not match expected value"
-            );
+            inv_assert!(inv.exists, "Failed to return ENOENT on nonexistant inode");
+            if fs_data.checks.meta {
+                inv_assert_eq_pretty!(
+                    fs_data
+                        .INV_INODE_CONTENTS
+                        .get(&inv.args.ino)
+                        .map(|x| x.reset_times()),
+                    Some(FileAttr::from(v).reset_times()),
+                    "Result did not match expected value"
+                );
+            }
         }
-        Err(libc::EACCES) => assert_eq!(
+        Err(libc::EACCES) => inv_assert_eq!(
             inv.perm,
             Some(libc::EACCES),
             "Returned EACCES on path where we have permission"
         ),
-        Err(libc::EPERM) => assert_eq!(
+        Err(libc::EPERM) => inv_assert_eq!(
             inv.perm,
             Some(libc::EPERM),
             "Returned EPERM on path where we have permission"
         ),
-        Err(libc::ENOENT) => assert!(!inv.exists, "Returned ENOENT on extant path"),
-        Err(e) => panic!("Got unexpected error code {}", e),
+        Err(libc::ENOENT) => inv_assert!(!inv.exists, "Returned ENOENT on extant path"),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
     }
+    Ok(())
 }
 
 // TODO: we need a blanket &T implementation in asserteq_pretty
@@ -0,0 +1,65 @@
+use std::sync::MutexGuard;
+
+use crate::{
+    inv_assert, inv_fail,
+    invariants::{posix_locks::PosixLock, violation::Violation, FSData},
+    log_inv,
+    logging::CallID,
+    req_rep::Request,
+};
+
+#[derive(Debug)]
+#[must_use]
+pub struct GetlkInv {
+    /// The locks other owners hold that would stand in the way.
+    conflicts: Vec<PosixLock>,
+}
+
+pub fn inv_getlk_before(
+    _callid: CallID,
+    _req: &Request,
+    ino: u64,
+    _fh: u64,
+    lock_owner: u64,
+    start: u64,
+    end: u64,
+    typ: i32,
+    _pid: u32,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> GetlkInv {
+    GetlkInv {
+        conflicts: fs_data
+            .posix_locks
+            .conflicts(ino, lock_owner, start, end, typ),
+    }
+}
+
+/// `res` is the conflicting lock's range and type, if there is one. Its owner is not checked: OFD
+/// locks don't report one.
+pub fn inv_getlk_after(
+    callid: CallID,
+    inv: GetlkInv,
+    res: &Result<Option<(u64, u64, i32)>, i32>,
+    _fs_data: &mut MutexGuard<'_, FSData>,
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
+    match res {
+        Ok(None) => inv_assert!(
+            inv.conflicts.is_empty(),
+            "Reported no conflict despite {:?}",
+            inv.conflicts
+        ),
+        Ok(Some((start, end, typ))) => inv_assert!(
+            inv.conflicts
+                .iter()
+                .any(|l| (l.start, l.end, l.typ) == (*start, *end, *typ)),
+            "Reported a conflicting lock on {}..={} of type {}, but the conflicts are {:?}",
+            start,
+            end,
+            typ,
+            inv.conflicts
+        ),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::{path::Path, sync::MutexGuard};
+
+use crate::{
+    inv_assert, inv_assert_eq, inv_fail,
+    invariants::{
+        common::{common_pre_ino, CPI},
+        perm::{check_perm, xattr_unchecked, Access},
+        violation::Violation,
+        FSData,
+    },
+    log_inv,
+    logging::CallID,
+    req_rep::{Request, Xattr},
+};
+
+#[derive(Debug)]
+#[must_use]
+pub struct GetxattrInv {
+    size: u32,
+    exists: bool,
+    value: Option<Vec<u8>>,
+    perm: Option<i32>,
+    unchecked: bool,
+}
+
+pub fn inv_getxattr_before(
+    callid: CallID,
+    req: &Request,
+    base: &Path,
+    ino: u64,
+    name: &std::ffi::OsStr,
+    size: u32,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> GetxattrInv {
+    let CPI { inode_path, exists } = common_pre_ino(callid, ino, fs_data);
+
+    let perm = check_perm(
+        callid,
+        req.uid(),
+        req.gid(),
+        req.pid(),
+        &inode_path,
+        base,
+        Access::XattrRead(name.to_os_string()),
+    );
+
+    let value = fs_data
+        .INV_XATTR_CONTENTS
+        .get(&ino)
+        .and_then(|x| x.get(name))
+        .cloned();
+
+    GetxattrInv {
+        size,
+        exists,
+        value,
+        perm,
+        unchecked: xattr_unchecked(name),
+    }
+}
+pub fn inv_getxattr_after(
+    callid: CallID,
+    inv: GetxattrInv,
+    res: &Result<Xattr, i32>,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
+    // security. attributes are up to the filesystem and LSMs.
+    match res {
+        Ok(_) => inv_assert!(
+            inv.perm.is_none(),
+            "Failed to return error on permission denied"
+        ),
+        Err(libc::EACCES) => inv_assert!(
+            inv.unchecked || inv.perm == Some(libc::EACCES),
+            "Returned EACCES on attribute where we have permission"
+        ),
+        Err(libc::EPERM) => inv_assert!(
+            inv.unchecked || inv.perm == Some(libc::EPERM),
+            "Returned EPERM on attribute where we have permission"
+        ),
+        // Attributes we may not read look missing.
+        Err(libc::ENODATA) if inv.perm == Some(libc::ENODATA) => return Ok(()),
+        Err(_) => {}
+    }
+    if !fs_data.checks.xattr {
+        return Ok(());
+    }
+    match res {
+        Ok(v) => {
+            inv_assert!(inv.exists, "Failed to return ENOENT on nonexistant inode");
+            let value = match &inv.value {
+                Some(v) => v,
+                None => inv_fail!("Failed to return ENODATA on a missing attribute"),
+            };
+            match v {
+                Xattr::Size(n) => {
+                    inv_assert!(inv.size == 0, "Returned a size for a sized request");
+                    inv_assert_eq!(value.len(), *n as usize, "Returned the wrong size");
+                }
+                Xattr::Data(d) => {
+                    inv_assert!(inv.size != 0, "Returned data for a size request");
+                    inv_assert_eq!(value, d, "Returned the wrong value");
+                }
+            }
+        }
+        Err(libc::ENODATA) => inv_assert!(
+            inv.value.is_none() && inv.perm.is_none(),
+            "Returned ENODATA on an existing attribute"
+        ),
+        Err(libc::ERANGE) => inv_assert!(
+            inv.value
+                .map_or(false, |v| inv.size != 0 && (inv.size as usize) < v.len()),
+            "Returned ERANGE on a large enough buffer"
+        ),
+        Err(libc::EOPNOTSUPP) => inv_assert!(
+            inv.value.is_none(),
+            "Returned EOPNOTSUPP on an existing attribute"
+        ),
+        Err(libc::EACCES) | Err(libc::EPERM) => {}
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
+    }
+    Ok(())
+}
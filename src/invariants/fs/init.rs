@@ -1,16 +1,34 @@
-use std::{os::unix::prelude::MetadataExt, path::PathBuf, sync::MutexGuard};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    fmt::Display,
+    fs::Metadata,
+    os::unix::prelude::MetadataExt,
+    path::{Path, PathBuf},
+    sync::MutexGuard,
+};
 
 use crate::{
     file_attr::FileAttr,
     fs::InvFS,
-    invariants::FSData,
-    load_prev_contents,
+    inv_assert, inv_fail,
+    invariants::{
+        diff::{diff, Difference},
+        durable::{self, Durable},
+        inode_flags,
+        sparse::DataRanges,
+        violation::Violation,
+        Checks, FSData,
+    },
+    log_more,
     logging::CallID,
     req_rep::{KernelConfig, Request},
+    snapshot::{self, SnapshotError},
 };
 
 pub struct InitInv {
     root: PathBuf,
+    snapshot: PathBuf,
 }
 
 pub fn inv_init_before(
@@ -21,101 +39,373 @@ pub fn inv_init_before(
 ) -> InitInv {
     InitInv {
         root: fs.root.clone(),
+        snapshot: fs.snapshot.clone(),
     }
 }
+/// Every violation found on mount. The durability record and the snapshot are checked
+/// independently, so one finding doesn't hide the other.
 pub fn inv_init_after(
-    _callid: CallID,
+    callid: CallID,
     inv: InitInv,
     _res: &Result<(), i32>,
     fs_data: &mut MutexGuard<'_, FSData>,
-) {
-    #[cfg(any(
-        feature = "check-meta",
-        feature = "check-dirs",
-        feature = "check-data",
-        feature = "check-xattr"
-    ))]
-    if load_prev_contents(fs_data) {
-        println!("Loaded previous filesystem contents");
-    } else {
-        println!("No previous filesystem contents, scanning...");
-        let ip = &mut fs_data.INV_INODE_PATHS;
-        ip.insert(1, inv.root.clone());
-        for e in walkdir::WalkDir::new(inv.root.clone()) {
-            let e = e.expect("Encountered error while scanning filesystem");
-            let m = e
-                .metadata()
-                .unwrap_or_else(|_| panic!("Failed to get metadata for {:?}", e.path()));
-            let ino = if e.path() == inv.root { 1 } else { m.ino() };
-            fs_data.INV_INODE_PATHS.insert(ino, e.path().to_path_buf());
-            #[cfg(feature = "check-dirs")]
-            if m.is_dir() {
-                let dc = e
-                    .path()
-                    .read_dir()
-                    .unwrap()
-                    .map(|x| x.unwrap())
-                    .map(|x| (x.file_name(), x.metadata().unwrap().ino()))
-                    .collect();
-                match fs_data.INV_DIR_CONTENTS.entry(ino) {
-                    std::collections::btree_map::Entry::Vacant(v) => {
-                        v.insert(dc);
-                    }
-                    std::collections::btree_map::Entry::Occupied(o) => {
-                        assert!(
-                            o.get() == &dc,
-                            "Same directory returned different contents at different paths"
-                        );
-                    }
-                }
+) -> Vec<Violation> {
+    if !fs_data.checks.any() {
+        return vec![];
+    }
+    let checks = fs_data.checks;
+    let prev = snapshot::take(&inv.snapshot, checks);
+    let record = durable::record_path(&inv.snapshot);
+    let promised = snapshot::read_durable(&record);
+    // Whatever the snapshot says, the model starts from what is actually there.
+    if let Err(e) = scan_backend(&inv.root, checks, fs_data) {
+        return vec![e.into()];
+    }
+    // The record belongs to the previous mount; this one starts its own.
+    if !matches!(promised, Ok(None)) {
+        if let Err(e) = std::fs::remove_file(&record) {
+            log_more!(callid, "Failed to remove {:?}: {}", record, e);
+        }
+    }
+    fs_data.durable = Durable::new(record.clone());
+    let lost = check_durable(callid, promised, &record, fs_data);
+    let changed = check_snapshot(callid, prev, &inv.snapshot, fs_data);
+    lost.err().into_iter().chain(changed.err()).collect()
+}
+
+fn check_durable(
+    callid: CallID,
+    promised: Result<Option<Durable>, SnapshotError>,
+    record: &Path,
+    fs_data: &FSData,
+) -> Result<(), Violation> {
+    match promised {
+        Ok(Some(promised)) => {
+            log_more!(callid, "Found a durability record, verifying...");
+            let lost = durable::verify(&promised, fs_data);
+            for d in &lost {
+                log_more!(callid, "{}", d);
             }
-            #[cfg(feature = "check-meta")]
-            {
-                match fs_data.INV_INODE_CONTENTS.entry(ino) {
-                    std::collections::btree_map::Entry::Vacant(v) => {
-                        v.insert(FileAttr::from(&m).set_ino(ino));
-                    }
-                    std::collections::btree_map::Entry::Occupied(o) => {
-                        assert!(
-                            o.get() == &FileAttr::from(&m).set_ino(ino),
-                            "Same inode returned different metadata at different paths"
-                        );
-                    }
-                }
+            inv_assert!(
+                lost.is_empty(),
+                "Synced state did not survive: {}",
+                summarize(&lost)
+            );
+        }
+        Ok(None) => (),
+        Err(e) => inv_fail!("Failed to load durability record {:?}: {}", record, e),
+    }
+    Ok(())
+}
+
+fn check_snapshot(
+    callid: CallID,
+    prev: Result<Option<FSData>, SnapshotError>,
+    path: &Path,
+    fs_data: &FSData,
+) -> Result<(), Violation> {
+    match prev {
+        Ok(Some(prev)) => {
+            log_more!(callid, "Loaded previous filesystem contents, verifying...");
+            let diffs = diff(&prev, fs_data);
+            for d in &diffs {
+                log_more!(callid, "{}", d);
             }
-            #[cfg(feature = "check-data")]
-            if m.is_file() {
-                let data = std::fs::read(e.path()).expect("Failed to read file");
-                match fs_data.INV_FILE_CONTENTS.entry(ino) {
-                    std::collections::btree_map::Entry::Vacant(v) => {
-                        v.insert(data);
-                    }
-                    std::collections::btree_map::Entry::Occupied(o) => {
-                        assert!(
-                            o.get() == &data,
-                            "Same inode returned different content at different paths"
-                        );
-                    }
+            inv_assert!(
+                diffs.is_empty(),
+                "Backing directory changed while unmounted: {}",
+                summarize(&diffs)
+            );
+        }
+        Ok(None) => log_more!(
+            callid,
+            "No previous filesystem contents, scanned the backend"
+        ),
+        Err(e) => inv_fail!("Failed to load snapshot {:?}: {}", path, e),
+    }
+    Ok(())
+}
+
+fn summarize(diffs: &[Difference]) -> String {
+    let mut s = diffs
+        .iter()
+        .take(10)
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join("; ");
+    if diffs.len() > 10 {
+        s += &format!("; and {} more", diffs.len() - 10);
+    }
+    s
+}
+
+/// Why the backing directory couldn't be scanned.
+#[derive(Debug)]
+pub enum ScanError {
+    /// An entry couldn't be read.
+    Io(PathBuf, std::io::Error),
+    /// The backing directory is inconsistent with itself, e.g. two links to one inode disagree.
+    Inconsistent(Violation),
+}
+
+impl Display for ScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScanError::Io(p, e) => write!(f, "failed to read {:?}: {}", p, e),
+            ScanError::Inconsistent(v) => write!(f, "{}", v.message),
+        }
+    }
+}
+
+impl From<ScanError> for Violation {
+    fn from(e: ScanError) -> Self {
+        match e {
+            ScanError::Io(..) => Violation::new(format!("Failed to scan the backend: {}", e)),
+            ScanError::Inconsistent(v) => v,
+        }
+    }
+}
+
+/// Walk the backing directory and fill in the model for the given check categories.
+pub fn scan_backend(root: &Path, checks: Checks, fs_data: &mut FSData) -> Result<(), ScanError> {
+    fs_data.INV_INODE_PATHS.insert(1, root.to_path_buf());
+    for e in walkdir::WalkDir::new(root) {
+        let e = e.map_err(|e| {
+            let path = e.path().unwrap_or(root).to_path_buf();
+            ScanError::Io(path, e.into())
+        })?;
+        let path = e.path();
+        let io = |e| ScanError::Io(path.to_path_buf(), e);
+        let m = e.metadata().map_err(|e| io(e.into()))?;
+        let ino = if path == root { 1 } else { m.ino() };
+        let listing = match checks.dirs && m.is_dir() {
+            true => Some(read_listing(path).map_err(io)?),
+            false => None,
+        };
+        let data = match checks.data && m.is_file() {
+            true => Some(std::fs::read(path).map_err(io)?),
+            false => None,
+        };
+        let xattrs = match checks.xattr {
+            true => Some(read_xattrs(path).map_err(io)?),
+            false => None,
+        };
+        add_entry(fs_data, path, ino, &m, checks.meta, listing, data, xattrs)
+            .map_err(ScanError::Inconsistent)?;
+    }
+    Ok(())
+}
+
+/// Add what was read of the entry at `path` to the model. An inode seen before, through another
+/// link, must look the same.
+fn add_entry(
+    fs_data: &mut FSData,
+    path: &Path,
+    ino: u64,
+    m: &Metadata,
+    meta: bool,
+    listing: Option<BTreeMap<OsString, u64>>,
+    data: Option<Vec<u8>>,
+    xattrs: Option<BTreeMap<OsString, Vec<u8>>>,
+) -> Result<(), Violation> {
+    fs_data.INV_INODE_PATHS.insert(ino, path.to_path_buf());
+    if let Some(dc) = listing {
+        match fs_data.INV_DIR_CONTENTS.entry(ino) {
+            std::collections::btree_map::Entry::Vacant(v) => {
+                v.insert(dc);
+            }
+            std::collections::btree_map::Entry::Occupied(o) => {
+                inv_assert!(
+                    o.get() == &dc,
+                    "Same directory returned different contents at different paths"
+                );
+            }
+        }
+    }
+    if meta {
+        match fs_data.INV_INODE_CONTENTS.entry(ino) {
+            std::collections::btree_map::Entry::Vacant(v) => {
+                v.insert(FileAttr::from(m).set_ino(ino));
+                if m.is_file() || m.is_dir() {
+                    let flags = inode_flags::read_flags(path);
+                    inode_flags::set_flags(fs_data, ino, flags);
                 }
             }
-            #[cfg(feature = "check-xattr")]
-            {
-                let xa = xattr::list(e.path()).unwrap();
-                let xa = xa
-                    .map(|x| (x.clone(), xattr::get(e.path(), x).unwrap().unwrap()))
-                    .collect();
-                match fs_data.INV_XATTR_CONTENTS.entry(ino) {
-                    std::collections::btree_map::Entry::Vacant(v) => {
-                        v.insert(xa);
-                    }
-                    std::collections::btree_map::Entry::Occupied(o) => {
-                        assert!(
-                            o.get() == &xa,
-                            "Same inode returned different xattrs at different paths"
-                        );
+            std::collections::btree_map::Entry::Occupied(o) => {
+                inv_assert!(
+                    o.get() == &FileAttr::from(m).set_ino(ino),
+                    "Same inode returned different metadata at different paths"
+                );
+            }
+        }
+    }
+    if let Some(data) = data {
+        fs_data
+            .INV_FILE_DATA
+            .insert(ino, DataRanges::from_contents(&data));
+        match fs_data.INV_FILE_CONTENTS.entry(ino) {
+            std::collections::btree_map::Entry::Vacant(v) => {
+                v.insert(data);
+            }
+            std::collections::btree_map::Entry::Occupied(o) => {
+                inv_assert!(
+                    o.get() == &data,
+                    "Same inode returned different content at different paths"
+                );
+            }
+        }
+    }
+    if let Some(xa) = xattrs {
+        match fs_data.INV_XATTR_CONTENTS.entry(ino) {
+            std::collections::btree_map::Entry::Vacant(v) => {
+                v.insert(xa);
+            }
+            std::collections::btree_map::Entry::Occupied(o) => {
+                inv_assert!(
+                    o.get() == &xa,
+                    "Same inode returned different xattrs at different paths"
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The entries of the directory at `path`, by name.
+fn read_listing(path: &Path) -> std::io::Result<BTreeMap<OsString, u64>> {
+    path.read_dir()?
+        .map(|x| {
+            let x = x?;
+            Ok((x.file_name(), x.metadata()?.ino()))
+        })
+        .collect()
+}
+
+fn read_xattrs(path: &Path) -> std::io::Result<BTreeMap<OsString, Vec<u8>>> {
+    xattr::list(path)?
+        .map(|x| {
+            let v = xattr::get(path, &x)?.unwrap_or_default();
+            Ok((x, v))
+        })
+        .collect()
+}
+
+/// Whether `path` still leads to `ino`.
+fn leads_to(path: &Path, ino: u64) -> bool {
+    ino == 1 || path.symlink_metadata().map_or(false, |m| m.ino() == ino)
+}
+
+/// Replace the model of `ino` with what the backend holds at `path`.
+fn load_inode(path: &Path, ino: u64, checks: Checks, fs_data: &mut FSData) {
+    let m = match path.symlink_metadata() {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+    if checks.meta {
+        fs_data
+            .INV_INODE_CONTENTS
+            .insert(ino, FileAttr::from(&m).set_ino(ino));
+        if m.is_file() || m.is_dir() {
+            inode_flags::set_flags(fs_data, ino, inode_flags::read_flags(path));
+        }
+    }
+    if checks.dirs && m.is_dir() {
+        if let Ok(dc) = read_listing(path) {
+            fs_data.INV_DIR_CONTENTS.insert(ino, dc);
+        }
+    }
+    if checks.data && m.is_file() {
+        if let Ok(data) = std::fs::read(path) {
+            fs_data
+                .INV_FILE_DATA
+                .insert(ino, DataRanges::from_contents(&data));
+            fs_data.INV_FILE_CONTENTS.insert(ino, data);
+        }
+    }
+    if checks.xattr {
+        if let Ok(xa) = read_xattrs(path) {
+            fs_data.INV_XATTR_CONTENTS.insert(ino, xa);
+        }
+    }
+}
+
+/// Bring the model of `inos` back in line with the backend after a violation, so later calls
+/// aren't checked against a change the failed check never applied. The entries of directories
+/// among them are resynced too: new ones are scanned, and those gone lose their paths.
+pub fn resync(callid: CallID, fs_data: &mut FSData, inos: &[u64]) {
+    let checks = fs_data.checks;
+    let mut touched: BTreeSet<u64> = inos.iter().copied().collect();
+    let mut listings = vec![];
+    for ino in inos {
+        let path = match fs_data.INV_INODE_PATHS.get_all(*ino) {
+            Some(v) => v.iter().find(|x| leads_to(x, *ino)).cloned(),
+            None => None,
+        };
+        if let Some(path) = path {
+            if let Ok(listing) = read_listing(&path) {
+                listings.push((path, listing));
+            }
+        }
+    }
+    // Entries that went away first, so an inode that moved between the directories can take
+    // its descendants along to where it is now.
+    let mut gone = BTreeMap::new();
+    for (ino, paths) in fs_data.INV_INODE_PATHS.store() {
+        for path in paths {
+            let listed = listings.iter().any(|(dir, listing)| {
+                Some(dir.as_path()) == path.parent()
+                    && path.file_name().and_then(|x| listing.get(x)) == Some(&ino)
+            });
+            let in_dirs = listings
+                .iter()
+                .any(|(dir, _)| Some(dir.as_path()) == path.parent());
+            if in_dirs && !listed {
+                fs_data.INV_INODE_PATHS.unmap(ino, &path);
+                gone.insert(ino, path);
+                touched.insert(ino);
+            }
+        }
+    }
+    for (dir, listing) in &listings {
+        for (name, ino) in listing {
+            let path = dir.join(name);
+            let known = fs_data.INV_INODE_PATHS.get_all(*ino).cloned();
+            match (known, gone.get(ino)) {
+                (Some(v), _) if v.contains(&path) => {}
+                (None, None) => {
+                    for e in walkdir::WalkDir::new(&path).into_iter().flatten() {
+                        let ino = match e.metadata() {
+                            Ok(m) => m.ino(),
+                            Err(_) => continue,
+                        };
+                        fs_data.INV_INODE_PATHS.insert(ino, e.path().to_path_buf());
+                        load_inode(e.path(), ino, checks, fs_data);
                     }
                 }
+                (_, Some(old)) => {
+                    fs_data
+                        .INV_INODE_PATHS
+                        .rename(callid, old.clone(), path.clone());
+                    fs_data.INV_INODE_PATHS.insert(*ino, path);
+                    touched.insert(*ino);
+                }
+                (Some(_), None) => {
+                    fs_data.INV_INODE_PATHS.insert(*ino, path);
+                    touched.insert(*ino);
+                }
             }
         }
     }
+    for ino in touched {
+        log_more!(callid, "Resyncing ino {} from the backend", ino);
+        let path = fs_data
+            .INV_INODE_PATHS
+            .get_all(ino)
+            .and_then(|x| x.iter().find(|x| leads_to(x, ino)).cloned());
+        match path {
+            Some(path) => load_inode(&path, ino, checks, fs_data),
+            // Nothing left to reload it from, but it may still be open.
+            None => fs_data.orphan(ino),
+        }
+    }
 }
@@ -0,0 +1,127 @@
+use std::sync::MutexGuard;
+
+use crate::{
+    inv_assert, inv_assert_eq, inv_fail,
+    invariants::{
+        inode_flags::{
+            self, FS_APPEND_FL, FS_IMMUTABLE_FL, FS_IOC_FSGETXATTR, FS_XFLAG_APPEND,
+            FS_XFLAG_IMMUTABLE,
+        },
+        perm::{caps, CAP_FOWNER},
+        violation::Violation,
+        FSData,
+    },
+    log_inv,
+    logging::CallID,
+    req_rep::Request,
+};
+
+#[derive(Debug)]
+#[must_use]
+pub struct IoctlInv {
+    ino: u64,
+    cmd: u32,
+    /// The tracked flags before the call.
+    flags: u32,
+    /// The flags `FS_IOC_SETFLAGS` asks for.
+    new_flags: Option<u32>,
+    perm: Option<i32>,
+}
+
+pub fn inv_ioctl_before(
+    _callid: CallID,
+    req: &Request,
+    ino: u64,
+    _fh: u64,
+    _flags: u32,
+    cmd: u32,
+    in_data: &[u8],
+    _out_size: u32,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> IoctlInv {
+    let new_flags = (cmd == libc::FS_IOC_SETFLAGS as u32)
+        .then(|| {
+            in_data
+                .get(..4)
+                .map(|x| u32::from_ne_bytes(x.try_into().unwrap()))
+        })
+        .flatten();
+    // Only the owner or CAP_FOWNER may set flags. Changing immutable or append-only also needs
+    // CAP_LINUX_IMMUTABLE, but the kernel checks that against the caller before sending the
+    // request on.
+    let owner = fs_data.INV_INODE_CONTENTS.get(&ino).map(|x| x.uid);
+    let fowner = caps(req.pid(), req.uid()).has(CAP_FOWNER);
+    let perm = match owner {
+        Some(uid) if new_flags.is_some() && !fowner && req.uid() != uid => Some(libc::EPERM),
+        _ => None,
+    };
+    IoctlInv {
+        ino,
+        cmd,
+        flags: inode_flags::flags(fs_data, ino),
+        new_flags,
+        perm,
+    }
+}
+
+pub fn inv_ioctl_after(
+    callid: CallID,
+    inv: IoctlInv,
+    res: &Result<Vec<u8>, i32>,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
+    let word = |x: &[u8]| {
+        x.get(..4)
+            .map(|x| u32::from_ne_bytes(x.try_into().unwrap()))
+    };
+    match res {
+        Ok(v) if inv.cmd == libc::FS_IOC_GETFLAGS as u32 => {
+            if fs_data.checks.meta {
+                inv_assert_eq!(
+                    word(v).map(|x| x & inode_flags::TRACKED),
+                    Some(inv.flags),
+                    "Reported inode flags that don't match the model"
+                );
+            }
+        }
+        Ok(v) if inv.cmd == FS_IOC_FSGETXATTR => {
+            let mut expected = 0;
+            if inv.flags & FS_IMMUTABLE_FL != 0 {
+                expected |= FS_XFLAG_IMMUTABLE;
+            }
+            if inv.flags & FS_APPEND_FL != 0 {
+                expected |= FS_XFLAG_APPEND;
+            }
+            if fs_data.checks.meta {
+                inv_assert_eq!(
+                    word(v).map(|x| x & (FS_XFLAG_IMMUTABLE | FS_XFLAG_APPEND)),
+                    Some(expected),
+                    "Reported extended flags that don't match the model"
+                );
+            }
+        }
+        Ok(_) => {
+            inv_assert!(
+                inv.perm.is_none(),
+                "Failed to return error on permission denied"
+            );
+            if let Some(v) = inv.new_flags {
+                inode_flags::set_flags(fs_data, inv.ino, v);
+            }
+        }
+        Err(libc::EPERM) => inv_assert_eq!(
+            inv.perm,
+            Some(libc::EPERM),
+            "Returned EPERM on inode we own"
+        ),
+        // Not every filesystem has flags, or supports every one of them.
+        Err(libc::ENOTTY) | Err(libc::EOPNOTSUPP) => (),
+        Err(libc::EINVAL) => inv_assert!(
+            inv.cmd == libc::FS_IOC_SETFLAGS as u32,
+            "Returned EINVAL reading flags"
+        ),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
+    }
+    Ok(())
+}
@@ -4,16 +4,16 @@ use std::{
     sync::MutexGuard,
 };
 
-use asserteq_pretty::assert_eq_pretty;
-
 use crate::{
     file_attr::FileAttr,
+    inv_assert, inv_assert_eq_pretty, inv_fail,
     invariants::{
         common::{common_pre_ino, common_pre_parent_name, CPI, CPPN},
         perm::{check_perm, Access},
+        violation::Violation,
         FSData,
     },
-    log_more,
+    log_inv,
     logging::CallID,
     req_rep::Request,
 };
@@ -52,6 +52,7 @@ pub fn inv_link_before(
     } = common_pre_parent_name(newparent, newname, fs_data);
 
     let old_perm = check_perm(
+        callid,
         req.uid(),
         req.gid(),
         req.pid(),
@@ -60,6 +61,7 @@ pub fn inv_link_before(
         Access::Lookup,
     );
     let new_perm = check_perm(
+        callid,
         req.uid(),
         req.gid(),
         req.pid(),
@@ -84,37 +86,35 @@ pub fn inv_link_after(
     inv: LinkInv,
     res: &Result<fuser::FileAttr, i32>,
     fs_data: &mut MutexGuard<'_, FSData>,
-) {
-    log_more!(callid, "invariant={:?}", inv);
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
     match res {
         Ok(v) => {
-            assert!(
+            inv_assert!(
                 !inv.toolong,
                 "Failed to return ENAMETOOLONG on name too long"
             );
-            assert!(
+            inv_assert!(
                 inv.old_perm.is_none() && inv.new_perm.is_none(),
                 "Failed to return error on permission denied"
             );
-            assert!(
+            inv_assert!(
                 inv.old_exists,
                 "Failed to return ENOENT on nonexistant source"
             );
-            assert!(
+            inv_assert!(
                 !inv.new_exists,
                 "Failed to return EEXIST on existant target"
             );
-            #[cfg(feature = "check-meta")]
-            {
+            if fs_data.checks.meta {
                 let ic = &mut fs_data.INV_INODE_CONTENTS;
                 let fa = ic.get_mut(&v.ino).expect("Inode does not exist");
                 fa.mtime = v.mtime;
                 fa.ctime = v.ctime;
                 fa.nlink += 1;
-                assert_eq_pretty!(*fa, FileAttr::from(v));
+                inv_assert_eq_pretty!(*fa, FileAttr::from(v));
             }
-            #[cfg(feature = "check-dirs")]
-            {
+            if fs_data.checks.dirs {
                 let dc = &mut fs_data.INV_DIR_CONTENTS;
                 dc.get_mut(&inv.parent)
                     .expect("Parent does not exist")
@@ -122,16 +122,17 @@ pub fn inv_link_after(
             }
             fs_data.INV_INODE_PATHS.insert(v.ino, inv.new_path);
         }
-        Err(libc::ENAMETOOLONG) => assert!(inv.toolong, "Returned ENAMETOOLONG on valid name"),
-        Err(libc::ENOENT) => assert!(!inv.old_exists, "Returned ENOENT on extant source"),
-        Err(libc::EACCES) => assert!(
+        Err(libc::ENAMETOOLONG) => inv_assert!(inv.toolong, "Returned ENAMETOOLONG on valid name"),
+        Err(libc::ENOENT) => inv_assert!(!inv.old_exists, "Returned ENOENT on extant source"),
+        Err(libc::EACCES) => inv_assert!(
             inv.old_perm == Some(libc::EACCES) || inv.new_perm == Some(libc::EACCES),
             "Returned EACCES on path where we have permission"
         ),
-        Err(libc::EPERM) => assert!(
+        Err(libc::EPERM) => inv_assert!(
             inv.old_perm == Some(libc::EPERM) || inv.new_perm == Some(libc::EPERM),
             "Returned EPERM on path where we have permission"
         ),
-        Err(e) => panic!("Got unexpected error code {}", e),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
     }
+    Ok(())
 }
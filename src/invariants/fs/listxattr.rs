@@ -0,0 +1,108 @@
+use std::{
+    collections::BTreeSet,
+    ffi::{OsStr, OsString},
+    os::unix::prelude::OsStrExt,
+    path::Path,
+    sync::MutexGuard,
+};
+
+use crate::{
+    inv_assert, inv_assert_eq, inv_fail,
+    invariants::{
+        common::{common_pre_ino, CPI},
+        perm::{caps, xattr_listed},
+        violation::Violation,
+        FSData,
+    },
+    log_inv, log_more,
+    logging::CallID,
+    req_rep::{Request, Xattr},
+};
+
+#[derive(Debug)]
+#[must_use]
+pub struct ListxattrInv {
+    size: u32,
+    exists: bool,
+    names: BTreeSet<OsString>,
+}
+
+impl ListxattrInv {
+    /// The length of the list: every name with its terminating NUL.
+    fn len(&self) -> usize {
+        self.names.iter().map(|x| x.len() + 1).sum()
+    }
+}
+
+pub fn inv_listxattr_before(
+    callid: CallID,
+    req: &Request,
+    _base: &Path,
+    ino: u64,
+    size: u32,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> ListxattrInv {
+    let CPI { exists, .. } = common_pre_ino(callid, ino, fs_data);
+
+    // Listing needs no permission, but hides the attributes the caller couldn't read.
+    let caps = caps(req.pid(), req.uid());
+    let names = fs_data
+        .INV_XATTR_CONTENTS
+        .get(&ino)
+        .map(|x| {
+            x.keys()
+                .filter(|name| xattr_listed(caps, name))
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default();
+
+    ListxattrInv {
+        size,
+        exists,
+        names,
+    }
+}
+pub fn inv_listxattr_after(
+    callid: CallID,
+    inv: ListxattrInv,
+    res: &Result<Xattr, i32>,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
+    if !fs_data.checks.xattr {
+        return Ok(());
+    }
+    match res {
+        Ok(v) => {
+            inv_assert!(inv.exists, "Failed to return ENOENT on nonexistant inode");
+            match v {
+                Xattr::Size(n) => {
+                    inv_assert!(inv.size == 0, "Returned a size for a sized request");
+                    inv_assert_eq!(inv.len(), *n as usize, "Returned the wrong size");
+                }
+                Xattr::Data(d) => {
+                    inv_assert!(inv.size != 0, "Returned data for a size request");
+                    let mut names = BTreeSet::new();
+                    for name in d.split(|x| *x == 0).filter(|x| !x.is_empty()) {
+                        inv_assert!(
+                            names.insert(OsStr::from_bytes(name).to_os_string()),
+                            "Listed {:?} twice",
+                            OsStr::from_bytes(name)
+                        );
+                    }
+                    inv_assert_eq!(inv.names, names, "Listed the wrong names");
+                }
+            }
+        }
+        Err(libc::ERANGE) => inv_assert!(
+            inv.size != 0 && (inv.size as usize) < inv.len(),
+            "Returned ERANGE on a large enough buffer"
+        ),
+        Err(libc::EACCES) | Err(libc::EPERM) => {
+            log_more!(callid, "Listing needs no permission, refused by an LSM")
+        }
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
+    }
+    Ok(())
+}
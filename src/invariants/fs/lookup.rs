@@ -1,26 +1,26 @@
 use std::{ffi::OsString, path::Path, sync::MutexGuard};
 
-use asserteq_pretty::assert_eq_pretty;
-
 use crate::{
     file_attr::FileAttr,
+    inv_assert, inv_assert_eq, inv_assert_eq_pretty, inv_fail,
     invariants::{
         common::{common_pre_parent_name, CPPN},
         perm::{check_perm, Access},
+        violation::Violation,
         FSData,
     },
-    log_more,
+    log_inv,
     logging::CallID,
     req_rep::Request,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LookupArgs {
     parent: u64,
     name: OsString,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[must_use]
 pub struct LookupInv {
     child_exists: bool,
@@ -30,8 +30,15 @@ pub struct LookupInv {
     args: LookupArgs,
 }
 
+impl LookupInv {
+    /// The inode the name resolved to when the invariant was computed.
+    pub fn child(&self) -> Option<u64> {
+        self.ino
+    }
+}
+
 pub fn inv_lookup_before(
-    _callid: CallID,
+    callid: CallID,
     req: &Request,
     base: &Path,
     parent: u64,
@@ -47,6 +54,7 @@ pub fn inv_lookup_before(
     } = common_pre_parent_name(parent, name, fs_data);
 
     let perm = check_perm(
+        callid,
         req.uid(),
         req.gid(),
         req.pid(),
@@ -71,56 +79,59 @@ pub fn inv_lookup_after(
     inv: LookupInv,
     res: &Result<fuser::FileAttr, i32>,
     fs_data: &mut MutexGuard<'_, FSData>,
-) {
-    log_more!(callid, "invariant={:?}", inv);
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
     match res {
         Ok(v) => {
-            assert!(
+            inv_assert!(
                 !inv.toolong,
                 "Failed to return ENAMETOOLONG on name too long"
             );
-            assert!(
+            inv_assert!(
                 inv.perm.is_none(),
                 "Failed to return error on permission denied"
             );
-            assert!(
+            inv_assert!(
                 inv.child_exists,
                 "Failed to return ENOENT on nonexistant child"
             );
             let ino = inv.ino.expect("Failed to get child inode");
-            assert_eq!(v.ino, ino, "Returned inode number does not match");
-            #[cfg(feature = "check-dirs")]
-            assert_eq!(
-                fs_data
-                    .INV_DIR_CONTENTS
-                    .get(&inv.args.parent)
-                    .expect("Parent does not exist")
-                    .get(&inv.args.name)
-                    .expect("Child does not exist"),
-                &ino
-            );
-            #[cfg(feature = "check-meta")]
-            assert_eq_pretty!(
-                fs_data
-                    .INV_INODE_CONTENTS
-                    .get(&ino)
-                    .map(|x| x.reset_times()),
-                Some(FileAttr::from(v).reset_times()),
-                "Result did not match expected value"
-            );
+            inv_assert_eq!(ino, v.ino, "Returned inode number does not match");
+            if fs_data.checks.dirs {
+                inv_assert_eq!(
+                    fs_data
+                        .INV_DIR_CONTENTS
+                        .get(&inv.args.parent)
+                        .expect("Parent does not exist")
+                        .get(&inv.args.name)
+                        .expect("Child does not exist"),
+                    &ino
+                );
+            }
+            if fs_data.checks.meta {
+                inv_assert_eq_pretty!(
+                    fs_data
+                        .INV_INODE_CONTENTS
+                        .get(&ino)
+                        .map(|x| x.reset_times()),
+                    Some(FileAttr::from(v).reset_times()),
+                    "Result did not match expected value"
+                );
+            }
         }
-        Err(libc::ENAMETOOLONG) => assert!(inv.toolong, "Returned ENAMETOOLONG on valid name"),
-        Err(libc::EACCES) => assert_eq!(
+        Err(libc::ENAMETOOLONG) => inv_assert!(inv.toolong, "Returned ENAMETOOLONG on valid name"),
+        Err(libc::EACCES) => inv_assert_eq!(
             inv.perm,
             Some(libc::EACCES),
             "Returned EACCES on path where we have permission"
         ),
-        Err(libc::EPERM) => assert_eq!(
+        Err(libc::EPERM) => inv_assert_eq!(
             inv.perm,
             Some(libc::EPERM),
             "Returned EPERM on path where we have permission"
         ),
-        Err(libc::ENOENT) => assert!(!inv.child_exists, "Returned ENOENT on extant path"),
-        Err(e) => panic!("Got unexpected error code {}", e),
+        Err(libc::ENOENT) => inv_assert!(!inv.child_exists, "Returned ENOENT on extant path"),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
     }
+    Ok(())
 }
@@ -0,0 +1,110 @@
+use std::sync::MutexGuard;
+
+use crate::{
+    inv_assert, inv_assert_eq, inv_fail,
+    invariants::{violation::Violation, FSData},
+    log_inv,
+    logging::CallID,
+    req_rep::Request,
+};
+
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct LseekInv {
+    ino: u64,
+    offset: i64,
+    whence: i32,
+}
+
+pub fn inv_lseek_before(
+    _callid: CallID,
+    _req: &Request,
+    ino: u64,
+    _fh: u64,
+    offset: i64,
+    whence: i32,
+    _fs_data: &mut MutexGuard<'_, FSData>,
+) -> LseekInv {
+    LseekInv {
+        ino,
+        offset,
+        whence,
+    }
+}
+
+pub fn inv_lseek_after(
+    callid: CallID,
+    inv: LseekInv,
+    res: &Result<i64, i32>,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
+    if !fs_data.checks.data {
+        return Ok(());
+    }
+    let size = fs_data
+        .INV_INODE_CONTENTS
+        .get(&inv.ino)
+        .expect("File missing inode")
+        .size;
+    let ranges = fs_data
+        .INV_FILE_DATA
+        .get(&inv.ino)
+        .expect("File missing data ranges");
+    let past_eof = inv.offset < 0 || inv.offset as u64 >= size;
+    match (inv.whence, res) {
+        (libc::SEEK_DATA | libc::SEEK_HOLE, Err(libc::ENXIO)) => {
+            if inv.whence == libc::SEEK_DATA {
+                inv_assert!(
+                    past_eof || ranges.next_data(inv.offset as u64).is_none(),
+                    "Returned ENXIO from SEEK_DATA with data left after the offset"
+                );
+            } else {
+                inv_assert!(past_eof, "Returned ENXIO from SEEK_HOLE before EOF");
+            }
+        }
+        (libc::SEEK_DATA, Ok(v)) => {
+            inv_assert!(!past_eof, "Failed to return ENXIO seeking past EOF");
+            let v = *v as u64;
+            inv_assert!(
+                v >= inv.offset as u64 && v < size,
+                "SEEK_DATA returned {} outside [{}, {})",
+                v,
+                inv.offset,
+                size
+            );
+            if let Some(next) = ranges.next_data(inv.offset as u64) {
+                inv_assert!(
+                    v <= next,
+                    "SEEK_DATA skipped data at {}, returning {}",
+                    next,
+                    v
+                );
+            }
+        }
+        (libc::SEEK_HOLE, Ok(v)) => {
+            inv_assert!(!past_eof, "Failed to return ENXIO seeking past EOF");
+            let v = *v as u64;
+            inv_assert!(
+                v >= inv.offset as u64 && v <= size,
+                "SEEK_HOLE returned {} outside [{}, {}]",
+                v,
+                inv.offset,
+                size
+            );
+            if let Some(end) = ranges.containing(v) {
+                inv_fail!("SEEK_HOLE returned {}, which is in data up to {}", v, end);
+            }
+        }
+        (libc::SEEK_SET, Ok(v)) => inv_assert_eq!(inv.offset, *v),
+        (libc::SEEK_END, Ok(v)) => inv_assert_eq!(size as i64 + inv.offset, *v),
+        (libc::SEEK_CUR, Ok(_)) => (),
+        (w, Err(libc::EINVAL)) => inv_assert!(
+            w != libc::SEEK_DATA && w != libc::SEEK_HOLE,
+            "Returned EINVAL from SEEK_DATA or SEEK_HOLE"
+        ),
+        (_, Err(e)) => inv_fail!("Got unexpected error code {}", e),
+        (w, Ok(_)) => inv_fail!("Succeeded with unknown whence {}", w),
+    }
+    Ok(())
+}
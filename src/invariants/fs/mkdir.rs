@@ -5,16 +5,17 @@ use std::{
     sync::MutexGuard,
 };
 
-use asserteq_pretty::assert_eq_pretty;
-
 use crate::{
     file_attr::{FileAttr, FileType},
+    inv_assert, inv_assert_eq, inv_assert_eq_pretty, inv_fail,
     invariants::{
+        acl::inherit,
         common::{common_pre_parent_name, CPPN},
         perm::{check_perm, Access},
+        violation::Violation,
         FSData,
     },
-    log_more,
+    log_inv, log_more,
     logging::CallID,
     req_rep::Request,
 };
@@ -30,17 +31,18 @@ pub struct MkdirInv {
     perm: Option<i32>,
     toolong: bool,
     child_path: PathBuf,
+    new_xattrs: BTreeMap<OsString, Vec<u8>>,
     mode: u32,
 }
 
 pub fn inv_mkdir_before(
-    _callid: CallID,
+    callid: CallID,
     req: &Request,
     base: &Path,
     parent: u64,
     name: &std::ffi::OsStr,
     mode: u32,
-    _umask: u32,
+    umask: u32,
     fs_data: &mut MutexGuard<'_, FSData>,
 ) -> MkdirInv {
     let CPPN {
@@ -51,6 +53,7 @@ pub fn inv_mkdir_before(
     } = common_pre_parent_name(parent, name, fs_data);
 
     let perm = check_perm(
+        callid,
         req.uid(),
         req.gid(),
         req.pid(),
@@ -59,6 +62,13 @@ pub fn inv_mkdir_before(
         Access::Create,
     );
 
+    let (perm_bits, new_xattrs) = inherit(
+        child_path.parent().expect("Child has no parent"),
+        mode,
+        umask,
+        true,
+    );
+
     MkdirInv {
         uid: req.uid(),
         gid: req.gid(),
@@ -68,7 +78,8 @@ pub fn inv_mkdir_before(
         toolong,
         perm,
         child_path,
-        mode,
+        mode: perm_bits,
+        new_xattrs,
     }
 }
 pub fn inv_mkdir_after(
@@ -76,19 +87,19 @@ pub fn inv_mkdir_after(
     inv: MkdirInv,
     res: &Result<fuser::FileAttr, i32>,
     fs_data: &mut MutexGuard<'_, FSData>,
-) {
-    log_more!(callid, "invariant={:?}", inv);
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
     match res {
         Ok(v) => {
-            assert!(
+            inv_assert!(
                 !inv.toolong,
                 "Failed to return ENAMETOOLONG on name too long"
             );
-            assert!(
+            inv_assert!(
                 inv.perm.is_none(),
                 "Failed to return error on permission denied"
             );
-            assert!(
+            inv_assert!(
                 inv.parent_exists,
                 "Failed to return ENOENT on nonexistant parent"
             );
@@ -109,49 +120,45 @@ pub fn inv_mkdir_after(
                 blksize: 4096,
                 flags: 0,
             };
-            assert_eq_pretty!(FileAttr::from(v), fa);
-            #[cfg(feature = "check-meta")]
-            {
+            inv_assert_eq_pretty!(fa, FileAttr::from(v));
+            if fs_data.checks.meta {
                 fs_data.INV_INODE_CONTENTS.insert(v.ino, fa);
             }
-            #[cfg(feature = "check-dirs")]
-            {
+            if fs_data.checks.dirs {
                 fs_data.INV_DIR_CONTENTS.insert(v.ino, BTreeMap::new());
             }
-            #[cfg(feature = "check-xattr")]
-            {
+            if fs_data.checks.xattr {
                 let xc = &mut fs_data.INV_XATTR_CONTENTS;
-                xc.insert(v.ino, BTreeMap::new());
+                xc.insert(v.ino, inv.new_xattrs);
             }
-            #[cfg(feature = "check-dirs")]
-            {
+            if fs_data.checks.dirs {
                 let dc = &mut fs_data.INV_DIR_CONTENTS;
                 dc.get_mut(&inv.parent)
                     .expect("Parent does not exist")
                     .insert(inv.name, v.ino);
             }
-            #[cfg(feature = "check-meta")]
-            {
+            if fs_data.checks.meta {
                 let dc = &mut fs_data.INV_INODE_CONTENTS;
                 dc.get_mut(&inv.parent)
                     .expect("Parent does not exist")
                     .nlink += 1;
             }
-            println!("III {}->{:?}", v.ino, inv.child_path);
+            log_more!(callid, "III {}->{:?}", v.ino, inv.child_path);
             fs_data.INV_INODE_PATHS.insert(v.ino, inv.child_path);
         }
-        Err(libc::ENAMETOOLONG) => assert!(inv.toolong, "Returned ENAMETOOLONG on valid name"),
-        Err(libc::EACCES) => assert_eq!(
+        Err(libc::ENAMETOOLONG) => inv_assert!(inv.toolong, "Returned ENAMETOOLONG on valid name"),
+        Err(libc::EACCES) => inv_assert_eq!(
             inv.perm,
             Some(libc::EACCES),
             "Returned EACCES on path where we have permission"
         ),
-        Err(libc::EPERM) => assert_eq!(
+        Err(libc::EPERM) => inv_assert_eq!(
             inv.perm,
             Some(libc::EPERM),
             "Returned EPERM on path where we have permission"
         ),
-        Err(libc::ENOENT) => assert!(!inv.parent_exists, "Returned ENOENT on extant parent"),
-        Err(e) => panic!("Got unexpected error code {}", e),
+        Err(libc::ENOENT) => inv_assert!(!inv.parent_exists, "Returned ENOENT on extant parent"),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
     }
+    Ok(())
 }
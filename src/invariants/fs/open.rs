@@ -0,0 +1,197 @@
+use std::{
+    fs::Metadata, os::linux::fs::MetadataExt, path::Path, sync::MutexGuard, time::SystemTime,
+};
+
+use crate::{
+    fs_to_fuse::FsToFuseAttr,
+    inv_assert, inv_assert_eq, inv_fail,
+    invariants::{
+        common::{common_pre_ino, CPI},
+        inode_flags::{self, FS_APPEND_FL, FS_IMMUTABLE_FL},
+        perm::{caps, check_perm, Access, CAP_FOWNER},
+        violation::Violation,
+        FSData,
+    },
+    log_inv, log_more,
+    logging::CallID,
+    req_rep::Request,
+};
+
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct OpenInv {
+    ino: u64,
+    exists: bool,
+    perm: Option<i32>,
+    /// The inode is a symlink the open follows, to a file the checks don't know.
+    follows: bool,
+    /// The open empties a regular file.
+    trunc: bool,
+}
+
+/// What opening with `flags` asks of the inode, as an `access(2)` mask. `O_TRUNC` writes even
+/// on a read-only handle.
+pub fn open_mask(flags: i32) -> u32 {
+    let mask = match flags & libc::O_ACCMODE {
+        libc::O_WRONLY => libc::W_OK,
+        libc::O_RDWR => libc::R_OK | libc::W_OK,
+        _ => libc::R_OK,
+    };
+    let trunc = if flags & libc::O_TRUNC != 0 {
+        libc::W_OK
+    } else {
+        0
+    };
+    (mask | trunc) as u32
+}
+
+/// The error opening the existing inode at `path` with `flags` must give, if any. `iflags` are
+/// its tracked inode flags. The type of the inode is checked first, then permission, then the
+/// flags that restrict how it may be opened.
+pub fn open_perm(
+    callid: CallID,
+    req: &Request,
+    base: &Path,
+    path: &Path,
+    meta: &Metadata,
+    iflags: u32,
+    flags: i32,
+) -> Option<i32> {
+    let mask = open_mask(flags);
+    let write = mask & libc::W_OK as u32 != 0;
+    let kind = meta.st_mode() & libc::S_IFMT;
+    if kind == libc::S_IFLNK && flags & libc::O_NOFOLLOW != 0 {
+        return Some(libc::ELOOP);
+    }
+    if flags & libc::O_DIRECTORY != 0 && kind != libc::S_IFDIR {
+        return Some(libc::ENOTDIR);
+    }
+    if kind == libc::S_IFDIR && write {
+        return Some(libc::EISDIR);
+    }
+    let perm = check_perm(
+        callid,
+        req.uid(),
+        req.gid(),
+        req.pid(),
+        path,
+        base,
+        Access::Mask(mask),
+    );
+    // An immutable inode refuses writing after a read-only filesystem, but ahead of the mode.
+    let immutable = (write && iflags & FS_IMMUTABLE_FL != 0).then(|| libc::EPERM);
+    let perm = match perm {
+        Some(libc::EROFS) => perm,
+        _ => immutable.or(perm),
+    };
+    if perm.is_some() {
+        return perm;
+    }
+    // Append-only files may only be opened to append, and never truncated.
+    if iflags & FS_APPEND_FL != 0
+        && ((write && flags & libc::O_APPEND == 0) || flags & libc::O_TRUNC != 0)
+    {
+        return Some(libc::EPERM);
+    }
+    if flags & libc::O_NOATIME != 0
+        && meta.st_uid() != req.uid()
+        && !caps(req.pid(), req.uid()).has(CAP_FOWNER)
+    {
+        return Some(libc::EPERM);
+    }
+    None
+}
+
+/// Empty `ino` in the model, as opening it with `O_TRUNC` does, with the times that left.
+pub fn truncate(fs_data: &mut FSData, ino: u64, mtime: SystemTime, ctime: SystemTime) {
+    if fs_data.checks.meta {
+        if let Some(fa) = fs_data.INV_INODE_CONTENTS.get_mut(&ino) {
+            fa.size = 0;
+            fa.mtime = mtime;
+            fa.ctime = ctime;
+        }
+    }
+    if fs_data.checks.data {
+        if let Some(fd) = fs_data.INV_FILE_CONTENTS.get_mut(&ino) {
+            fd.clear();
+        }
+        if let Some(r) = fs_data.INV_FILE_DATA.get_mut(&ino) {
+            r.truncate(0);
+        }
+    }
+}
+
+pub fn inv_open_before(
+    callid: CallID,
+    req: &Request,
+    base: &Path,
+    ino: u64,
+    flags: i32,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> OpenInv {
+    let CPI { inode_path, exists } = common_pre_ino(callid, ino, fs_data);
+
+    let meta = inode_path.symlink_metadata().ok();
+    let kind = meta.as_ref().map(|x| x.st_mode() & libc::S_IFMT);
+    let perm = meta.as_ref().and_then(|meta| {
+        let iflags = inode_flags::flags(fs_data, ino);
+        open_perm(callid, req, base, &inode_path, meta, iflags, flags)
+    });
+
+    OpenInv {
+        ino,
+        exists,
+        perm,
+        follows: kind == Some(libc::S_IFLNK) && flags & libc::O_NOFOLLOW == 0,
+        trunc: kind == Some(libc::S_IFREG) && flags & libc::O_TRUNC != 0,
+    }
+}
+
+pub fn inv_open_after(
+    callid: CallID,
+    inv: OpenInv,
+    res: &Result<i32, i32>,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
+    if inv.follows {
+        log_more!(callid, "Opened through a symlink");
+        return Ok(());
+    }
+    match res {
+        Ok(fd) => {
+            inv_assert!(inv.exists, "Failed to return ENOENT on nonexistant inode");
+            inv_assert!(
+                inv.perm.is_none(),
+                "Failed to return error on permission denied"
+            );
+            if inv.trunc {
+                let attr = match Path::new("/proc/self/fd").join(fd.to_string()).metadata() {
+                    Ok(v) => v.to_fuse_attr(inv.ino),
+                    Err(e) => inv_fail!("Failed to stat the opened file: {}", e),
+                };
+                inv_assert_eq!(0, attr.size, "O_TRUNC left data in the file");
+                truncate(fs_data, inv.ino, attr.mtime, attr.ctime);
+            }
+        }
+        Err(libc::EACCES) => inv_assert_eq!(
+            inv.perm,
+            Some(libc::EACCES),
+            "Returned EACCES on path where we have permission"
+        ),
+        Err(libc::EPERM) => inv_assert_eq!(
+            inv.perm,
+            Some(libc::EPERM),
+            "Returned EPERM on path where we have permission"
+        ),
+        Err(e @ (libc::ELOOP | libc::ENOTDIR | libc::EISDIR | libc::EROFS)) => inv_assert_eq!(
+            inv.perm,
+            Some(*e),
+            "Returned {} on an inode that may be opened this way",
+            e
+        ),
+        Err(libc::ENOENT) => inv_assert!(!inv.exists, "Returned ENOENT on extant inode"),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
+    }
+    Ok(())
+}
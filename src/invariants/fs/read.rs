@@ -1,17 +1,19 @@
 use std::{cmp::min, path::Path, sync::MutexGuard};
 
 use crate::{
+    inv_assert, inv_assert_eq, inv_fail,
     invariants::{
-        common::{common_pre_ino, CPI},
+        common::{common_pre_fh, CPI},
         perm::{check_perm, Access},
+        violation::Violation,
         FSData,
     },
-    log_more,
+    log_inv,
     logging::CallID,
     req_rep::Request,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[must_use]
 pub struct ReadInv {
     exists: bool,
@@ -33,16 +35,21 @@ pub fn inv_read_before(
     _lock_owner: Option<u64>,
     fs_data: &mut MutexGuard<'_, FSData>,
 ) -> ReadInv {
-    let CPI { inode_path, exists } = common_pre_ino(callid, ino, fs_data);
-
-    let perm = check_perm(
-        req.uid(),
-        req.gid(),
-        req.pid(),
-        &inode_path,
-        base,
-        Access::Lookup,
-    );
+    let (exists, perm) = match common_pre_fh(callid, ino, fs_data) {
+        Some(CPI { inode_path, exists }) => (
+            exists,
+            check_perm(
+                callid,
+                req.uid(),
+                req.gid(),
+                req.pid(),
+                &inode_path,
+                base,
+                Access::Lookup,
+            ),
+        ),
+        None => (true, None),
+    };
 
     ReadInv {
         ino,
@@ -57,17 +64,16 @@ pub fn inv_read_after(
     inv: ReadInv,
     res: &Result<Vec<u8>, i32>,
     fs_data: &mut MutexGuard<'_, FSData>,
-) {
-    log_more!(callid, "invariant={:?}", inv);
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
     match res {
         Ok(v) => {
-            assert!(
+            inv_assert!(
                 inv.perm.is_none(),
                 "Failed to return error on permission denied"
             );
-            assert!(inv.exists, "Failed to return ENOENT on nonexistant child");
-            #[cfg(feature = "check-data")]
-            {
+            inv_assert!(inv.exists, "Failed to return ENOENT on nonexistant child");
+            if fs_data.checks.data {
                 let fc = &fs_data.INV_FILE_CONTENTS;
                 let exp_content = fc
                     .get(&inv.ino)
@@ -76,20 +82,21 @@ pub fn inv_read_after(
                 let end = inv.offset + inv.size;
                 let end = min(end, exp_content.len());
                 let start = min(start, end);
-                assert_eq!(&exp_content[start..end], v, "File contents differ")
+                inv_assert_eq!(&exp_content[start..end], v, "File contents differ")
             }
         }
-        Err(libc::EACCES) => assert_eq!(
+        Err(libc::EACCES) => inv_assert_eq!(
             inv.perm,
             Some(libc::EACCES),
             "Returned EACCES on path where we have permission"
         ),
-        Err(libc::EPERM) => assert_eq!(
+        Err(libc::EPERM) => inv_assert_eq!(
             inv.perm,
             Some(libc::EPERM),
             "Returned EPERM on path where we have permission"
         ),
-        Err(libc::ENOENT) => assert!(!inv.exists, "Returned ENOENT on extant path"),
-        Err(e) => panic!("Got unexpected error code {}", e),
+        Err(libc::ENOENT) => inv_assert!(!inv.exists, "Returned ENOENT on extant path"),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
     }
+    Ok(())
 }
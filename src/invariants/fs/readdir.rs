@@ -0,0 +1,122 @@
+use std::{ffi::OsString, sync::MutexGuard};
+
+use crate::{
+    file_attr::FileType,
+    inv_assert, inv_assert_eq, inv_fail,
+    invariants::{violation::Violation, FSData},
+    log_inv,
+    logging::CallID,
+};
+
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct ReaddirInv {
+    ino: u64,
+    fh: u64,
+}
+
+pub fn inv_readdir_before(
+    _callid: CallID,
+    ino: u64,
+    fh: u64,
+    offset: i64,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> ReaddirInv {
+    if let Some(l) = fs_data.listings.get_mut(fh) {
+        l.read_at(offset);
+    }
+    ReaddirInv { ino, fh }
+}
+
+pub fn inv_readdir_after(
+    callid: CallID,
+    inv: ReaddirInv,
+    res: &Result<Vec<(u64, i64, fuser::FileType, OsString)>, i32>,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
+    for (d_ino, _, kind, name) in res.iter().flatten() {
+        if name == "." || name == ".." {
+            inv_assert_eq!(
+                FileType::from(*kind),
+                FileType::Directory,
+                "Returned {:?} as a non-directory",
+                name
+            );
+            // The root's `.` is the backing directory's own inode, which we present as 1.
+            inv_assert!(
+                name == ".." || inv.ino == 1 || *d_ino == inv.ino,
+                "Returned the wrong inode for ."
+            );
+            continue;
+        }
+        if fs_data.checks.dirs {
+            inv_assert_eq!(
+                fs_data
+                    .INV_DIR_CONTENTS
+                    .get(&inv.ino)
+                    .and_then(|x| x.get(name)),
+                Some(d_ino),
+                "Returned an entry that does not match the directory contents"
+            );
+        }
+        if fs_data.checks.meta {
+            inv_assert_eq!(
+                fs_data.INV_INODE_CONTENTS.get(d_ino).map(|x| x.kind),
+                Some(FileType::from(*kind)),
+                "Returned the wrong type for {:?}",
+                name
+            );
+        }
+    }
+    let entries = res
+        .as_ref()
+        .map(|x| {
+            x.iter()
+                .map(|(_, off, _, name)| (*off, name.clone()))
+                .collect()
+        })
+        .map_err(|e| *e);
+    inv_readdir_pass(inv, &entries, fs_data)
+}
+
+/// Check a read against the pass it belongs to: no entry that stayed put all pass may be
+/// returned twice, and once a read comes back empty every one of them must have been returned.
+/// Shared with `readdirplus`, which reads from the same handles.
+pub fn inv_readdir_pass(
+    inv: ReaddirInv,
+    res: &Result<Vec<(i64, OsString)>, i32>,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> Result<(), Violation> {
+    let dirs = fs_data.checks.dirs;
+    let contents = fs_data.INV_DIR_CONTENTS.get(&inv.ino).cloned();
+    let l = match fs_data.listings.get_mut(inv.fh) {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    match res {
+        Ok(entries) if entries.is_empty() => {
+            if dirs && !l.partial() {
+                let missing = l.missing(&contents.unwrap_or_default());
+                inv_assert!(
+                    missing.is_empty(),
+                    "Pass ended without returning {:?}",
+                    missing
+                );
+            }
+        }
+        Ok(entries) => {
+            for (off, name) in entries {
+                let n = l.see(name, *off);
+                inv_assert!(
+                    n == 1 || l.partial() || l.touched(name),
+                    "Returned {:?} {} times in one pass",
+                    name,
+                    n
+                );
+            }
+        }
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
+    }
+    Ok(())
+}
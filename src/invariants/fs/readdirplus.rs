@@ -0,0 +1,77 @@
+use std::{ffi::OsString, sync::MutexGuard};
+
+use crate::{
+    file_attr::FileAttr,
+    inv_assert_eq, inv_assert_eq_pretty, inv_fail,
+    invariants::{
+        fs::readdir::{inv_readdir_before, ReaddirInv},
+        violation::Violation,
+        FSData,
+    },
+    log_inv,
+    logging::CallID,
+};
+
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct ReaddirplusInv {
+    ino: u64,
+    pass: ReaddirInv,
+}
+
+impl ReaddirplusInv {
+    /// What to check the pass over the handle against, see
+    /// [`inv_readdir_pass`](crate::invariants::fs::readdir::inv_readdir_pass).
+    pub fn pass(&self) -> ReaddirInv {
+        self.pass.clone()
+    }
+}
+
+pub fn inv_readdirplus_before(
+    callid: CallID,
+    ino: u64,
+    fh: u64,
+    offset: i64,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> ReaddirplusInv {
+    let pass = inv_readdir_before(callid, ino, fh, offset, fs_data);
+    ReaddirplusInv { ino, pass }
+}
+
+/// `.` and `..` are not checked, as the kernel takes nothing from them.
+pub fn inv_readdirplus_after(
+    callid: CallID,
+    inv: ReaddirplusInv,
+    res: &Result<Option<(i64, OsString, fuser::FileAttr)>, i32>,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
+    match res {
+        Ok(Some((_, name, _))) if name == "." || name == ".." => (),
+        Ok(Some((_, name, attr))) => {
+            if fs_data.checks.dirs {
+                inv_assert_eq!(
+                    fs_data
+                        .INV_DIR_CONTENTS
+                        .get(&inv.ino)
+                        .and_then(|x| x.get(name)),
+                    Some(&attr.ino),
+                    "Returned an entry that does not match the directory contents"
+                );
+            }
+            if fs_data.checks.meta {
+                inv_assert_eq_pretty!(
+                    fs_data
+                        .INV_INODE_CONTENTS
+                        .get(&attr.ino)
+                        .map(|x| x.reset_times()),
+                    Some(FileAttr::from(attr).reset_times()),
+                    "Returned attributes did not match expected value"
+                );
+            }
+        }
+        Ok(None) => (),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
+    }
+    Ok(())
+}
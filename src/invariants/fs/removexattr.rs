@@ -1,45 +1,105 @@
-use std::{path::Path, sync::MutexGuard};
+use std::{ffi::OsString, path::Path, sync::MutexGuard};
 
 use crate::{
+    inv_assert, inv_fail,
     invariants::{
         common::{common_pre_ino, CPI},
-        perm::{check_perm, Access},
+        inode_flags::unchangeable,
+        perm::{check_perm, xattr_unchecked, Access},
+        violation::Violation,
         FSData,
     },
-    log_more,
+    log_inv, log_more,
     logging::CallID,
+    req_rep::Request,
 };
 
 #[derive(Debug)]
 #[must_use]
-pub struct RemovexattrInv {}
+pub struct RemovexattrInv {
+    ino: u64,
+    name: OsString,
+    exists: bool,
+    present: bool,
+    perm: Option<i32>,
+    unchecked: bool,
+}
 
 pub fn inv_removexattr_before(
     callid: CallID,
-    req: &fuser::Request<'_>,
+    req: &Request,
     base: &Path,
     ino: u64,
-    _name: &std::ffi::OsStr,
+    name: &std::ffi::OsStr,
     fs_data: &mut MutexGuard<'_, FSData>,
 ) -> RemovexattrInv {
-    let CPI { inode_path, .. } = common_pre_ino(callid, ino, fs_data);
+    let CPI { inode_path, exists } = common_pre_ino(callid, ino, fs_data);
 
-    let _perm = check_perm(
+    let perm = check_perm(
+        callid,
         req.uid(),
         req.gid(),
         req.pid(),
         &inode_path,
         base,
-        Access::Lookup,
+        Access::XattrRemove(name.to_os_string()),
     );
+    let perm = unchangeable(fs_data, ino).or(perm);
 
-    RemovexattrInv {}
-}
-pub fn inv_removexattr_after(callid: CallID, inv: RemovexattrInv, _res: &Result<(), i32>) {
-    log_more!(callid, "invariant={:?}", inv);
+    let present = fs_data
+        .INV_XATTR_CONTENTS
+        .get(&ino)
+        .map_or(false, |x| x.contains_key(name));
 
-    #[cfg(feature = "check-xattr")]
-    {
-        compile_error!("XATTR validation is not yet implemented")
+    RemovexattrInv {
+        ino,
+        name: name.to_os_string(),
+        exists,
+        present,
+        perm,
+        unchecked: xattr_unchecked(name),
+    }
+}
+pub fn inv_removexattr_after(
+    callid: CallID,
+    inv: RemovexattrInv,
+    res: &Result<(), i32>,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
+    let checked = fs_data.checks.xattr;
+    match res {
+        Ok(()) => {
+            inv_assert!(inv.exists, "Failed to return ENOENT on nonexistant inode");
+            inv_assert!(
+                inv.perm.is_none(),
+                "Failed to return error on permission denied"
+            );
+            if checked {
+                inv_assert!(
+                    inv.present,
+                    "Failed to return ENODATA on a missing attribute"
+                );
+                if let Some(x) = fs_data.INV_XATTR_CONTENTS.get_mut(&inv.ino) {
+                    x.remove(&inv.name);
+                }
+            }
+        }
+        Err(libc::ENODATA) => inv_assert!(
+            !checked || (!inv.present && inv.perm.is_none()),
+            "Returned ENODATA on an existing attribute"
+        ),
+        Err(libc::EOPNOTSUPP) => log_more!(callid, "Not supported"),
+        // security. attributes are up to the filesystem and LSMs.
+        Err(libc::EACCES) => inv_assert!(
+            inv.unchecked || inv.perm == Some(libc::EACCES),
+            "Returned EACCES on attribute where we have permission"
+        ),
+        Err(libc::EPERM) => inv_assert!(
+            inv.unchecked || inv.perm == Some(libc::EPERM),
+            "Returned EPERM on attribute where we have permission"
+        ),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
     }
+    Ok(())
 }
@@ -4,16 +4,17 @@ use std::{
     sync::MutexGuard,
 };
 
-use asserteq_pretty::assert_eq_pretty;
-
 use crate::{
     file_attr::{FileAttr, FileType},
+    inv_assert, inv_assert_eq_pretty, inv_fail,
     invariants::{
         common::{common_pre_parent_name, CPPN},
+        inode_flags::{immutable, undeletable},
         perm::{check_perm, Access},
+        violation::Violation,
         FSData,
     },
-    log_more,
+    log_inv, log_more,
     logging::CallID,
     req_rep::Request,
 };
@@ -40,7 +41,7 @@ pub struct RenameInv {
 }
 
 pub fn inv_rename_before(
-    _callid: CallID,
+    callid: CallID,
     req: &Request,
     base: &Path,
     parent: u64,
@@ -68,6 +69,7 @@ pub fn inv_rename_before(
     } = common_pre_parent_name(newparent, newname, fs_data);
 
     let old_perm = check_perm(
+        callid,
         req.uid(),
         req.gid(),
         req.pid(),
@@ -76,6 +78,7 @@ pub fn inv_rename_before(
         Access::Delete,
     );
     let new_perm = check_perm(
+        callid,
         req.uid(),
         req.gid(),
         req.pid(),
@@ -83,6 +86,13 @@ pub fn inv_rename_before(
         base,
         Access::Create,
     );
+    // Moving a directory rewrites its `..`, so the moved inode itself has to be changeable too.
+    let old_perm = immutable(fs_data, parent)
+        .or(old_perm)
+        .or_else(|| undeletable(fs_data, parent, old_ino));
+    let new_perm = immutable(fs_data, newparent)
+        .or(new_perm)
+        .or_else(|| new_ino.and_then(|x| undeletable(fs_data, newparent, Some(x))));
 
     let new_notempty = new_child_exists
         && new_child_path.symlink_metadata().unwrap().is_dir()
@@ -112,34 +122,33 @@ pub fn inv_rename_after(
     inv: RenameInv,
     res: &Result<(), i32>,
     fs_data: &mut MutexGuard<'_, FSData>,
-) {
-    log_more!(callid, "invariant={:?}", inv);
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
     match res {
         Ok(()) => {
-            assert!(
+            inv_assert!(
                 !inv.old_toolong && !inv.new_toolong,
                 "Failed to return ENAMETOOLONG on name too long"
             );
-            assert!(
+            inv_assert!(
                 inv.old_perm.is_none() && inv.new_perm.is_none(),
                 "Returned OK when error expected ({:?}) -> ({:?})",
                 inv.old_perm,
                 inv.new_perm
             );
-            assert!(
+            inv_assert!(
                 inv.old_child_exists,
                 "Failed to return ENOENT on nonexistant child"
             );
-            assert!(
+            inv_assert!(
                 inv.new_parent_exists,
                 "Failed to return ENOENT on nonexistant parent"
             );
-            assert!(
+            inv_assert!(
                 !inv.new_notempty,
                 "Failed to return ENOTEMPTY on nonempty new dir"
             );
-            #[cfg(feature = "check-dirs")]
-            {
+            if fs_data.checks.dirs {
                 let dc = &mut fs_data.INV_DIR_CONTENTS;
                 let ino = dc
                     .get_mut(&inv.old_parent)
@@ -150,94 +159,85 @@ pub fn inv_rename_after(
                     .expect("Parent does not exist")
                     .insert(inv.new_name, ino);
             }
-            let ic = &mut fs_data.INV_INODE_CONTENTS;
-            let ino = ic
-                .get(&inv.old_ino.unwrap())
-                .expect("Overwriting dest, but no file to delete");
-            let ik = ino.kind;
-            if inv.new_child_exists {
-                #[cfg(feature = "check-meta")]
-                {
-                    let ic = &mut fs_data.INV_INODE_CONTENTS;
+            if fs_data.checks.meta {
+                let ic = &mut fs_data.INV_INODE_CONTENTS;
+                let ino = ic
+                    .get(&inv.old_ino.unwrap())
+                    .expect("Overwriting dest, but no file to delete");
+                let ik = ino.kind;
+                if inv.new_child_exists {
                     let ino = ic
                         .get_mut(&inv.new_ino.unwrap())
                         .expect("Overwriting dest, but no file to delete");
-                    println!("DEC N");
+                    log_more!(callid, "DEC N");
                     ino.nlink -= 1;
                     if ino.nlink == 0 {
-                        ic.remove(&inv.new_ino.unwrap());
-                        #[cfg(feature = "check-data")]
-                        {
-                            let fc = &mut fs_data.INV_FILE_CONTENTS;
-                            fc.remove(&inv.new_ino.unwrap());
-                        }
-                        #[cfg(feature = "check-xattr")]
-                        {
-                            let xc = &mut fs_data.INV_XATTR_CONTENTS;
-                            xc.remove(&inv.new_ino.unwrap());
-                        }
+                        fs_data.orphan(inv.new_ino.unwrap());
                     }
                 }
-            }
-            let ic = &mut fs_data.INV_INODE_CONTENTS;
-            if ik == FileType::Directory {
-                let old_parent_ino = ic
-                    .get_mut(&inv.old_parent)
-                    .expect("Can't get parent to decrement refcount");
-                println!("DEC OP");
-                old_parent_ino.nlink -= 1;
-                if !inv.new_child_exists {
-                    let new_parent_ino = ic
-                        .get_mut(&inv.new_parent)
-                        .expect("Can't get parent to increment refcount");
-                    println!("INC NP");
-                    new_parent_ino.nlink += 1;
+                let ic = &mut fs_data.INV_INODE_CONTENTS;
+                if ik == FileType::Directory {
+                    let old_parent_ino = ic
+                        .get_mut(&inv.old_parent)
+                        .expect("Can't get parent to decrement refcount");
+                    log_more!(callid, "DEC OP");
+                    old_parent_ino.nlink -= 1;
+                    if !inv.new_child_exists {
+                        let new_parent_ino = ic
+                            .get_mut(&inv.new_parent)
+                            .expect("Can't get parent to increment refcount");
+                        log_more!(callid, "INC NP");
+                        new_parent_ino.nlink += 1;
+                    }
                 }
             }
             fs_data
                 .INV_INODE_PATHS
-                .rename(inv.old_child_path, inv.new_child_path);
+                .rename(callid, inv.old_child_path, inv.new_child_path);
 
-            let opk = FileAttr::from(
-                std::fs::symlink_metadata(fs_data.INODE_PATHS.get(inv.old_parent)).unwrap(),
-            );
-            let ope = fs_data.INV_INODE_CONTENTS.get(&inv.old_parent).unwrap();
-            if ope.ino != 1 {
-                assert_eq_pretty!(opk.reset_times(), ope.reset_times());
-            }
+            if fs_data.checks.meta {
+                let opk = FileAttr::from(
+                    std::fs::symlink_metadata(fs_data.INODE_PATHS.get(inv.old_parent)).unwrap(),
+                );
+                let ope = fs_data.INV_INODE_CONTENTS.get(&inv.old_parent).unwrap();
+                if ope.ino != 1 {
+                    inv_assert_eq_pretty!(ope.reset_times(), opk.reset_times());
+                }
 
-            let npk = FileAttr::from(
-                std::fs::symlink_metadata(fs_data.INODE_PATHS.get(inv.new_parent)).unwrap(),
-            );
-            let npe = fs_data.INV_INODE_CONTENTS.get(&inv.new_parent).unwrap();
-            if npe.ino != 1 {
-                assert_eq_pretty!(npk.reset_times(), npe.reset_times());
+                let npk = FileAttr::from(
+                    std::fs::symlink_metadata(fs_data.INODE_PATHS.get(inv.new_parent)).unwrap(),
+                );
+                let npe = fs_data.INV_INODE_CONTENTS.get(&inv.new_parent).unwrap();
+                if npe.ino != 1 {
+                    inv_assert_eq_pretty!(npe.reset_times(), npk.reset_times());
+                }
             }
 
             //let nk = FileAttr::from(std::fs::metadata(fs_data.INODE_PATHS.get(inv.new_ino.unwrap())).unwrap());
             //let ne = fs_data.INV_INODE_CONTENTS.get(&inv.new_ino.unwrap()).unwrap();
             //assert_eq_pretty!(nk.reset_times(),ne.reset_times());
         }
-        Err(libc::ENOTEMPTY) => assert!(
+        Err(libc::ENOTEMPTY) => inv_assert!(
             inv.new_notempty,
             "Returned ENOTEMPTY on nonexistant/nondir/empty new"
         ),
-        Err(libc::ENAMETOOLONG) => assert!(
+        Err(libc::ENAMETOOLONG) => inv_assert!(
             inv.old_toolong || inv.new_toolong,
             "Returned ENAMETOOLONG on valid name"
         ),
-        Err(libc::ENOENT) => assert!(
+        Err(libc::ENOENT) => inv_assert!(
             !inv.old_child_exists || !inv.new_parent_exists,
             "Returned ENOENT on extant item"
         ),
-        Err(libc::EACCES) => assert!(
+        Err(libc::EACCES) => inv_assert!(
             inv.old_perm == Some(libc::EACCES) || inv.new_perm == Some(libc::EACCES),
             "Returned EACCES on path where we have permission"
         ),
-        Err(libc::EPERM) => assert!(
+        Err(libc::EPERM) => inv_assert!(
             inv.old_perm == Some(libc::EPERM) || inv.new_perm == Some(libc::EPERM),
             "Returned EPERM on path where we have permission"
         ),
-        Err(e) => panic!("Got unexpected error code {}", e),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
     }
+    Ok(())
 }
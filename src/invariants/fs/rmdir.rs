@@ -5,12 +5,15 @@ use std::{
 };
 
 use crate::{
+    inv_assert, inv_assert_eq, inv_fail,
     invariants::{
         common::{common_pre_parent_name, CPPN},
+        inode_flags::{immutable, undeletable},
         perm::{check_perm, Access},
+        violation::Violation,
         FSData,
     },
-    log_more,
+    log_inv,
     logging::CallID,
     req_rep::Request,
 };
@@ -29,7 +32,7 @@ pub struct RmdirInv {
 }
 
 pub fn inv_rmdir_before(
-    _callid: CallID,
+    callid: CallID,
     req: &Request,
     base: &Path,
     parent: u64,
@@ -45,6 +48,7 @@ pub fn inv_rmdir_before(
     } = common_pre_parent_name(parent, name, fs_data);
 
     let perm = check_perm(
+        callid,
         req.uid(),
         req.gid(),
         req.pid(),
@@ -52,6 +56,9 @@ pub fn inv_rmdir_before(
         base,
         Access::Delete,
     );
+    let perm = immutable(fs_data, parent)
+        .or(perm)
+        .or_else(|| undeletable(fs_data, parent, ino));
 
     let notempty = child_exists
         && child_path.symlink_metadata().unwrap().is_dir()
@@ -73,54 +80,41 @@ pub fn inv_rmdir_after(
     inv: RmdirInv,
     res: &Result<(), i32>,
     fs_data: &mut MutexGuard<'_, FSData>,
-) {
-    log_more!(callid, "invariant={:?}", inv);
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
     match res {
         Ok(()) => {
-            assert!(
+            inv_assert!(
                 !inv.toolong,
                 "Failed to return ENAMETOOLONG on name too long"
             );
-            assert!(
+            inv_assert!(
                 inv.perm.is_none(),
                 "Failed to return error on permission denied"
             );
-            assert!(
+            inv_assert!(
                 inv.child_exists,
                 "Failed to return ENOENT on nonexistant directory"
             );
-            assert!(
+            inv_assert!(
                 !inv.notempty,
                 "Failed to return ENOTEMPTY on nonempty new dir"
             );
-            #[cfg(feature = "check-meta")]
-            {
+            if fs_data.checks.meta {
                 let ic = &mut fs_data.INV_INODE_CONTENTS;
                 let fa = ic.get_mut(&inv.ino.unwrap()).unwrap();
                 fa.nlink -= 1;
                 if fa.nlink == 0 {
-                    ic.remove(&inv.ino.unwrap());
-                    #[cfg(feature = "check-data")]
-                    {
-                        let fc = &mut fs_data.INV_FILE_CONTENTS;
-                        fc.remove(&inv.ino.unwrap());
-                    }
-                    #[cfg(feature = "check-xattr")]
-                    {
-                        let xc = &mut fs_data.INV_XATTR_CONTENTS;
-                        xc.remove(&inv.ino.unwrap());
-                    }
+                    fs_data.orphan(inv.ino.unwrap());
                 }
             }
-            #[cfg(feature = "check-dirs")]
-            {
+            if fs_data.checks.dirs {
                 let dc = &mut fs_data.INV_DIR_CONTENTS;
                 dc.get_mut(&inv.parent)
                     .expect("Parent does not exist")
                     .remove(&inv.name);
             }
-            #[cfg(feature = "check-meta")]
-            {
+            if fs_data.checks.meta {
                 let dc = &mut fs_data.INV_INODE_CONTENTS;
                 dc.get_mut(&inv.parent)
                     .expect("Parent does not exist")
@@ -128,22 +122,23 @@ pub fn inv_rmdir_after(
             }
             fs_data.INV_INODE_PATHS.remove(&inv.child_path);
         }
-        Err(libc::ENOTEMPTY) => assert!(
+        Err(libc::ENOTEMPTY) => inv_assert!(
             inv.notempty,
             "Returned ENOTEMPTY on nonexistant/nondir/empty new"
         ),
-        Err(libc::ENAMETOOLONG) => assert!(inv.toolong, "Returned ENAMETOOLONG on valid name"),
-        Err(libc::EACCES) => assert_eq!(
+        Err(libc::ENAMETOOLONG) => inv_assert!(inv.toolong, "Returned ENAMETOOLONG on valid name"),
+        Err(libc::EACCES) => inv_assert_eq!(
             inv.perm,
             Some(libc::EACCES),
             "Returned EACCES on path where we have permission"
         ),
-        Err(libc::EPERM) => assert_eq!(
+        Err(libc::EPERM) => inv_assert_eq!(
             inv.perm,
             Some(libc::EPERM),
             "Returned EPERM on path where we have permission"
         ),
-        Err(libc::ENOENT) => assert!(!inv.child_exists, "Returned ENOENT on extant directory"),
-        Err(e) => panic!("Got unexpected error code {}", e),
+        Err(libc::ENOENT) => inv_assert!(!inv.child_exists, "Returned ENOENT on extant directory"),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
     }
+    Ok(())
 }
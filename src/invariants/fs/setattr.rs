@@ -1,15 +1,17 @@
-use std::{os::linux::fs::MetadataExt, path::Path, sync::MutexGuard};
-
-use asserteq_pretty::assert_eq_pretty;
+use std::{ffi::OsStr, os::linux::fs::MetadataExt, path::Path, sync::MutexGuard};
 
 use crate::{
     file_attr::FileAttr,
+    inv_assert, inv_assert_eq, inv_assert_eq_pretty, inv_fail,
     invariants::{
+        acl::{Acl, ACL_ACCESS},
         common::{common_pre_ino, CPI},
-        perm::{check_perm, sgids, Access},
+        inode_flags::{immutable, unchangeable},
+        perm::{caps, check_perm, sgids, Access, CAP_FSETID},
+        violation::Violation,
         FSData,
     },
-    log_more,
+    log_inv, log_more,
     logging::CallID,
     req_rep::Request,
 };
@@ -66,6 +68,7 @@ pub fn inv_setattr_before(
     if let Some(uid) = uid {
         if perm.is_none() {
             perm = check_perm(
+                callid,
                 req.uid(),
                 req.gid(),
                 req.pid(),
@@ -78,6 +81,7 @@ pub fn inv_setattr_before(
     if let Some(gid) = gid {
         if perm.is_none() {
             perm = check_perm(
+                callid,
                 req.uid(),
                 req.gid(),
                 req.pid(),
@@ -89,6 +93,7 @@ pub fn inv_setattr_before(
     }
     if (mode.is_some()) && perm.is_none() {
         perm = check_perm(
+            callid,
             req.uid(),
             req.gid(),
             req.pid(),
@@ -99,6 +104,7 @@ pub fn inv_setattr_before(
     }
     if (size.is_some()) && perm.is_none() {
         perm = check_perm(
+            callid,
             req.uid(),
             req.gid(),
             req.pid(),
@@ -108,9 +114,24 @@ pub fn inv_setattr_before(
         );
     }
 
+    // Immutable refuses every change, append-only every one but setting the times to now.
+    let times_set = [atime, mtime]
+        .iter()
+        .any(|x| matches!(x, Some(fuser::TimeOrNow::SpecificTime(_))));
+    let touch = atime.is_some() || mtime.is_some();
+    let flags_perm =
+        if mode.is_some() || uid.is_some() || gid.is_some() || size.is_some() || times_set {
+            unchangeable(fs_data, ino)
+        } else if touch {
+            immutable(fs_data, ino)
+        } else {
+            None
+        };
+    let perm = flags_perm.or(perm);
+
     let sgids = sgids(req.pid());
     let mut clear_setgid = true;
-    if req.uid() == 0 {
+    if caps(req.pid(), req.uid()).has(CAP_FSETID) {
         clear_setgid = false;
     }
     if req.gid() == inode_path.symlink_metadata().unwrap().st_gid() {
@@ -146,17 +167,16 @@ pub fn inv_setattr_after(
     inv: SetattrInv,
     res: &Result<fuser::FileAttr, i32>,
     fs_data: &mut MutexGuard<'_, FSData>,
-) {
-    log_more!(callid, "invariant={:?}", inv);
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
     match res {
         Ok(v) => {
-            assert!(
+            inv_assert!(
                 inv.perm.is_none(),
                 "Failed to return error on permission denied"
             );
-            assert!(inv.exists, "Failed to return ENOENT on nonexistant parent");
-            #[cfg(feature = "check-meta")]
-            {
+            inv_assert!(inv.exists, "Failed to return ENOENT on nonexistant parent");
+            if fs_data.checks.meta {
                 let ic = &mut fs_data.INV_INODE_CONTENTS;
                 let mut fa = ic.get(&inv.args.ino).expect("Inode does not exist").clone();
                 fa.ctime = v.ctime;
@@ -168,11 +188,13 @@ pub fn inv_setattr_after(
                 }
                 if let Some(v) = inv.args.size {
                     fa.size = v;
-                    #[cfg(feature = "check-data")]
-                    {
+                    if fs_data.checks.data {
                         let fc = &mut fs_data.INV_FILE_CONTENTS;
                         let fd = fc.get_mut(&inv.args.ino).expect("Contents do not exist");
                         fd.resize(v.try_into().unwrap(), 0);
+                        if let Some(r) = fs_data.INV_FILE_DATA.get_mut(&inv.args.ino) {
+                            r.truncate(v);
+                        }
                     }
                 }
                 if let Some(v) = inv.args.uid {
@@ -181,22 +203,33 @@ pub fn inv_setattr_after(
                 if let Some(v) = inv.args.gid {
                     fa.gid = v;
                 }
-                println!("{:o} : {:o}", FileAttr::from(v).perm, fa.perm);
-                assert_eq_pretty!(FileAttr::from(v).reset_times(), fa.reset_times());
+                log_more!(callid, "{:o} : {:o}", FileAttr::from(v).perm, fa.perm);
+                inv_assert_eq_pretty!(fa.reset_times(), FileAttr::from(v).reset_times());
                 fs_data.INV_INODE_CONTENTS.insert(inv.args.ino, fa);
             }
+            // The access ACL mirrors the mode bits, so chmod rewrites it.
+            if let (Some(mode), true) = (inv.args.mode, fs_data.checks.xattr) {
+                if let Some(x) = fs_data.INV_XATTR_CONTENTS.get_mut(&inv.args.ino) {
+                    if let Some(mut acl) = x.get(OsStr::new(ACL_ACCESS)).and_then(|v| Acl::parse(v))
+                    {
+                        acl.chmod(mode);
+                        x.insert(ACL_ACCESS.into(), acl.to_bytes());
+                    }
+                }
+            }
         }
-        Err(libc::EACCES) => assert_eq!(
+        Err(libc::EACCES) => inv_assert_eq!(
             inv.perm,
             Some(libc::EACCES),
             "Returned EACCES on path where we have permission"
         ),
-        Err(libc::EPERM) => assert_eq!(
+        Err(libc::EPERM) => inv_assert_eq!(
             inv.perm,
             Some(libc::EPERM),
             "Returned EPERM on path where we have permission"
         ),
-        Err(libc::EFBIG) => println!("FBIG"),
-        Err(e) => panic!("Got unexpected error code {}", e),
+        Err(libc::EFBIG) => log_more!(callid, "FBIG"),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
     }
+    Ok(())
 }
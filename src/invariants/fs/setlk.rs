@@ -0,0 +1,76 @@
+use std::sync::MutexGuard;
+
+use crate::{
+    inv_assert, inv_fail,
+    invariants::{posix_locks::PosixLock, violation::Violation, FSData},
+    log_inv,
+    logging::CallID,
+    req_rep::Request,
+};
+
+#[derive(Debug)]
+#[must_use]
+pub struct SetlkInv {
+    ino: u64,
+    lock: PosixLock,
+    /// The locks other owners hold that stand in the way.
+    conflicts: Vec<PosixLock>,
+}
+
+pub fn inv_setlk_before(
+    _callid: CallID,
+    _req: &Request,
+    ino: u64,
+    _fh: u64,
+    lock_owner: u64,
+    start: u64,
+    end: u64,
+    typ: i32,
+    pid: u32,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> SetlkInv {
+    let conflicts = if typ == libc::F_UNLCK {
+        Vec::new()
+    } else {
+        fs_data
+            .posix_locks
+            .conflicts(ino, lock_owner, start, end, typ)
+    };
+    SetlkInv {
+        ino,
+        lock: PosixLock {
+            owner: lock_owner,
+            pid,
+            start,
+            end,
+            typ,
+        },
+        conflicts,
+    }
+}
+
+pub fn inv_setlk_after(
+    callid: CallID,
+    inv: SetlkInv,
+    res: &Result<(), i32>,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
+    match res {
+        Ok(()) => {
+            inv_assert!(
+                inv.conflicts.is_empty(),
+                "Granted a lock that conflicts with {:?}",
+                inv.conflicts
+            );
+            fs_data.posix_locks.set(inv.ino, inv.lock);
+        }
+        Err(libc::EAGAIN) | Err(libc::EACCES) => inv_assert!(
+            !inv.conflicts.is_empty(),
+            "Refused a lock nothing conflicts with"
+        ),
+        Err(libc::ENOLCK) => (),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
+    }
+    Ok(())
+}
@@ -1,48 +1,175 @@
-use std::{path::Path, sync::MutexGuard};
+use std::{
+    ffi::OsString,
+    os::{linux::fs::MetadataExt, unix::prelude::OsStrExt},
+    path::Path,
+    sync::MutexGuard,
+};
 
 use crate::{
+    inv_assert, inv_fail,
     invariants::{
+        acl::{Acl, ACL_ACCESS},
         common::{common_pre_ino, CPI},
-        perm::{check_perm, Access},
+        inode_flags::unchangeable,
+        perm::{caps, check_perm, sgids, xattr_unchecked, Access, CAP_FSETID},
+        violation::Violation,
         FSData,
     },
-    log_more,
+    log_inv, log_more,
     logging::CallID,
+    req_rep::Request,
 };
 
+/// The longest attribute name the VFS accepts.
+pub const XATTR_NAME_MAX: usize = 255;
+/// The largest attribute value the VFS accepts.
+pub const XATTR_SIZE_MAX: usize = 65536;
+
 #[derive(Debug)]
 #[must_use]
-pub struct SetxattrInv {}
+pub struct SetxattrInv {
+    ino: u64,
+    name: OsString,
+    value: Vec<u8>,
+    flags: i32,
+    exists: bool,
+    present: bool,
+    toolong: bool,
+    perm: Option<i32>,
+    unchecked: bool,
+    acl: Option<Acl>,
+    clear_setgid: bool,
+}
 
 pub fn inv_setxattr_before(
     callid: CallID,
-    req: &fuser::Request<'_>,
+    req: &Request,
     base: &Path,
     ino: u64,
-    _name: &std::ffi::OsStr,
-    _value: &[u8],
-    _flags: i32,
+    name: &std::ffi::OsStr,
+    value: &[u8],
+    flags: i32,
     _position: u32,
     fs_data: &mut MutexGuard<'_, FSData>,
 ) -> SetxattrInv {
-    let CPI { inode_path, .. } = common_pre_ino(callid, ino, fs_data);
+    let CPI { inode_path, exists } = common_pre_ino(callid, ino, fs_data);
 
-    let _perm = check_perm(
+    let perm = check_perm(
+        callid,
         req.uid(),
         req.gid(),
         req.pid(),
         &inode_path,
         base,
-        Access::Lookup,
+        Access::XattrWrite(name.to_os_string()),
     );
+    let perm = unchangeable(fs_data, ino).or(perm);
 
-    SetxattrInv {}
-}
-pub fn inv_setxattr_after(callid: CallID, inv: SetxattrInv, _res: &Result<(), i32>) {
-    log_more!(callid, "invariant={:?}", inv);
+    let present = fs_data
+        .INV_XATTR_CONTENTS
+        .get(&ino)
+        .map_or(false, |x| x.contains_key(name));
 
-    #[cfg(feature = "check-xattr")]
-    {
-        compile_error!("XATTR validation is not yet implemented")
+    // Setting the access ACL sets the mode bits too, and like chmod drops setgid for
+    // requesters outside the owning group.
+    let acl = (name == ACL_ACCESS).then(|| Acl::parse(value)).flatten();
+    let clear_setgid = match inode_path.symlink_metadata() {
+        Ok(m) => {
+            !caps(req.pid(), req.uid()).has(CAP_FSETID)
+                && req.gid() != m.st_gid()
+                && !sgids(req.pid()).contains(&m.st_gid())
+        }
+        Err(_) => false,
+    };
+
+    SetxattrInv {
+        ino,
+        name: name.to_os_string(),
+        value: value.to_vec(),
+        flags,
+        exists,
+        present,
+        toolong: name.len() > XATTR_NAME_MAX,
+        perm,
+        unchecked: xattr_unchecked(name),
+        acl,
+        clear_setgid,
+    }
+}
+pub fn inv_setxattr_after(
+    callid: CallID,
+    inv: SetxattrInv,
+    res: &Result<(), i32>,
+    fs_data: &mut MutexGuard<'_, FSData>,
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
+    let create = inv.flags & libc::XATTR_CREATE != 0;
+    let replace = inv.flags & libc::XATTR_REPLACE != 0;
+    let checked = fs_data.checks.xattr;
+    match res {
+        Ok(()) => {
+            inv_assert!(inv.exists, "Failed to return ENOENT on nonexistant inode");
+            inv_assert!(!inv.toolong, "Failed to return ERANGE on name too long");
+            inv_assert!(
+                inv.perm.is_none(),
+                "Failed to return error on permission denied"
+            );
+            if let (Some(acl), true) = (&inv.acl, fs_data.checks.meta) {
+                if let Some(fa) = fs_data.INV_INODE_CONTENTS.get_mut(&inv.ino) {
+                    fa.perm = (fa.perm & !0o777) | acl.mode() as u16;
+                    if inv.clear_setgid {
+                        fa.perm &= !0o2000;
+                    }
+                }
+            }
+            if checked {
+                inv_assert!(
+                    !create || !inv.present,
+                    "Failed to return EEXIST on XATTR_CREATE of an existing attribute"
+                );
+                inv_assert!(
+                    !replace || inv.present,
+                    "Failed to return ENODATA on XATTR_REPLACE of a missing attribute"
+                );
+                let xc = fs_data.INV_XATTR_CONTENTS.entry(inv.ino).or_default();
+                // An access ACL that says no more than the mode isn't kept.
+                if inv.acl.map_or(false, |x| x.is_minimal()) {
+                    xc.remove(&inv.name);
+                } else {
+                    xc.insert(inv.name, inv.value);
+                }
+            }
+        }
+        Err(libc::EEXIST) => inv_assert!(
+            !checked || (create && inv.present && inv.perm.is_none()),
+            "Returned EEXIST without XATTR_CREATE of an existing attribute"
+        ),
+        Err(libc::ENODATA) => inv_assert!(
+            !checked || (replace && !inv.present && inv.perm.is_none()),
+            "Returned ENODATA without XATTR_REPLACE of a missing attribute"
+        ),
+        Err(libc::ERANGE) => inv_assert!(inv.toolong, "Returned ERANGE on valid name"),
+        Err(libc::E2BIG) => inv_assert!(
+            inv.value.len() > XATTR_SIZE_MAX,
+            "Returned E2BIG on valid value"
+        ),
+        // Backends are free to run out of room for attributes, or not to support a namespace.
+        Err(libc::ENOSPC) | Err(libc::EOPNOTSUPP) => log_more!(callid, "Not supported"),
+        // The kernel parses system. attributes such as ACLs, and refuses malformed ones.
+        Err(libc::EINVAL) => inv_assert!(
+            inv.unchecked || inv.name.as_bytes().starts_with(b"system."),
+            "Returned EINVAL on an attribute the filesystem doesn't interpret"
+        ),
+        // security. attributes are up to the filesystem and LSMs.
+        Err(libc::EACCES) => inv_assert!(
+            inv.unchecked || inv.perm == Some(libc::EACCES),
+            "Returned EACCES on attribute where we have permission"
+        ),
+        Err(libc::EPERM) => inv_assert!(
+            inv.unchecked || inv.perm == Some(libc::EPERM),
+            "Returned EPERM on attribute where we have permission"
+        ),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
     }
+    Ok(())
 }
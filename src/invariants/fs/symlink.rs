@@ -1,20 +1,21 @@
 use std::{
+    collections::BTreeMap,
     ffi::OsString,
     os::unix::prelude::OsStrExt,
     path::{Path, PathBuf},
     sync::MutexGuard,
 };
 
-use asserteq_pretty::assert_eq_pretty;
-
 use crate::{
     file_attr::{FileAttr, FileType},
+    inv_assert, inv_assert_eq, inv_assert_eq_pretty, inv_fail,
     invariants::{
         common::{common_pre_parent_name, CPPN},
         perm::{check_perm, Access},
+        violation::Violation,
         FSData,
     },
-    log_more,
+    log_inv,
     logging::CallID,
     req_rep::Request,
 };
@@ -33,7 +34,7 @@ pub struct SymlinkInv {
     link: PathBuf,
 }
 pub fn inv_symlink_before(
-    _callid: CallID,
+    callid: CallID,
     req: &Request,
     base: &Path,
     parent: u64,
@@ -49,6 +50,7 @@ pub fn inv_symlink_before(
     } = common_pre_parent_name(parent, name, fs_data);
 
     let perm = check_perm(
+        callid,
         req.uid(),
         req.gid(),
         req.pid(),
@@ -74,19 +76,19 @@ pub fn inv_symlink_after(
     inv: SymlinkInv,
     res: &Result<fuser::FileAttr, i32>,
     fs_data: &mut MutexGuard<'_, FSData>,
-) {
-    log_more!(callid, "invariant={:?}", inv);
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
     match res {
         Ok(v) => {
-            assert!(
+            inv_assert!(
                 !inv.toolong,
                 "Failed to return ENAMETOOLONG on name too long"
             );
-            assert!(
+            inv_assert!(
                 inv.perm.is_none(),
                 "Failed to return error on permission denied"
             );
-            assert!(
+            inv_assert!(
                 inv.parent_exists,
                 "Failed to return ENOENT on nonexistant parent"
             );
@@ -107,24 +109,20 @@ pub fn inv_symlink_after(
                 blksize: 4096,
                 flags: 0,
             };
-            assert_eq_pretty!(FileAttr::from(v), fa);
-            #[cfg(feature = "check-meta")]
-            {
+            inv_assert_eq_pretty!(fa, FileAttr::from(v));
+            if fs_data.checks.meta {
                 let ic = &mut fs_data.INV_INODE_CONTENTS;
                 ic.insert(v.ino, fa);
             }
-            #[cfg(feature = "check-data")]
-            {
+            if fs_data.checks.data {
                 let fc = &mut fs_data.INV_FILE_CONTENTS;
                 fc.insert(v.ino, inv.link.as_os_str().as_bytes().to_vec());
             }
-            #[cfg(feature = "check-xattr")]
-            {
+            if fs_data.checks.xattr {
                 let xc = &mut fs_data.INV_XATTR_CONTENTS;
                 xc.insert(v.ino, BTreeMap::new());
             }
-            #[cfg(feature = "check-dirs")]
-            {
+            if fs_data.checks.dirs {
                 let dc = &mut fs_data.INV_DIR_CONTENTS;
                 dc.get_mut(&inv.parent)
                     .expect("Parent does not exist")
@@ -132,18 +130,19 @@ pub fn inv_symlink_after(
             }
             fs_data.INV_INODE_PATHS.insert(v.ino, inv.child_path);
         }
-        Err(libc::ENAMETOOLONG) => assert!(inv.toolong, "Returned ENAMETOOLONG on valid name"),
-        Err(libc::EACCES) => assert_eq!(
+        Err(libc::ENAMETOOLONG) => inv_assert!(inv.toolong, "Returned ENAMETOOLONG on valid name"),
+        Err(libc::EACCES) => inv_assert_eq!(
             inv.perm,
             Some(libc::EACCES),
             "Returned EACCES on path where we have permission"
         ),
-        Err(libc::EPERM) => assert_eq!(
+        Err(libc::EPERM) => inv_assert_eq!(
             inv.perm,
             Some(libc::EPERM),
             "Returned EPERM on path where we have permission"
         ),
-        Err(libc::ENOENT) => assert!(!inv.parent_exists, "Returned ENOENT on extant parent"),
-        Err(e) => panic!("Got unexpected error code {}", e),
+        Err(libc::ENOENT) => inv_assert!(!inv.parent_exists, "Returned ENOENT on extant parent"),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
     }
+    Ok(())
 }
@@ -1,12 +1,15 @@
 use std::{cmp::max, path::Path, sync::MutexGuard};
 
 use crate::{
+    inv_assert, inv_assert_eq, inv_fail,
     invariants::{
-        common::{common_pre_ino, CPI},
+        common::{common_pre_fh, CPI},
+        inode_flags::immutable,
         perm::{check_perm, Access},
+        violation::Violation,
         FSData,
     },
-    log_more,
+    log_inv,
     logging::CallID,
     req_rep::Request,
 };
@@ -19,6 +22,8 @@ pub struct WriteInv {
     ino: u64,
     offset: usize,
     data: Vec<u8>,
+    /// The handle was opened with `O_APPEND`.
+    append: bool,
 }
 
 pub fn inv_write_before(
@@ -26,7 +31,7 @@ pub fn inv_write_before(
     req: &Request,
     base: &Path,
     ino: u64,
-    _fh: u64,
+    fh: u64,
     offset: i64,
     data: &[u8],
     _write_flags: u32,
@@ -34,16 +39,26 @@ pub fn inv_write_before(
     _lock_owner: Option<u64>,
     fs_data: &mut MutexGuard<'_, FSData>,
 ) -> WriteInv {
-    let CPI { inode_path, exists } = common_pre_ino(callid, ino, fs_data);
-
-    let perm = check_perm(
-        req.uid(),
-        req.gid(),
-        req.pid(),
-        &inode_path,
-        base,
-        Access::Lookup,
-    );
+    let (exists, perm) = match common_pre_fh(callid, ino, fs_data) {
+        Some(CPI { inode_path, exists }) => (
+            exists,
+            check_perm(
+                callid,
+                req.uid(),
+                req.gid(),
+                req.pid(),
+                &inode_path,
+                base,
+                Access::Lookup,
+            ),
+        ),
+        None => (true, None),
+    };
+    let perm = perm.or_else(|| immutable(fs_data, ino));
+    // Append-only is enforced when a file is opened for writing, so on such a file every
+    // handle that can write appends. Appends go to the end of the file, whatever the offset.
+    let fl = unsafe { libc::fcntl(fh.try_into().unwrap(), libc::F_GETFL) };
+    let append = fl != -1 && fl & libc::O_APPEND != 0;
 
     WriteInv {
         ino,
@@ -51,6 +66,7 @@ pub fn inv_write_before(
         data: data.to_vec(),
         exists,
         perm,
+        append,
     }
 }
 pub fn inv_write_after(
@@ -58,45 +74,50 @@ pub fn inv_write_after(
     inv: WriteInv,
     res: &Result<isize, i32>,
     fs_data: &mut MutexGuard<'_, FSData>,
-) {
-    log_more!(callid, "invariant={:?}", inv);
+) -> Result<(), Violation> {
+    log_inv!(callid, inv);
     match res {
         Ok(v) => {
-            assert!(
+            inv_assert!(
                 inv.perm.is_none(),
                 "Failed to return error on permission denied"
             );
-            assert!(inv.exists, "Failed to return ENOENT on nonexistant file");
-            assert_eq!(inv.data.len(), usize::try_from(*v).unwrap());
-            #[cfg(feature = "check-meta")]
-            {
+            inv_assert!(inv.exists, "Failed to return ENOENT on nonexistant file");
+            inv_assert_eq!(inv.data.len(), usize::try_from(*v).unwrap());
+            let offset = match fs_data.INV_INODE_CONTENTS.get(&inv.ino) {
+                Some(fa) if inv.append => fa.size.try_into().unwrap(),
+                _ => inv.offset,
+            };
+            if fs_data.checks.meta {
                 let ic = &mut fs_data.INV_INODE_CONTENTS;
                 let fa = ic.get_mut(&inv.ino).expect("File missing inode");
-                fa.size = max(fa.size, (inv.offset + inv.data.len()).try_into().unwrap());
+                fa.size = max(fa.size, (offset + inv.data.len()).try_into().unwrap());
                 //fa.blocks = ((fa.size + (u64::from(fa.blksize) - 1)) / u64::from(fa.blksize)) * (u64::from(fa.blksize) / 512);
             }
-            #[cfg(feature = "check-data")]
-            {
+            if fs_data.checks.data {
                 let fc = &mut fs_data.INV_FILE_CONTENTS;
                 let fd = fc.get_mut(&inv.ino).expect("File missing contents");
-                if inv.offset + inv.data.len() > fd.len() {
-                    fd.resize(inv.offset + inv.data.len(), 0);
+                if offset + inv.data.len() > fd.len() {
+                    fd.resize(offset + inv.data.len(), 0);
+                }
+                fd[offset..offset + inv.data.len()].copy_from_slice(&inv.data);
+                if let Some(r) = fs_data.INV_FILE_DATA.get_mut(&inv.ino) {
+                    r.insert(offset as u64, (offset + inv.data.len()) as u64);
                 }
-                fd[inv.offset..inv.offset + inv.data.len()].copy_from_slice(&inv.data);
             }
         }
-        Err(libc::EACCES) => assert_eq!(
+        Err(libc::EACCES) => inv_assert_eq!(
             inv.perm,
             Some(libc::EACCES),
             "Returned EACCES on path where we have permission"
         ),
-        Err(libc::EPERM) => assert_eq!(
+        Err(libc::EPERM) => inv_assert_eq!(
             inv.perm,
             Some(libc::EPERM),
             "Returned EPERM on path where we have permission"
         ),
-        Err(libc::ENOENT) => assert!(!inv.exists, "Returned ENOENT on extant file"),
-        Err(e) => panic!("Got unexpected error code {}", e),
+        Err(libc::ENOENT) => inv_assert!(!inv.exists, "Returned ENOENT on extant file"),
+        Err(e) => inv_fail!("Got unexpected error code {}", e),
     }
     /*let mut fc = INV_FILE_CONTENTS.lock().unwrap();
     let fce = fc.get_mut(&inv.ino).unwrap();
@@ -109,4 +130,5 @@ pub fn inv_write_after(
     ic.insert(atr.st_ino(), atr.into());
 
     todo!();*/
+    Ok(())
 }
@@ -0,0 +1,68 @@
+//! The inode flags the VFS enforces on other calls: immutable and append-only.
+//!
+//! Only those two are tracked. The rest can be inherited from the parent or adjusted by the
+//! filesystem as they are set, and change nothing about what other calls may do. The flags
+//! aren't part of `stat`, so they're rebuilt by the scan on every mount rather than saved.
+
+use std::{fs::File, os::unix::prelude::AsRawFd, path::Path};
+
+use super::FSData;
+
+pub const FS_IMMUTABLE_FL: u32 = 0x10;
+pub const FS_APPEND_FL: u32 = 0x20;
+pub const FS_XFLAG_IMMUTABLE: u32 = 0x8;
+pub const FS_XFLAG_APPEND: u32 = 0x10;
+
+/// `_IOR('X', 31, struct fsxattr)`, which libc doesn't export.
+pub const FS_IOC_FSGETXATTR: u32 = 0x801c581f;
+
+/// The flags the model tracks.
+pub const TRACKED: u32 = FS_IMMUTABLE_FL | FS_APPEND_FL;
+
+/// The tracked flags of `ino`.
+pub fn flags(fs_data: &FSData, ino: u64) -> u32 {
+    fs_data.INV_INODE_FLAGS.get(&ino).copied().unwrap_or(0)
+}
+
+pub fn set_flags(fs_data: &mut FSData, ino: u64, flags: u32) {
+    if !fs_data.checks.meta {
+        return;
+    }
+    if flags & TRACKED == 0 {
+        fs_data.INV_INODE_FLAGS.remove(&ino);
+    } else {
+        fs_data.INV_INODE_FLAGS.insert(ino, flags & TRACKED);
+    }
+}
+
+/// EPERM if `ino` is immutable, which refuses any change to it.
+pub fn immutable(fs_data: &FSData, ino: u64) -> Option<i32> {
+    (flags(fs_data, ino) & FS_IMMUTABLE_FL != 0).then(|| libc::EPERM)
+}
+
+/// EPERM if `ino` is immutable or append-only, which refuses any change but adding to it.
+pub fn unchangeable(fs_data: &FSData, ino: u64) -> Option<i32> {
+    (flags(fs_data, ino) & TRACKED != 0).then(|| libc::EPERM)
+}
+
+/// EPERM if `child` can't be removed from `parent`, or replaced there.
+/// An immutable parent refuses that ahead of any permission check, see [`immutable`].
+pub fn undeletable(fs_data: &FSData, parent: u64, child: Option<u64>) -> Option<i32> {
+    unchangeable(fs_data, parent).or_else(|| child.and_then(|x| unchangeable(fs_data, x)))
+}
+
+/// The tracked flags of a file or directory in the backend. Filesystems without flags have
+/// none set.
+pub fn read_flags(path: &Path) -> u32 {
+    let file = match File::open(path) {
+        Ok(v) => v,
+        Err(_) => return 0,
+    };
+    let mut flags: libc::c_int = 0;
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) };
+    if res == 0 {
+        flags as u32 & TRACKED
+    } else {
+        0
+    }
+}
@@ -0,0 +1,135 @@
+//! The passes being made over open directory handles.
+//!
+//! POSIX leaves it open whether a name added or removed after a pass started shows up in it, but
+//! every other entry has to show up exactly once. Changes to a directory are noted on its
+//! listings as they happen, so a name removed and put back between two reads is still excused.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::{OsStr, OsString},
+};
+
+#[derive(Debug)]
+pub struct Listing {
+    pub ino: u64,
+    /// The offset a read continuing the pass is expected to ask for.
+    next: i64,
+    /// Set when a read skipped around instead of continuing the pass, which then says nothing
+    /// about what was missed or repeated.
+    partial: bool,
+    seen: BTreeMap<OsString, u32>,
+    touched: BTreeSet<OsString>,
+}
+
+impl Listing {
+    fn new(ino: u64) -> Self {
+        Self {
+            ino,
+            next: 0,
+            partial: false,
+            seen: BTreeMap::new(),
+            touched: BTreeSet::new(),
+        }
+    }
+
+    /// Account for a read at `offset`: one at 0 starts a new pass, one anywhere but where the
+    /// last left off makes the current one partial.
+    pub fn read_at(&mut self, offset: i64) {
+        if offset == 0 {
+            *self = Self::new(self.ino);
+        } else if offset != self.next {
+            self.partial = true;
+        }
+    }
+
+    /// Note `name` as returned, with `next` as the offset to continue from. Returns how many
+    /// times the pass has now returned it.
+    pub fn see(&mut self, name: &OsStr, next: i64) -> u32 {
+        self.next = next;
+        let n = self.seen.entry(name.to_os_string()).or_default();
+        *n += 1;
+        *n
+    }
+
+    pub fn partial(&self) -> bool {
+        self.partial
+    }
+
+    /// Whether `name` was added or removed since the pass started.
+    pub fn touched(&self, name: &OsStr) -> bool {
+        self.touched.contains(name)
+    }
+
+    /// The names of `contents`, with `.` and `..`, that were there all pass but were not returned.
+    pub fn missing(&self, contents: &BTreeMap<OsString, u64>) -> Vec<OsString> {
+        [OsStr::new("."), OsStr::new("..")]
+            .into_iter()
+            .chain(contents.keys().map(|x| x.as_os_str()))
+            .filter(|x| !self.touched(x) && !self.seen.contains_key(*x))
+            .map(|x| x.to_os_string())
+            .collect()
+    }
+}
+
+/// The listing of every open directory handle.
+#[derive(Debug, Default)]
+pub struct Listings(BTreeMap<u64, Listing>);
+
+impl Listings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(&mut self, fh: u64, ino: u64) {
+        self.0.insert(fh, Listing::new(ino));
+    }
+
+    pub fn close(&mut self, fh: u64) {
+        self.0.remove(&fh);
+    }
+
+    pub fn get_mut(&mut self, fh: u64) -> Option<&mut Listing> {
+        self.0.get_mut(&fh)
+    }
+
+    /// Note that `name` was added to or removed from `ino`.
+    pub fn touch(&mut self, ino: u64, name: &OsStr) {
+        for l in self.0.values_mut().filter(|l| l.ino == ino) {
+            l.touched.insert(name.to_os_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, ffi::OsString};
+
+    use super::Listings;
+
+    #[test]
+    fn test_pass() {
+        let contents: BTreeMap<OsString, u64> =
+            [("a", 2), ("b", 3)].map(|(n, i)| (n.into(), i)).into();
+        let mut l = Listings::new();
+        l.open(1, 1);
+        let p = l.get_mut(1).unwrap();
+        p.read_at(0);
+        assert_eq!(p.see(".".as_ref(), 1), 1);
+        p.read_at(1);
+        assert_eq!(p.see("a".as_ref(), 2), 1);
+        assert_eq!(p.missing(&contents), vec![OsString::from(".."), "b".into()]);
+
+        l.touch(1, "b".as_ref());
+        l.touch(2, "..".as_ref());
+        let p = l.get_mut(1).unwrap();
+        assert_eq!(p.missing(&contents), vec![OsString::from("..")]);
+        p.read_at(2);
+        assert!(!p.partial());
+        p.read_at(7);
+        assert!(p.partial());
+
+        p.read_at(0);
+        assert!(!p.partial() && !p.touched("b".as_ref()));
+        assert_eq!(p.missing(&contents).len(), 4);
+    }
+}
@@ -0,0 +1,472 @@
+//! Locking the model at a finer grain than the [`FSData`] mutex.
+//!
+//! The `FSData` mutex is only held while an invariant is computed or checked, never across the
+//! backend call. An operation that changes the model locks the inodes it changes in
+//! [`ModelLocks`], so changes to the same inode are serialized in the order they hit the backend,
+//! and changes to unrelated inodes are checked concurrently.
+//!
+//! Operations that only observe an inode (getattr, read, and the child of a lookup) don't lock it.
+//! They open a watch on it instead, which records the state of the inode around every change
+//! that overlapped the call. The observation is accepted if it matches any of those states,
+//! i.e. if the call can be ordered somewhere among the changes it raced with.
+//!
+//! Anything that can move paths around or change permissions takes the whole tree exclusively,
+//! because the `before` half of every check resolves paths and permissions on the backend.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Condvar, Mutex, MutexGuard},
+};
+
+use crate::file_attr::FileAttr;
+
+use crate::logging::{emit, enabled, CallID, Event};
+
+use super::{diff::diff, fs::init::resync, sparse::DataRanges, violation::Violation, FSData};
+
+/// Orders the changes made to the model: a change with a lower ticket started first.
+pub type Ticket = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tree {
+    /// Paths and permissions stay put. Inode locks are honoured.
+    Shared,
+    /// Nothing else runs. Inode locks are implied.
+    Exclusive,
+}
+
+#[derive(Debug, Default)]
+struct LockState {
+    next: Ticket,
+    tree_readers: usize,
+    tree_writer: bool,
+    /// Exclusive tree lockers that are waiting, so new readers don't starve them.
+    tree_waiting: usize,
+    shared: BTreeMap<u64, usize>,
+    exclusive: BTreeMap<u64, Ticket>,
+}
+
+impl LockState {
+    fn available(&self, tree: Tree, shared: &[u64], exclusive: &[u64]) -> bool {
+        match tree {
+            Tree::Exclusive => !self.tree_writer && self.tree_readers == 0,
+            Tree::Shared => {
+                !self.tree_writer
+                    && self.tree_waiting == 0
+                    && shared.iter().all(|x| !self.exclusive.contains_key(x))
+                    && exclusive
+                        .iter()
+                        .all(|x| !self.exclusive.contains_key(x) && !self.shared.contains_key(x))
+            }
+        }
+    }
+
+    fn ticket(&mut self) -> Ticket {
+        let t = self.next;
+        self.next += 1;
+        t
+    }
+}
+
+/// Per-inode reader/writer locks on the model, under a lock on the whole tree.
+///
+/// Every lock an operation needs is taken in one go, so there is no lock order to get wrong.
+/// Never wait on these with the `FSData` mutex held.
+#[derive(Debug, Default)]
+pub struct ModelLocks {
+    state: Mutex<LockState>,
+    released: Condvar,
+}
+
+impl ModelLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn lock(&self, tree: Tree, shared: &[u64], exclusive: &[u64]) -> ModelGuard<'_> {
+        let mut exclusive = exclusive.to_vec();
+        exclusive.sort_unstable();
+        exclusive.dedup();
+        let shared: Vec<u64> = shared
+            .iter()
+            .copied()
+            .filter(|x| !exclusive.contains(x))
+            .collect();
+
+        let mut st = self.state.lock().unwrap();
+        if tree == Tree::Exclusive {
+            st.tree_waiting += 1;
+        }
+        while !st.available(tree, &shared, &exclusive) {
+            st = self.released.wait(st).unwrap();
+        }
+        let ticket = st.ticket();
+        match tree {
+            Tree::Exclusive => {
+                st.tree_waiting -= 1;
+                st.tree_writer = true;
+            }
+            Tree::Shared => {
+                st.tree_readers += 1;
+                for i in &shared {
+                    *st.shared.entry(*i).or_default() += 1;
+                }
+                for i in &exclusive {
+                    st.exclusive.insert(*i, ticket);
+                }
+            }
+        }
+        ModelGuard {
+            locks: self,
+            tree,
+            shared,
+            exclusive,
+            ticket,
+        }
+    }
+
+    /// A ticket that no change has yet, marking the end of an observation.
+    pub fn ticket(&self) -> Ticket {
+        self.state.lock().unwrap().ticket()
+    }
+
+    /// Wait until every change to `ino` that started before `end` has finished.
+    pub fn wait_for(&self, ino: u64, end: Ticket) {
+        let mut st = self.state.lock().unwrap();
+        while st.exclusive.get(&ino).map_or(false, |t| *t < end) {
+            st = self.released.wait(st).unwrap();
+        }
+    }
+}
+
+#[must_use]
+pub struct ModelGuard<'a> {
+    locks: &'a ModelLocks,
+    tree: Tree,
+    shared: Vec<u64>,
+    exclusive: Vec<u64>,
+    ticket: Ticket,
+}
+
+impl ModelGuard<'_> {
+    pub fn ticket(&self) -> Ticket {
+        self.ticket
+    }
+}
+
+impl Drop for ModelGuard<'_> {
+    fn drop(&mut self) {
+        let mut st = self.locks.state.lock().unwrap();
+        match self.tree {
+            Tree::Exclusive => st.tree_writer = false,
+            Tree::Shared => {
+                st.tree_readers -= 1;
+                for i in &self.shared {
+                    let n = st.shared.get_mut(i).unwrap();
+                    *n -= 1;
+                    if *n == 0 {
+                        st.shared.remove(i);
+                    }
+                }
+                for i in &self.exclusive {
+                    st.exclusive.remove(i);
+                }
+            }
+        }
+        drop(st);
+        self.locks.released.notify_all();
+    }
+}
+
+/// The parts of the model for one inode that can change under an observer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InodeState {
+    attr: Option<FileAttr>,
+    data: Option<Vec<u8>>,
+    ranges: Option<DataRanges>,
+}
+
+impl InodeState {
+    fn capture(fs_data: &FSData, ino: u64) -> Self {
+        Self {
+            attr: fs_data.INV_INODE_CONTENTS.get(&ino).cloned(),
+            data: fs_data.INV_FILE_CONTENTS.get(&ino).cloned(),
+            ranges: fs_data.INV_FILE_DATA.get(&ino).cloned(),
+        }
+    }
+
+    /// Put this state in the model, returning the one it replaced.
+    fn install(self, fs_data: &mut FSData, ino: u64) -> Self {
+        let prev = Self::capture(fs_data, ino);
+        match self.attr {
+            Some(v) => fs_data.INV_INODE_CONTENTS.insert(ino, v),
+            None => fs_data.INV_INODE_CONTENTS.remove(&ino),
+        };
+        match self.data {
+            Some(v) => fs_data.INV_FILE_CONTENTS.insert(ino, v),
+            None => fs_data.INV_FILE_CONTENTS.remove(&ino),
+        };
+        match self.ranges {
+            Some(v) => fs_data.INV_FILE_DATA.insert(ino, v),
+            None => fs_data.INV_FILE_DATA.remove(&ino),
+        };
+        prev
+    }
+}
+
+#[derive(Debug)]
+struct Watch {
+    ino: u64,
+    end: Option<Ticket>,
+    states: Vec<InodeState>,
+}
+
+/// An open watch, returned by [`Watches::open`].
+#[derive(Debug)]
+#[must_use]
+pub struct WatchId {
+    id: u64,
+    pub ino: u64,
+}
+
+/// The observations in flight, and the states of the inodes they watch.
+#[derive(Debug, Default)]
+pub struct Watches {
+    next: u64,
+    active: BTreeMap<u64, Watch>,
+}
+
+impl Watches {
+    pub fn open(&mut self, ino: u64) -> WatchId {
+        let id = self.next;
+        self.next += 1;
+        self.active.insert(
+            id,
+            Watch {
+                ino,
+                end: None,
+                states: vec![],
+            },
+        );
+        WatchId { id, ino }
+    }
+
+    /// The observation is over; changes that start at or after `end` can't be ordered before it.
+    pub fn close(&mut self, watch: &WatchId, end: Ticket) {
+        self.active.get_mut(&watch.id).unwrap().end = Some(end);
+    }
+
+    /// The states the inode went through while watched, oldest first.
+    /// Empty if nothing changed it.
+    pub fn finish(&mut self, watch: WatchId) -> Vec<InodeState> {
+        self.active.remove(&watch.id).unwrap().states
+    }
+
+    /// Drop a watch whose observation never happened.
+    pub fn cancel(&mut self, watch: WatchId) {
+        self.active.remove(&watch.id);
+    }
+
+    fn watching(&self, ino: u64) -> bool {
+        self.active.values().any(|w| w.ino == ino)
+    }
+}
+
+/// Apply a change to the model of `inos`, and log what it changed. If the change finds a
+/// violation it may not have been applied, so `inos` are resynced from the backend instead.
+pub fn apply(
+    callid: CallID,
+    fs_data: &mut MutexGuard<'_, FSData>,
+    inos: &[u64],
+    change: impl FnOnce(&mut MutexGuard<'_, FSData>) -> Result<(), Violation>,
+) -> Result<(), Violation> {
+    let before = enabled(2).then(|| {
+        let entries = fs_data.with_entries(inos);
+        let before = fs_data.slice(&entries);
+        (entries, before)
+    });
+    let res = change(fs_data);
+    if res.is_err() {
+        resync(callid, fs_data, inos);
+    }
+    if let Some((mut entries, before)) = before {
+        entries.extend(fs_data.with_entries(inos));
+        for d in diff(&before, &fs_data.slice(&entries)) {
+            emit(Event::Model { callid, diff: d });
+        }
+    }
+    res
+}
+
+/// [`apply`] a change to `inos` made by the operation holding `ticket`.
+/// The state after it is recorded for every watch that could be ordered after it. A watch that
+/// hasn't seen a change yet also gets the state before it, which is where it started from.
+/// Whatever was synced of `inos` is no longer promised to survive a crash.
+pub fn record(
+    callid: CallID,
+    fs_data: &mut MutexGuard<'_, FSData>,
+    inos: &[u64],
+    ticket: Ticket,
+    change: impl FnOnce(&mut MutexGuard<'_, FSData>) -> Result<(), Violation>,
+) -> Result<(), Violation> {
+    fs_data.durable.forget(callid, inos);
+    let changed = inos;
+    let inos: Vec<u64> = inos
+        .iter()
+        .copied()
+        .filter(|x| fs_data.watches.watching(*x))
+        .collect();
+    for ino in &inos {
+        let state = InodeState::capture(fs_data, *ino);
+        for w in fs_data.watches.active.values_mut() {
+            if w.ino == *ino && w.states.is_empty() {
+                w.states.push(state.clone());
+            }
+        }
+    }
+    let res = apply(callid, fs_data, changed, change);
+    for ino in &inos {
+        let state = InodeState::capture(fs_data, *ino);
+        for w in fs_data.watches.active.values_mut() {
+            if w.ino == *ino && w.end.map_or(true, |e| ticket < e) {
+                w.states.push(state.clone());
+            }
+        }
+    }
+    res
+}
+
+/// Run an observer's `check` against each state `ino` went through, newest first,
+/// and accept the first ordering it passes in.
+pub fn check_serializations(
+    fs_data: &mut MutexGuard<'_, FSData>,
+    ino: u64,
+    states: Vec<InodeState>,
+    mut check: impl FnMut(&mut MutexGuard<'_, FSData>) -> Result<(), Violation>,
+) -> Result<(), Violation> {
+    if states.is_empty() {
+        return check(fs_data);
+    }
+    let orderings = states.len();
+    let mut newest = None;
+    for state in states.into_iter().rev() {
+        let current = state.install(fs_data, ino);
+        let res = check(fs_data);
+        current.install(fs_data, ino);
+        match res {
+            Ok(()) => return Ok(()),
+            Err(v) => {
+                newest.get_or_insert(v);
+            }
+        }
+    }
+    let v = newest.unwrap();
+    if orderings == 1 {
+        return Err(v);
+    }
+    Err(Violation {
+        message: format!(
+            "{} (in all {} orderings with concurrent changes)",
+            v.message, orderings
+        ),
+        ..v
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    use crate::{
+        inv_assert_eq,
+        invariants::{violation::Violation, FSData},
+    };
+
+    use super::{check_serializations, record, ModelLocks, Tree};
+
+    #[test]
+    fn test_inode_locks() {
+        let locks = ModelLocks::new();
+        let done = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            let a = locks.lock(Tree::Shared, &[], &[2]);
+            // A different inode doesn't wait.
+            drop(locks.lock(Tree::Shared, &[], &[3]));
+            let t = s.spawn(|| {
+                let _b = locks.lock(Tree::Shared, &[2], &[]);
+                done.load(Ordering::SeqCst)
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            done.store(true, Ordering::SeqCst);
+            drop(a);
+            assert!(t.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_tree_exclusive() {
+        let locks = ModelLocks::new();
+        let done = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            let a = locks.lock(Tree::Shared, &[], &[]);
+            let t = s.spawn(|| {
+                let _b = locks.lock(Tree::Exclusive, &[], &[]);
+                done.load(Ordering::SeqCst)
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            done.store(true, Ordering::SeqCst);
+            drop(a);
+            assert!(t.join().unwrap());
+        });
+    }
+
+    fn check_contents(fs_data: &FSData, expected: &[u8]) -> Result<(), Violation> {
+        inv_assert_eq!(
+            Some(&expected.to_vec()),
+            fs_data.INV_FILE_CONTENTS.get(&2),
+            "File contents differ"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_serializations() {
+        let data = Mutex::new(FSData::new());
+        let mut dl = data.lock().unwrap();
+        dl.INV_FILE_CONTENTS.insert(2, b"old".to_vec());
+        let watch = dl.watches.open(2);
+        record(0, &mut dl, &[2], 0, |dl| {
+            dl.INV_FILE_CONTENTS.insert(2, b"new".to_vec());
+            Ok(())
+        })
+        .unwrap();
+        dl.watches.close(&watch, 1);
+        // Started after the observation ended, so it can't be ordered before it.
+        record(0, &mut dl, &[2], 1, |dl| {
+            dl.INV_FILE_CONTENTS.insert(2, b"newer".to_vec());
+            Ok(())
+        })
+        .unwrap();
+        let states = dl.watches.finish(watch);
+        assert_eq!(states.len(), 2);
+        assert_eq!(dl.INV_FILE_CONTENTS.get(&2), Some(&b"newer".to_vec()));
+        assert_eq!(
+            check_serializations(&mut dl, 2, states.clone(), |dl| check_contents(dl, b"old")),
+            Ok(())
+        );
+        assert_eq!(
+            check_serializations(&mut dl, 2, states.clone(), |dl| check_contents(dl, b"new")),
+            Ok(())
+        );
+        assert!(
+            check_serializations(&mut dl, 2, states, |dl| check_contents(dl, b"newer")).is_err()
+        );
+        assert_eq!(dl.INV_FILE_CONTENTS.get(&2), Some(&b"newer".to_vec()));
+    }
+}
@@ -1,33 +1,232 @@
-use std::{collections::BTreeMap, ffi::OsString};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+};
 
 use crate::{file_attr::FileAttr, inode_mapper::InodeMapper};
 
+use self::{
+    durable::Durable, listings::Listings, locks::Watches, posix_locks::LockTable,
+    sparse::DataRanges,
+};
+
+/// Which parts of the model are tracked and checked.
+///
+/// A disabled category's maps are left empty and every check that would read them is skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checks {
+    pub meta: bool,
+    pub dirs: bool,
+    pub data: bool,
+    pub xattr: bool,
+}
+
+impl Default for Checks {
+    fn default() -> Self {
+        Self {
+            meta: true,
+            dirs: true,
+            data: true,
+            xattr: false,
+        }
+    }
+}
+
+impl Checks {
+    pub fn none() -> Self {
+        Self {
+            meta: false,
+            dirs: false,
+            data: false,
+            xattr: false,
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        self.meta || self.dirs || self.data || self.xattr
+    }
+
+    /// Directory, data and xattr checks all update the metadata model, so they imply it.
+    pub fn normalize(self) -> Self {
+        Self {
+            meta: self.any(),
+            ..self
+        }
+    }
+
+    /// The categories enabled in `self` but not in `prev`.
+    pub fn added(self, prev: Self) -> Self {
+        Self {
+            meta: self.meta && !prev.meta,
+            dirs: self.dirs && !prev.dirs,
+            data: self.data && !prev.data,
+            xattr: self.xattr && !prev.xattr,
+        }
+    }
+
+    /// Parses a comma-separated list of categories, or `all` / `none`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut checks = Self::none();
+        for c in s.split(',').filter(|x| !x.is_empty()) {
+            match c {
+                "all" => checks = Self::default(),
+                "none" => checks = Self::none(),
+                "meta" => checks.meta = true,
+                "dirs" => checks.dirs = true,
+                "data" => checks.data = true,
+                "xattr" => checks.xattr = true,
+                v => return Err(format!("Unknown check category {:?}", v)),
+            }
+        }
+        Ok(checks.normalize())
+    }
+}
+
 #[derive(Default)]
 #[allow(non_snake_case)]
 pub struct FSData {
+    pub checks: Checks,
+
     pub INODE_PATHS: InodeMapper,
 
     pub INV_INODE_PATHS: InodeMapper,
 
-    #[cfg(feature = "check-meta")]
     pub INV_INODE_CONTENTS: BTreeMap<u64, FileAttr>,
 
-    #[cfg(feature = "check-dirs")]
+    /// Tracked along with the metadata, see [`inode_flags`]. Inodes with no flags set are absent.
+    pub INV_INODE_FLAGS: BTreeMap<u64, u32>,
+
     pub INV_DIR_CONTENTS: BTreeMap<u64, BTreeMap<OsString, u64>>,
 
-    #[cfg(feature = "check-data")]
     pub INV_FILE_CONTENTS: BTreeMap<u64, Vec<u8>>,
 
-    #[cfg(feature = "check-xattr")]
+    /// Tracked along with the contents, see [`sparse`].
+    pub INV_FILE_DATA: BTreeMap<u64, DataRanges>,
+
     pub INV_XATTR_CONTENTS: BTreeMap<u64, BTreeMap<OsString, Vec<u8>>>,
+
+    /// What the last syncs promised, see [`durable`].
+    pub durable: Durable,
+
+    /// The record locks granted so far, see [`posix_locks`]. Always checked, and never saved:
+    /// locks don't outlive the mount.
+    pub posix_locks: LockTable,
+
+    /// Not part of the model: the observations in flight, see [`locks`].
+    pub watches: Watches,
+
+    /// Not part of the model either: the passes over open directory handles, see [`listings`].
+    pub listings: Listings,
 }
 
 impl FSData {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// `inos`, and the entries of those that are directories.
+    pub fn with_entries(&self, inos: &[u64]) -> BTreeSet<u64> {
+        let mut out: BTreeSet<u64> = inos.iter().copied().collect();
+        for ino in inos {
+            if let Some(dc) = self.INV_DIR_CONTENTS.get(ino) {
+                out.extend(dc.values());
+            }
+        }
+        out
+    }
+
+    /// Drop everything the model knows about `ino`.
+    pub fn drop_inode(&mut self, ino: u64) {
+        self.INV_INODE_CONTENTS.remove(&ino);
+        self.INV_INODE_FLAGS.remove(&ino);
+        self.INV_DIR_CONTENTS.remove(&ino);
+        self.INV_FILE_CONTENTS.remove(&ino);
+        self.INV_FILE_DATA.remove(&ino);
+        self.INV_XATTR_CONTENTS.remove(&ino);
+    }
+
+    /// `ino` lost its last name. Its model goes too, unless the kernel still holds a lookup of
+    /// it: an open file stays usable until then, and `forget` drops it instead.
+    pub fn orphan(&mut self, ino: u64) {
+        if self.INODE_PATHS.lookups(ino) == 0 {
+            self.drop_inode(ino);
+        }
+    }
+
+    /// Drop the inodes kept around by [`FSData::orphan`]: the backend won't have them.
+    pub fn drop_orphans(&mut self) {
+        let orphans: Vec<u64> = self
+            .INV_INODE_CONTENTS
+            .keys()
+            .chain(self.INV_FILE_CONTENTS.keys())
+            .chain(self.INV_XATTR_CONTENTS.keys())
+            .filter(|ino| self.INV_INODE_PATHS.get_all(**ino).is_none())
+            .copied()
+            .collect();
+        for ino in orphans {
+            self.drop_inode(ino);
+        }
+    }
+
+    /// A copy of the model of `inos`, to [`diff`](diff::diff) against another.
+    pub fn slice(&self, inos: &BTreeSet<u64>) -> Self {
+        Self {
+            checks: self.checks,
+            INV_INODE_PATHS: InodeMapper::load(pick(&self.INV_INODE_PATHS.store(), inos)),
+            INV_INODE_CONTENTS: pick(&self.INV_INODE_CONTENTS, inos),
+            INV_DIR_CONTENTS: pick(&self.INV_DIR_CONTENTS, inos),
+            INV_FILE_CONTENTS: pick(&self.INV_FILE_CONTENTS, inos),
+            INV_XATTR_CONTENTS: pick(&self.INV_XATTR_CONTENTS, inos),
+            ..Self::default()
+        }
+    }
+}
+
+fn pick<V: Clone>(m: &BTreeMap<u64, V>, inos: &BTreeSet<u64>) -> BTreeMap<u64, V> {
+    inos.iter()
+        .filter_map(|x| m.get(x).map(|v| (*x, v.clone())))
+        .collect()
 }
 
+pub mod acl;
 pub mod common;
+pub mod diff;
+pub mod durable;
 pub mod fs;
+pub mod inode_flags;
+pub mod listings;
+pub mod locks;
 pub mod perm;
+pub mod posix_locks;
+pub mod sparse;
+pub mod violation;
+
+#[cfg(test)]
+mod tests {
+    use super::Checks;
+
+    #[test]
+    fn test_parse_checks() {
+        assert_eq!(Checks::parse("all"), Ok(Checks::default()));
+        assert_eq!(Checks::parse("none"), Ok(Checks::none()));
+        assert_eq!(
+            Checks::parse("dirs"),
+            Ok(Checks {
+                meta: true,
+                dirs: true,
+                data: false,
+                xattr: false
+            })
+        );
+        assert_eq!(
+            Checks::parse("data,xattr"),
+            Ok(Checks {
+                meta: true,
+                dirs: false,
+                data: true,
+                xattr: true
+            })
+        );
+        assert!(Checks::parse("bogus").is_err());
+    }
+}
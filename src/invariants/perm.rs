@@ -1,11 +1,55 @@
-use std::{collections::BTreeSet, fs::Metadata, os::linux::fs::MetadataExt, path::Path};
+use std::{
+    collections::BTreeSet,
+    ffi::{CString, OsStr, OsString},
+    fs::Metadata,
+    mem::MaybeUninit,
+    os::{linux::fs::MetadataExt, unix::prelude::OsStrExt},
+    path::Path,
+};
 
-use crate::fs::get_groups;
+use crate::{
+    fs::{get_caps, get_groups},
+    invariants::acl::{Acl, ACL_ACCESS, ACL_DEFAULT},
+    log_perm,
+    logging::CallID,
+};
 
 pub fn sgids(pid: u32) -> BTreeSet<u32> {
     BTreeSet::from_iter(get_groups(pid.try_into().unwrap()).unwrap_or(vec![]))
 }
 
+pub const CAP_CHOWN: u32 = 0;
+pub const CAP_DAC_OVERRIDE: u32 = 1;
+pub const CAP_DAC_READ_SEARCH: u32 = 2;
+pub const CAP_FOWNER: u32 = 3;
+pub const CAP_FSETID: u32 = 4;
+pub const CAP_SYS_ADMIN: u32 = 21;
+
+/// The capabilities the checks depend on.
+pub const MODELLED_CAPS: u64 = 1 << CAP_CHOWN
+    | 1 << CAP_DAC_OVERRIDE
+    | 1 << CAP_DAC_READ_SEARCH
+    | 1 << CAP_FOWNER
+    | 1 << CAP_FSETID
+    | 1 << CAP_SYS_ADMIN;
+
+/// A requester's effective capability set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Caps(pub u64);
+
+impl Caps {
+    pub fn has(self, cap: u32) -> bool {
+        self.0 & (1 << cap) != 0
+    }
+}
+
+/// The effective capabilities of `pid`. If the process can't be asked, root is taken to have
+/// every capability and anyone else none.
+pub fn caps(pid: u32, uid: u32) -> Caps {
+    let fallback = if uid == 0 { u64::MAX } else { 0 };
+    Caps(get_caps(pid.try_into().unwrap()).unwrap_or(fallback))
+}
+
 #[derive(Debug)]
 pub enum Access {
     Lookup,
@@ -16,9 +60,25 @@ pub enum Access {
     Chgrp(u32),
     Write,
     Delete,
+    XattrRead(OsString),
+    XattrWrite(OsString),
+    XattrRemove(OsString),
+    /// A mask of `R_OK`, `W_OK` and `X_OK`, or `F_OK`, as `access(2)` and `open(2)` check it.
+    Mask(u32),
+}
+
+/// Whether the namespace of an attribute leaves its permissions to the filesystem and LSMs.
+pub fn xattr_unchecked(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(b"security.")
+}
+
+/// Whether an attribute shows up when a requester with `caps` lists the attributes of an inode.
+pub fn xattr_listed(caps: Caps, name: &OsStr) -> bool {
+    caps.has(CAP_SYS_ADMIN) || !name.as_bytes().starts_with(b"trusted.")
 }
 
 pub fn check_perm(
+    callid: CallID,
     uid: u32,
     gid: u32,
     pid: u32,
@@ -28,9 +88,17 @@ pub fn check_perm(
 ) -> Option<i32> {
     // Get supplementary groups
     let sgids = sgids(pid);
-    println!(
-        "\tPERM: u{} g{}+{:?} p{} {:?} {:?}",
-        uid, gid, sgids, pid, path, access
+    let caps = caps(pid, uid);
+    log_perm!(
+        callid,
+        "PERM: u{} g{}+{:?} c{:x} p{} {:?} {:?}",
+        uid,
+        gid,
+        sgids,
+        caps.0,
+        pid,
+        path,
+        access
     );
     let mut p = path;
     // Check for traversal permissions
@@ -41,100 +109,188 @@ pub fn check_perm(
             None => break,
         };
         if let Ok(meta) = p.metadata() {
-            println!("\t parent perm {:?}", p);
-            match perm(meta, uid, gid, &sgids, 1, libc::EACCES) {
+            log_perm!(callid, " parent perm {:?}", p);
+            match perm(callid, p, meta, uid, gid, &sgids, caps, 1, libc::EACCES) {
                 None => {}
                 Some(libc::EACCES) => {
-                    println!("EA");
+                    log_perm!(callid, "EA");
                     return Some(libc::EACCES);
                 }
                 Some(_) => todo!(),
             }
         } else {
-            println!("ENOE");
+            log_perm!(callid, "ENOE");
             return Some(libc::ENOENT);
         }
     }
-    println!("\t self perm {:?}:{:?}", access, path);
-    match (
-        access,
-        path.symlink_metadata(),
-        path.parent().map(|x| x.metadata()),
-    ) {
+    log_perm!(callid, " self perm {:?}:{:?}", access, path);
+    let parent = path.parent().unwrap_or(path);
+    match (access, path.symlink_metadata(), parent.metadata()) {
         (Access::Lookup, _, _) => None,
-        (Access::Create, Ok(m), Some(Ok(m_p))) => perm_overwrite(m, m_p, uid, gid, &sgids),
-        (Access::Create, Err(_), Some(Ok(m))) => perm(m, uid, gid, &sgids, 2, libc::EACCES),
-        (Access::Delete, Ok(m), Some(Ok(m_p))) => perm_delete(m, m_p, uid, gid, &sgids),
+        (Access::Create, Ok(m), Ok(m_p)) => {
+            perm_overwrite(callid, parent, m, m_p, uid, gid, &sgids, caps)
+        }
+        (Access::Create, Err(_), Ok(m)) => {
+            perm(callid, parent, m, uid, gid, &sgids, caps, 2, libc::EACCES)
+        }
+        (Access::Delete, Ok(m), Ok(m_p)) => {
+            perm_delete(callid, parent, m, m_p, uid, gid, &sgids, caps)
+        }
         (Access::Delete, Err(_), _) => todo!("ENOE"),
-        (Access::Chmod, Ok(m), _) => perm_chmod(m, uid),
-        (Access::Chown(new_uid), Ok(m), _) => perm_chown(m, uid, new_uid),
-        (Access::Chgrp(new_gid), Ok(m), _) => perm_chgrp(m, uid, gid, &sgids, new_gid),
-        (Access::Write, Ok(m), _) => perm(m, uid, gid, &sgids, 2, libc::EACCES),
+        (Access::Chmod, Ok(m), _) => perm_chmod(m, uid, caps),
+        (Access::Chown(new_uid), Ok(m), _) => perm_chown(m, caps, new_uid),
+        (Access::Chgrp(new_gid), Ok(m), _) => perm_chgrp(m, uid, gid, &sgids, caps, new_gid),
+        (Access::Write, Ok(m), _) => perm(callid, path, m, uid, gid, &sgids, caps, 2, libc::EACCES),
+        (Access::XattrRead(name), Ok(m), _) => {
+            perm_xattr(callid, path, m, uid, gid, &sgids, caps, &name, false)
+        }
+        // A default ACL only means something on a directory
+        (Access::XattrWrite(name), Ok(m), _) if name == ACL_DEFAULT && !m.is_dir() => {
+            Some(libc::EACCES)
+        }
+        (Access::XattrWrite(name), Ok(m), _) | (Access::XattrRemove(name), Ok(m), _) => {
+            perm_xattr(callid, path, m, uid, gid, &sgids, caps, &name, true)
+        }
+        (Access::Mask(mask), Ok(m), _) => perm_mask(callid, path, m, uid, gid, &sgids, caps, mask),
         (_, Err(e), _) if e.kind() == std::io::ErrorKind::NotFound => Some(libc::ENOENT),
         (a, b, c) => todo!("\t  {:?} {:?} {:?}", a, b, c),
     }
-
-    /*if let Ok(meta) = path.metadata() {
-        match access {
-            Access::Lookup => {println!("\t  lookup OK"); None},
-            Access::Create => todo!(),
-            Access::Write => perm(meta, uid, gid, &sgids, 2),
-        }
-    } else {
-        println!("\t  noent");
-        Some(libc::ENOENT)
-    }*/
 }
 
 fn perm(
+    callid: CallID,
+    path: &Path,
     meta: Metadata,
     uid: u32,
     gid: u32,
     sgids: &BTreeSet<u32>,
+    caps: Caps,
     mode: u32,
     code: i32,
 ) -> Option<i32> {
-    println!(
-        "\t  perm on file: u{} g{} m{:o}?{}",
+    log_perm!(
+        callid,
+        " perm on file: u{} g{} m{:o}?{}",
         meta.st_uid(),
         meta.st_gid(),
         meta.st_mode(),
         mode
     );
     assert!(mode < 8, "Mode must be a single octal digit");
-    // Root always has perms
-    if uid == 0 {
-        return None;
+    if perm_bits(callid, path, &meta, uid, gid, sgids, mode) || perm_caps(&meta, caps, mode) {
+        None
+    } else {
+        Some(code)
     }
+}
+
+fn perm_bits(
+    callid: CallID,
+    path: &Path,
+    meta: &Metadata,
+    uid: u32,
+    gid: u32,
+    sgids: &BTreeSet<u32>,
+    mode: u32,
+) -> bool {
+    // An access ACL takes the place of the mode bits; its owner, mask and other entries are
+    // them anyway. Symlinks have none, and reading one would follow the link.
+    if meta.st_mode() & libc::S_IFMT != libc::S_IFLNK {
+        if let Some(acl) = Acl::read(path, ACL_ACCESS) {
+            log_perm!(callid, "   ACL {:?}", acl);
+            let owner = (meta.st_uid(), meta.st_gid());
+            if acl.permits(owner.0, owner.1, uid, gid, sgids, mode as u16) {
+                return true;
+            }
+            log_perm!(callid, "   Ea");
+            return false;
+        }
+    }
+    // Every bit asked for must be granted
     if meta.st_uid() == uid {
-        if meta.st_mode() & (mode << 6) == 0 {
-            println!("\t   Eu");
-            return Some(code);
+        if (meta.st_mode() >> 6) & mode != mode {
+            log_perm!(callid, "   Eu");
+            return false;
         }
     } else if meta.st_gid() == gid || sgids.contains(&meta.st_gid()) {
-        if meta.st_mode() & (mode << 3) == 0 {
-            println!("\t   Eg");
-            return Some(code);
+        if (meta.st_mode() >> 3) & mode != mode {
+            log_perm!(callid, "   Eg");
+            return false;
         }
-    } else if meta.st_mode() & (mode) == 0 {
-        println!("\t   Eo");
-        return Some(code);
+    } else if meta.st_mode() & mode != mode {
+        log_perm!(callid, "   Eo");
+        return false;
+    }
+    true
+}
+
+/// Whether capabilities override the mode bits: CAP_DAC_OVERRIDE for anything but executing a
+/// file with no execute bit at all, CAP_DAC_READ_SEARCH for reading files and reading or
+/// searching directories.
+fn perm_caps(meta: &Metadata, caps: Caps, mode: u32) -> bool {
+    if meta.st_mode() & libc::S_IFMT == libc::S_IFDIR {
+        return caps.has(CAP_DAC_OVERRIDE) || (mode & 2 == 0 && caps.has(CAP_DAC_READ_SEARCH));
     }
-    None
+    (caps.has(CAP_DAC_OVERRIDE) && (mode & 1 == 0 || meta.st_mode() & 0o111 != 0))
+        || (mode == 4 && caps.has(CAP_DAC_READ_SEARCH))
 }
 
-fn perm_chmod(meta: Metadata, uid: u32) -> Option<i32> {
-    // You need to be owner or root to chmod
-    if uid == meta.st_uid() || uid == 0 {
+/// `access(2)`: F_OK only needs the path to resolve, and asking to write a file, directory or
+/// symlink on a read-only filesystem is refused before the mode bits are looked at.
+fn perm_mask(
+    callid: CallID,
+    path: &Path,
+    meta: Metadata,
+    uid: u32,
+    gid: u32,
+    sgids: &BTreeSet<u32>,
+    caps: Caps,
+    mask: u32,
+) -> Option<i32> {
+    if mask == libc::F_OK as u32 {
+        return None;
+    }
+    let kind = meta.st_mode() & libc::S_IFMT;
+    if mask & libc::W_OK as u32 != 0
+        && [libc::S_IFREG, libc::S_IFDIR, libc::S_IFLNK].contains(&kind)
+        && read_only(path)
+    {
+        log_perm!(callid, "   Erofs");
+        return Some(libc::EROFS);
+    }
+    perm(
+        callid,
+        path,
+        meta,
+        uid,
+        gid,
+        sgids,
+        caps,
+        mask,
+        libc::EACCES,
+    )
+}
+
+/// Whether the filesystem `path` is on is mounted read-only.
+fn read_only(path: &Path) -> bool {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let mut buf = MaybeUninit::<libc::statvfs>::zeroed();
+    let rc = unsafe { libc::statvfs(path.as_ptr(), buf.as_mut_ptr()) };
+    rc == 0 && unsafe { buf.assume_init() }.f_flag & libc::ST_RDONLY != 0
+}
+
+fn perm_chmod(meta: Metadata, uid: u32, caps: Caps) -> Option<i32> {
+    // You need to be owner or have CAP_FOWNER to chmod
+    if uid == meta.st_uid() || caps.has(CAP_FOWNER) {
         None
     } else {
         Some(libc::EPERM)
     }
 }
 
-fn perm_chown(meta: Metadata, uid: u32, new_uid: u32) -> Option<i32> {
-    // You must be root to chown
-    if uid == 0 {
+fn perm_chown(meta: Metadata, caps: Caps, new_uid: u32) -> Option<i32> {
+    // You must have CAP_CHOWN to chown
+    if caps.has(CAP_CHOWN) {
         return None;
     }
     // We're allowed to do a no-op
@@ -149,10 +305,11 @@ fn perm_chgrp(
     uid: u32,
     gid: u32,
     sgids: &BTreeSet<u32>,
+    caps: Caps,
     new_gid: u32,
 ) -> Option<i32> {
-    // You must be root to chgrp, unless you are changing the group to one you're in
-    if uid == 0 {
+    // You must have CAP_CHOWN to chgrp, unless you are changing the group to one you're in
+    if caps.has(CAP_CHOWN) {
         return None;
     }
     // If we're the owner, we're allowed to change it to a group we're in
@@ -162,42 +319,125 @@ fn perm_chgrp(
     Some(libc::EPERM)
 }
 
-fn perm_delete(
+fn perm_xattr(
+    callid: CallID,
+    path: &Path,
     meta: Metadata,
-    meta_parent: Metadata,
     uid: u32,
     gid: u32,
     sgids: &BTreeSet<u32>,
+    caps: Caps,
+    name: &OsStr,
+    write: bool,
 ) -> Option<i32> {
-    if uid == 0 {
+    // A refused read looks like the attribute isn't there
+    let refused = if write { libc::EPERM } else { libc::ENODATA };
+    if xattr_unchecked(name) {
         return None;
     }
+    if name.as_bytes().starts_with(b"system.") {
+        // Anyone may read the ACLs, but only the owner may change them, as with chmod. The
+        // kernel doesn't check other system attributes itself.
+        let acl = name == ACL_ACCESS || name == ACL_DEFAULT;
+        return if write && acl {
+            perm_chmod(meta, uid, caps)
+        } else {
+            None
+        };
+    }
+    if name.as_bytes().starts_with(b"trusted.") {
+        // Only CAP_SYS_ADMIN gets at trusted attributes
+        return if caps.has(CAP_SYS_ADMIN) {
+            None
+        } else {
+            Some(refused)
+        };
+    }
+    if name.as_bytes().starts_with(b"user.") {
+        let kind = meta.st_mode() & libc::S_IFMT;
+        if kind != libc::S_IFREG && kind != libc::S_IFDIR {
+            log_perm!(callid, "User attribute on special file");
+            return Some(refused);
+        }
+        if kind == libc::S_IFDIR
+            && (meta.st_mode() & libc::S_ISVTX) != 0
+            && write
+            && meta.st_uid() != uid
+            && !caps.has(CAP_FOWNER)
+        {
+            log_perm!(callid, "Sticky");
+            return Some(libc::EPERM);
+        }
+    }
+    perm(
+        callid,
+        path,
+        meta,
+        uid,
+        gid,
+        sgids,
+        caps,
+        if write { 2 } else { 4 },
+        libc::EACCES,
+    )
+}
+
+fn perm_delete(
+    callid: CallID,
+    parent: &Path,
+    meta: Metadata,
+    meta_parent: Metadata,
+    uid: u32,
+    gid: u32,
+    sgids: &BTreeSet<u32>,
+    caps: Caps,
+) -> Option<i32> {
     if (meta_parent.st_mode() & libc::S_ISVTX) != 0 {
-        println!("Sticky");
-        if meta.st_uid() != uid && meta_parent.st_uid() != uid {
+        log_perm!(callid, "Sticky");
+        if meta.st_uid() != uid && meta_parent.st_uid() != uid && !caps.has(CAP_FOWNER) {
             return Some(libc::EPERM);
         }
     }
 
-    perm(meta_parent, uid, gid, sgids, 2, libc::EACCES)
+    perm(
+        callid,
+        parent,
+        meta_parent,
+        uid,
+        gid,
+        sgids,
+        caps,
+        2,
+        libc::EACCES,
+    )
 }
 
 fn perm_overwrite(
+    callid: CallID,
+    parent: &Path,
     meta: Metadata,
     meta_parent: Metadata,
     uid: u32,
     gid: u32,
     sgids: &BTreeSet<u32>,
+    caps: Caps,
 ) -> Option<i32> {
-    if uid == 0 {
-        return None;
-    }
     if (meta_parent.st_mode() & libc::S_ISVTX) != 0 {
-        println!("Sticky");
-        if meta.st_uid() != uid && meta_parent.st_uid() != uid {
+        log_perm!(callid, "Sticky");
+        if meta.st_uid() != uid && meta_parent.st_uid() != uid && !caps.has(CAP_FOWNER) {
             return Some(libc::EPERM);
         }
     }
 
-    perm(meta_parent, uid, gid, sgids, 2, libc::EACCES)
+    perm(
+        callid,
+        parent,
+        meta_parent,
+        uid,
+        gid,
+        sgids,
+        caps,
+        2,
+        libc::EACCES,
+    )
 }
@@ -0,0 +1,187 @@
+//! The byte-range locks `setlk` has granted, per inode.
+//!
+//! Ranges are inclusive, as the kernel sends them: a lock to EOF ends at `OFFSET_MAX`. Each
+//! owner's locks on an inode are kept split and merged the way the kernel keeps them, so a
+//! conflict reported by the backend has to match one of them exactly.
+
+use std::collections::BTreeMap;
+
+/// What the kernel uses as the end of a lock that runs to EOF.
+pub const OFFSET_MAX: u64 = i64::MAX as u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PosixLock {
+    pub owner: u64,
+    pub pid: u32,
+    pub start: u64,
+    pub end: u64,
+    pub typ: i32,
+}
+
+impl PosixLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    /// Whether `self` keeps `owner` from taking a `typ` lock on `[start, end]`.
+    pub fn conflicts(&self, owner: u64, start: u64, end: u64, typ: i32) -> bool {
+        self.owner != owner
+            && self.overlaps(start, end)
+            && (self.typ == libc::F_WRLCK || typ == libc::F_WRLCK)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LockTable(BTreeMap<u64, Vec<PosixLock>>);
+
+impl LockTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every lock on `ino` that keeps `owner` from taking a `typ` lock on `[start, end]`.
+    pub fn conflicts(
+        &self,
+        ino: u64,
+        owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+    ) -> Vec<PosixLock> {
+        self.0
+            .get(&ino)
+            .map(|l| {
+                l.iter()
+                    .filter(|x| x.conflicts(owner, start, end, typ))
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Grant `lock`, or release its range if it is `F_UNLCK`. Whatever the owner already held in
+    /// the range is replaced, splitting locks that stick out of it, and the new lock is merged
+    /// with the owner's touching locks of the same type.
+    pub fn set(&mut self, ino: u64, lock: PosixLock) {
+        let locks = self.0.entry(ino).or_default();
+        let (mut start, mut end) = (lock.start, lock.end);
+        let mut kept = Vec::new();
+        for l in locks.drain(..) {
+            if l.owner != lock.owner {
+                kept.push(l);
+                continue;
+            }
+            let touches = l.start <= end.saturating_add(1) && start <= l.end.saturating_add(1);
+            if lock.typ != libc::F_UNLCK && l.typ == lock.typ && touches {
+                start = start.min(l.start);
+                end = end.max(l.end);
+                continue;
+            }
+            if !l.overlaps(start, end) {
+                kept.push(l);
+                continue;
+            }
+            if l.start < start {
+                kept.push(PosixLock {
+                    end: start - 1,
+                    ..l
+                });
+            }
+            if l.end > end {
+                kept.push(PosixLock {
+                    start: end + 1,
+                    ..l
+                });
+            }
+        }
+        if lock.typ != libc::F_UNLCK {
+            kept.push(PosixLock { start, end, ..lock });
+        }
+        kept.sort_by_key(|l| (l.start, l.owner));
+        if kept.is_empty() {
+            self.0.remove(&ino);
+        } else {
+            *locks = kept;
+        }
+    }
+
+    /// Release everything `owner` holds on `ino`.
+    pub fn drop_owner(&mut self, ino: u64, owner: u64) {
+        if let Some(locks) = self.0.get_mut(&ino) {
+            locks.retain(|l| l.owner != owner);
+            if locks.is_empty() {
+                self.0.remove(&ino);
+            }
+        }
+    }
+
+    /// Whether `owner` holds anything on `ino`.
+    pub fn holds(&self, ino: u64, owner: u64) -> bool {
+        self.owned(ino, owner).next().is_some()
+    }
+
+    pub fn owned(&self, ino: u64, owner: u64) -> impl Iterator<Item = &PosixLock> {
+        self.0
+            .get(&ino)
+            .into_iter()
+            .flatten()
+            .filter(move |l| l.owner == owner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LockTable, PosixLock};
+
+    fn lock(owner: u64, start: u64, end: u64, typ: i32) -> PosixLock {
+        PosixLock {
+            owner,
+            pid: owner as u32,
+            start,
+            end,
+            typ,
+        }
+    }
+
+    fn ranges(t: &LockTable, owner: u64) -> Vec<(u64, u64, i32)> {
+        t.owned(2, owner).map(|l| (l.start, l.end, l.typ)).collect()
+    }
+
+    #[test]
+    fn test_set() {
+        let mut t = LockTable::new();
+        t.set(2, lock(1, 0, 99, libc::F_WRLCK));
+        t.set(2, lock(1, 10, 19, libc::F_UNLCK));
+        assert_eq!(
+            ranges(&t, 1),
+            vec![(0, 9, libc::F_WRLCK), (20, 99, libc::F_WRLCK)]
+        );
+        t.set(2, lock(1, 5, 24, libc::F_RDLCK));
+        assert_eq!(
+            ranges(&t, 1),
+            vec![
+                (0, 4, libc::F_WRLCK),
+                (5, 24, libc::F_RDLCK),
+                (25, 99, libc::F_WRLCK)
+            ]
+        );
+        t.set(2, lock(1, 0, 4, libc::F_RDLCK));
+        t.set(2, lock(1, 25, 99, libc::F_RDLCK));
+        assert_eq!(ranges(&t, 1), vec![(0, 99, libc::F_RDLCK)]);
+
+        t.set(2, lock(2, 50, 149, libc::F_RDLCK));
+        assert!(t.conflicts(2, 2, 0, 49, libc::F_RDLCK).is_empty());
+        assert_eq!(
+            t.conflicts(2, 2, 0, 49, libc::F_WRLCK),
+            vec![lock(1, 0, 99, libc::F_RDLCK)]
+        );
+        assert_eq!(
+            t.conflicts(2, 3, 120, 130, libc::F_WRLCK),
+            vec![lock(2, 50, 149, libc::F_RDLCK)]
+        );
+
+        t.drop_owner(2, 1);
+        assert!(!t.holds(2, 1));
+        assert!(t.holds(2, 2));
+    }
+}
@@ -0,0 +1,186 @@
+//! Which parts of a file have to be data rather than holes.
+//!
+//! A filesystem is free to report a hole as data (plenty don't track holes at all), but never
+//! the other way around. So the model only tracks the ranges that must be data: everything
+//! written since the mount, plus every nonzero byte found when the backend was scanned. Punching
+//! or zeroing a range takes it back out.
+
+use std::collections::BTreeMap;
+
+/// Disjoint, non-adjacent `[start, end)` ranges, keyed by start.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DataRanges(BTreeMap<u64, u64>);
+
+impl DataRanges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The nonzero bytes of `contents`: whatever else is in a file, those can't be a hole.
+    pub fn from_contents(contents: &[u8]) -> Self {
+        let mut ranges = Self::new();
+        let mut start = None;
+        for (i, b) in contents.iter().enumerate() {
+            match (start, *b) {
+                (None, b) if b != 0 => start = Some(i as u64),
+                (Some(s), 0) => {
+                    ranges.insert(s, i as u64);
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+        if let Some(s) = start {
+            ranges.insert(s, contents.len() as u64);
+        }
+        ranges
+    }
+
+    pub fn insert(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let (mut start, mut end) = (start, end);
+        // Absorb every range that overlaps or touches the new one.
+        let touching: Vec<(u64, u64)> = self
+            .0
+            .range(..=end)
+            .filter(|(_, e)| **e >= start)
+            .map(|(s, e)| (*s, *e))
+            .collect();
+        for (s, e) in touching {
+            self.0.remove(&s);
+            start = start.min(s);
+            end = end.max(e);
+        }
+        self.0.insert(start, end);
+    }
+
+    pub fn remove(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let overlapping: Vec<(u64, u64)> = self
+            .0
+            .range(..end)
+            .filter(|(_, e)| **e > start)
+            .map(|(s, e)| (*s, *e))
+            .collect();
+        for (s, e) in overlapping {
+            self.0.remove(&s);
+            if s < start {
+                self.0.insert(s, start);
+            }
+            if e > end {
+                self.0.insert(end, e);
+            }
+        }
+    }
+
+    /// Drop everything at or past `size`.
+    pub fn truncate(&mut self, size: u64) {
+        self.remove(size, u64::MAX)
+    }
+
+    /// Cut `[start, start + len)` out, moving everything after it down.
+    pub fn collapse(&mut self, start: u64, len: u64) {
+        self.remove(start, start + len);
+        let after: Vec<(u64, u64)> = self.0.range(start..).map(|(s, e)| (*s, *e)).collect();
+        for (s, _) in &after {
+            self.0.remove(s);
+        }
+        for (s, e) in after {
+            self.insert(s - len, e - len);
+        }
+    }
+
+    /// Open a hole of `len` bytes at `start`, moving everything after it up.
+    pub fn expand(&mut self, start: u64, len: u64) {
+        // Split the range `start` is strictly inside of.
+        let split = self
+            .0
+            .range(..start)
+            .next_back()
+            .map(|(s, e)| (*s, *e))
+            .filter(|(_, e)| *e > start);
+        if let Some((s, e)) = split {
+            self.0.insert(s, start);
+            self.0.insert(start, e);
+        }
+        let after: Vec<(u64, u64)> = self.0.range(start..).map(|(s, e)| (*s, *e)).collect();
+        for (s, _) in &after {
+            self.0.remove(s);
+        }
+        for (s, e) in after {
+            self.0.insert(s + len, e + len);
+        }
+    }
+
+    /// The end of the range `offset` is in, if it is in one.
+    pub fn containing(&self, offset: u64) -> Option<u64> {
+        self.0
+            .range(..=offset)
+            .next_back()
+            .filter(|(_, e)| **e > offset)
+            .map(|(_, e)| *e)
+    }
+
+    /// The first offset at or after `offset` that must be data.
+    pub fn next_data(&self, offset: u64) -> Option<u64> {
+        if self.containing(offset).is_some() {
+            return Some(offset);
+        }
+        self.0.range(offset..).next().map(|(s, _)| *s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DataRanges;
+
+    fn ranges(r: &DataRanges) -> Vec<(u64, u64)> {
+        r.0.iter().map(|(s, e)| (*s, *e)).collect()
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let mut r = DataRanges::new();
+        r.insert(10, 20);
+        r.insert(30, 40);
+        r.insert(20, 25);
+        assert_eq!(ranges(&r), vec![(10, 25), (30, 40)]);
+        r.insert(5, 35);
+        assert_eq!(ranges(&r), vec![(5, 40)]);
+        r.remove(10, 20);
+        assert_eq!(ranges(&r), vec![(5, 10), (20, 40)]);
+        r.truncate(30);
+        assert_eq!(ranges(&r), vec![(5, 10), (20, 30)]);
+        assert_eq!(r.containing(7), Some(10));
+        assert_eq!(r.containing(10), None);
+        assert_eq!(r.next_data(10), Some(20));
+        assert_eq!(r.next_data(25), Some(25));
+        assert_eq!(r.next_data(30), None);
+    }
+
+    #[test]
+    fn test_shift() {
+        let mut r = DataRanges::new();
+        r.insert(0, 10);
+        r.insert(20, 30);
+        r.expand(5, 4);
+        assert_eq!(ranges(&r), vec![(0, 5), (9, 14), (24, 34)]);
+        r.collapse(5, 4);
+        assert_eq!(ranges(&r), vec![(0, 10), (20, 30)]);
+        r.expand(20, 4);
+        assert_eq!(ranges(&r), vec![(0, 10), (24, 34)]);
+    }
+
+    #[test]
+    fn test_from_contents() {
+        assert_eq!(
+            ranges(&DataRanges::from_contents(b"a\0\0bc\0")),
+            vec![(0, 1), (3, 5)]
+        );
+        assert_eq!(ranges(&DataRanges::from_contents(b"\0\0")), vec![]);
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::logging::CallID;
+
+/// What to do when an invariant check fails.
+///
+/// A failed check stops at the first violation, before it has applied the call's change to the
+/// model, so the inodes the call changed are resynced from the backend and checking carries on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationPolicy {
+    /// Panic on the first violation, killing the daemon.
+    Panic,
+    /// Record the violation and hand the backend's result to the caller unchanged.
+    Log,
+    /// Record the violation and fail the call with EIO.
+    Eio,
+}
+
+impl Default for ViolationPolicy {
+    fn default() -> Self {
+        Self::Panic
+    }
+}
+
+/// A deviation from the expected behaviour, reported by an `inv_*_after` check.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Violation {
+    pub op: &'static str,
+    pub callid: CallID,
+    pub ino: Option<u64>,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+    pub message: String,
+}
+
+impl Violation {
+    pub fn new(message: String) -> Self {
+        Self {
+            op: "",
+            callid: 0,
+            ino: None,
+            expected: None,
+            actual: None,
+            message,
+        }
+    }
+
+    pub fn with_values(self, expected: String, actual: String) -> Self {
+        Self {
+            expected: Some(expected),
+            actual: Some(actual),
+            ..self
+        }
+    }
+
+    /// Attach the call the violation was found in; the checks themselves don't know it.
+    pub fn context(self, op: &'static str, callid: CallID, ino: Option<u64>) -> Self {
+        Self {
+            op,
+            callid,
+            ino,
+            ..self
+        }
+    }
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}({})", self.op, self.callid)?;
+        if let Some(ino) = self.ino {
+            write!(f, " ino={}", ino)?;
+        }
+        write!(f, ": {}", self.message)?;
+        if let (Some(e), Some(a)) = (&self.expected, &self.actual) {
+            write!(f, " (expected `{}`, got `{}`)", e, a)?;
+        }
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! inv_fail {
+    ($($arg:tt)+) => {
+        return Err($crate::invariants::violation::Violation::new(format!($($arg)+)))
+    };
+}
+
+#[macro_export]
+macro_rules! inv_assert {
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::inv_fail!($($arg)+);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! inv_assert_eq {
+    ($expected:expr, $actual:expr $(,)?) => {
+        $crate::inv_assert_eq!($expected, $actual, "Result did not match expected value")
+    };
+    ($expected:expr, $actual:expr, $($arg:tt)+) => {
+        match (&$expected, &$actual) {
+            (expected, actual) => {
+                if !(*expected == *actual) {
+                    return Err(
+                        $crate::invariants::violation::Violation::new(format!($($arg)+))
+                            .with_values(format!("{:?}", expected), format!("{:?}", actual)),
+                    );
+                }
+            }
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! inv_assert_eq_pretty {
+    ($expected:expr, $actual:expr $(,)?) => {
+        $crate::inv_assert_eq_pretty!($expected, $actual, "Result did not match expected value")
+    };
+    ($expected:expr, $actual:expr, $($arg:tt)+) => {
+        match (&$expected, &$actual) {
+            (expected, actual) => {
+                if !(*expected == *actual) {
+                    return Err($crate::invariants::violation::Violation::new(format!(
+                        "{}: {}",
+                        format_args!($($arg)+),
+                        asserteq_pretty::PrettyDiff::pretty_diff(&*expected, &*actual)
+                    ))
+                    .with_values(format!("{:?}", expected), format!("{:?}", actual)));
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Violation;
+
+    fn check_assert(v: bool) -> Result<(), Violation> {
+        inv_assert!(v, "Value was {}", v);
+        Ok(())
+    }
+
+    fn check_eq(e: u32, a: u32) -> Result<(), Violation> {
+        inv_assert_eq!(e, a, "Mismatch");
+        Ok(())
+    }
+
+    #[test]
+    fn test_assert_ok() {
+        assert_eq!(check_assert(true), Ok(()))
+    }
+
+    #[test]
+    fn test_assert_fail() {
+        assert_eq!(
+            check_assert(false),
+            Err(Violation::new(String::from("Value was false")))
+        )
+    }
+
+    #[test]
+    fn test_assert_eq_fail() {
+        assert_eq!(
+            check_eq(1, 2),
+            Err(Violation::new(String::from("Mismatch"))
+                .with_values(String::from("1"), String::from("2")))
+        )
+    }
+
+    #[test]
+    fn test_display() {
+        let v = Violation::new(String::from("Mismatch"))
+            .with_values(String::from("1"), String::from("2"))
+            .context("WRITE", 3, Some(4));
+        assert_eq!(
+            v.to_string(),
+            "WRITE(3) ino=4: Mismatch (expected `1`, got `2`)"
+        )
+    }
+}
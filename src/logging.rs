@@ -1,33 +1,381 @@
-use std::sync::atomic::AtomicU64;
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    io::{LineWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Mutex,
+    },
+};
 
 use lazy_static::lazy_static;
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::invariants::{diff::Difference, violation::Violation};
 
 lazy_static! {
     pub static ref CALL_ID: AtomicU64 = AtomicU64::new(0);
+    static ref SINK: Mutex<Box<dyn LogSink>> = Mutex::new(Box::new(TextSink::stdout()));
 }
 
 pub type CallID = u64;
 
+/// Events above this level are dropped; see [`Event::level`].
+static VERBOSITY: AtomicU8 = AtomicU8::new(3);
+
+/// A single entry in the event log.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Call {
+        callid: CallID,
+        op: &'static str,
+        args: Args,
+    },
+    More {
+        callid: CallID,
+        msg: String,
+    },
+    Invariant {
+        callid: CallID,
+        state: String,
+    },
+    Perm {
+        callid: CallID,
+        msg: String,
+    },
+    Result {
+        callid: CallID,
+        result: String,
+        errno: Option<i32>,
+    },
+    Violation {
+        callid: CallID,
+        violation: Violation,
+    },
+    /// A call changed the model.
+    Model {
+        callid: CallID,
+        diff: Difference,
+    },
+    /// Something the checker itself failed to do, such as saving its state.
+    Error {
+        callid: CallID,
+        msg: String,
+    },
+}
+
+impl Event {
+    /// How chatty an event is: 0 is violations and errors only, 1 adds calls and results,
+    /// 2 adds invariant state and notes, 3 adds permission tracing.
+    pub fn level(&self) -> u8 {
+        match self {
+            Event::Violation { .. } | Event::Error { .. } => 0,
+            Event::Call { .. } | Event::Result { .. } => 1,
+            Event::More { .. } | Event::Invariant { .. } | Event::Model { .. } => 2,
+            Event::Perm { .. } => 3,
+        }
+    }
+}
+
+/// The arguments of a call, in order. Each keeps its value, which is what goes into the JSON log,
+/// and how it reads in the text log.
+#[derive(Debug, Default)]
+pub struct Args(Vec<(&'static str, Value, String)>);
+
+impl Args {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, key: &'static str, value: Value, text: String) {
+        self.0.push((key, value, text))
+    }
+}
+
+impl std::fmt::Display for Args {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (key, _, text)) in self.0.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            write!(f, "{}{}={}", sep, key, text)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Args {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, value, _) in &self.0 {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+/// How a call argument goes into the JSON log: numbers stay numbers, names become strings.
+pub trait LogValue {
+    fn log_value(&self) -> Value;
+}
+
+macro_rules! log_value_from {
+    ($($t:ty),*) => {
+        $(impl LogValue for $t {
+            fn log_value(&self) -> Value {
+                Value::from(*self)
+            }
+        })*
+    };
+}
+
+log_value_from!(bool, u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl LogValue for str {
+    fn log_value(&self) -> Value {
+        Value::from(self)
+    }
+}
+
+impl LogValue for String {
+    fn log_value(&self) -> Value {
+        Value::from(self.as_str())
+    }
+}
+
+impl LogValue for Cow<'_, str> {
+    fn log_value(&self) -> Value {
+        Value::from(self.as_ref())
+    }
+}
+
+impl LogValue for OsStr {
+    fn log_value(&self) -> Value {
+        Value::from(self.to_string_lossy())
+    }
+}
+
+impl LogValue for OsString {
+    fn log_value(&self) -> Value {
+        self.as_os_str().log_value()
+    }
+}
+
+impl LogValue for Path {
+    fn log_value(&self) -> Value {
+        self.as_os_str().log_value()
+    }
+}
+
+impl LogValue for PathBuf {
+    fn log_value(&self) -> Value {
+        self.as_os_str().log_value()
+    }
+}
+
+impl<T: LogValue> LogValue for Option<T> {
+    fn log_value(&self) -> Value {
+        self.as_ref().map_or(Value::Null, T::log_value)
+    }
+}
+
+impl<T: LogValue + ?Sized> LogValue for &T {
+    fn log_value(&self) -> Value {
+        (**self).log_value()
+    }
+}
+
+/// Somewhere to send log events.
+pub trait LogSink: Send {
+    fn log(&mut self, event: &Event);
+}
+
+/// The human-readable format, one line per event.
+pub struct TextSink<W: Write + Send>(W);
+
+impl TextSink<std::io::Stdout> {
+    pub fn stdout() -> Self {
+        Self(std::io::stdout())
+    }
+}
+
+impl<W: Write + Send> TextSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self(writer)
+    }
+}
+
+impl<W: Write + Send> LogSink for TextSink<W> {
+    fn log(&mut self, event: &Event) {
+        _ = match event {
+            Event::Call { callid, op, args } => writeln!(self.0, "{}({}): {}", op, args, callid),
+            Event::More { callid, msg } => writeln!(self.0, " {} : {}", callid, msg),
+            Event::Invariant { callid, state } => {
+                writeln!(self.0, " {} : invariant={}", callid, state)
+            }
+            Event::Perm { msg, .. } => writeln!(self.0, "\t{}", msg),
+            Event::Result { callid, result, .. } => writeln!(self.0, " {} => {}", callid, result),
+            Event::Violation { callid, violation } => {
+                writeln!(self.0, " {} : VIOLATION: {}", callid, violation)
+            }
+            Event::Model { callid, diff } => writeln!(self.0, " {} : model: {}", callid, diff),
+            Event::Error { callid, msg } => writeln!(self.0, " {} : ERROR: {}", callid, msg),
+        };
+    }
+}
+
+/// One JSON object per line, flushed after every event so the log survives a crash.
+pub struct JsonSink<W: Write + Send>(LineWriter<W>);
+
+impl<W: Write + Send> JsonSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self(LineWriter::new(writer))
+    }
+}
+
+impl<W: Write + Send> LogSink for JsonSink<W> {
+    fn log(&mut self, event: &Event) {
+        if serde_json::to_writer(&mut self.0, event).is_ok() {
+            _ = self.0.write_all(b"\n");
+        }
+    }
+}
+
+/// Replace the sink all log events are written to.
+pub fn set_sink(sink: Box<dyn LogSink>) {
+    *SINK.lock().unwrap() = sink;
+}
+
+pub fn set_verbosity(level: u8) {
+    VERBOSITY.store(level, Ordering::SeqCst)
+}
+
+/// Whether events of `level` are logged, for those that are costly to put together.
+pub fn enabled(level: u8) -> bool {
+    level <= VERBOSITY.load(Ordering::SeqCst)
+}
+
+pub fn emit(event: Event) {
+    if enabled(event.level()) {
+        SINK.lock().unwrap().log(&event)
+    }
+}
+
+/// Extracts the errno from a call result, for the `errno` field of result events.
+pub trait LogErrno {
+    fn errno(&self) -> Option<i32>;
+}
+
+impl<T> LogErrno for Result<T, i32> {
+    fn errno(&self) -> Option<i32> {
+        self.as_ref().err().copied()
+    }
+}
+
+/// Log a call and return its id. Each argument is `key`, `key: "{fmt}"`, `key = value` or
+/// `key: "{fmt}" = value`, where the format is how it reads in the text log (`{}` by default).
 #[macro_export]
 macro_rules! log_call {
-    ($call:literal, $($arg:expr),* $(,)?) => {{
+    ($call:literal $(, $($arg:tt)*)?) => {{
         let id = $crate::logging::CALL_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-        println!("{}({}): {}",$call,format!($($arg,)*),id);
+        #[allow(unused_mut)]
+        let mut args = $crate::logging::Args::new();
+        $crate::log_args!(args; $($($arg)*)?);
+        $crate::logging::emit($crate::logging::Event::Call {
+            callid: id,
+            op: $call,
+            args,
+        });
         id
     }};
 }
 
+#[doc(hidden)]
+#[macro_export]
+macro_rules! log_args {
+    ($args:ident;) => {};
+    ($args:ident; $key:ident : $fmt:literal = $val:expr $(, $($rest:tt)*)?) => {
+        $args.push(
+            stringify!($key),
+            $crate::logging::LogValue::log_value(&$val),
+            format!($fmt, $val),
+        );
+        $crate::log_args!($args; $($($rest)*)?);
+    };
+    ($args:ident; $key:ident = $val:expr $(, $($rest:tt)*)?) => {
+        $crate::log_args!($args; $key: "{}" = $val $(, $($rest)*)?);
+    };
+    ($args:ident; $key:ident : $fmt:literal $(, $($rest:tt)*)?) => {
+        $crate::log_args!($args; $key: $fmt = $key $(, $($rest)*)?);
+    };
+    ($args:ident; $key:ident $(, $($rest:tt)*)?) => {
+        $crate::log_args!($args; $key: "{}" = $key $(, $($rest)*)?);
+    };
+}
+
 #[macro_export]
 macro_rules! log_more {
     ($callid: ident, $($arg:expr),* $(,)?) => {{
-        println!(" {} : {}",$callid,format!($($arg,)*));
+        $crate::logging::emit($crate::logging::Event::More {
+            callid: $callid,
+            msg: format!($($arg,)*),
+        });
+    }};
+}
+
+#[macro_export]
+macro_rules! log_err {
+    ($callid: ident, $($arg:expr),* $(,)?) => {{
+        $crate::logging::emit($crate::logging::Event::Error {
+            callid: $callid,
+            msg: format!($($arg,)*),
+        });
+    }};
+}
+
+#[macro_export]
+macro_rules! log_inv {
+    ($callid: ident, $inv: expr) => {{
+        $crate::logging::emit($crate::logging::Event::Invariant {
+            callid: $callid,
+            state: format!("{:?}", $inv),
+        });
+    }};
+}
+
+#[macro_export]
+macro_rules! log_perm {
+    ($callid: ident, $($arg:expr),* $(,)?) => {{
+        $crate::logging::emit($crate::logging::Event::Perm {
+            callid: $callid,
+            msg: format!($($arg,)*),
+        });
     }};
 }
 
 #[macro_export]
 macro_rules! log_res {
+    ($callid: ident, $fmt:literal, $res:ident $(,)?) => {{
+        $crate::logging::emit($crate::logging::Event::Result {
+            callid: $callid,
+            result: format!($fmt, $res),
+            errno: $crate::logging::LogErrno::errno(&$res),
+        });
+    }};
+    ($callid: ident, $fmt:literal, $res:ident.$method:ident() $(,)?) => {{
+        $crate::logging::emit($crate::logging::Event::Result {
+            callid: $callid,
+            result: format!($fmt, $res.$method()),
+            errno: $crate::logging::LogErrno::errno(&$res),
+        });
+    }};
     ($callid: ident, $($arg:expr),* $(,)?) => {{
-        println!(" {} => {}",$callid,format!($($arg,)*));
+        $crate::logging::emit($crate::logging::Event::Result {
+            callid: $callid,
+            result: format!($($arg,)*),
+            errno: None,
+        });
     }};
 }
 
@@ -59,3 +407,97 @@ macro_rules! log_acces {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::invariants::diff::{DiffKind, Difference};
+
+    use serde_json::Value;
+
+    use super::{Args, Event, JsonSink, LogSink, TextSink};
+
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buf {
+        fn string(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_text_sink() {
+        let buf = Buf::default();
+        let mut sink = TextSink::new(buf.clone());
+        let mut args = Args::new();
+        args.push("ino", Value::from(1), String::from("1"));
+        args.push("fh", Value::from(26), String::from("1a"));
+        sink.log(&Event::Call {
+            callid: 3,
+            op: "GETATTR",
+            args,
+        });
+        sink.log(&Event::Result {
+            callid: 3,
+            result: String::from("Err(2)"),
+            errno: Some(2),
+        });
+        sink.log(&Event::Error {
+            callid: 3,
+            msg: String::from("Failed to save"),
+        });
+        assert_eq!(
+            buf.string(),
+            "GETATTR(ino=1,fh=1a): 3\n 3 => Err(2)\n 3 : ERROR: Failed to save\n"
+        )
+    }
+
+    #[test]
+    fn test_json_sink() {
+        let buf = Buf::default();
+        let mut sink = JsonSink::new(buf.clone());
+        let mut args = Args::new();
+        args.push("ino", Value::from(1), String::from("1"));
+        args.push("name", Value::from("foo"), String::from("\"foo\""));
+        sink.log(&Event::Call {
+            callid: 3,
+            op: "LOOKUP",
+            args,
+        });
+        sink.log(&Event::Result {
+            callid: 3,
+            result: String::from("Err(2)"),
+            errno: Some(2),
+        });
+        sink.log(&Event::Model {
+            callid: 3,
+            diff: Difference {
+                kind: DiffKind::Data,
+                ino: 2,
+                path: None,
+                what: String::from("appeared"),
+            },
+        });
+        assert_eq!(
+            buf.string(),
+            concat!(
+                "{\"event\":\"call\",\"callid\":3,\"op\":\"LOOKUP\",",
+                "\"args\":{\"ino\":1,\"name\":\"foo\"}}\n",
+                "{\"event\":\"result\",\"callid\":3,\"result\":\"Err(2)\",\"errno\":2}\n",
+                "{\"event\":\"model\",\"callid\":3,\"diff\":",
+                "{\"kind\":\"Data\",\"ino\":2,\"path\":null,\"what\":\"appeared\"}}\n"
+            )
+        )
+    }
+}
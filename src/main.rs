@@ -1,21 +1,140 @@
-use std::path::PathBuf;
+use std::process::exit;
 
-use fuser::MountOption;
-use posix_invariant_checker::fs::InvFS;
+use posix_invariant_checker::{
+    cli::{CliError, Command, FsckOptions, LogFormat, USAGE},
+    dispatch::Dispatcher,
+    fs::InvFS,
+    fsck::{fsck, fsck_durable},
+    logging::{set_sink, set_verbosity, JsonSink, LogSink, TextSink},
+};
+
+#[cfg(not(tarpaulin_include))]
+fn run_fsck(opts: FsckOptions) -> ! {
+    let diffs = if opts.durable {
+        fsck_durable(&opts.base, &opts.snapshot, opts.checks)
+    } else {
+        fsck(&opts.base, &opts.snapshot, opts.checks)
+    };
+    let diffs = diffs.unwrap_or_else(|e| {
+        eprintln!("fsck failed: {}", e);
+        exit(2)
+    });
+    for d in &diffs {
+        if opts.json {
+            println!("{}", serde_json::to_string(d).unwrap());
+        } else {
+            println!("{}", d);
+        }
+    }
+    if !opts.json {
+        eprintln!("{} differences", diffs.len());
+    }
+    exit(if diffs.is_empty() { 0 } else { 1 })
+}
 
 #[cfg(not(tarpaulin_include))]
 fn main() {
-    let base = std::env::args_os().nth(1).expect("[base] [mountpoint]");
-    let mountpoint = std::env::args_os().nth(2).expect("[base] [mountpoint]");
+    let opts = match Command::parse(std::env::args_os().skip(1)) {
+        Ok(Command::Mount(v)) => v,
+        Ok(Command::Fsck(v)) => run_fsck(v),
+        Err(CliError::Help) => {
+            print!("{}", USAGE);
+            exit(0)
+        }
+        Err(CliError::Invalid(e)) => {
+            eprint!("{}\n\n{}", e, USAGE);
+            exit(2)
+        }
+    };
+
+    let sink: Box<dyn LogSink> = match (&opts.log_path, opts.log_format) {
+        (None, LogFormat::Text) => Box::new(TextSink::stdout()),
+        (None, LogFormat::Json) => Box::new(JsonSink::new(std::io::stdout())),
+        (Some(path), format) => {
+            let file = std::fs::File::create(path).unwrap_or_else(|e| {
+                eprintln!("Failed to create log file {:?}: {}", path, e);
+                exit(2)
+            });
+            match format {
+                LogFormat::Text => Box::new(TextSink::new(file)),
+                LogFormat::Json => Box::new(JsonSink::new(file)),
+            }
+        }
+    };
+    set_sink(sink);
+    set_verbosity(opts.verbosity);
+
+    if !opts.base.is_dir() {
+        eprintln!("{:?} is not a directory", opts.base);
+        exit(2)
+    }
+
+    let fs = InvFS::new(opts.base)
+        .with_policy(opts.policy)
+        .with_snapshot(opts.snapshot)
+        .with_checks(opts.checks);
+    let violations = fs.violation_log();
+
+    // Block SIGINT and SIGTERM before any worker thread exists, so that they all inherit the
+    // mask and the signals can only be picked up by the waiter below. It unmounts instead of
+    // letting the process die, so that the session ends through destroy and the model is saved.
+    // SIGUSR1 asks it to reload the checks instead, if there is a file to reload them from.
+    let signals = unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        if opts.checks_file.is_some() {
+            libc::sigaddset(&mut set, libc::SIGUSR1);
+        }
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        set
+    };
+
+    let dispatcher = Dispatcher::new(fs, opts.threads);
+    let fs = dispatcher.fs();
+    let mut session = fuser::Session::new(dispatcher, &opts.mountpoint, &opts.mount_options)
+        .unwrap_or_else(|e| {
+            eprintln!("Mount failed: {}", e);
+            exit(2)
+        });
+
+    let mut unmounter = session.unmount_callable();
+    let checks_file = opts.checks_file;
+    std::thread::spawn(move || {
+        let mut sig = 0;
+        loop {
+            unsafe { libc::sigwait(&signals, &mut sig) };
+            match (sig, &checks_file) {
+                (libc::SIGUSR1, Some(path)) => match fs.reload_checks(path) {
+                    Ok(v) => eprintln!("Switched to checks {:?}", v),
+                    Err(e) => eprintln!("Failed to reload the checks: {}", e),
+                },
+                _ => break,
+            }
+        }
+        eprintln!("Caught signal {}, unmounting", sig);
+        if let Err(e) = unmounter.unmount() {
+            eprintln!("Unmount failed: {}", e);
+        }
+    });
 
-    fuser::mount2(
-        InvFS::new(PathBuf::from(base)),
-        mountpoint,
-        &[MountOption::AutoUnmount, MountOption::AllowOther],
-    )
-    .unwrap();
+    let res = session.run();
+    // Dropping the session destroys the filesystem, which checkpoints the model.
+    drop(session);
+    if let Err(e) = res {
+        eprintln!("Session failed: {}", e);
+        exit(2)
+    }
 
-    //store_prev_contents();
+    eprintln!("Unmounted");
 
-    println!("Unmounted")
+    let violations = violations.lock().unwrap();
+    if !violations.is_empty() {
+        eprintln!("{} violations:", violations.len());
+        for v in violations.iter() {
+            eprintln!("  {}", v);
+        }
+        exit(1)
+    }
 }
@@ -1,8 +1,15 @@
-use std::{fmt::Debug, sync::Mutex, time::Duration};
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::Debug,
+    sync::Mutex,
+    time::Duration,
+};
 
-use fuser::FileAttr;
+use fuser::{FileAttr, FileType};
 use once_cell::sync::OnceCell;
 
+use crate::logging::LogValue;
+
 pub struct Request {
     pub uid: u32,
     pub gid: u32,
@@ -40,6 +47,20 @@ impl<'a> KernelConfig<'a> {
     pub fn empty() -> Self {
         Self(None)
     }
+    /// Ask for `capabilities`, see [`fuser::KernelConfig::add_capabilities`].
+    /// Always succeeds without a kernel to ask.
+    pub fn add_capabilities(&self, capabilities: u32) -> Result<(), u32> {
+        match &self.0 {
+            Some(v) => v.lock().unwrap().add_capabilities(capabilities),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> LogValue for KernelConfig<'a> {
+    fn log_value(&self) -> serde_json::Value {
+        serde_json::Value::from(format!("{:?}", self))
+    }
 }
 
 impl<'a> Debug for KernelConfig<'a> {
@@ -158,6 +179,81 @@ impl ReplyWrite {
     }
 }
 
+type ReplyLseekOK = i64;
+
+pub struct ReplyLseek(OnceCell<Result<ReplyLseekOK, i32>>);
+
+impl ReplyLseek {
+    pub fn new() -> Self {
+        Self(OnceCell::new())
+    }
+    pub fn offset(&self, offset: i64) {
+        self.0.set(Ok(offset)).unwrap();
+    }
+    pub fn error(&self, e: i32) {
+        self.0.set(Err(e)).unwrap()
+    }
+    pub fn get(&self) -> Result<ReplyLseekOK, i32> {
+        *self.0.get().unwrap()
+    }
+    pub fn reply(&self, rep: fuser::ReplyLseek) {
+        match self.0.get().unwrap() {
+            Ok(offset) => rep.offset(*offset),
+            Err(e) => rep.error(*e),
+        }
+    }
+}
+
+type ReplyIoctlOK = (i32, Vec<u8>);
+
+pub struct ReplyIoctl(OnceCell<Result<ReplyIoctlOK, i32>>);
+
+impl ReplyIoctl {
+    pub fn new() -> Self {
+        Self(OnceCell::new())
+    }
+    pub fn ioctl(&self, result: i32, data: &[u8]) {
+        self.0.set(Ok((result, data.to_vec()))).unwrap();
+    }
+    pub fn error(&self, e: i32) {
+        self.0.set(Err(e)).unwrap()
+    }
+    pub fn get(&self) -> Result<ReplyIoctlOK, i32> {
+        self.0.get().unwrap().clone()
+    }
+    pub fn reply(&self, rep: fuser::ReplyIoctl) {
+        match self.0.get().unwrap() {
+            Ok((result, data)) => rep.ioctl(*result, data),
+            Err(e) => rep.error(*e),
+        }
+    }
+}
+
+type ReplyLockOK = (u64, u64, i32, u32);
+
+pub struct ReplyLock(OnceCell<Result<ReplyLockOK, i32>>);
+
+impl ReplyLock {
+    pub fn new() -> Self {
+        Self(OnceCell::new())
+    }
+    pub fn locked(&self, start: u64, end: u64, typ: i32, pid: u32) {
+        self.0.set(Ok((start, end, typ, pid))).unwrap();
+    }
+    pub fn error(&self, e: i32) {
+        self.0.set(Err(e)).unwrap()
+    }
+    pub fn get(&self) -> Result<ReplyLockOK, i32> {
+        *self.0.get().unwrap()
+    }
+    pub fn reply(&self, rep: fuser::ReplyLock) {
+        match self.0.get().unwrap() {
+            Ok((start, end, typ, pid)) => rep.locked(*start, *end, *typ, *pid),
+            Err(e) => rep.error(*e),
+        }
+    }
+}
+
 type ReplyOpenOK = (u64, u32);
 
 pub struct ReplyOpen(OnceCell<Result<ReplyOpenOK, i32>>);
@@ -208,6 +304,174 @@ impl ReplyData {
     }
 }
 
+type ReplyDirectoryOK = Vec<(u64, i64, FileType, OsString)>;
+
+/// What the kernel asks a READDIR for. fuser doesn't pass the size on, so entries are counted
+/// against this the way fuser fills its buffer, which the real one is never smaller than.
+const READDIR_SIZE: usize = 4096;
+
+pub struct ReplyDirectory {
+    entries: Mutex<(usize, ReplyDirectoryOK)>,
+    res: OnceCell<Result<(), i32>>,
+}
+
+impl ReplyDirectory {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new((0, Vec::new())),
+            res: OnceCell::new(),
+        }
+    }
+    /// Add an entry, unless the reply is full. Returns whether it was.
+    #[must_use]
+    pub fn add(&self, ino: u64, offset: i64, kind: FileType, name: &OsStr) -> bool {
+        // A `fuse_dirent` header and the name, padded to 8 bytes.
+        let size = (24 + name.len() + 7) & !7;
+        let mut entries = self.entries.lock().unwrap();
+        if entries.0 + size > READDIR_SIZE {
+            return true;
+        }
+        entries.0 += size;
+        entries.1.push((ino, offset, kind, name.to_os_string()));
+        false
+    }
+    pub fn ok(&self) {
+        self.res.set(Ok(())).unwrap();
+    }
+    pub fn error(&self, e: i32) {
+        self.res.set(Err(e)).unwrap()
+    }
+    pub fn get(&self) -> Result<ReplyDirectoryOK, i32> {
+        self.res
+            .get()
+            .unwrap()
+            .map(|()| self.entries.lock().unwrap().1.clone())
+    }
+    pub fn reply(&self, mut rep: fuser::ReplyDirectory) {
+        match self.res.get().unwrap() {
+            Ok(()) => {
+                for (ino, offset, kind, name) in &self.entries.lock().unwrap().1 {
+                    assert!(
+                        !rep.add(*ino, *offset, *kind, name),
+                        "READDIR reply smaller than {} bytes",
+                        READDIR_SIZE
+                    );
+                }
+                rep.ok()
+            }
+            Err(e) => rep.error(*e),
+        }
+    }
+}
+
+type ReplyDirectoryPlusOK = Vec<(u64, i64, OsString, Duration, FileAttr, u64)>;
+
+pub struct ReplyDirectoryPlus {
+    entries: Mutex<(usize, ReplyDirectoryPlusOK)>,
+    res: OnceCell<Result<(), i32>>,
+}
+
+impl ReplyDirectoryPlus {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new((0, Vec::new())),
+            res: OnceCell::new(),
+        }
+    }
+    fn size(name: &OsStr) -> usize {
+        // A `fuse_entry_out`, a `fuse_dirent` header and the name, padded to 8 bytes.
+        (128 + 24 + name.len() + 7) & !7
+    }
+    /// Whether an entry named `name` no longer fits in the reply.
+    pub fn full(&self, name: &OsStr) -> bool {
+        self.entries.lock().unwrap().0 + Self::size(name) > READDIR_SIZE
+    }
+    /// Add an entry, unless the reply is full. Returns whether it was.
+    #[must_use]
+    pub fn add(
+        &self,
+        ino: u64,
+        offset: i64,
+        name: &OsStr,
+        ttl: Duration,
+        attr: FileAttr,
+        generation: u64,
+    ) -> bool {
+        if self.full(name) {
+            return true;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.0 += Self::size(name);
+        entries
+            .1
+            .push((ino, offset, name.to_os_string(), ttl, attr, generation));
+        false
+    }
+    pub fn ok(&self) {
+        self.res.set(Ok(())).unwrap();
+    }
+    pub fn error(&self, e: i32) {
+        self.res.set(Err(e)).unwrap()
+    }
+    pub fn get(&self) -> Result<ReplyDirectoryPlusOK, i32> {
+        self.res
+            .get()
+            .unwrap()
+            .map(|()| self.entries.lock().unwrap().1.clone())
+    }
+    pub fn reply(&self, mut rep: fuser::ReplyDirectoryPlus) {
+        match self.res.get().unwrap() {
+            Ok(()) => {
+                for (ino, offset, name, ttl, attr, generation) in &self.entries.lock().unwrap().1 {
+                    assert!(
+                        !rep.add(*ino, *offset, name, ttl, attr, *generation),
+                        "READDIRPLUS reply smaller than {} bytes",
+                        READDIR_SIZE
+                    );
+                }
+                rep.ok()
+            }
+            Err(e) => rep.error(*e),
+        }
+    }
+}
+
+/// A getxattr or listxattr reply: the size a zero-sized request asks for, or the data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Xattr {
+    Size(u32),
+    Data(Vec<u8>),
+}
+
+type ReplyXattrOK = Xattr;
+
+pub struct ReplyXattr(OnceCell<Result<ReplyXattrOK, i32>>);
+
+impl ReplyXattr {
+    pub fn new() -> Self {
+        Self(OnceCell::new())
+    }
+    pub fn size(&self, size: u32) {
+        self.0.set(Ok(Xattr::Size(size))).unwrap();
+    }
+    pub fn data(&self, data: &[u8]) {
+        self.0.set(Ok(Xattr::Data(data.to_vec()))).unwrap();
+    }
+    pub fn error(&self, e: i32) {
+        self.0.set(Err(e)).unwrap()
+    }
+    pub fn get(&self) -> Result<ReplyXattrOK, i32> {
+        self.0.get().unwrap().clone()
+    }
+    pub fn reply(&self, rep: fuser::ReplyXattr) {
+        match self.0.get().unwrap() {
+            Ok(Xattr::Size(size)) => rep.size(*size),
+            Ok(Xattr::Data(data)) => rep.data(data),
+            Err(e) => rep.error(*e),
+        }
+    }
+}
+
 type ReplyEmptyOK = ();
 
 pub struct ReplyEmpty(OnceCell<Result<ReplyEmptyOK, i32>>);
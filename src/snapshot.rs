@@ -0,0 +1,483 @@
+//! The model, saved between mounts.
+//!
+//! A snapshot is one file: a magic string, a format version and a section count, followed by one
+//! section per part of the model. Every section carries a tag, its length and a CRC-32 of its
+//! payload, so a truncated or corrupted snapshot is refused rather than half-loaded.
+//!
+//! The durability record (see [`crate::invariants::durable`]) uses the same container.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::Write,
+    os::unix::prelude::{OsStrExt, OsStringExt},
+    path::Path,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use stfu8::{decode_u8, encode_u8};
+
+use crate::{
+    inode_mapper::InodeMapper,
+    invariants::{
+        durable::{Change, Durable},
+        Checks, FSData,
+    },
+};
+
+pub const MAGIC: &[u8; 8] = b"PICSNAP\0";
+pub const VERSION: u32 = 1;
+
+const PATH: &[u8; 4] = b"PATH";
+const META: &[u8; 4] = b"META";
+const DIRS: &[u8; 4] = b"DIRS";
+const DATA: &[u8; 4] = b"DATA";
+const XATTR: &[u8; 4] = b"XATR";
+const DURABLE: &[u8; 4] = b"DURB";
+const CHANGE: &[u8; 4] = b"DURC";
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    /// Not a snapshot at all.
+    BadMagic,
+    /// Written by a different version of the checker.
+    Version(u32),
+    /// The file ends before the header says it should.
+    Truncated,
+    Checksum(String),
+    Corrupt(String, String),
+    /// The snapshot was taken with a check category off that is now on.
+    Missing(&'static str),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::BadMagic => write!(f, "not a snapshot file"),
+            SnapshotError::Version(v) => write!(
+                f,
+                "snapshot format version {} is not supported (expected {})",
+                v, VERSION
+            ),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Checksum(s) => write!(f, "checksum mismatch in section {}", s),
+            SnapshotError::Corrupt(s, e) => write!(f, "section {} is corrupt: {}", s, e),
+            SnapshotError::Missing(s) => write!(
+                f,
+                "snapshot has no {} section; it was taken with those checks off",
+                s
+            ),
+        }
+    }
+}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// CRC-32 (IEEE), as used by zlib and friends.
+pub fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in buf {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn tag_name(tag: &[u8; 4]) -> String {
+    String::from_utf8_lossy(tag).into_owned()
+}
+
+fn ron_section<T: Serialize>(v: &T) -> Vec<u8> {
+    ron::ser::to_string_pretty(v, ron::ser::PrettyConfig::default())
+        .expect("Failed to serialize model")
+        .into_bytes()
+}
+
+fn from_ron<T: DeserializeOwned>(tag: &[u8; 4], buf: &[u8]) -> Result<T, SnapshotError> {
+    let s = std::str::from_utf8(buf)
+        .map_err(|e| SnapshotError::Corrupt(tag_name(tag), e.to_string()))?;
+    ron::from_str(s).map_err(|e| SnapshotError::Corrupt(tag_name(tag), e.to_string()))
+}
+
+/// Serialize the parts of the model enabled in `fs_data.checks`.
+pub fn encode(fs_data: &FSData) -> Vec<u8> {
+    let checks = fs_data.checks;
+    let mut sections: Vec<(&[u8; 4], Vec<u8>)> =
+        vec![(PATH, ron_section(&fs_data.INV_INODE_PATHS.store()))];
+    if checks.meta {
+        sections.push((META, ron_section(&fs_data.INV_INODE_CONTENTS)));
+    }
+    if checks.dirs {
+        // Names aren't necessarily UTF-8, and RON wants strings.
+        let dirs: BTreeMap<u64, BTreeMap<String, u64>> = fs_data
+            .INV_DIR_CONTENTS
+            .iter()
+            .map(|(k, v)| {
+                (
+                    *k,
+                    v.iter()
+                        .map(|(k, v)| (encode_u8(k.as_bytes()), *v))
+                        .collect(),
+                )
+            })
+            .collect();
+        sections.push((DIRS, ron_section(&dirs)));
+    }
+    if checks.data {
+        let mut data = vec![];
+        for (ino, contents) in &fs_data.INV_FILE_CONTENTS {
+            data.extend_from_slice(&ino.to_le_bytes());
+            data.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+            data.extend_from_slice(contents);
+        }
+        sections.push((DATA, data));
+    }
+    if checks.xattr {
+        sections.push((XATTR, ron_section(&fs_data.INV_XATTR_CONTENTS)));
+    }
+
+    container(sections)
+}
+
+fn container(sections: Vec<(&[u8; 4], Vec<u8>)>) -> Vec<u8> {
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+    buf.extend_from_slice(&(sections.len() as u32).to_le_bytes());
+    for (tag, payload) in sections {
+        buf.extend_from_slice(&frame(tag, &payload));
+    }
+    buf
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.0.len() < n {
+            return Err(SnapshotError::Truncated);
+        }
+        let (v, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(v)
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> Result<usize, SnapshotError> {
+        self.u64()?.try_into().map_err(|_| SnapshotError::Truncated)
+    }
+}
+
+/// A section's tag and payload.
+type Section<'a> = ([u8; 4], &'a [u8]);
+
+fn frame(tag: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut buf = tag.to_vec();
+    buf.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    buf.extend_from_slice(&crc32(payload).to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+fn section<'a>(r: &mut Reader<'a>) -> Result<Section<'a>, SnapshotError> {
+    let tag: [u8; 4] = r.take(4)?.try_into().unwrap();
+    let len = r.len()?;
+    let crc = r.u32()?;
+    let payload = r.take(len)?;
+    if crc32(payload) != crc {
+        return Err(SnapshotError::Checksum(tag_name(&tag)));
+    }
+    Ok((tag, payload))
+}
+
+/// Check the header and read the sections it announces, leaving `r` after the last one.
+fn header_sections<'a>(r: &mut Reader<'a>) -> Result<Vec<Section<'a>>, SnapshotError> {
+    if r.take(MAGIC.len()).map_err(|_| SnapshotError::BadMagic)? != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let version = r.u32()?;
+    if version != VERSION {
+        return Err(SnapshotError::Version(version));
+    }
+    let count = r.u32()?;
+    (0..count).map(|_| section(r)).collect()
+}
+
+/// Split a file into its sections, checking the header and every checksum on the way.
+fn sections(buf: &[u8]) -> Result<Vec<Section<'_>>, SnapshotError> {
+    let mut r = Reader(buf);
+    let sections = header_sections(&mut r)?;
+    if !r.0.is_empty() {
+        return Err(SnapshotError::Corrupt(
+            String::from("trailer"),
+            String::from("unexpected data after the last section"),
+        ));
+    }
+    Ok(sections)
+}
+
+/// Parse a snapshot. `checks` on the result says which sections it had.
+pub fn decode(buf: &[u8]) -> Result<FSData, SnapshotError> {
+    let mut fs_data = FSData::new();
+    fs_data.checks = Checks::none();
+    let mut have_paths = false;
+    for (tag, payload) in sections(buf)? {
+        match &tag {
+            PATH => {
+                fs_data.INV_INODE_PATHS = InodeMapper::load(from_ron(&tag, payload)?);
+                have_paths = true;
+            }
+            META => {
+                fs_data.INV_INODE_CONTENTS = from_ron(&tag, payload)?;
+                fs_data.checks.meta = true;
+            }
+            DIRS => {
+                let dirs: BTreeMap<u64, BTreeMap<String, u64>> = from_ron(&tag, payload)?;
+                let mut dc = BTreeMap::new();
+                for (ino, entries) in dirs {
+                    let mut d = BTreeMap::new();
+                    for (name, child) in entries {
+                        let name = decode_u8(&name)
+                            .map_err(|e| SnapshotError::Corrupt(tag_name(&tag), e.to_string()))?;
+                        d.insert(OsString::from_vec(name), child);
+                    }
+                    dc.insert(ino, d);
+                }
+                fs_data.INV_DIR_CONTENTS = dc;
+                fs_data.checks.dirs = true;
+            }
+            DATA => {
+                let mut d = Reader(payload);
+                while !d.0.is_empty() {
+                    let ino = d.u64()?;
+                    let len = d.len()?;
+                    fs_data.INV_FILE_CONTENTS.insert(ino, d.take(len)?.to_vec());
+                }
+                fs_data.checks.data = true;
+            }
+            XATTR => {
+                fs_data.INV_XATTR_CONTENTS = from_ron(&tag, payload)?;
+                fs_data.checks.xattr = true;
+            }
+            _ => {
+                return Err(SnapshotError::Corrupt(
+                    tag_name(&tag),
+                    String::from("unknown section"),
+                ))
+            }
+        }
+    }
+    if !have_paths {
+        return Err(SnapshotError::Missing("PATH"));
+    }
+    Ok(fs_data)
+}
+
+/// Read a snapshot, or `None` if there isn't one at `path`.
+pub fn read(path: &Path) -> Result<Option<FSData>, SnapshotError> {
+    match std::fs::read(path) {
+        Ok(buf) => decode(&buf).map(Some),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Write `buf` next to `path` and rename it into place, so a crash never leaves a partial file.
+fn write_atomic(path: &Path, buf: &[u8]) -> Result<(), SnapshotError> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut f = File::create(&tmp)?;
+    f.write_all(buf)?;
+    f.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Save the model to `path`.
+pub fn save(fs_data: &FSData, path: &Path) -> Result<(), SnapshotError> {
+    write_atomic(path, &encode(fs_data))
+}
+
+/// Serialize a durability record. It uses the snapshot container with a single section, which
+/// changes are appended to one section each, see [`append_durable`].
+pub fn encode_durable(durable: &Durable) -> Vec<u8> {
+    container(vec![(DURABLE, ron_section(durable))])
+}
+
+/// Parse a durability record and replay the changes appended to it. A change cut short by a
+/// crash was never synced, so whatever follows the last complete one is ignored.
+pub fn decode_durable(buf: &[u8]) -> Result<Durable, SnapshotError> {
+    let mut r = Reader(buf);
+    let mut durable: Durable = match header_sections(&mut r)?.as_slice() {
+        [(tag, payload)] if tag == DURABLE => from_ron(tag, payload)?,
+        _ => return Err(SnapshotError::Missing("DURB")),
+    };
+    while !r.0.is_empty() {
+        match section(&mut r) {
+            Ok((tag, payload)) if &tag == CHANGE => durable.apply(from_ron(&tag, payload)?),
+            Ok((tag, _)) => {
+                return Err(SnapshotError::Corrupt(
+                    tag_name(&tag),
+                    String::from("unknown section"),
+                ))
+            }
+            Err(SnapshotError::Truncated) | Err(SnapshotError::Checksum(_)) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(durable)
+}
+
+/// Read a durability record, or `None` if there isn't one at `path`.
+pub fn read_durable(path: &Path) -> Result<Option<Durable>, SnapshotError> {
+    match std::fs::read(path) {
+        Ok(buf) => decode_durable(&buf).map(Some),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Rewrite the durability record at `path` as a whole. Returns its size.
+pub fn save_durable(durable: &Durable, path: &Path) -> Result<usize, SnapshotError> {
+    let buf = encode_durable(durable);
+    write_atomic(path, &buf)?;
+    Ok(buf.len())
+}
+
+/// Append one change to the durability record at `path`, and sync it. Returns how much that
+/// added to the record.
+pub fn append_durable(change: &Change, path: &Path) -> Result<usize, SnapshotError> {
+    let buf = frame(CHANGE, &ron_section(change));
+    let mut f = OpenOptions::new().append(true).open(path)?;
+    f.write_all(&buf)?;
+    f.sync_data()?;
+    Ok(buf.len())
+}
+
+/// Make sure a loaded snapshot has a section for every category in `checks`.
+pub fn covers(prev: &FSData, checks: Checks) -> Result<(), SnapshotError> {
+    let missing = checks.added(prev.checks);
+    if missing.meta {
+        return Err(SnapshotError::Missing("META"));
+    }
+    if missing.dirs {
+        return Err(SnapshotError::Missing("DIRS"));
+    }
+    if missing.data {
+        return Err(SnapshotError::Missing("DATA"));
+    }
+    if missing.xattr {
+        return Err(SnapshotError::Missing("XATR"));
+    }
+    Ok(())
+}
+
+/// Read the snapshot at `path`, making sure it covers every category in `checks`, and remove it,
+/// so a crash before the next save can't bring back a stale model.
+pub fn take(path: &Path, checks: Checks) -> Result<Option<FSData>, SnapshotError> {
+    let prev = match read(path)? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    covers(&prev, checks)?;
+    std::fs::remove_file(path)?;
+    Ok(Some(prev))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, path::PathBuf};
+
+    use maplit::btreemap;
+
+    use crate::invariants::{Checks, FSData};
+
+    use super::{crc32, decode, encode, take, SnapshotError, MAGIC};
+
+    fn model() -> FSData {
+        let mut fs_data = FSData::new();
+        fs_data
+            .INV_INODE_PATHS
+            .insert(2, PathBuf::from("/base/foo"));
+        fs_data.INV_DIR_CONTENTS = btreemap! {
+            1 => btreemap! { OsString::from("foo") => 2 },
+        };
+        fs_data.INV_FILE_CONTENTS = btreemap! { 2 => b"foo".to_vec() };
+        fs_data
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let fs_data = model();
+        let prev = decode(&encode(&fs_data)).unwrap();
+        assert_eq!(prev.checks, Checks::default());
+        assert_eq!(
+            prev.INV_INODE_PATHS.store(),
+            fs_data.INV_INODE_PATHS.store()
+        );
+        assert_eq!(prev.INV_DIR_CONTENTS, fs_data.INV_DIR_CONTENTS);
+        assert_eq!(prev.INV_FILE_CONTENTS, fs_data.INV_FILE_CONTENTS);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let buf = encode(&model());
+        assert!(matches!(decode(b"garbage"), Err(SnapshotError::BadMagic)));
+        assert!(matches!(
+            decode(&buf[..buf.len() - 1]),
+            Err(SnapshotError::Truncated)
+        ));
+        let mut flipped = buf.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(decode(&flipped), Err(SnapshotError::Checksum(_))));
+        let mut old = buf;
+        old[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(decode(&old), Err(SnapshotError::Version(0))));
+    }
+
+    #[test]
+    fn test_missing_section() {
+        let mut fs_data = model();
+        fs_data.checks = Checks::parse("meta").unwrap();
+        let path: PathBuf = std::env::var("PIC_TEST_PATH")
+            .expect("Set PIC_TEST_PATH to an empty directory")
+            .into();
+        let path = path.join(format!("{}.snapshot", uuid::Uuid::new_v4()));
+        std::fs::write(&path, encode(&fs_data)).unwrap();
+        assert!(matches!(
+            take(&path, Checks::default()),
+            Err(SnapshotError::Missing("DIRS"))
+        ));
+        let prev = take(&path, Checks::parse("meta").unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(prev.checks, Checks::parse("meta").unwrap());
+        assert!(!path.exists());
+        assert!(take(&path, Checks::default()).unwrap().is_none());
+    }
+}
@@ -1,12 +1,46 @@
-use std::path::PathBuf;
+use std::{ffi::OsStr, path::PathBuf};
 
-use crate::fs::InvFS;
+use crate::{
+    fs::InvFS,
+    req_rep::{KernelConfig, ReplyCreate, ReplyOpen, Request},
+};
 
-pub fn create_ifs() -> InvFS {
+/// A request from root, with every capability.
+pub const ROOT: Request = Request {
+    uid: 0,
+    gid: 0,
+    pid: 0,
+};
+
+pub fn tempdir() -> PathBuf {
     let path: PathBuf = std::env::var("PIC_TEST_PATH")
         .expect("Set PIC_TEST_PATH to an empty directory")
         .into();
     let tempdir = path.join(uuid::Uuid::new_v4().to_string());
     std::fs::create_dir(tempdir.clone()).unwrap();
-    InvFS::new(tempdir)
+    tempdir
+}
+
+pub fn create_ifs() -> InvFS {
+    let tempdir = tempdir();
+    InvFS::new(tempdir.clone()).with_snapshot(tempdir.with_extension("snapshot"))
+}
+
+/// Mount `ifs`, as the kernel's INIT does.
+pub fn init(ifs: &InvFS) {
+    ifs.do_init(ROOT, &KernelConfig::empty()).unwrap();
+}
+
+/// Create the file `name` in the root directory as root, and return its inode.
+pub fn create(ifs: &InvFS, name: &str, mode: u32) -> u64 {
+    let rep = ReplyCreate::new();
+    ifs.do_create(ROOT, 1, OsStr::new(name), mode, 0, libc::O_CREAT, &rep);
+    rep.get().unwrap().1.ino
+}
+
+/// Open `ino` as root, and return the handle.
+pub fn open(ifs: &InvFS, ino: u64, flags: i32) -> u64 {
+    let rep = ReplyOpen::new();
+    ifs.do_open(ROOT, ino, flags, &rep);
+    rep.get().unwrap().0
 }